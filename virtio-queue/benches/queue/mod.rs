@@ -79,9 +79,44 @@ pub fn benchmark_queue(c: &mut Criterion) {
         );
     }
 
+    // Walking the chain reads four descriptors at a time instead of one.
+    bench_queue(
+        c,
+        "single chain (prefetch)",
+        || {
+            let mut q = queue_with_chains(1, 128, false);
+            q.set_descriptor_prefetch(true);
+            q
+        },
+        |mut q| {
+            let (num_chains, num_descriptors) = walk_queue(&mut q, &mem);
+            assert_eq!(num_chains, 1);
+            assert_eq!(num_descriptors, 128);
+        },
+    );
+
+    // Same workload as "multiple chains (indirect=false)", without walking the chains.
+    bench_queue(
+        c,
+        "single descriptors (pop_single_descriptor)",
+        || queue_with_chains(128, 1, false),
+        |mut q| {
+            let mut num_descriptors = 0;
+            while q.pop_single_descriptor(&mem).unwrap().is_some() {
+                num_descriptors += 1;
+            }
+            assert_eq!(num_descriptors, 128);
+        },
+    );
+
     bench_queue(c, "add used", empty_queue, |mut q| {
         for _ in 0..128 {
             q.add_used(&mem, 123, 0x1000).unwrap();
         }
     });
+
+    bench_queue(c, "add used batch", empty_queue, |mut q| {
+        q.add_used_batch(&mem, (0..128).map(|_| (123, 0x1000)))
+            .unwrap();
+    });
 }
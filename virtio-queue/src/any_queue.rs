@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use vm_memory::{GuestAddress, GuestMemory};
+
+use crate::{Error, PackedQueue, Queue, QueueCommonT, QueueT, RingLayout};
+
+/// A virtio queue using either the split or the packed layout.
+///
+/// Which layout a queue uses depends on whether `VIRTIO_F_RING_PACKED` was negotiated, which is
+/// only known at runtime. [`create_queue`] picks the right one, and the [`QueueCommonT`]
+/// implementation forwards the methods shared by both layouts to the wrapped queue. Processing
+/// the queue is specific to each layout, and goes through the wrapped queue directly.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AnyQueue {
+    /// A split virtqueue.
+    Split(Queue),
+    /// A packed virtqueue.
+    Packed(PackedQueue),
+}
+
+/// Create a virtio queue with the given `max_size`, using the packed layout if `packed` is
+/// `true` (i.e. `VIRTIO_F_RING_PACKED` was negotiated), and the split one otherwise.
+///
+/// Returns an error if `max_size` is invalid.
+pub fn create_queue(max_size: u16, packed: bool) -> Result<AnyQueue, Error> {
+    if packed {
+        PackedQueue::new(max_size).map(AnyQueue::Packed)
+    } else {
+        Queue::new(max_size).map(AnyQueue::Split)
+    }
+}
+
+impl AnyQueue {
+    /// Check whether the queue uses the packed layout.
+    pub fn is_packed(&self) -> bool {
+        matches!(self, AnyQueue::Packed(_))
+    }
+}
+
+// Forward a `QueueCommonT` method call to the wrapped queue.
+macro_rules! dispatch {
+    ($self:ident, $method:ident($($arg:expr),*)) => {
+        match $self {
+            AnyQueue::Split(q) => QueueCommonT::$method(q, $($arg),*),
+            AnyQueue::Packed(q) => QueueCommonT::$method(q, $($arg),*),
+        }
+    };
+}
+
+impl QueueCommonT for AnyQueue {
+    fn layout(&self) -> RingLayout {
+        dispatch!(self, layout())
+    }
+
+    fn is_valid<M: GuestMemory>(&self, mem: &M) -> bool {
+        dispatch!(self, is_valid(mem))
+    }
+
+    fn reset(&mut self) {
+        dispatch!(self, reset())
+    }
+
+    fn max_size(&self) -> u16 {
+        dispatch!(self, max_size())
+    }
+
+    fn size(&self) -> u16 {
+        dispatch!(self, size())
+    }
+
+    fn try_set_size(&mut self, size: u16) -> Result<(), Error> {
+        dispatch!(self, try_set_size(size))
+    }
+
+    fn ready(&self) -> bool {
+        dispatch!(self, ready())
+    }
+
+    fn set_ready(&mut self, ready: bool) {
+        dispatch!(self, set_ready(ready))
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        dispatch!(self, set_event_idx(enabled))
+    }
+
+    fn event_idx_enabled(&self) -> bool {
+        dispatch!(self, event_idx_enabled())
+    }
+
+    fn try_set_desc_table_address(&mut self, desc_table: GuestAddress) -> Result<(), Error> {
+        dispatch!(self, try_set_desc_table_address(desc_table))
+    }
+
+    fn add_used<M: GuestMemory>(&mut self, mem: &M, id: u16, len: u32) -> Result<(), Error> {
+        dispatch!(self, add_used(mem, id, len))
+    }
+
+    fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        dispatch!(self, enable_notification(mem))
+    }
+
+    fn disable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<(), Error> {
+        dispatch!(self, disable_notification(mem))
+    }
+
+    fn needs_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        dispatch!(self, needs_notification(mem))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestMemoryMmap;
+
+    #[test]
+    fn test_create_queue() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        for packed in [false, true] {
+            let mut q = create_queue(256, packed).unwrap();
+            assert_eq!(q.is_packed(), packed);
+            match &q {
+                AnyQueue::Split(q) => {
+                    assert!(!packed);
+                    assert_eq!(QueueT::layout(q), RingLayout::Split);
+                }
+                AnyQueue::Packed(q) => {
+                    assert!(packed);
+                    assert_eq!(QueueCommonT::layout(q), RingLayout::Packed);
+                }
+            }
+            assert_eq!(q.layout() == RingLayout::Packed, packed);
+
+            assert_eq!(q.max_size(), 256);
+            assert_eq!(q.size(), 256);
+            q.try_set_size(16).unwrap();
+            assert_eq!(q.size(), 16);
+            assert_eq!(q.try_set_size(3).unwrap_err(), Error::InvalidSize);
+            q.set_event_idx(true);
+            assert!(q.event_idx_enabled());
+            assert_eq!(
+                q.try_set_desc_table_address(GuestAddress(0x1001))
+                    .unwrap_err(),
+                Error::InvalidDescTableAlign
+            );
+            q.try_set_desc_table_address(GuestAddress(0x1000)).unwrap();
+            if let AnyQueue::Split(q) = &mut q {
+                // The rings must not overlap the descriptor table.
+                q.try_set_avail_ring_address(GuestAddress(0x2000)).unwrap();
+                q.try_set_used_ring_address(GuestAddress(0x3000)).unwrap();
+            }
+
+            assert!(!q.is_valid(m));
+            q.set_ready(true);
+            assert!(q.ready());
+            assert!(q.is_valid(m));
+
+            q.reset();
+            assert!(!q.ready());
+            assert_eq!(q.size(), 256);
+            assert_eq!(q.is_packed(), packed);
+        }
+
+        assert_eq!(create_queue(3, true).unwrap_err(), Error::InvalidMaxSize);
+        assert_eq!(create_queue(3, false).unwrap_err(), Error::InvalidMaxSize);
+    }
+
+    // Only uses the methods of `QueueCommonT`, so it can't compile unless `Q` implements it.
+    fn check_common<Q: QueueCommonT>(mut q: Q, layout: RingLayout) {
+        assert_eq!(q.layout(), layout);
+        assert_eq!(q.max_size(), 16);
+        q.try_set_size(8).unwrap();
+        assert_eq!(q.size(), 8);
+        q.try_set_desc_table_address(GuestAddress(0x1000)).unwrap();
+        q.set_event_idx(true);
+        q.set_ready(true);
+        assert!(q.ready() && q.event_idx_enabled());
+
+        q.reset();
+        assert_eq!(q.size(), 16);
+        assert!(!q.ready() && !q.event_idx_enabled());
+    }
+
+    #[test]
+    fn test_common_trait() {
+        check_common(Queue::new(16).unwrap(), RingLayout::Split);
+        check_common(PackedQueue::new(16).unwrap(), RingLayout::Packed);
+        check_common(create_queue(16, false).unwrap(), RingLayout::Split);
+        check_common(create_queue(16, true).unwrap(), RingLayout::Packed);
+    }
+}
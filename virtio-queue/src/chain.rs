@@ -15,11 +15,22 @@ use std::mem::size_of;
 use std::ops::Deref;
 
 use vm_memory::bitmap::{BitmapSlice, WithBitmapSlice};
-use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryRegion};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryRegion};
 
-use crate::{desc::split::Descriptor, Error, Reader, Writer};
+use crate::{desc::split::Descriptor, Error, QueueT, Reader, Writer};
 use virtio_bindings::bindings::virtio_ring::VRING_DESC_ALIGN_SIZE;
 
+// Number of consecutive descriptors read at once when prefetching is enabled.
+const PREFETCH_WINDOW: usize = 4;
+
+// Copy of the descriptors `start..start + len` of the current table.
+#[derive(Clone, Debug, Default)]
+struct PrefetchWindow {
+    descs: [Descriptor; PREFETCH_WINDOW],
+    start: u16,
+    len: u16,
+}
+
 /// A virtio descriptor chain.
 #[derive(Clone, Debug)]
 pub struct DescriptorChain<M> {
@@ -30,7 +41,17 @@ pub struct DescriptorChain<M> {
     next_index: u16,
     ttl: u16,
     yielded_bytes: u32,
+    descriptors_read: u32,
     is_indirect: bool,
+    seen_writable: bool,
+    strict_validation: bool,
+    indirect_only: bool,
+    // Whether the descriptors use the big-endian byte order of legacy queues.
+    legacy_big_endian: bool,
+    // Only allocated when prefetching is enabled, to keep the chains small otherwise.
+    prefetch: Option<Box<PrefetchWindow>>,
+    // Start and length of the rings the buffers must not overlap, if checked.
+    ring_regions: Option<[(GuestAddress, u64); 3]>,
 }
 
 impl<M> DescriptorChain<M>
@@ -53,7 +74,14 @@ where
             next_index: head_index,
             ttl,
             is_indirect: false,
+            seen_writable: false,
             yielded_bytes: 0,
+            descriptors_read: 0,
+            strict_validation: false,
+            indirect_only: false,
+            legacy_big_endian: false,
+            prefetch: None,
+            ring_regions: None,
         }
     }
 
@@ -69,6 +97,70 @@ where
         Self::with_ttl(mem, desc_table, queue_size, queue_size, head_index)
     }
 
+    /// Create a `DescriptorChain` out of the descriptors in `descs`, for testing device logic
+    /// without setting up a whole queue.
+    ///
+    /// The descriptors are written as a descriptor table at the end of the last region of
+    /// `mem`, so the buffers they point to should be elsewhere. The `next` fields are indexes
+    /// in `descs`, and `head` is the index of the first descriptor of the chain.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn from_descriptors(mem: M, descs: &[Descriptor], head: u16) -> Result<Self, Error> {
+        let queue_size = u16::try_from(descs.len()).map_err(|_| Error::InvalidSize)?;
+        if queue_size == 0 {
+            return Err(Error::InvalidSize);
+        }
+
+        let desc_size = size_of::<Descriptor>() as u64;
+        let desc_table = mem
+            .last_addr()
+            .checked_sub(u64::from(queue_size) * desc_size - 1)
+            .map(|addr| addr.mask(!(VRING_DESC_ALIGN_SIZE as u64 - 1)))
+            .map(GuestAddress)
+            .ok_or(Error::AddressOverflow)?;
+        for (i, desc) in descs.iter().enumerate() {
+            mem.write_obj(*desc, desc_table.unchecked_add(i as u64 * desc_size))
+                .map_err(Error::GuestMemory)?;
+        }
+
+        Ok(Self::new(mem, desc_table, queue_size, head))
+    }
+
+    // Enable or disable the checks that are only performed in strict mode.
+    pub(crate) fn with_strict_validation(mut self, strict_validation: bool) -> Self {
+        self.strict_validation = strict_validation;
+        self
+    }
+
+    // Enable or disable rejecting chains of several descriptors that don't use an indirect
+    // table.
+    pub(crate) fn with_indirect_only(mut self, indirect_only: bool) -> Self {
+        self.indirect_only = indirect_only;
+        self
+    }
+
+    // Read the descriptors using the big-endian byte order of legacy queues instead of
+    // little-endian.
+    pub(crate) fn with_legacy_endian(mut self, legacy_big_endian: bool) -> Self {
+        self.legacy_big_endian = legacy_big_endian;
+        self
+    }
+
+    // Enable or disable reading several consecutive descriptors of the table at once.
+    pub(crate) fn with_prefetch(mut self, prefetch: bool) -> Self {
+        self.prefetch = prefetch.then(Box::default);
+        self
+    }
+
+    // Set the rings that the buffers of the chain must not overlap, or `None` to skip the
+    // check.
+    pub(crate) fn with_ring_regions(
+        mut self,
+        ring_regions: Option<[(GuestAddress, u64); 3]>,
+    ) -> Self {
+        self.ring_regions = ring_regions;
+        self
+    }
+
     /// Get the descriptor index of the chain head.
     pub fn head_index(&self) -> u16 {
         self.head_index
@@ -80,12 +172,54 @@ where
         self.mem.deref()
     }
 
+    /// Return the chain to the driver by adding its head to the used ring of `queue`, with `len`
+    /// bytes written to its buffers.
+    ///
+    /// This is a shorthand for calling [`QueueT::add_used`] with the head index and the memory
+    /// of the chain, for devices that complete chains after processing them instead of keeping
+    /// their head index around. `queue` must be the queue the chain was popped from.
+    pub fn complete<Q: QueueT>(self, queue: &mut Q, len: u32) -> Result<(), Error>
+    where
+        M::Target: Sized,
+    {
+        queue.add_used(self.memory(), self.head_index, len)
+    }
+
     /// Return an iterator that only yields the readable descriptors in the chain.
     pub fn readable(self) -> DescriptorChainRwIter<M> {
-        DescriptorChainRwIter {
-            chain: self,
-            writable: false,
-        }
+        DescriptorChainRwIter::new(self, false)
+    }
+
+    /// Return an iterator that yields the descriptors in the chain along with their offset in
+    /// bytes from the start of the chain.
+    ///
+    /// This helps mapping a range of bytes of the chain to the descriptors holding it.
+    pub fn with_offsets(self) -> impl Iterator<Item = (u64, Descriptor)> {
+        self.scan(0u64, |offset, desc| {
+            let start = *offset;
+            *offset += u64::from(desc.len());
+            Some((start, desc))
+        })
+    }
+
+    /// Return an iterator that yields the descriptors in the chain along with the index they
+    /// were read from.
+    ///
+    /// The index is the position of the descriptor in the descriptor table, or in the indirect
+    /// table for the descriptors of an indirect chain. Same as for [`Iterator::next`], the
+    /// iteration stops silently on a malformed chain.
+    pub fn enumerate_indices(mut self) -> impl Iterator<Item = (u16, Descriptor)> {
+        std::iter::from_fn(move || {
+            let was_indirect = self.is_indirect;
+            let index = self.next_index;
+            let desc = self.try_next().ok().flatten()?;
+            // Switching to an indirect table restarts from its first entry.
+            if self.is_indirect && !was_indirect {
+                Some((0, desc))
+            } else {
+                Some((index, desc))
+            }
+        })
     }
 
     /// Return a new instance of Writer
@@ -108,9 +242,18 @@ where
 
     /// Return an iterator that only yields the writable descriptors in the chain.
     pub fn writable(self) -> DescriptorChainRwIter<M> {
-        DescriptorChainRwIter {
-            chain: self,
-            writable: true,
+        DescriptorChainRwIter::new(self, true)
+    }
+
+    /// Return an iterator over the writable buffers in the chain, split in segments of at most
+    /// `max_len` bytes.
+    ///
+    /// A `max_len` of 0 is handled as 1.
+    pub fn writable_segments(self, max_len: u32) -> WritableSegments<M> {
+        WritableSegments {
+            descs: self.writable(),
+            max_len: max_len.max(1),
+            current: None,
         }
     }
 
@@ -143,70 +286,504 @@ where
         self.next_index = 0;
         self.ttl = self.queue_size;
         self.is_indirect = true;
+        if let Some(window) = self.prefetch.as_deref_mut() {
+            window.len = 0;
+        }
 
         Ok(())
     }
-}
-
-impl<M> Iterator for DescriptorChain<M>
-where
-    M: Deref,
-    M::Target: GuestMemory,
-{
-    type Item = Descriptor;
 
-    /// Return the next descriptor in this descriptor chain, if there is one.
+    /// Return the next descriptor in this descriptor chain, `Ok(None)` at the end of the chain,
+    /// or an error if the chain is malformed.
     ///
-    /// Note that this is distinct from the next descriptor chain returned by
-    /// [`AvailIter`](struct.AvailIter.html), which is the head of the next
-    /// _available_ descriptor chain.
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.ttl == 0 || self.next_index >= self.queue_size {
-            return None;
+    /// This is the fallible counterpart of [`Iterator::next`]. In particular it returns
+    /// [`Error::DescriptorChainTooLong`] when the driver links more descriptors than the
+    /// descriptor table (or indirect table) holds, which can only happen if the `next` fields
+    /// form a loop. [`Error::EmptyDescriptorChain`] is returned if the head descriptor can't be
+    /// read from guest memory.
+    ///
+    /// For chains popped from a queue in strict mode (see [`Queue::set_strict_validation`]),
+    /// [`Error::ZeroLengthWritableDescriptor`] is returned for device-writable descriptors with
+    /// a zero length, and [`Error::InvalidChainOrder`] for device-readable descriptors following
+    /// a device-writable one.
+    ///
+    /// For chains popped from a queue in indirect-only mode (see [`Queue::set_indirect_only`]),
+    /// [`Error::IndirectTableRequired`] is returned if the head descriptor is linked to another
+    /// descriptor instead of referring to an indirect table.
+    ///
+    /// [`Queue::set_strict_validation`]: crate::Queue::set_strict_validation
+    /// [`Queue::set_indirect_only`]: crate::Queue::set_indirect_only
+    pub fn try_next(&mut self) -> Result<Option<Descriptor>, Error> {
+        if self.ttl == 0 {
+            return Ok(None);
         }
 
-        let desc_addr = self
-            .desc_table
-            // The multiplication can not overflow an u64 since we are multiplying an u16 with a
-            // small number.
-            .checked_add(self.next_index as u64 * size_of::<Descriptor>() as u64)?;
+        if self.next_index >= self.queue_size {
+            return Err(Error::InvalidDescriptorIndex);
+        }
 
-        // The guest device driver should not touch the descriptor once submitted, so it's safe
-        // to use read_obj() here.
-        let desc = self.mem.read_obj::<Descriptor>(desc_addr).ok()?;
+        // Nothing has been yielded yet as long as `ttl` is untouched and we're not following an
+        // indirect table.
+        let is_head = self.ttl == self.queue_size && !self.is_indirect;
+
+        let desc = match self.read_descriptor() {
+            // A chain whose head can't be read would otherwise look like an empty chain.
+            Err(_) if is_head => return Err(Error::EmptyDescriptorChain),
+            desc => desc?,
+        };
+
+        if is_head && self.indirect_only && desc.has_next() && !desc.refers_to_indirect_table() {
+            return Err(Error::IndirectTableRequired);
+        }
+
+        if let Some(regions) = self.ring_regions {
+            let start = desc.addr().raw_value();
+            let end = start.saturating_add(u64::from(desc.len()));
+            if regions.iter().any(|&(ring, len)| {
+                start < ring.raw_value().saturating_add(len) && ring.raw_value() < end
+            }) {
+                return Err(Error::BufferOverlapsRing);
+            }
+        }
 
         if desc.refers_to_indirect_table() {
-            self.switch_to_indirect_table(desc).ok()?;
-            return self.next();
+            self.switch_to_indirect_table(desc)?;
+            return self.try_next();
+        }
+
+        if self.strict_validation {
+            if desc.is_write_only() {
+                if desc.len() == 0 {
+                    return Err(Error::ZeroLengthWritableDescriptor(self.next_index));
+                }
+                self.seen_writable = true;
+            } else if self.seen_writable {
+                return Err(Error::InvalidChainOrder);
+            }
         }
 
         // constructing a chain that is longer than 2^32 bytes is illegal,
         // let's terminate the iteration if something violated this.
         // (VIRTIO v1.2, 2.7.5.2: "Drivers MUST NOT add a descriptor chain
         // longer than 2^32 bytes in total;")
-        match self.yielded_bytes.checked_add(desc.len()) {
-            Some(yielded_bytes) => self.yielded_bytes = yielded_bytes,
-            None => return None,
-        };
+        self.yielded_bytes = self
+            .yielded_bytes
+            .checked_add(desc.len())
+            .ok_or(Error::DescriptorChainOverflow)?;
 
         if desc.has_next() {
-            self.next_index = desc.next();
             // It's ok to decrement `self.ttl` here because we check at the start of the method
             // that it's greater than 0.
             self.ttl -= 1;
+            // A valid chain never has more descriptors than the table it lives in, so running
+            // out of `ttl` while the `next` flag is still set means the chain loops.
+            if self.ttl == 0 {
+                return Err(Error::DescriptorChainTooLong(self.head_index));
+            }
+            self.next_index = desc.next();
         } else {
             self.ttl = 0;
         }
 
-        Some(desc)
+        self.descriptors_read += 1;
+        Ok(Some(desc))
+    }
+
+    /// Return the number of descriptors returned so far by the chain.
+    ///
+    /// When walking the chain fails partway, e.g. because a descriptor can't be read from guest
+    /// memory, this tells how many descriptors were successfully processed before the error.
+    /// Descriptors referring to an indirect table are not counted, the descriptors of the table
+    /// are.
+    pub fn descriptors_read(&self) -> u32 {
+        self.descriptors_read
+    }
+
+    /// Return the guest address and the length in bytes of the indirect descriptor table the
+    /// head descriptor refers to, or `None` if the head is a regular descriptor.
+    ///
+    /// This lets a device check that the indirect table is backed by guest memory before
+    /// walking it. Once the chain is following an indirect table, that table is returned.
+    pub fn indirect_table(&self) -> Result<Option<(GuestAddress, u32)>, Error> {
+        if self.is_indirect {
+            // The length of the table was checked to be a multiple of the descriptor size when
+            // switching to it, and can't overflow an u32.
+            let len = u32::from(self.queue_size) * size_of::<Descriptor>() as u32;
+            return Ok(Some((self.desc_table, len)));
+        }
+
+        if self.head_index >= self.queue_size {
+            return Err(Error::InvalidDescriptorIndex);
+        }
+        let head_addr = self
+            .desc_table
+            // The multiplication can not overflow an u64 since we are multiplying an u16 with a
+            // small number.
+            .checked_add(u64::from(self.head_index) * size_of::<Descriptor>() as u64)
+            .ok_or(Error::AddressOverflow)?;
+        let head = self
+            .mem
+            .read_obj::<Descriptor>(head_addr)
+            .map(|desc| self.desc_to_cpu(desc))
+            .map_err(Error::GuestMemory)?;
+
+        if head.refers_to_indirect_table() {
+            Ok(Some((head.addr(), head.len())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Convert a descriptor read from guest memory to the little-endian layout expected by the
+    // `Descriptor` accessors.
+    fn desc_to_cpu(&self, desc: Descriptor) -> Descriptor {
+        if self.legacy_big_endian {
+            desc.swap_bytes()
+        } else {
+            desc
+        }
+    }
+
+    // Read the descriptor at `next_index` in the current table, which must be in bounds.
+    fn read_descriptor(&mut self) -> Result<Descriptor, Error> {
+        let desc = self.read_raw_descriptor()?;
+        Ok(self.desc_to_cpu(desc))
+    }
+
+    // Read the descriptor at `next_index` as it's laid out in guest memory.
+    fn read_raw_descriptor(&mut self) -> Result<Descriptor, Error> {
+        let index = self.next_index;
+        if let Some(window) = self.prefetch.as_deref() {
+            if let Some(offset) = index.checked_sub(window.start) {
+                if offset < window.len {
+                    return Ok(window.descs[usize::from(offset)]);
+                }
+            }
+        }
+
+        let desc_size = size_of::<Descriptor>();
+        let desc_addr = self
+            .desc_table
+            // The multiplication can not overflow an u64 since we are multiplying an u16 with a
+            // small number.
+            .checked_add(u64::from(index) * desc_size as u64)
+            .ok_or(Error::AddressOverflow)?;
+
+        if let Some(window) = self.prefetch.as_deref_mut() {
+            // The window doesn't go past the end of the table.
+            let len = usize::from(self.queue_size - index).min(PREFETCH_WINDOW);
+            let mut buf = [0u8; PREFETCH_WINDOW * size_of::<Descriptor>()];
+            let buf = &mut buf[..len * desc_size];
+            // If the memory following the descriptor can't be read, fall back to reading the
+            // descriptor alone below, so that prefetching never changes the outcome.
+            if self.mem.read_slice(buf, desc_addr).is_ok() {
+                for (desc, bytes) in window.descs.iter_mut().zip(buf.chunks_exact(desc_size)) {
+                    desc.as_mut_slice().copy_from_slice(bytes);
+                }
+                window.start = index;
+                // `len` is at most `PREFETCH_WINDOW`.
+                window.len = len as u16;
+                return Ok(window.descs[0]);
+            }
+        }
+
+        // The guest device driver should not touch the descriptor once submitted, so it's safe
+        // to use read_obj() here.
+        self.mem
+            .read_obj::<Descriptor>(desc_addr)
+            .map_err(Error::GuestMemory)
+    }
+
+    /// Return the next descriptor in this descriptor chain, treating the end of the chain as an
+    /// error.
+    ///
+    /// This is meant for devices expecting a fixed layout, e.g. a header descriptor followed by a
+    /// data descriptor: going past the last descriptor returns [`Error::NoMoreDescriptors`]
+    /// instead of following a stale `next` index. Errors found in a malformed chain are
+    /// reported the same way as [`try_next`](DescriptorChain::try_next) does.
+    pub fn advance(&mut self) -> Result<Descriptor, Error> {
+        self.try_next()?.ok_or(Error::NoMoreDescriptors)
+    }
+
+    /// Walk the whole chain and check that the device is allowed to write to the buffers of
+    /// all the device-writable descriptors.
+    ///
+    /// Each writable buffer must be backed by guest memory, and accepted by `is_writable`, which
+    /// is given the address and the length of the buffer. This allows VMMs that keep parts of
+    /// guest memory read-only for the device to reject the chain upfront, as a device-writable
+    /// descriptor pointing there is a driver bug. [`Error::WriteToReadOnly`] is returned for
+    /// such descriptors, and any error found while walking the chain is returned as well.
+    pub fn check_write_access<F>(&self, mut is_writable: F) -> Result<(), Error>
+    where
+        M: Clone,
+        F: FnMut(GuestAddress, usize) -> bool,
+    {
+        let mut chain = self.clone();
+        while let Some(desc) = chain.try_next()? {
+            if !desc.is_write_only() {
+                continue;
+            }
+
+            let len = desc.len() as usize;
+            self.mem
+                .get_slice(desc.addr(), len)
+                .map_err(Error::GuestMemory)?;
+            if !is_writable(desc.addr(), len) {
+                return Err(Error::WriteToReadOnly);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Walk the whole chain and check that the buffers of its descriptors don't overlap.
+    ///
+    /// A driver could point several descriptors of a chain to the same memory, so that a device
+    /// ends up processing (or overwriting) the same data more than once. Devices for which this
+    /// matters can use this method to reject such chains with
+    /// [`Error::OverlappingDescriptors`]. Zero-length buffers never overlap anything.
+    pub fn check_no_overlap(&self) -> Result<(), Error>
+    where
+        M: Clone,
+    {
+        let mut ranges = Vec::new();
+        let mut chain = self.clone();
+        while let Some(desc) = chain.try_next()? {
+            if desc.len() == 0 {
+                continue;
+            }
+            let end = desc
+                .addr()
+                .checked_add(u64::from(desc.len()))
+                .ok_or(Error::AddressOverflow)?;
+            ranges.push((desc.addr(), end));
+        }
+
+        ranges.sort_unstable();
+        if ranges.windows(2).any(|w| w[1].0 < w[0].1) {
+            return Err(Error::OverlappingDescriptors);
+        }
+
+        Ok(())
+    }
+
+    /// Walk the whole chain and check that the buffers of its descriptors start at an address
+    /// aligned to `page_size`.
+    ///
+    /// Offload backends that require page-aligned buffers can use this method to fail fast with
+    /// [`Error::Misaligned`] before handing the chain over. A `page_size` of 0 makes every
+    /// descriptor misaligned.
+    pub fn check_page_aligned(&self, page_size: u64) -> Result<(), Error>
+    where
+        M: Clone,
+    {
+        let mut chain = self.clone();
+        while let Some(desc) = chain.try_next()? {
+            if desc.addr().0.checked_rem(page_size) != Some(0) {
+                return Err(Error::Misaligned);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the combined length of all the device-readable buffers of the chain.
+    ///
+    /// This allows checking the size of a request before processing it. Errors found while
+    /// walking the chain are returned, including [`Error::DescriptorChainOverflow`] for chains
+    /// longer than `u32::MAX` bytes.
+    pub fn total_readable_len(&self) -> Result<u32, Error>
+    where
+        M: Clone,
+    {
+        self.total_len(false)
+    }
+
+    /// Return the combined length of all the device-writable buffers of the chain.
+    ///
+    /// Errors are reported the same way as with
+    /// [`total_readable_len`](DescriptorChain::total_readable_len).
+    pub fn total_writable_len(&self) -> Result<u32, Error>
+    where
+        M: Clone,
+    {
+        self.total_len(true)
+    }
+
+    fn total_len(&self, writable: bool) -> Result<u32, Error>
+    where
+        M: Clone,
+    {
+        self.fold_len(|desc| desc.is_write_only() == writable)
+    }
+
+    /// Return the guest memory ranges backing bytes `[offset, offset + len)` of the
+    /// device-writable part of the chain.
+    ///
+    /// The device-writable buffers are considered as a single contiguous area, and the returned
+    /// `(address, length)` pairs cover the requested window in order, so a device can split a
+    /// large buffer across several I/O operations. [`Error::SplitOutOfBounds`] is returned,
+    /// with the end of the window, if the window doesn't fit in the device-writable buffers.
+    pub fn writable_window(&self, offset: u64, len: u64) -> Result<Vec<(GuestAddress, u64)>, Error>
+    where
+        M: Clone,
+    {
+        let out_of_bounds =
+            |end: u64| Error::SplitOutOfBounds(usize::try_from(end).unwrap_or(usize::MAX));
+        let end = offset
+            .checked_add(len)
+            .ok_or_else(|| out_of_bounds(u64::MAX))?;
+
+        let mut window = Vec::new();
+        // Position of the current buffer in the device-writable area.
+        let mut pos = 0u64;
+        let mut chain = self.clone();
+        while pos < end {
+            let desc = match chain.try_next()? {
+                Some(desc) if desc.is_write_only() => desc,
+                Some(_) => continue,
+                None => return Err(out_of_bounds(end)),
+            };
+
+            let desc_end = pos + u64::from(desc.len());
+            let start = offset.max(pos);
+            let stop = end.min(desc_end);
+            if start < stop {
+                let addr = desc
+                    .addr()
+                    .checked_add(start - pos)
+                    .ok_or(Error::AddressOverflow)?;
+                window.push((addr, stop - start));
+            }
+            pos = desc_end;
+        }
+
+        Ok(window)
+    }
+
+    /// Return the offset in bytes, from the start of the chain, of the first device-writable
+    /// buffer, or `None` if the chain only has device-readable buffers.
+    ///
+    /// For requests made of a device-readable header followed by a device-writable body, this
+    /// is where the body starts.
+    pub fn writable_start_offset(&self) -> Result<Option<u64>, Error>
+    where
+        M: Clone,
+    {
+        let mut offset = 0u64;
+        let mut chain = self.clone();
+        while let Some(desc) = chain.try_next()? {
+            if desc.is_write_only() {
+                return Ok(Some(offset));
+            }
+            // Can't overflow, the length of a chain fits in an u32.
+            offset += u64::from(desc.len());
+        }
+
+        Ok(None)
+    }
+
+    /// Walk the whole chain and return the combined length of the buffers of the descriptors
+    /// accepted by `filter`.
+    ///
+    /// Unlike summing `Descriptor::len` by hand, this can't silently wrap around. Errors found
+    /// while walking the chain are returned, including [`Error::DescriptorChainOverflow`] for
+    /// chains longer than `u32::MAX` bytes, which the specification forbids.
+    pub fn fold_len<F>(&self, mut filter: F) -> Result<u32, Error>
+    where
+        M: Clone,
+        F: FnMut(&Descriptor) -> bool,
+    {
+        let mut total = 0u32;
+        let mut chain = self.clone();
+        while let Some(desc) = chain.try_next()? {
+            if filter(&desc) {
+                total = total
+                    .checked_add(desc.len())
+                    .ok_or(Error::DescriptorChainOverflow)?;
+            }
+        }
+
+        Ok(total)
+    }
+}
+
+impl<M> Iterator for DescriptorChain<M>
+where
+    M: Deref,
+    M::Target: GuestMemory,
+{
+    type Item = Descriptor;
+
+    /// Return the next descriptor in this descriptor chain, if there is one.
+    ///
+    /// Note that this is distinct from the next descriptor chain returned by
+    /// [`AvailIter`](struct.AvailIter.html), which is the head of the next
+    /// _available_ descriptor chain.
+    ///
+    /// Iteration stops silently on a malformed chain; use
+    /// [`try_next`](DescriptorChain::try_next) to find out why.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().ok().flatten()
     }
 }
 
 /// An iterator for readable or writable descriptors.
+///
+/// The virtio specification requires all the device-readable descriptors of a chain to come
+/// before the device-writable ones. The iterator doesn't enforce this, but records whether the
+/// descriptors it walked over violate it, which can be queried with
+/// [`is_misordered`](DescriptorChainRwIter::is_misordered).
 #[derive(Clone)]
 pub struct DescriptorChainRwIter<M> {
     chain: DescriptorChain<M>,
     writable: bool,
+    seen_writable: bool,
+    misordered: bool,
+}
+
+impl<M> DescriptorChainRwIter<M> {
+    fn new(chain: DescriptorChain<M>, writable: bool) -> Self {
+        DescriptorChainRwIter {
+            chain,
+            writable,
+            seen_writable: false,
+            misordered: false,
+        }
+    }
+
+    /// Check whether a device-readable descriptor was found after a device-writable one among
+    /// the descriptors walked so far.
+    pub fn is_misordered(&self) -> bool {
+        self.misordered
+    }
+}
+
+impl<M> DescriptorChainRwIter<M>
+where
+    M: Deref,
+    M::Target: GuestMemory,
+{
+    /// Return the next readable/writeable descriptor (depending on the `writable` value) in this
+    /// descriptor chain, `Ok(None)` at the end of the chain, or an error if the chain is
+    /// malformed.
+    ///
+    /// This is the fallible counterpart of [`Iterator::next`], reporting the same errors as
+    /// [`DescriptorChain::try_next`], for the descriptors of the chain that are skipped as well.
+    pub fn try_next(&mut self) -> Result<Option<Descriptor>, Error> {
+        while let Some(v) = self.chain.try_next()? {
+            if v.is_write_only() {
+                self.seen_writable = true;
+            } else if self.seen_writable {
+                self.misordered = true;
+            }
+
+            if v.is_write_only() == self.writable {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl<M> Iterator for DescriptorChainRwIter<M>
@@ -222,17 +799,65 @@ where
     /// Note that this is distinct from the next descriptor chain returned by
     /// [`AvailIter`](struct.AvailIter.html), which is the head of the next
     /// _available_ descriptor chain.
+    ///
+    /// Iteration stops silently on a malformed chain; use
+    /// [`try_next`](DescriptorChainRwIter::try_next) to find out why.
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.chain.next() {
-                Some(v) => {
-                    if v.is_write_only() == self.writable {
-                        return Some(v);
-                    }
+        self.try_next().ok().flatten()
+    }
+}
+
+/// An iterator over the writable buffers of a descriptor chain, split in bounded segments.
+///
+/// Created by [`DescriptorChain::writable_segments`], it yields `(address, length)` pairs of at
+/// most the requested length, in chain order. Writable buffers longer than that are split in
+/// several segments, only the last of which may be shorter. Zero-length buffers yield nothing.
+#[derive(Clone)]
+pub struct WritableSegments<M> {
+    descs: DescriptorChainRwIter<M>,
+    max_len: u32,
+    // The part of the current buffer not yielded yet.
+    current: Option<(GuestAddress, u32)>,
+}
+
+impl<M> Iterator for WritableSegments<M>
+where
+    M: Deref,
+    M::Target: GuestMemory,
+{
+    type Item = (GuestAddress, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (addr, len) = loop {
+            match self.current.take() {
+                Some(remaining) if remaining.1 > 0 => break remaining,
+                _ => {
+                    let desc = self.descs.next()?;
+                    self.current = Some((desc.addr(), desc.len()));
                 }
-                None => return None,
             }
-        }
+        };
+
+        let segment_len = len.min(self.max_len);
+        // A buffer wrapping around the address space is cut short.
+        self.current = addr
+            .checked_add(u64::from(segment_len))
+            .map(|next| (next, len - segment_len));
+        Some((addr, segment_len))
+    }
+}
+
+// We can't derive Debug, because rustc doesn't generate the `M::T: Debug` constraint
+impl<M> Debug for WritableSegments<M>
+where
+    M: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WritableSegments")
+            .field("descs", &self.descs)
+            .field("max_len", &self.max_len)
+            .field("current", &self.current)
+            .finish()
     }
 }
 
@@ -245,6 +870,8 @@ where
         f.debug_struct("DescriptorChainRwIter")
             .field("chain", &self.chain)
             .field("writable", &self.writable)
+            .field("seen_writable", &self.seen_writable)
+            .field("misordered", &self.misordered)
             .finish()
     }
 }
@@ -254,7 +881,9 @@ mod tests {
     use super::*;
     use crate::desc::{split::Descriptor as SplitDescriptor, RawDescriptor};
     use crate::mock::{DescriptorTable, MockSplitQueue};
-    use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT};
+    use virtio_bindings::bindings::virtio_ring::{
+        VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE,
+    };
     use vm_memory::GuestMemoryMmap;
 
     #[test]
@@ -277,6 +906,18 @@ mod tests {
                 .next()
                 .is_none()
         );
+        assert_eq!(
+            DescriptorChain::<&GuestMemoryMmap>::new(m, GuestAddress(0x00ff_ffff_ffff), 16, 0)
+                .try_next()
+                .unwrap_err(),
+            Error::EmptyDescriptorChain
+        );
+        assert_eq!(
+            DescriptorChain::<&GuestMemoryMmap>::new(m, GuestAddress(u64::MAX - 0x10), 16, 1)
+                .try_next()
+                .unwrap_err(),
+            Error::EmptyDescriptorChain
+        );
 
         {
             // the first desc has a normal len, and the next_descriptor flag is set
@@ -365,6 +1006,708 @@ mod tests {
         assert!(c.next().is_none());
     }
 
+    #[test]
+    fn test_chain_loop_detection() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        {
+            // A single descriptor pointing back to itself.
+            let desc = RawDescriptor::from(SplitDescriptor::new(
+                0x1000,
+                0x10,
+                VRING_DESC_F_NEXT as u16,
+                0,
+            ));
+            vq.desc_table().store(0, desc).unwrap();
+
+            let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+            for _ in 0..15 {
+                assert!(c.try_next().unwrap().is_some());
+            }
+            assert_eq!(c.try_next().unwrap_err(), Error::DescriptorChainTooLong(0));
+            // The chain is exhausted after reporting the error.
+            assert!(c.try_next().unwrap().is_none());
+
+            let c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+            assert_eq!(c.count(), 15);
+        }
+
+        {
+            // A longer cycle: 0 -> 1 -> 2 -> 3 -> 1.
+            for i in 0..4u16 {
+                let next = if i == 3 { 1 } else { i + 1 };
+                let desc = RawDescriptor::from(SplitDescriptor::new(
+                    0x1000 * (i as u64 + 1),
+                    0x10,
+                    VRING_DESC_F_NEXT as u16,
+                    next,
+                ));
+                vq.desc_table().store(i, desc).unwrap();
+            }
+
+            let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+            let mut visited = 0;
+            let err = loop {
+                match c.try_next() {
+                    Ok(Some(_)) => visited += 1,
+                    Ok(None) => panic!("a looping chain must not end cleanly"),
+                    Err(e) => break e,
+                }
+            };
+            assert_eq!(err, Error::DescriptorChainTooLong(0));
+            assert_eq!(visited, 15);
+        }
+    }
+
+    #[test]
+    fn test_descriptors_read() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        // Only the first two entries of the descriptor table are backed by guest memory.
+        let desc_table = GuestAddress(0x10000 - 2 * 16);
+        for i in 0..2u16 {
+            let desc = SplitDescriptor::new(0x1000, 0x100, VRING_DESC_F_NEXT as u16, i + 1);
+            m.write_obj(desc, desc_table.unchecked_add(u64::from(i) * 16))
+                .unwrap();
+        }
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, desc_table, 16, 0);
+        assert_eq!(c.descriptors_read(), 0);
+        assert!(c.try_next().unwrap().is_some());
+        assert!(c.try_next().unwrap().is_some());
+        assert!(matches!(c.try_next().unwrap_err(), Error::GuestMemory(_)));
+        assert_eq!(c.descriptors_read(), 2);
+    }
+
+    #[test]
+    fn test_advance_past_end() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        // The last descriptor has no NEXT flag but a stale `next` index pointing back to the head.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(
+                0x1000,
+                0x100,
+                VRING_DESC_F_NEXT as u16,
+                1,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, 0, 0)),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert_eq!(c.advance().unwrap().addr(), GuestAddress(0x1000));
+        assert_eq!(c.advance().unwrap().addr(), GuestAddress(0x2000));
+        assert_eq!(c.advance().unwrap_err(), Error::NoMoreDescriptors);
+        assert_eq!(c.advance().unwrap_err(), Error::NoMoreDescriptors);
+
+        // The iterator ends cleanly instead.
+        let mut c = DescriptorChain::<&GuestMemoryMmap>::new(m, vq.start(), 16, 0);
+        assert!(c.next().is_some());
+        assert!(c.next().is_some());
+        assert!(c.next().is_none());
+        assert!(c.try_next().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_check_write_access() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        // The device is only allowed to write to [0x8000, 0x10000).
+        let is_writable =
+            |addr: GuestAddress, len: usize| addr.0 >= 0x8000 && addr.0 + len as u64 <= 0x10000;
+
+        // Readable descriptors can point anywhere.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x8000,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert!(chain.check_write_access(is_writable).is_ok());
+
+        // A writable descriptor targets the read-only part of guest memory.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x8000,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(
+            chain.check_write_access(is_writable).unwrap_err(),
+            Error::WriteToReadOnly
+        );
+        // The chain itself is left untouched.
+        assert_eq!(chain.count(), 3);
+
+        // A writable descriptor that isn't backed by guest memory at all.
+        let descs = [RawDescriptor::from(SplitDescriptor::new(
+            0xff00,
+            0x200,
+            VRING_DESC_F_WRITE as u16,
+            0,
+        ))];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert!(matches!(
+            chain.check_write_access(|_, _| true),
+            Err(Error::GuestMemory(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_no_overlap() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        // Adjacent buffers, listed out of order, don't overlap.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x1000, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x3000,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert!(chain.check_no_overlap().is_ok());
+
+        // A device-writable buffer overlaps a readable one.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x200, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x11ff,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(
+            chain.check_no_overlap().unwrap_err(),
+            Error::OverlappingDescriptors
+        );
+        // The chain itself is left untouched.
+        assert_eq!(chain.count(), 2);
+    }
+
+    #[test]
+    fn test_check_page_aligned() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x4000,
+                0x1000,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert!(chain.check_page_aligned(0x1000).is_ok());
+        assert_eq!(
+            chain.check_page_aligned(0x2000).unwrap_err(),
+            Error::Misaligned
+        );
+        assert_eq!(chain.check_page_aligned(0).unwrap_err(), Error::Misaligned);
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2100,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert!(chain.check_page_aligned(0x100).is_ok());
+        assert_eq!(
+            chain.check_page_aligned(0x1000).unwrap_err(),
+            Error::Misaligned
+        );
+        // The chain itself is left untouched.
+        assert_eq!(chain.count(), 2);
+    }
+
+    #[test]
+    fn test_total_len() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x10, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x200, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x3000,
+                0x1000,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x5000,
+                0x1,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(chain.total_readable_len().unwrap(), 0x210);
+        assert_eq!(chain.total_writable_len().unwrap(), 0x1001);
+
+        // Only the lengths of the buffers are summed up, so they don't need to be backed by
+        // guest memory.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x8000_0000, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x8000_0000,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(
+            chain.total_readable_len().unwrap_err(),
+            Error::DescriptorChainOverflow
+        );
+        assert_eq!(
+            chain.total_writable_len().unwrap_err(),
+            Error::DescriptorChainOverflow
+        );
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x8000_0000, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x8000_0000, 0, 0)),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(
+            chain.total_readable_len().unwrap_err(),
+            Error::DescriptorChainOverflow
+        );
+    }
+
+    #[test]
+    fn test_with_offsets() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x10, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x3000,
+                0x200,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x4000,
+                0x8,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        let offsets: Vec<(u64, u64)> = chain
+            .with_offsets()
+            .map(|(offset, desc)| (offset, desc.addr().0))
+            .collect();
+        assert_eq!(
+            offsets,
+            [(0, 0x1000), (0x10, 0x2000), (0x10, 0x3000), (0x210, 0x4000)]
+        );
+    }
+
+    #[test]
+    fn test_enumerate_indices() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let dtable = vq.desc_table();
+        let next = VRING_DESC_F_NEXT as u16;
+
+        // The chain starting at 3 goes through 9 and 5.
+        dtable
+            .store(
+                3,
+                RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, next, 9)),
+            )
+            .unwrap();
+        dtable
+            .store(
+                9,
+                RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, next, 5)),
+            )
+            .unwrap();
+        dtable
+            .store(
+                5,
+                RawDescriptor::from(SplitDescriptor::new(0x3000, 0x100, 0, 0)),
+            )
+            .unwrap();
+        let c: DescriptorChain<&GuestMemoryMmap> = DescriptorChain::new(m, vq.start(), 16, 3);
+        let indices: Vec<(u16, u64)> = c
+            .enumerate_indices()
+            .map(|(index, desc)| (index, desc.addr().0))
+            .collect();
+        assert_eq!(indices, [(3, 0x1000), (9, 0x2000), (5, 0x3000)]);
+
+        // The indices of an indirect chain are positions in the indirect table.
+        dtable
+            .store(
+                1,
+                RawDescriptor::from(SplitDescriptor::new(
+                    0x7000,
+                    0x40,
+                    VRING_DESC_F_INDIRECT as u16,
+                    0,
+                )),
+            )
+            .unwrap();
+        let idtable = DescriptorTable::new(m, GuestAddress(0x7000), 4);
+        idtable
+            .store(
+                0,
+                RawDescriptor::from(SplitDescriptor::new(0x4000, 0x100, next, 2)),
+            )
+            .unwrap();
+        idtable
+            .store(
+                2,
+                RawDescriptor::from(SplitDescriptor::new(0x5000, 0x100, 0, 0)),
+            )
+            .unwrap();
+        let c: DescriptorChain<&GuestMemoryMmap> = DescriptorChain::new(m, vq.start(), 16, 1);
+        let indices: Vec<(u16, u64)> = c
+            .enumerate_indices()
+            .map(|(index, desc)| (index, desc.addr().0))
+            .collect();
+        assert_eq!(indices, [(0, 0x4000), (2, 0x5000)]);
+    }
+
+    #[test]
+    fn test_memory_handle() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        m.write_obj(0x1234_5678u32, GuestAddress(0x1000)).unwrap();
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x4, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x4,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let mut chain = vq.build_desc_chain(&descs).unwrap();
+
+        // The buffers are accessed only through the memory handle held by the chain.
+        let mut value = 0u32;
+        while let Some(desc) = chain.next() {
+            if desc.is_write_only() {
+                chain.memory().write_obj(value + 1, desc.addr()).unwrap();
+            } else {
+                value = chain.memory().read_obj(desc.addr()).unwrap();
+            }
+        }
+        assert_eq!(value, 0x1234_5678);
+        assert_eq!(
+            m.read_obj::<u32>(GuestAddress(0x2000)).unwrap(),
+            0x1234_5679
+        );
+    }
+
+    #[test]
+    fn test_writable_start_offset() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        // A header made of two buffers, followed by the body.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x10, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x8, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x3000,
+                0x200,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x4000,
+                0x1,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(chain.writable_start_offset().unwrap(), Some(0x18));
+
+        let descs = [RawDescriptor::from(SplitDescriptor::new(
+            0x3000,
+            0x200,
+            VRING_DESC_F_WRITE as u16,
+            0,
+        ))];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(chain.writable_start_offset().unwrap(), Some(0));
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x10, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x8, 0, 0)),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(chain.writable_start_offset().unwrap(), None);
+    }
+
+    #[test]
+    fn test_writable_window() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x3000,
+                0x80,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x4000,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+
+        // Within a single buffer.
+        assert_eq!(
+            chain.writable_window(0x10, 0x20).unwrap(),
+            [(GuestAddress(0x2010), 0x20)]
+        );
+        // Spanning the three buffers.
+        assert_eq!(
+            chain.writable_window(0xf0, 0xa0).unwrap(),
+            [
+                (GuestAddress(0x20f0), 0x10),
+                (GuestAddress(0x3000), 0x80),
+                (GuestAddress(0x4000), 0x10)
+            ]
+        );
+        // Up to the very end.
+        assert_eq!(
+            chain.writable_window(0x180, 0x100).unwrap(),
+            [(GuestAddress(0x4000), 0x100)]
+        );
+        assert!(chain.writable_window(0x280, 0).unwrap().is_empty());
+
+        assert_eq!(
+            chain.writable_window(0x180, 0x101).unwrap_err(),
+            Error::SplitOutOfBounds(0x281)
+        );
+        assert_eq!(
+            chain.writable_window(0x300, 0).unwrap_err(),
+            Error::SplitOutOfBounds(0x300)
+        );
+        assert!(matches!(
+            chain.writable_window(1, u64::MAX).unwrap_err(),
+            Error::SplitOutOfBounds(_)
+        ));
+    }
+
+    #[test]
+    fn test_writable_segments() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let write = VRING_DESC_F_WRITE as u16;
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x10000, 0x10000, write, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x30000, 0, write, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x40000, 0x100, write, 0)),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+
+        let segments: Vec<_> = chain.clone().writable_segments(1500).collect();
+        // The 64 KiB buffer is split in 43 full segments and a shorter one, while the
+        // readable and zero-length buffers are skipped.
+        assert_eq!(segments.len(), 45);
+        for (i, &(addr, len)) in segments[..43].iter().enumerate() {
+            assert_eq!(addr, GuestAddress(0x10000 + i as u64 * 1500));
+            assert_eq!(len, 1500);
+        }
+        assert_eq!(
+            segments[43],
+            (GuestAddress(0x10000 + 43 * 1500), 0x10000 - 43 * 1500)
+        );
+        assert_eq!(segments[44], (GuestAddress(0x40000), 0x100));
+
+        // Buffers shorter than the maximum are left whole.
+        let segments: Vec<_> = chain.writable_segments(0x10000).collect();
+        assert_eq!(
+            segments,
+            [
+                (GuestAddress(0x10000), 0x10000),
+                (GuestAddress(0x40000), 0x100)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_len() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        // Three buffers adding up to exactly `u32::MAX` bytes.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x8000_0000, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x7fff_ffff,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0, 0, 0)),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(chain.fold_len(|_| true).unwrap(), u32::MAX);
+        assert_eq!(
+            chain.fold_len(|desc| !desc.is_write_only()).unwrap(),
+            0x8000_0000
+        );
+        assert_eq!(
+            chain.fold_len(|desc| desc.addr().0 == 0x2000).unwrap(),
+            0x7fff_ffff
+        );
+        assert_eq!(chain.fold_len(|_| false).unwrap(), 0);
+
+        // Summing these into an `u32` would wrap around to 0x1000.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0xffff_f000, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x2000, 0, 0)),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+        assert_eq!(
+            chain.fold_len(|_| true).unwrap_err(),
+            Error::DescriptorChainOverflow
+        );
+    }
+
+    #[test]
+    fn test_from_descriptors() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        let descs = [
+            SplitDescriptor::new(0x1000, 0x100, VRING_DESC_F_WRITE as u16, 0),
+            SplitDescriptor::new(0x2000, 0x200, 0, 0),
+            SplitDescriptor::new(0x3000, 0x300, VRING_DESC_F_NEXT as u16, 0),
+        ];
+        let chain = DescriptorChain::from_descriptors(m, &descs, 2).unwrap();
+        assert_eq!(chain.head_index(), 2);
+        let addrs: Vec<u64> = chain.map(|desc| desc.addr().0).collect();
+        assert_eq!(addrs, [0x3000, 0x1000]);
+
+        let chain = DescriptorChain::from_descriptors(m, &descs, 1).unwrap();
+        assert_eq!(chain.total_readable_len().unwrap(), 0x200);
+
+        assert_eq!(
+            DescriptorChain::from_descriptors(m, &[], 0).unwrap_err(),
+            Error::InvalidSize
+        );
+    }
+
+    #[test]
+    fn test_readable_writable_iterators() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        // Two readable descriptors followed by two writable ones.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x10, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x20, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x3000,
+                0x30,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x4000,
+                0x40,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+
+        let mut readable = chain.clone().readable();
+        assert_eq!(readable.next().unwrap().addr(), GuestAddress(0x1000));
+        assert_eq!(readable.next().unwrap().addr(), GuestAddress(0x2000));
+        assert!(readable.next().is_none());
+        assert!(!readable.is_misordered());
+
+        let mut writable = chain.writable();
+        assert_eq!(writable.next().unwrap().addr(), GuestAddress(0x3000));
+        assert_eq!(writable.next().unwrap().addr(), GuestAddress(0x4000));
+        assert!(writable.next().is_none());
+        assert!(!writable.is_misordered());
+
+        // A readable descriptor placed after a writable one.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x10, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x20,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x30, 0, 0)),
+        ];
+        let chain = vq.build_desc_chain(&descs).unwrap();
+
+        let mut readable = chain.clone().readable();
+        assert_eq!(readable.next().unwrap().addr(), GuestAddress(0x1000));
+        assert!(!readable.is_misordered());
+        assert_eq!(readable.next().unwrap().addr(), GuestAddress(0x3000));
+        assert!(readable.is_misordered());
+
+        // The writable iterator has to walk the whole chain to notice the violation.
+        let mut writable = chain.writable();
+        assert_eq!(writable.next().unwrap().addr(), GuestAddress(0x2000));
+        assert!(!writable.is_misordered());
+        assert!(writable.next().is_none());
+        assert!(writable.is_misordered());
+    }
+
     #[test]
     fn test_new_from_indirect_descriptor() {
         // This is testing that chaining an indirect table works as expected. It is also a negative
@@ -434,6 +1777,79 @@ mod tests {
         assert!(c.next().is_none());
     }
 
+    #[test]
+    fn test_indirect_table() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let dtable = vq.desc_table();
+
+        let desc = RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0));
+        dtable.store(0, desc).unwrap();
+        let c: DescriptorChain<&GuestMemoryMmap> = DescriptorChain::new(m, vq.start(), 16, 0);
+        assert_eq!(c.indirect_table().unwrap(), None);
+
+        let desc = RawDescriptor::from(SplitDescriptor::new(
+            0x7000,
+            0x20,
+            VRING_DESC_F_INDIRECT as u16,
+            0,
+        ));
+        dtable.store(1, desc).unwrap();
+        let idtable = DescriptorTable::new(m, GuestAddress(0x7000), 2);
+        let desc = RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, 0, 0));
+        idtable.store(0, desc).unwrap();
+        let mut c: DescriptorChain<&GuestMemoryMmap> = DescriptorChain::new(m, vq.start(), 16, 1);
+        assert_eq!(
+            c.indirect_table().unwrap(),
+            Some((GuestAddress(0x7000), 0x20))
+        );
+        assert_eq!(c.next().unwrap().addr(), GuestAddress(0x2000));
+        assert_eq!(
+            c.indirect_table().unwrap(),
+            Some((GuestAddress(0x7000), 0x20))
+        );
+
+        let c: DescriptorChain<&GuestMemoryMmap> = DescriptorChain::new(m, vq.start(), 16, 16);
+        assert_eq!(
+            c.indirect_table().unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
+    }
+
+    #[test]
+    fn test_prefetch_indirect() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let dtable = vq.desc_table();
+
+        let desc = RawDescriptor::from(SplitDescriptor::new(
+            0x6000,
+            0x1000,
+            VRING_DESC_F_NEXT as u16,
+            1,
+        ));
+        dtable.store(0, desc).unwrap();
+        let desc = RawDescriptor::from(SplitDescriptor::new(
+            0x7000,
+            0x40,
+            VRING_DESC_F_INDIRECT as u16,
+            0,
+        ));
+        dtable.store(1, desc).unwrap();
+        let idtable = DescriptorTable::new(m, GuestAddress(0x7000), 4);
+        for i in 0..4u16 {
+            let flags = if i < 3 { VRING_DESC_F_NEXT as u16 } else { 0 };
+            let desc = SplitDescriptor::new(0x1000 * u64::from(i), 0x100, flags, i + 1);
+            idtable.store(i, RawDescriptor::from(desc)).unwrap();
+        }
+
+        // The descriptors prefetched from the queue table are not mistaken for the ones of the
+        // indirect table.
+        let c = DescriptorChain::new(m, vq.start(), 16, 0).with_prefetch(true);
+        let addrs: Vec<u64> = c.map(|desc| desc.addr().0).collect();
+        assert_eq!(addrs, [0x6000, 0x0, 0x1000, 0x2000, 0x3000]);
+    }
+
     #[test]
     fn test_indirect_descriptor_address_noaligned() {
         // Alignment requirements for vring elements start from virtio 1.0,
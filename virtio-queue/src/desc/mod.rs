@@ -5,6 +5,9 @@ use vm_memory::{ByteValued, Le16, Le32, Le64};
 pub mod packed;
 pub mod split;
 
+pub use self::packed::Descriptor as PackedDescriptor;
+pub use self::split::Descriptor as SplitDescriptor;
+
 /// a virtio descriptor
 #[deprecated = "Descriptor has been deprecated. Please use RawDescriptor"]
 pub type Descriptor = RawDescriptor;
@@ -14,6 +17,10 @@ pub type Descriptor = RawDescriptor;
 /// for packed descriptors and split descriptors.
 /// This type corresponds to struct virtq_desc, see:
 /// https://docs.oasis-open.org/virtio/virtio/v1.3/csd01/virtio-v1.3-csd01.html#x1-720008
+///
+/// A `RawDescriptor` doesn't record which layout it was read with, the caller knows it from
+/// the queue it comes from. Use `SplitDescriptor::from` or `PackedDescriptor::from` to access
+/// the fields of the layout, e.g. the `id` of a packed descriptor.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct RawDescriptor(Le64, Le32, Le16, Le16);
@@ -61,7 +68,7 @@ impl From<RawDescriptor> for packed::Descriptor {
 mod tests {
     use vm_memory::{Le16, Le32, Le64};
 
-    use super::{packed, split, RawDescriptor};
+    use super::{packed, split, PackedDescriptor, RawDescriptor, SplitDescriptor};
 
     #[test]
     fn test_desc_from_split() {
@@ -93,6 +100,21 @@ mod tests {
         assert_eq!(packed_desc.flags(), desc.3);
     }
 
+    #[test]
+    fn test_constructors() {
+        let split_desc = SplitDescriptor::new(0x1000, 0x200, 0x3, 0x4);
+        assert_eq!(split_desc.addr().0, 0x1000);
+        assert_eq!(split_desc.len(), 0x200);
+        assert_eq!(split_desc.flags(), 0x3);
+        assert_eq!(split_desc.next(), 0x4);
+
+        let packed_desc = PackedDescriptor::new(0x1000, 0x200, 0x3, 0x4);
+        assert_eq!(packed_desc.addr().0, 0x1000);
+        assert_eq!(packed_desc.len(), 0x200);
+        assert_eq!(packed_desc.id(), 0x3);
+        assert_eq!(packed_desc.flags(), 0x4);
+    }
+
     #[test]
     fn test_packed_from_desc() {
         let desc = RawDescriptor(Le64::from(1), Le32::from(2), Le16::from(3), Le16::from(4));
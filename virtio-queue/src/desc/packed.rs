@@ -5,8 +5,16 @@ use virtio_bindings::bindings::virtio_ring::{
 use vm_memory::{ByteValued, GuestAddress, Le16, Le32, Le64};
 
 /// A virtio packed descriptor constraints with C representation.
+///
+/// With the `serde` feature, it is serialized as a `PackedDescriptor` structure holding the
+/// plain values of the `addr`, `len`, `id` and `flags` fields.
 #[repr(C)]
 #[derive(Default, Clone, Copy, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "DescriptorSer", into = "DescriptorSer")
+)]
 pub struct Descriptor {
     /// Guest physical address of device specific data.
     addr: Le64,
@@ -62,11 +70,14 @@ impl Descriptor {
 impl Descriptor {
     /// Create a new descriptor.
     ///
+    /// The arguments follow the layout of the descriptor in memory, so `id` comes before
+    /// `flags`, while for split descriptors `flags` comes before `next`.
+    ///
     /// # Arguments
     /// * `addr` - the guest physical address of the descriptor buffer.
     /// * `len` - the length of the descriptor buffer.
+    /// * `id` - the buffer ID of the descriptor.
     /// * `flags` - the `flags` for the descriptor.
-    /// * `next` - the `next` field of the descriptor.
     pub fn new(addr: u64, len: u32, id: u16, flags: u16) -> Self {
         Descriptor {
             addr: addr.into(),
@@ -91,7 +102,7 @@ impl Descriptor {
         self.flags = flags.into();
     }
 
-    /// Set the value stored in the `next` field of the descriptor.
+    /// Set the buffer ID of the descriptor.
     pub fn set_id(&mut self, id: u16) {
         self.id = id.into();
     }
@@ -102,6 +113,37 @@ impl Descriptor {
 // included in there.
 unsafe impl ByteValued for Descriptor {}
 
+// The little endian wrappers from `vm-memory` can't be serialized, so the descriptor goes
+// through this plain representation instead.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename = "PackedDescriptor")]
+struct DescriptorSer {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+#[cfg(feature = "serde")]
+impl From<Descriptor> for DescriptorSer {
+    fn from(desc: Descriptor) -> Self {
+        DescriptorSer {
+            addr: desc.addr().0,
+            len: desc.len(),
+            id: desc.id(),
+            flags: desc.flags(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<DescriptorSer> for Descriptor {
+    fn from(desc: DescriptorSer) -> Self {
+        Descriptor::new(desc.addr, desc.len, desc.id, desc.flags)
+    }
+}
+
 /// A packed descriptor event constraints with C representation.
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
@@ -111,11 +153,12 @@ pub struct PackedDescEvent {
 }
 
 impl PackedDescEvent {
-    /// Create a new `VirtqUsedElem` instance.
+    /// Create a new `PackedDescEvent` instance.
     ///
     /// # Arguments
-    /// * `id` - the index of the used descriptor chain.
-    /// * `len` - the total length of the descriptor chain which was used (written to).
+    /// * `off_wrap` - the descriptor ring offset (bits 0 to 14) and wrap counter (bit 15) of the
+    ///   event.
+    /// * `flags` - the event suppression mode.
     #[allow(unused)]
     pub(crate) fn new(off_wrap: u16, flags: u16) -> Self {
         PackedDescEvent {
@@ -123,6 +166,22 @@ impl PackedDescEvent {
             flags: flags.into(),
         }
     }
+
+    /// Return the descriptor ring offset (bits 0 to 14) and wrap counter (bit 15) of the event.
+    pub fn off_wrap(&self) -> u16 {
+        self.off_wrap.into()
+    }
+
+    /// Return the event suppression mode, one of `VRING_PACKED_EVENT_FLAG_ENABLE`,
+    /// `VRING_PACKED_EVENT_FLAG_DISABLE` or `VRING_PACKED_EVENT_FLAG_DESC`.
+    pub fn flags(&self) -> u16 {
+        self.flags.into()
+    }
+
+    /// Set the event suppression mode.
+    pub fn set_flags(&mut self, flags: u16) {
+        self.flags = flags.into();
+    }
 }
 
 // SAFETY: This is safe because `PackedDescEvent` contains only wrappers over POD types and
@@ -199,5 +258,24 @@ mod tests {
         e2.as_mut_slice().copy_from_slice(e1.as_slice());
         assert_eq!(e1.off_wrap, e2.off_wrap);
         assert_eq!(e1.flags, e2.flags);
+
+        e2.set_flags(1);
+        assert_eq!(e2.flags(), 1);
+        assert_eq!(e2.off_wrap(), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_descriptor_serde() {
+        let desc = Descriptor::new(0x1000, 0x200, 5, VRING_DESC_F_WRITE as u16);
+
+        let json = serde_json::to_string(&desc).unwrap();
+        assert_eq!(json, r#"{"addr":4096,"len":512,"id":5,"flags":2}"#);
+
+        let restored: Descriptor = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.addr(), desc.addr());
+        assert_eq!(restored.len(), desc.len());
+        assert_eq!(restored.id(), desc.id());
+        assert_eq!(restored.flags(), desc.flags());
     }
 }
@@ -11,8 +11,12 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //! split descriptor
 
-use vm_memory::{ByteValued, GuestAddress, Le16, Le32, Le64};
+use vm_memory::bitmap::{BitmapSlice, WithBitmapSlice};
+use vm_memory::{
+    ByteValued, GuestAddress, GuestMemory, GuestMemoryRegion, Le16, Le32, Le64, VolatileSlice,
+};
 
+use crate::Error;
 use virtio_bindings::bindings::virtio_ring::{
     VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE,
 };
@@ -58,8 +62,16 @@ use virtio_bindings::bindings::virtio_ring::{
 /// let _refers_to_ind_table = desc.refers_to_indirect_table();
 /// ```
 /// A virtio split descriptor constraints with C representation.
+///
+/// With the `serde` feature, it is serialized as a `SplitDescriptor` structure holding the
+/// plain values of the `addr`, `len`, `flags` and `next` fields.
 #[repr(C)]
 #[derive(Default, Clone, Copy, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "DescriptorSer", into = "DescriptorSer")
+)]
 pub struct Descriptor {
     /// Guest physical address of device specific data.
     addr: Le64,
@@ -78,11 +90,15 @@ pub struct Descriptor {
 impl Descriptor {
     /// Create a new descriptor.
     ///
+    /// The arguments follow the layout of the descriptor in memory, so `flags` comes before
+    /// `next`, while for packed descriptors `id` comes before `flags`.
+    ///
     /// # Arguments
     /// * `addr` - the guest physical address of the descriptor buffer.
     /// * `len` - the length of the descriptor buffer.
     /// * `flags` - the `flags` for the descriptor.
-    /// * `next` - the `next` field of the descriptor.
+    /// * `next` - the index of the next descriptor of the chain, used when `flags` has
+    ///   `VRING_DESC_F_NEXT` set.
     pub fn new(addr: u64, len: u32, flags: u16, next: u16) -> Self {
         Descriptor {
             addr: addr.into(),
@@ -112,6 +128,40 @@ impl Descriptor {
         self.next.into()
     }
 
+    /// Check whether the descriptor buffer `[addr, addr + len)` is entirely backed by `mem`.
+    ///
+    /// Returns `false` if the end of the buffer overflows the guest address space. An empty
+    /// buffer is always valid since it's never accessed.
+    pub fn is_valid<M: GuestMemory>(&self, mem: &M) -> bool {
+        let len = self.len();
+        if len == 0 {
+            return true;
+        }
+
+        self.addr().0.checked_add(u64::from(len)).is_some()
+            && mem.check_range(self.addr(), len as usize)
+    }
+
+    /// Return a volatile slice over the descriptor buffer `[addr, addr + len)`.
+    ///
+    /// This gives direct access to the buffer without copying it. `Error::FindMemoryRegion` is
+    /// returned if `addr` is not backed by `mem`, and `Error::GuestMemory` if the buffer
+    /// doesn't fit in the memory region holding `addr`, e.g. because it crosses a region
+    /// boundary.
+    pub fn slice<'a, M, B>(&self, mem: &'a M) -> Result<VolatileSlice<'a, B>, Error>
+    where
+        M: GuestMemory,
+        B: BitmapSlice,
+        <<M as GuestMemory>::R as GuestMemoryRegion>::B: WithBitmapSlice<'a, S = B>,
+    {
+        let (region, addr) = mem
+            .to_region_addr(self.addr())
+            .ok_or(Error::FindMemoryRegion)?;
+        region
+            .get_slice(addr, self.len() as usize)
+            .map_err(Error::GuestMemory)
+    }
+
     /// Check whether this descriptor refers to a buffer containing an indirect descriptor table.
     pub fn refers_to_indirect_table(&self) -> bool {
         self.flags() & VRING_DESC_F_INDIRECT as u16 != 0
@@ -129,6 +179,17 @@ impl Descriptor {
     pub fn is_write_only(&self) -> bool {
         self.flags() & VRING_DESC_F_WRITE as u16 != 0
     }
+
+    // Reverse the byte order of all the fields, to convert between the little-endian layout
+    // and the big-endian one of legacy queues.
+    pub(crate) fn swap_bytes(&self) -> Self {
+        Descriptor::new(
+            u64::from(self.addr).swap_bytes(),
+            self.len().swap_bytes(),
+            self.flags().swap_bytes(),
+            self.next().swap_bytes(),
+        )
+    }
 }
 
 #[cfg(any(test, feature = "test-utils"))]
@@ -159,6 +220,37 @@ impl Descriptor {
 // included in there.
 unsafe impl ByteValued for Descriptor {}
 
+// The little endian wrappers from `vm-memory` can't be serialized, so the descriptor goes
+// through this plain representation instead.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename = "SplitDescriptor")]
+struct DescriptorSer {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[cfg(feature = "serde")]
+impl From<Descriptor> for DescriptorSer {
+    fn from(desc: Descriptor) -> Self {
+        DescriptorSer {
+            addr: desc.addr().0,
+            len: desc.len(),
+            flags: desc.flags(),
+            next: desc.next(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<DescriptorSer> for Descriptor {
+    fn from(desc: DescriptorSer) -> Self {
+        Descriptor::new(desc.addr, desc.len, desc.flags, desc.next)
+    }
+}
+
 /// Represents the contents of an element from the used virtqueue ring.
 // Note that the `ByteValued` implementation of this structure expects the `VirtqUsedElem` to store
 // only plain old data types.
@@ -169,24 +261,20 @@ pub struct VirtqUsedElem {
     len: Le32,
 }
 
+#[allow(clippy::len_without_is_empty)]
 impl VirtqUsedElem {
     /// Create a new `VirtqUsedElem` instance.
     ///
     /// # Arguments
     /// * `id` - the index of the used descriptor chain.
     /// * `len` - the total length of the descriptor chain which was used (written to).
-    #[allow(unused)]
-    pub(crate) fn new(id: u32, len: u32) -> Self {
+    pub fn new(id: u32, len: u32) -> Self {
         VirtqUsedElem {
             id: id.into(),
             len: len.into(),
         }
     }
-}
 
-#[cfg(any(test, feature = "test-utils"))]
-#[allow(clippy::len_without_is_empty)]
-impl VirtqUsedElem {
     /// Get the index of the used descriptor chain.
     pub fn id(&self) -> u32 {
         self.id.into()
@@ -196,6 +284,22 @@ impl VirtqUsedElem {
     pub fn len(&self) -> u32 {
         self.len.into()
     }
+
+    /// Set the index of the used descriptor chain.
+    pub fn set_id(&mut self, id: u32) {
+        self.id = id.into();
+    }
+
+    /// Set the `length` field of the used ring entry.
+    pub fn set_len(&mut self, len: u32) {
+        self.len = len.into();
+    }
+
+    // Reverse the byte order of all the fields, to convert between the little-endian layout
+    // and the big-endian one of legacy queues.
+    pub(crate) fn swap_bytes(&self) -> Self {
+        VirtqUsedElem::new(self.id().swap_bytes(), self.len().swap_bytes())
+    }
 }
 
 // SAFETY: This is safe because `VirtqUsedElem` contains only wrappers over POD types
@@ -208,6 +312,7 @@ mod tests {
     use super::*;
     use memoffset::offset_of;
     use std::mem::{align_of, size_of};
+    use vm_memory::{Bytes, GuestMemoryMmap};
 
     #[test]
     fn test_descriptor_offset() {
@@ -246,6 +351,27 @@ mod tests {
         assert_eq!(desc.next(), 3);
     }
 
+    #[test]
+    fn test_descriptor_is_valid() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+            (GuestAddress(0x4000), 0x1000),
+        ])
+        .unwrap();
+
+        assert!(Descriptor::new(0x100, 0x100, 0, 0).is_valid(&mem));
+        // Buffers may span contiguous regions.
+        assert!(Descriptor::new(0xf00, 0x200, 0, 0).is_valid(&mem));
+        // The buffer spills past the end of a region into a hole.
+        assert!(!Descriptor::new(0x1f00, 0x200, 0, 0).is_valid(&mem));
+        assert!(!Descriptor::new(0x2000, 0x10, 0, 0).is_valid(&mem));
+        // Overflow at the top of the address space.
+        assert!(!Descriptor::new(u64::MAX - 0xf, 0x100, 0, 0).is_valid(&mem));
+        // Empty buffers are never accessed.
+        assert!(Descriptor::new(u64::MAX, 0, 0, 0).is_valid(&mem));
+    }
+
     #[test]
     fn test_descriptor_copy() {
         let e1 = Descriptor::new(1, 2, VRING_DESC_F_NEXT as u16, 3);
@@ -258,6 +384,64 @@ mod tests {
         assert_eq!(e1.next(), e2.next());
     }
 
+    #[test]
+    fn test_descriptor_slice() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+        ])
+        .unwrap();
+
+        let desc = Descriptor::new(0x800, 0x100, 0, 0);
+        let slice = desc.slice(&mem).unwrap();
+        assert_eq!(slice.len(), 0x100);
+        slice.copy_from(&[0xaa_u8; 0x100]);
+        let mut buf = [0u8; 0x100];
+        mem.read_slice(&mut buf, GuestAddress(0x800)).unwrap();
+        assert_eq!(buf, [0xaa; 0x100]);
+
+        // The buffer crosses the boundary between the two regions.
+        let desc = Descriptor::new(0xf00, 0x200, 0, 0);
+        assert!(matches!(
+            desc.slice(&mem).unwrap_err(),
+            Error::GuestMemory(_)
+        ));
+
+        let desc = Descriptor::new(0x3000, 0x100, 0, 0);
+        assert_eq!(desc.slice(&mem).unwrap_err(), Error::FindMemoryRegion);
+    }
+
+    #[test]
+    fn test_descriptor_guest_memory_layout() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let desc = Descriptor::new(
+            0x0807_0605_0403_0201,
+            0x0c0b_0a09,
+            VRING_DESC_F_NEXT as u16,
+            0x100f,
+        );
+        mem.write_obj(desc, GuestAddress(0x100)).unwrap();
+
+        // The descriptor is stored as its little-endian fields, with nothing in between.
+        let mut bytes = [0u8; 16];
+        mem.read_slice(&mut bytes, GuestAddress(0x100)).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x01, 0x00,
+                0x0f, 0x10
+            ]
+        );
+        assert_eq!(desc.as_slice(), bytes);
+
+        let read: Descriptor = mem.read_obj(GuestAddress(0x100)).unwrap();
+        assert_eq!(read.as_slice(), desc.as_slice());
+        assert_eq!(read.addr(), GuestAddress(0x0807_0605_0403_0201));
+        assert_eq!(read.len(), 0x0c0b_0a09);
+        assert_eq!(read.flags(), VRING_DESC_F_NEXT as u16);
+        assert_eq!(read.next(), 0x100f);
+    }
+
     #[test]
     fn test_used_elem_offset() {
         assert_eq!(offset_of!(VirtqUsedElem, id), 0);
@@ -274,4 +458,39 @@ mod tests {
         assert_eq!(e1.id, e2.id);
         assert_eq!(e1.len, e2.len);
     }
+
+    #[test]
+    fn test_used_elem_guest_memory() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut elem = VirtqUsedElem::new(0, 0);
+        elem.set_id(0x0403_0201);
+        elem.set_len(0x0807_0605);
+        assert_eq!(elem.id(), 0x0403_0201);
+        assert_eq!(elem.len(), 0x0807_0605);
+        mem.write_obj(elem, GuestAddress(0x100)).unwrap();
+
+        // The element is stored as its little-endian fields.
+        let mut bytes = [0u8; 8];
+        mem.read_slice(&mut bytes, GuestAddress(0x100)).unwrap();
+        assert_eq!(bytes, [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+
+        let read: VirtqUsedElem = mem.read_obj(GuestAddress(0x100)).unwrap();
+        assert_eq!(read.id(), 0x0403_0201);
+        assert_eq!(read.len(), 0x0807_0605);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_descriptor_serde() {
+        let desc = Descriptor::new(0x1000, 0x200, VRING_DESC_F_NEXT as u16, 3);
+
+        let json = serde_json::to_string(&desc).unwrap();
+        assert_eq!(json, r#"{"addr":4096,"len":512,"flags":1,"next":3}"#);
+
+        let restored: Descriptor = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.addr(), desc.addr());
+        assert_eq!(restored.len(), desc.len());
+        assert_eq!(restored.flags(), desc.flags());
+        assert_eq!(restored.next(), desc.next());
+    }
 }
@@ -16,11 +16,38 @@ use std::{cmp, result};
 use crate::{DescriptorChain, Error};
 use vm_memory::bitmap::{BitmapSlice, WithBitmapSlice};
 use vm_memory::{
-    Address, ByteValued, GuestMemory, GuestMemoryRegion, MemoryRegionAddress, VolatileSlice,
+    Address, ByteValued, GuestAddress, GuestMemory, GuestMemoryRegion, MemoryRegionAddress,
+    VolatileSlice,
 };
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Translates the buffers referenced by descriptors into host memory slices.
+///
+/// Every `GuestMemory` implements this trait, so by default descriptor buffers are looked up in
+/// guest memory. Devices that expose memory which is not part of `GuestMemory` (e.g. host visible
+/// shared memory regions) can provide their own implementation, and use it with
+/// [`Reader::with_resolver`] and [`Writer::with_resolver`].
+pub trait MemoryResolver<'a, B: BitmapSlice> {
+    /// Return a slice covering the `len` bytes starting at `addr`.
+    fn resolve(&'a self, addr: GuestAddress, len: usize) -> Result<VolatileSlice<'a, B>>;
+}
+
+impl<'a, M, B> MemoryResolver<'a, B> for M
+where
+    M: GuestMemory,
+    B: BitmapSlice,
+    <<M as GuestMemory>::R as GuestMemoryRegion>::B: WithBitmapSlice<'a, S = B>,
+{
+    fn resolve(&'a self, addr: GuestAddress, len: usize) -> Result<VolatileSlice<'a, B>> {
+        let region = self.find_region(addr).ok_or(Error::FindMemoryRegion)?;
+        let offset = addr.checked_sub(region.start_addr().raw_value()).unwrap();
+        region
+            .get_slice(MemoryRegionAddress(offset.raw_value()), len)
+            .map_err(Error::GuestMemoryError)
+    }
+}
+
 #[derive(Clone)]
 struct DescriptorChainConsumer<'a, B> {
     buffers: VecDeque<VolatileSlice<'a, B>>,
@@ -157,36 +184,45 @@ pub struct Reader<'a, B = ()> {
 
 impl<'a, B: BitmapSlice> Reader<'a, B> {
     /// Construct a new Reader wrapper over `desc_chain`.
+    ///
+    /// The whole chain is walked, so an error is returned if it's malformed, as reported by
+    /// [`DescriptorChain::try_next`].
     pub fn new<M, T>(mem: &'a M, desc_chain: DescriptorChain<T>) -> Result<Reader<'a, B>>
     where
         M: GuestMemory,
         <<M as GuestMemory>::R as GuestMemoryRegion>::B: WithBitmapSlice<'a, S = B>,
         T: Deref,
         T::Target: GuestMemory + Sized,
+    {
+        Self::with_resolver(mem, desc_chain)
+    }
+
+    /// Construct a new Reader wrapper over `desc_chain`, looking up the descriptor buffers
+    /// through `resolver` instead of guest memory.
+    pub fn with_resolver<R, T>(
+        resolver: &'a R,
+        desc_chain: DescriptorChain<T>,
+    ) -> Result<Reader<'a, B>>
+    where
+        R: MemoryResolver<'a, B> + ?Sized,
+        T: Deref,
+        T::Target: GuestMemory + Sized,
     {
         let mut total_len: usize = 0;
-        let buffers = desc_chain
-            .readable()
-            .map(|desc| {
-                // Verify that summing the descriptor sizes does not overflow.
-                // This can happen if a driver tricks a device into reading more data than
-                // fits in a `usize`.
-                total_len = total_len
-                    .checked_add(desc.len() as usize)
-                    .ok_or(Error::DescriptorChainOverflow)?;
-
-                let region = mem
-                    .find_region(desc.addr())
-                    .ok_or(Error::FindMemoryRegion)?;
-                let offset = desc
-                    .addr()
-                    .checked_sub(region.start_addr().raw_value())
-                    .unwrap();
-                region
-                    .get_slice(MemoryRegionAddress(offset.raw_value()), desc.len() as usize)
-                    .map_err(Error::GuestMemoryError)
-            })
-            .collect::<Result<VecDeque<VolatileSlice<'a, B>>>>()?;
+        let mut buffers = VecDeque::new();
+        // Walking the chain with `try_next` makes sure a malformed chain, e.g. one rejected by
+        // strict validation, isn't silently cut short.
+        let mut descs = desc_chain.readable();
+        while let Some(desc) = descs.try_next()? {
+            // Verify that summing the descriptor sizes does not overflow.
+            // This can happen if a driver tricks a device into reading more data than
+            // fits in a `usize`.
+            total_len = total_len
+                .checked_add(desc.len() as usize)
+                .ok_or(Error::DescriptorChainOverflow)?;
+
+            buffers.push_back(resolver.resolve(desc.addr(), desc.len() as usize)?);
+        }
         Ok(Reader {
             buffer: DescriptorChainConsumer {
                 buffers,
@@ -269,36 +305,43 @@ pub struct Writer<'a, B = ()> {
 
 impl<'a, B: BitmapSlice> Writer<'a, B> {
     /// Construct a new Writer wrapper over `desc_chain`.
+    ///
+    /// Same as for [`Reader::new`], an error is returned if the chain is malformed.
     pub fn new<M, T>(mem: &'a M, desc_chain: DescriptorChain<T>) -> Result<Writer<'a, B>>
     where
         M: GuestMemory,
         <<M as GuestMemory>::R as GuestMemoryRegion>::B: WithBitmapSlice<'a, S = B>,
         T: Deref,
         T::Target: GuestMemory + Sized,
+    {
+        Self::with_resolver(mem, desc_chain)
+    }
+
+    /// Construct a new Writer wrapper over `desc_chain`, looking up the descriptor buffers
+    /// through `resolver` instead of guest memory.
+    pub fn with_resolver<R, T>(
+        resolver: &'a R,
+        desc_chain: DescriptorChain<T>,
+    ) -> Result<Writer<'a, B>>
+    where
+        R: MemoryResolver<'a, B> + ?Sized,
+        T: Deref,
+        T::Target: GuestMemory + Sized,
     {
         let mut total_len: usize = 0;
-        let buffers = desc_chain
-            .writable()
-            .map(|desc| {
-                // Verify that summing the descriptor sizes does not overflow.
-                // This can happen if a driver tricks a device into writing more data than
-                // fits in a `usize`.
-                total_len = total_len
-                    .checked_add(desc.len() as usize)
-                    .ok_or(Error::DescriptorChainOverflow)?;
-
-                let region = mem
-                    .find_region(desc.addr())
-                    .ok_or(Error::FindMemoryRegion)?;
-                let offset = desc
-                    .addr()
-                    .checked_sub(region.start_addr().raw_value())
-                    .unwrap();
-                region
-                    .get_slice(MemoryRegionAddress(offset.raw_value()), desc.len() as usize)
-                    .map_err(Error::GuestMemoryError)
-            })
-            .collect::<Result<VecDeque<VolatileSlice<'a, B>>>>()?;
+        let mut buffers = VecDeque::new();
+        // Same as for `Reader`, don't let a malformed chain look like a shorter valid one.
+        let mut descs = desc_chain.writable();
+        while let Some(desc) = descs.try_next()? {
+            // Verify that summing the descriptor sizes does not overflow.
+            // This can happen if a driver tricks a device into writing more data than
+            // fits in a `usize`.
+            total_len = total_len
+                .checked_add(desc.len() as usize)
+                .ok_or(Error::DescriptorChainOverflow)?;
+
+            buffers.push_back(resolver.resolve(desc.addr(), desc.len() as usize)?);
+        }
 
         Ok(Writer {
             buffer: DescriptorChainConsumer {
@@ -362,6 +405,62 @@ impl<B: BitmapSlice> io::Write for Writer<'_, B> {
     }
 }
 
+/// A [`Writer`] that writes a device generated prefix, such as a response header, into the
+/// writable descriptors before any payload.
+///
+/// The prefix is written on the first write (or flush), so the header and the body end up
+/// back to back in the chain without the caller having to write them separately.
+pub struct ChainWriter<'a, 'p, B = ()> {
+    writer: Writer<'a, B>,
+    prefix: &'p [u8],
+}
+
+impl<'a, 'p, B: BitmapSlice> ChainWriter<'a, 'p, B> {
+    /// Construct a new `ChainWriter` that writes `prefix` through `writer` ahead of the payload.
+    pub fn new(writer: Writer<'a, B>, prefix: &'p [u8]) -> Self {
+        ChainWriter { writer, prefix }
+    }
+
+    /// Returns number of bytes still available for the payload.
+    pub fn available_bytes(&self) -> usize {
+        self.writer
+            .available_bytes()
+            .saturating_sub(self.prefix.len())
+    }
+
+    /// Returns the total number of bytes written to the descriptor chain buffer, including the
+    /// prefix once it's written.
+    pub fn bytes_written(&self) -> usize {
+        self.writer.bytes_written()
+    }
+
+    /// Write the prefix if it wasn't written yet, and return the underlying `Writer`.
+    pub fn into_inner(mut self) -> io::Result<Writer<'a, B>> {
+        self.write_prefix()?;
+        Ok(self.writer)
+    }
+
+    fn write_prefix(&mut self) -> io::Result<()> {
+        if !self.prefix.is_empty() {
+            self.writer.write_all(self.prefix)?;
+            self.prefix = &[];
+        }
+        Ok(())
+    }
+}
+
+impl<B: BitmapSlice> io::Write for ChainWriter<'_, '_, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_prefix()?;
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_prefix()?;
+        self.writer.flush()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -369,7 +468,7 @@ mod tests {
         desc::{split::Descriptor as SplitDescriptor, RawDescriptor},
         Queue, QueueOwnedT, QueueT,
     };
-    use vm_memory::{GuestAddress, GuestMemoryMmap, Le32};
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap, Le32};
 
     use crate::mock::MockSplitQueue;
     use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
@@ -434,6 +533,76 @@ mod tests {
         Ok(chain.clone())
     }
 
+    // Services addresses starting at `base` from a separate buffer, and everything else from
+    // guest memory.
+    struct SharedRegionResolver<'m> {
+        mem: &'m GuestMemoryMmap,
+        base: GuestAddress,
+        shm: VolatileSlice<'m>,
+    }
+
+    impl<'a, 'm: 'a> MemoryResolver<'a, ()> for SharedRegionResolver<'m> {
+        fn resolve(&'a self, addr: GuestAddress, len: usize) -> Result<VolatileSlice<'a>> {
+            match addr.checked_offset_from(self.base) {
+                Some(offset) => self
+                    .shm
+                    .subslice(offset as usize, len)
+                    .map_err(Error::VolatileMemoryError),
+                None => self.mem.resolve(addr, len),
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_memory_resolver() {
+        let memory: GuestMemoryMmap =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        let mut shm_buf = vec![0u8; 0x1000];
+        let resolver = SharedRegionResolver {
+            mem: &memory,
+            base: GuestAddress(0x10_0000),
+            shm: VolatileSlice::from(shm_buf.as_mut_slice()),
+        };
+
+        let queue = MockSplitQueue::create(&memory, GuestAddress(0x0), MAX_QUEUE_SIZE);
+        // The first buffer is in guest memory, the second one in the shared region, which is
+        // not part of guest memory.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x8000, 4, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x10_0010, 4, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x10_0020,
+                8,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        let chain = queue.build_desc_chain(&descs).unwrap();
+
+        memory
+            .write_slice(&[1, 2, 3, 4], GuestAddress(0x8000))
+            .unwrap();
+        resolver.shm.write_slice(&[5, 6, 7, 8], 0x10).unwrap();
+
+        // Guest memory alone can't resolve the second buffer.
+        assert!(matches!(
+            Reader::new(&memory, chain.clone()),
+            Err(Error::FindMemoryRegion)
+        ));
+
+        let mut reader = Reader::with_resolver(&resolver, chain.clone()).unwrap();
+        assert_eq!(reader.available_bytes(), 8);
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let mut writer = Writer::with_resolver(&resolver, chain).unwrap();
+        writer.write_all(&[9; 8]).unwrap();
+        let mut buf = [0u8; 8];
+        resolver.shm.read_slice(&mut buf, 0x20).unwrap();
+        assert_eq!(buf, [9; 8]);
+    }
+
     #[test]
     fn reader_test_inv_desc_addr() {
         let memory: GuestMemoryMmap =
@@ -659,6 +828,100 @@ mod tests {
         }
     }
 
+    #[test]
+    fn reader_writer_object_straddles_two_descriptors() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let memory = GuestMemoryMmap::from_ranges(&[(memory_start_addr, 0x10000)]).unwrap();
+
+        // A 4 byte header followed by a 8 byte payload that starts in the first descriptor and
+        // ends in the second one.
+        let header: Le32 = 0xdead_beef.into();
+        let payload: u64 = 0x0102_0304_0506_0708;
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            vec![(Writable, 6), (Writable, 6)],
+            64,
+        )
+        .expect("create_descriptor_chain failed");
+        let mut writer = Writer::new(&memory, chain).expect("failed to create Writer");
+        writer.write_obj(header).unwrap();
+        writer.write_obj(payload).unwrap();
+        assert_eq!(writer.bytes_written(), 12);
+        assert_eq!(writer.available_bytes(), 0);
+        // The chain is exhausted, so any further write is short.
+        assert_eq!(
+            writer.write_obj(0u8).unwrap_err().kind(),
+            io::ErrorKind::WriteZero
+        );
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            vec![(Readable, 6), (Readable, 6)],
+            64,
+        )
+        .expect("create_descriptor_chain failed");
+        let mut reader = Reader::new(&memory, chain).expect("failed to create Reader");
+        assert_eq!(reader.read_obj::<Le32>().unwrap(), header);
+        assert_eq!(reader.read_obj::<u64>().unwrap(), payload);
+        assert_eq!(reader.bytes_read(), 12);
+        assert_eq!(
+            reader.read_obj::<u8>().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn chain_writer_prefix() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let memory = GuestMemoryMmap::from_ranges(&[(memory_start_addr, 0x10000)]).unwrap();
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            vec![(Readable, 8), (Writable, 4), (Writable, 8)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        let desc = chain.clone().writable().next().unwrap();
+
+        let writer = Writer::new(&memory, chain.clone()).expect("failed to create Writer");
+        let header = [0xaa_u8; 6];
+        let mut writer = ChainWriter::new(writer, &header);
+        assert_eq!(writer.available_bytes(), 6);
+        assert_eq!(writer.bytes_written(), 0);
+
+        writer.write_all(&[0x55; 6]).unwrap();
+        assert_eq!(writer.bytes_written(), 12);
+        assert_eq!(writer.available_bytes(), 0);
+        // The chain has no more room.
+        assert_eq!(writer.write(&[0x55]).unwrap(), 0);
+
+        // The writable descriptors are contiguous in memory, the header spills over the first
+        // one and the body follows it.
+        let mut buf = [0u8; 12];
+        memory.read_slice(&mut buf, desc.addr()).unwrap();
+        assert_eq!(
+            buf,
+            [0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55]
+        );
+
+        // The prefix is written even without any payload, but has to fit in the chain.
+        let writer = Writer::new(&memory, chain.clone()).expect("failed to create Writer");
+        let writer = ChainWriter::new(writer, &[0x11; 4]).into_inner().unwrap();
+        assert_eq!(writer.bytes_written(), 4);
+
+        let writer = Writer::new(&memory, chain).expect("failed to create Writer");
+        let mut writer = ChainWriter::new(writer, &[0x11; 16]);
+        assert_eq!(writer.flush().unwrap_err().kind(), io::ErrorKind::WriteZero);
+    }
+
     #[test]
     fn reader_unexpected_eof() {
         use DescriptorType::*;
@@ -20,21 +20,29 @@ use std::ops::{Deref, DerefMut};
 use std::sync::atomic::Ordering;
 
 use log::error;
-use vm_memory::{GuestMemory, GuestMemoryError, VolatileMemoryError};
-
-pub use self::chain::{DescriptorChain, DescriptorChainRwIter};
-pub use self::descriptor_utils::{Reader, Writer};
-pub use self::queue::{AvailIter, Queue};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError, VolatileMemoryError};
+
+pub use self::any_queue::{create_queue, AnyQueue};
+pub use self::chain::{DescriptorChain, DescriptorChainRwIter, WritableSegments};
+pub use self::descriptor_utils::{ChainWriter, MemoryResolver, Reader, Writer};
+pub use self::notify::AvailNotify;
+pub use self::packed_chain::PackedDescriptorChain;
+pub use self::packed_queue::PackedQueue;
+pub use self::queue::{publish_all, AvailIter, AvailSnapshot, Queue, QueueStats};
 pub use self::queue_sync::QueueSync;
-pub use self::state::QueueState;
+pub use self::state::{CompactQueueState, QueueState, QueueStateFfi};
 
 pub mod defs;
 pub mod desc;
 #[cfg(any(test, feature = "test-utils"))]
 pub mod mock;
 
+mod any_queue;
 mod chain;
 mod descriptor_utils;
+mod notify;
+mod packed_chain;
+mod packed_queue;
 mod queue;
 mod queue_sync;
 mod state;
@@ -78,6 +86,44 @@ pub enum Error {
     GuestMemoryError(GuestMemoryError),
     /// DescriptorChain split is out of bounds.
     SplitOutOfBounds(usize),
+    /// The descriptor chain starting at the given head index is longer than the descriptor table
+    /// it belongs to.
+    DescriptorChainTooLong(u16),
+    /// The restored ring positions imply more in-flight descriptor chains than the queue size.
+    InconsistentRestore,
+    /// A device-writable descriptor points to memory the device is not allowed to write to.
+    WriteToReadOnly,
+    /// Attempted to go past the last descriptor of a chain.
+    NoMoreDescriptors,
+    /// Two descriptors of the same chain point to overlapping buffers.
+    OverlappingDescriptors,
+    /// The device-writable descriptor at the given index of its table has a zero length (only
+    /// reported in strict mode).
+    ZeroLengthWritableDescriptor(u16),
+    /// The head descriptor of a chain can't be read.
+    EmptyDescriptorChain,
+    /// A descriptor buffer is not aligned as required.
+    Misaligned,
+    /// The descriptor table, the available ring and the used ring overlap.
+    OverlappingRings,
+    /// The queue is paused and can't write to guest memory.
+    QueuePaused,
+    /// The queue is being reset.
+    QueueResetting,
+    /// A chain of several descriptors doesn't use an indirect table.
+    IndirectTableRequired,
+    /// A device-readable descriptor follows a device-writable one.
+    InvalidChainOrder,
+    /// More elements were added to the used ring than chains were popped.
+    TooManyUsed,
+    /// A descriptor chain has more than one descriptor.
+    MultiDescriptorChain,
+    /// A descriptor buffer overlaps the descriptor table, the available ring or the used ring.
+    BufferOverlapsRing,
+    /// A batch of used elements is longer than the queue size.
+    UsedBatchTooLong,
+    /// Attempted to give back more chains than are in flight.
+    RewindTooFar,
 }
 
 impl Display for Error {
@@ -117,12 +163,124 @@ impl Display for Error {
             FindMemoryRegion => write!(f, "no memory region for this address range"),
             GuestMemoryError(e) => write!(f, "descriptor guest memory error: {e}"),
             SplitOutOfBounds(off) => write!(f, "`DescriptorChain` split is out of bounds: {off}"),
+            DescriptorChainTooLong(head) => write!(
+                f,
+                "descriptor chain with head {head} is longer than the descriptor table (possible loop)"
+            ),
+            InconsistentRestore => write!(
+                f,
+                "inconsistent queue state (more chains in flight than queue size)"
+            ),
+            WriteToReadOnly => write!(f, "device-writable descriptor points to read-only memory"),
+            NoMoreDescriptors => write!(f, "no more descriptors in the chain"),
+            OverlappingDescriptors => write!(
+                f,
+                "descriptors of the same chain point to overlapping buffers"
+            ),
+            ZeroLengthWritableDescriptor(index) => {
+                write!(f, "device-writable descriptor {index} has zero length")
+            }
+            EmptyDescriptorChain => write!(f, "the head descriptor of the chain can't be read"),
+            Misaligned => write!(f, "descriptor buffer is not aligned as required"),
+            OverlappingRings => write!(f, "virtio queue rings overlap each other"),
+            QueuePaused => write!(f, "virtio queue is paused and can't write to guest memory"),
+            QueueResetting => write!(f, "virtio queue is being reset"),
+            IndirectTableRequired => write!(
+                f,
+                "descriptor chain must use an indirect table to hold several descriptors"
+            ),
+            InvalidChainOrder => write!(
+                f,
+                "device-readable descriptor follows a device-writable one in the chain"
+            ),
+            TooManyUsed => write!(
+                f,
+                "more descriptor chains added to the used ring than popped from the available ring"
+            ),
+            MultiDescriptorChain => write!(f, "descriptor chain has more than one descriptor"),
+            BufferOverlapsRing => write!(f, "descriptor buffer overlaps the virtio queue rings"),
+            UsedBatchTooLong => write!(f, "batch of used elements longer than the queue size"),
+            RewindTooFar => write!(
+                f,
+                "attempted to go back past descriptor chains already added to the used ring"
+            ),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+/// The layout of the rings of a virtio queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RingLayout {
+    /// A split virtqueue, with a descriptor table, an available ring and a used ring.
+    Split,
+    /// A packed virtqueue, with a single descriptor ring and two event suppression areas.
+    Packed,
+}
+
+/// Trait for the methods shared by the split and the packed virtio queues.
+///
+/// Unlike [`QueueT`], it doesn't expose anything specific to the split layout, so it's also
+/// implemented by [`PackedQueue`], and can be used to handle a queue whatever its layout.
+pub trait QueueCommonT {
+    /// Return the layout of the rings of the queue.
+    fn layout(&self) -> RingLayout;
+
+    /// Check whether the queue configuration is valid.
+    fn is_valid<M: GuestMemory>(&self, mem: &M) -> bool;
+
+    /// Reset the queue to the initial state.
+    fn reset(&mut self);
+
+    /// Get the maximum size of the virtio queue.
+    fn max_size(&self) -> u16;
+
+    /// Get the actual size configured by the guest.
+    fn size(&self) -> u16;
+
+    /// Configure the queue size, returning an error in case of invalid size.
+    fn try_set_size(&mut self, size: u16) -> Result<(), Error>;
+
+    /// Check whether the queue is ready to be processed.
+    fn ready(&self) -> bool;
+
+    /// Configure the queue to `ready for processing` state.
+    fn set_ready(&mut self, ready: bool);
+
+    /// Enable/disable the VIRTIO_F_RING_EVENT_IDX feature for interrupt coalescing.
+    fn set_event_idx(&mut self, enabled: bool);
+
+    /// Checks whether `VIRTIO_F_RING_EVENT_IDX` is negotiated.
+    fn event_idx_enabled(&self) -> bool;
+
+    /// Tries to set the descriptor table (or ring, for the packed layout) address. In case of
+    /// an invalid value, the address is not updated.
+    fn try_set_desc_table_address(&mut self, desc_table: GuestAddress) -> Result<(), Error>;
+
+    /// Mark a descriptor chain as used, after the device wrote `len` bytes to its buffers.
+    ///
+    /// The chain is identified by `id`, which is the index of its head descriptor for the split
+    /// layout, and its buffer ID for the packed one.
+    fn add_used<M: GuestMemory>(&mut self, mem: &M, id: u16, len: u32) -> Result<(), Error>;
+
+    /// Enable notification events from the guest driver.
+    ///
+    /// Return true if one or more descriptors can be consumed from the available ring after
+    /// notifications were enabled (and thus it's possible there will be no corresponding
+    /// notification).
+    fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error>;
+
+    /// Disable notification events from the guest driver.
+    fn disable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<(), Error>;
+
+    /// Check whether a notification to the guest is needed.
+    ///
+    /// Please note this method has side effects: it considers the driver is notified about all
+    /// the descriptors used so far whenever it returns `true`.
+    fn needs_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error>;
+}
+
 /// Trait for objects returned by `QueueT::lock()`.
 pub trait QueueGuard<'a> {
     /// Type for guard returned by `Self::lock()`.
@@ -150,6 +308,9 @@ pub trait QueueT: for<'a> QueueGuard<'a> {
     /// Reset the queue to the initial state.
     fn reset(&mut self);
 
+    /// Return the layout of the rings of the queue.
+    fn layout(&self) -> RingLayout;
+
     /// Get an exclusive reference to the underlying `Queue` object.
     ///
     /// Logically this method will acquire the underlying lock protecting the `Queue` Object.
@@ -194,24 +355,53 @@ pub trait QueueT: for<'a> QueueGuard<'a> {
 
     /// Read the `idx` field from the available ring.
     ///
+    /// Like [`QueueOwnedT::iter`], this accepts any handle dereferencing to a `GuestMemory`
+    /// object, such as a reference or an `Arc`.
+    ///
     /// # Panics
     ///
     /// Panics if order is Release or AcqRel.
-    fn avail_idx<M>(&self, mem: &M, order: Ordering) -> Result<Wrapping<u16>, Error>
+    fn avail_idx<M>(&self, mem: M, order: Ordering) -> Result<Wrapping<u16>, Error>
     where
-        M: GuestMemory + ?Sized;
+        M: Deref,
+        M::Target: GuestMemory;
 
     /// Read the `idx` field from the used ring.
     ///
+    /// Like [`QueueOwnedT::iter`], this accepts any handle dereferencing to a `GuestMemory`
+    /// object, such as a reference or an `Arc`.
+    ///
     /// # Panics
     ///
     /// Panics if order is Release or AcqRel.
-    fn used_idx<M: GuestMemory>(&self, mem: &M, order: Ordering) -> Result<Wrapping<u16>, Error>;
+    fn used_idx<M>(&self, mem: M, order: Ordering) -> Result<Wrapping<u16>, Error>
+    where
+        M: Deref,
+        M::Target: GuestMemory;
 
     /// Put a used descriptor head into the used ring.
     fn add_used<M: GuestMemory>(&mut self, mem: &M, head_index: u16, len: u32)
         -> Result<(), Error>;
 
+    /// Put several used descriptor heads into the used ring, given as `(head_index, len)` pairs.
+    ///
+    /// The `idx` field of the used ring is only updated once, after all the elements have been
+    /// written. If any `head_index` is out of bounds, or the batch holds more elements than the
+    /// queue size, an error is returned and none of the elements of the batch are made visible
+    /// to the driver.
+    ///
+    /// The default implementation can't update the `idx` field on its own, so it calls
+    /// [`add_used`](QueueT::add_used) for each element instead: the elements added before an
+    /// error are visible to the driver. Implementations should override it.
+    fn add_used_batch<M, I>(&mut self, mem: &M, used: I) -> Result<(), Error>
+    where
+        M: GuestMemory,
+        I: IntoIterator<Item = (u16, u32)>,
+    {
+        used.into_iter()
+            .try_for_each(|(head_index, len)| self.add_used(mem, head_index, len))
+    }
+
     /// Enable notification events from the guest driver.
     ///
     /// Return true if one or more descriptors can be consumed from the available ring after
@@ -266,6 +456,28 @@ pub trait QueueT: for<'a> QueueGuard<'a> {
     where
         M: Clone + Deref,
         M::Target: GuestMemory;
+
+    /// Return the next available descriptor chain without consuming it, or `None` when there
+    /// are no more descriptor chains available.
+    ///
+    /// The available ring is read the same way as with `pop_descriptor_chain`, but the next
+    /// available index is left unchanged, so the same chain is returned again by the next call
+    /// to `peek_descriptor_chain` or `pop_descriptor_chain`. This allows a device to check it
+    /// has the resources to process a chain before committing to it.
+    ///
+    /// The default implementation pops the chain and restores the next available index
+    /// afterwards, so any other state updated when popping a chain is updated as well.
+    /// Implementations should override it.
+    fn peek_descriptor_chain<M>(&mut self, mem: M) -> Option<DescriptorChain<M>>
+    where
+        M: Clone + Deref,
+        M::Target: GuestMemory,
+    {
+        let next_avail = self.next_avail();
+        let chain = self.pop_descriptor_chain(mem);
+        self.set_next_avail(next_avail);
+        chain
+    }
 }
 
 /// Trait to access and manipulate a Virtio queue that's known to be exclusively accessed
@@ -273,6 +485,10 @@ pub trait QueueT: for<'a> QueueGuard<'a> {
 pub trait QueueOwnedT: QueueT {
     /// Get a consuming iterator over all available descriptor chain heads offered by the driver.
     ///
+    /// Returns [`Error::InvalidAvailRingIndex`] when the `idx` field of the available ring is
+    /// more than the queue size ahead of the next available index, which a well-behaved driver
+    /// can never cause (e.g. it decremented `idx`, or the ring is corrupted).
+    ///
     /// # Arguments
     /// * `mem` - the `GuestMemory` object that can be used to access the queue buffers.
     fn iter<M>(&mut self, mem: M) -> Result<AvailIter<'_, M>, Error>
@@ -283,4 +499,175 @@ pub trait QueueOwnedT: QueueT {
     /// Undo the last advancement of the next available index field by decrementing its
     /// value by one.
     fn go_to_previous_position(&mut self);
+
+    /// Undo the last `n` advancements of the next available index field by decrementing its
+    /// value by `n`, e.g. to give back several descriptor chains the device can't process yet.
+    ///
+    /// Only the chains which were not added to the used ring yet can be given back:
+    /// `Error::RewindTooFar` is returned, and the next available index is left unchanged, if `n`
+    /// is larger than the distance between the next available and the next used indices.
+    fn go_back(&mut self, n: u16) -> Result<(), Error> {
+        if self.next_avail().wrapping_sub(self.next_used()) < n {
+            return Err(Error::RewindTooFar);
+        }
+        for _ in 0..n {
+            self.go_to_previous_position();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let cases = [
+            (Error::AddressOverflow, "address overflow"),
+            (Error::InvalidChain, "invalid descriptor chain"),
+            (
+                Error::InvalidIndirectDescriptor,
+                "invalid indirect descriptor",
+            ),
+            (
+                Error::InvalidIndirectDescriptorTable,
+                "invalid indirect descriptor table",
+            ),
+            (Error::InvalidDescriptorIndex, "invalid descriptor index"),
+            (Error::InvalidMaxSize, "invalid queue maximum size"),
+            (Error::InvalidSize, "invalid queue size"),
+            (
+                Error::InvalidDescTableAlign,
+                "virtio queue descriptor table breaks alignment constraints",
+            ),
+            (
+                Error::InvalidAvailRingAlign,
+                "virtio queue available ring breaks alignment constraints",
+            ),
+            (
+                Error::InvalidUsedRingAlign,
+                "virtio queue used ring breaks alignment constraints",
+            ),
+            (
+                Error::InvalidAvailRingIndex,
+                "invalid available ring index (more descriptors to process than queue size)",
+            ),
+            (
+                Error::QueueNotReady,
+                "trying to process requests on a queue that's not ready",
+            ),
+            (
+                Error::DescriptorChainOverflow,
+                "the combined length of all the buffers in a `DescriptorChain` would overflow",
+            ),
+            (
+                Error::FindMemoryRegion,
+                "no memory region for this address range",
+            ),
+            (
+                Error::SplitOutOfBounds(0x10),
+                "`DescriptorChain` split is out of bounds: 16",
+            ),
+            (
+                Error::DescriptorChainTooLong(3),
+                "descriptor chain with head 3 is longer than the descriptor table (possible loop)",
+            ),
+            (
+                Error::InconsistentRestore,
+                "inconsistent queue state (more chains in flight than queue size)",
+            ),
+            (
+                Error::WriteToReadOnly,
+                "device-writable descriptor points to read-only memory",
+            ),
+            (Error::NoMoreDescriptors, "no more descriptors in the chain"),
+            (
+                Error::OverlappingDescriptors,
+                "descriptors of the same chain point to overlapping buffers",
+            ),
+            (
+                Error::ZeroLengthWritableDescriptor(5),
+                "device-writable descriptor 5 has zero length",
+            ),
+            (
+                Error::EmptyDescriptorChain,
+                "the head descriptor of the chain can't be read",
+            ),
+            (
+                Error::Misaligned,
+                "descriptor buffer is not aligned as required",
+            ),
+            (
+                Error::OverlappingRings,
+                "virtio queue rings overlap each other",
+            ),
+            (
+                Error::QueuePaused,
+                "virtio queue is paused and can't write to guest memory",
+            ),
+            (Error::QueueResetting, "virtio queue is being reset"),
+            (
+                Error::IndirectTableRequired,
+                "descriptor chain must use an indirect table to hold several descriptors",
+            ),
+            (
+                Error::InvalidChainOrder,
+                "device-readable descriptor follows a device-writable one in the chain",
+            ),
+            (
+                Error::TooManyUsed,
+                "more descriptor chains added to the used ring than popped from the available ring",
+            ),
+            (
+                Error::MultiDescriptorChain,
+                "descriptor chain has more than one descriptor",
+            ),
+            (
+                Error::BufferOverlapsRing,
+                "descriptor buffer overlaps the virtio queue rings",
+            ),
+            (
+                Error::UsedBatchTooLong,
+                "batch of used elements longer than the queue size",
+            ),
+            (
+                Error::RewindTooFar,
+                "attempted to go back past descriptor chains already added to the used ring",
+            ),
+        ];
+        for (err, msg) in cases {
+            assert_eq!(err.to_string(), msg);
+        }
+
+        let mem_err = || GuestMemoryError::InvalidGuestAddress(GuestAddress(0x1000));
+        assert_eq!(
+            Error::GuestMemory(mem_err()).to_string(),
+            "error accessing guest memory"
+        );
+        assert_eq!(
+            Error::GuestMemoryError(mem_err()).to_string(),
+            format!("descriptor guest memory error: {}", mem_err())
+        );
+        let volatile_err = || VolatileMemoryError::OutOfBounds { addr: 0x1000 };
+        assert_eq!(
+            Error::VolatileMemoryError(volatile_err()).to_string(),
+            format!("volatile memory error: {}", volatile_err())
+        );
+    }
+
+    #[test]
+    fn test_ring_layout() {
+        let q = Queue::new(16).unwrap();
+        assert_eq!(QueueT::layout(&q), RingLayout::Split);
+        assert_eq!(QueueCommonT::layout(&q), RingLayout::Split);
+        assert_eq!(
+            QueueT::layout(&QueueSync::new(16).unwrap()),
+            RingLayout::Split
+        );
+        assert_eq!(
+            QueueCommonT::layout(&PackedQueue::new(16).unwrap()),
+            RingLayout::Packed
+        );
+    }
 }
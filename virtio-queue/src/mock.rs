@@ -14,13 +14,16 @@ use vm_memory::{
 use crate::defs::{VIRTQ_AVAIL_ELEMENT_SIZE, VIRTQ_AVAIL_RING_HEADER_SIZE};
 use crate::{
     desc::{
+        packed::{Descriptor as PackedDescriptor, PackedDescEvent},
         split::{Descriptor as SplitDescriptor, VirtqUsedElem},
         RawDescriptor,
     },
-    DescriptorChain, Error, Queue, QueueOwnedT, QueueT,
+    DescriptorChain, Error, PackedQueue, Queue, QueueCommonT, QueueOwnedT, QueueT,
 };
 use std::fmt::{self, Debug, Display};
-use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT};
+use virtio_bindings::bindings::virtio_ring::{
+    VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT, VRING_PACKED_DESC_F_AVAIL, VRING_PACKED_DESC_F_USED,
+};
 
 /// Mock related errors.
 #[derive(Debug)]
@@ -158,10 +161,11 @@ impl<'a, M: GuestMemory, T: ByteValued> SplitQueueRing<'a, M, T> {
         self.ring.addr
     }
 
-    /// Return the end address of the `SplitQueueRing`.
+    /// Return the end address of the `SplitQueueRing`, right after its trailing event field.
     pub fn end(&self) -> GuestAddress {
-        self.start()
-            .checked_add(self.ring.len as GuestUsize)
+        self.event
+            .addr
+            .checked_add(size_of::<u16>() as GuestUsize)
             .unwrap()
     }
 
@@ -366,7 +370,7 @@ impl<'a, M: GuestMemory> MockSplitQueue<'a, M> {
         // no longer the case, we should make sure the starting address of the descriptor table
         // we're  creating below is properly aligned.
 
-        let table_len = if len % 16 == 0 {
+        let table_len = if len.is_multiple_of(16) {
             len
         } else {
             16 * (len / 16 + 1)
@@ -523,3 +527,277 @@ impl<'a, M: GuestMemory> MockSplitQueue<'a, M> {
         Ok(())
     }
 }
+
+/// A mock version of a packed virtio queue implemented from the perspective of the driver.
+pub struct MockPackedQueue<'a, M> {
+    len: u16,
+    desc_ring_addr: GuestAddress,
+    desc_ring: ArrayRef<'a, M, PackedDescriptor>,
+    driver_event_addr: GuestAddress,
+    driver_event: Ref<'a, M, PackedDescEvent>,
+    device_event_addr: GuestAddress,
+    device_event: Ref<'a, M, PackedDescEvent>,
+    // Position in the descriptor ring where the driver makes the next descriptor available.
+    avail_idx: u16,
+    // Driver ring wrap counter, flipped every time `avail_idx` wraps around.
+    avail_wrap_counter: bool,
+}
+
+impl<'a, M: GuestMemory> MockPackedQueue<'a, M> {
+    /// Create a new `MockPackedQueue` instance with 0 as the default guest
+    /// physical starting address.
+    pub fn new(mem: &'a M, len: u16) -> Self {
+        Self::create(mem, GuestAddress(0), len)
+    }
+
+    /// Create a new `MockPackedQueue` instance.
+    ///
+    /// The descriptor ring starts at `start`, and is followed by the driver and the device
+    /// event suppression structures.
+    pub fn create(mem: &'a M, start: GuestAddress, len: u16) -> Self {
+        const EVENT_ALIGN: GuestUsize = 4;
+
+        let desc_ring_addr = start;
+        let desc_ring = ArrayRef::new(mem, desc_ring_addr, len as usize);
+        for i in 0..len as usize {
+            desc_ring
+                .ref_at(i)
+                .unwrap()
+                .store(PackedDescriptor::default());
+        }
+
+        let driver_event_addr = desc_ring_addr
+            .checked_add(size_of::<PackedDescriptor>() as GuestUsize * len as GuestUsize)
+            .unwrap()
+            .align_up(EVENT_ALIGN);
+        let driver_event = Ref::new(mem, driver_event_addr);
+        driver_event.store(PackedDescEvent::new(0, 0));
+
+        let device_event_addr = driver_event_addr
+            .checked_add(size_of::<PackedDescEvent>() as GuestUsize)
+            .unwrap();
+        let device_event = Ref::new(mem, device_event_addr);
+        device_event.store(PackedDescEvent::new(0, 0));
+
+        MockPackedQueue {
+            len,
+            desc_ring_addr,
+            desc_ring,
+            driver_event_addr,
+            driver_event,
+            device_event_addr,
+            device_event,
+            avail_idx: 0,
+            avail_wrap_counter: true,
+        }
+    }
+
+    /// Return the starting address of the queue.
+    pub fn start(&self) -> GuestAddress {
+        self.desc_ring_addr
+    }
+
+    /// Return the end address of the queue.
+    pub fn end(&self) -> GuestAddress {
+        self.device_event_addr
+            .checked_add(size_of::<PackedDescEvent>() as GuestUsize)
+            .unwrap()
+    }
+
+    /// Descriptor ring accessor.
+    pub fn desc_ring(&self) -> &ArrayRef<'a, M, PackedDescriptor> {
+        &self.desc_ring
+    }
+
+    /// Driver event suppression structure accessor.
+    pub fn driver_event(&self) -> &Ref<'a, M, PackedDescEvent> {
+        &self.driver_event
+    }
+
+    /// Device event suppression structure accessor.
+    pub fn device_event(&self) -> &Ref<'a, M, PackedDescEvent> {
+        &self.device_event
+    }
+
+    /// Return the starting address of the descriptor ring.
+    pub fn desc_ring_addr(&self) -> GuestAddress {
+        self.desc_ring_addr
+    }
+
+    /// Return the address of the driver event suppression structure.
+    pub fn driver_event_addr(&self) -> GuestAddress {
+        self.driver_event_addr
+    }
+
+    /// Return the address of the device event suppression structure.
+    pub fn device_event_addr(&self) -> GuestAddress {
+        self.device_event_addr
+    }
+
+    /// Return the position where the next descriptor is made available, and the wrap counter
+    /// it will be made available with.
+    pub fn avail_position(&self) -> (u16, bool) {
+        (self.avail_idx, self.avail_wrap_counter)
+    }
+
+    /// Set the `AVAIL` and `USED` flags of the descriptor at `index` so that it's available
+    /// for the device when the driver ring wrap counter is `wrap_counter`.
+    pub fn set_avail_flags(&self, index: u16, wrap_counter: bool) -> Result<(), MockError> {
+        let desc_ref = self.desc_ring.ref_at(index as usize)?;
+        let mut desc = desc_ref.load();
+        desc.set_flags(Self::avail_flags(desc.flags(), wrap_counter));
+        desc_ref.store(desc);
+        Ok(())
+    }
+
+    /// Set the `AVAIL` and `USED` flags of the descriptor at `index` the way the device does
+    /// when it marks the descriptor as used with the device ring wrap counter `wrap_counter`.
+    pub fn set_used_flags(&self, index: u16, wrap_counter: bool) -> Result<(), MockError> {
+        let desc_ref = self.desc_ring.ref_at(index as usize)?;
+        let mut desc = desc_ref.load();
+        let mask = (1 << VRING_PACKED_DESC_F_AVAIL) | (1 << VRING_PACKED_DESC_F_USED);
+        let flags = if wrap_counter {
+            desc.flags() | mask
+        } else {
+            desc.flags() & !mask
+        };
+        desc.set_flags(flags);
+        desc_ref.store(desc);
+        Ok(())
+    }
+
+    fn avail_flags(flags: u16, wrap_counter: bool) -> u16 {
+        let avail = 1 << VRING_PACKED_DESC_F_AVAIL;
+        let used = 1 << VRING_PACKED_DESC_F_USED;
+        if wrap_counter {
+            (flags | avail) & !used
+        } else {
+            (flags | used) & !avail
+        }
+    }
+
+    /// Make a descriptor chain available in the descriptor ring, starting at the current
+    /// driver position, and return the position of its head.
+    ///
+    /// The `NEXT` flag is set on all the descriptors except the last one, and the `AVAIL` and
+    /// `USED` flags are set according to the driver ring wrap counter, which is flipped when
+    /// the chain wraps around the end of the ring. The head descriptor is written last, like a
+    /// driver does to publish the whole chain at once.
+    pub fn add_desc_chain(&mut self, descs: &[PackedDescriptor]) -> Result<u16, MockError> {
+        if descs.is_empty() || descs.len() > usize::from(self.len) {
+            return Err(MockError::InvalidIndex);
+        }
+
+        let head = self.avail_idx;
+        let mut entries = Vec::with_capacity(descs.len());
+        for (idx, desc) in descs.iter().enumerate() {
+            let mut flags = if idx == descs.len() - 1 {
+                desc.flags() & !VRING_DESC_F_NEXT as u16
+            } else {
+                desc.flags() | VRING_DESC_F_NEXT as u16
+            };
+            flags = Self::avail_flags(flags, self.avail_wrap_counter);
+            entries.push((
+                self.avail_idx,
+                PackedDescriptor::new(desc.addr().0, desc.len(), desc.id(), flags),
+            ));
+
+            self.avail_idx += 1;
+            if self.avail_idx == self.len {
+                self.avail_idx = 0;
+                self.avail_wrap_counter = !self.avail_wrap_counter;
+            }
+        }
+
+        for (index, desc) in entries.into_iter().rev() {
+            self.desc_ring.ref_at(index as usize)?.store(desc);
+        }
+
+        Ok(head)
+    }
+
+    /// Creates a new `PackedQueue`, using the underlying memory regions represented
+    /// by the `MockPackedQueue`.
+    pub fn create_queue(&self) -> Result<PackedQueue, Error> {
+        let mut q = PackedQueue::new(self.len)?;
+        q.try_set_size(self.len)?;
+        q.try_set_desc_table_address(self.desc_ring_addr)?;
+        q.try_set_driver_event_address(self.driver_event_addr)?;
+        q.try_set_device_event_address(self.device_event_addr)?;
+        q.set_ready(true);
+        Ok(q)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestMemoryMmap;
+
+    #[test]
+    fn test_mock_packed_queue() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockPackedQueue::create(mem, GuestAddress(0x1000), 4);
+
+        assert_eq!(vq.desc_ring_addr(), GuestAddress(0x1000));
+        assert_eq!(vq.driver_event_addr(), GuestAddress(0x1040));
+        assert_eq!(vq.device_event_addr(), GuestAddress(0x1044));
+        assert_eq!(vq.end(), GuestAddress(0x1048));
+
+        let q = vq.create_queue().unwrap();
+        assert!(q.is_valid(mem));
+        assert_eq!(q.size(), 4);
+        assert_eq!(q.desc_table(), vq.desc_ring_addr());
+        assert_eq!(q.driver_event(), vq.driver_event_addr());
+        assert_eq!(q.device_event(), vq.device_event_addr());
+
+        let avail = 1 << VRING_PACKED_DESC_F_AVAIL;
+        let used = 1 << VRING_PACKED_DESC_F_USED;
+
+        let head = vq
+            .add_desc_chain(&[
+                PackedDescriptor::new(0x2000, 0x100, 0, 0),
+                PackedDescriptor::new(0x3000, 0x200, 0, 0),
+                PackedDescriptor::new(0x4000, 0x300, 0, 0),
+            ])
+            .unwrap();
+        assert_eq!(head, 0);
+
+        let desc = vq.desc_ring().ref_at(1).unwrap().load();
+        assert_eq!(desc.addr(), GuestAddress(0x3000));
+        assert_eq!(desc.len(), 0x200);
+        assert!(desc.has_next());
+        assert_eq!(desc.flags() & (avail | used), avail);
+        let desc = vq.desc_ring().ref_at(2).unwrap().load();
+        assert!(!desc.has_next());
+
+        // The second chain wraps around the end of the ring, so its last descriptor is made
+        // available with the flipped wrap counter.
+        let head = vq
+            .add_desc_chain(&[
+                PackedDescriptor::new(0x5000, 0x100, 1, 0),
+                PackedDescriptor::new(0x6000, 0x100, 1, 0),
+            ])
+            .unwrap();
+        assert_eq!(head, 3);
+        assert_eq!(vq.avail_position(), (1, false));
+        let desc = vq.desc_ring().ref_at(3).unwrap().load();
+        assert_eq!(desc.flags() & (avail | used), avail);
+        let desc = vq.desc_ring().ref_at(0).unwrap().load();
+        assert_eq!(desc.addr(), GuestAddress(0x6000));
+        assert_eq!(desc.id(), 1);
+        assert_eq!(desc.flags() & (avail | used), used);
+
+        vq.set_used_flags(0, true).unwrap();
+        let desc = vq.desc_ring().ref_at(0).unwrap().load();
+        assert_eq!(desc.flags() & (avail | used), avail | used);
+        vq.set_avail_flags(0, true).unwrap();
+        let desc = vq.desc_ring().ref_at(0).unwrap().load();
+        assert_eq!(desc.flags() & (avail | used), avail);
+
+        assert!(matches!(
+            vq.set_avail_flags(4, true),
+            Err(MockError::InvalidIndex)
+        ));
+    }
+}
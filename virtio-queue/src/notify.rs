@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use std::future::Future;
+use std::ops::Deref;
+
+use vm_memory::GuestMemory;
+
+use crate::{DescriptorChain, Error, QueueT};
+
+/// Helper waiting for the driver to make descriptor chains available.
+///
+/// `AvailNotify` wraps a `wait` closure blocking until the driver kicks the queue, and
+/// handles the notification steps recommended for processing the available ring: notifications
+/// are disabled while chains are popped, and re-enabled once the ring is empty, checking again
+/// for chains the driver added right before they were enabled, which wouldn't come with a kick.
+///
+/// The `wait` closure must:
+/// - block until the driver notifies the queue, e.g. by reading the queue eventfd, and return
+///   `true`. Spurious wake-ups are allowed, the ring is checked again anyway.
+/// - return `false` when the device must stop waiting, e.g. when it's being shut down. The
+///   kick it may have consumed is lost.
+/// - not access the queue, which is borrowed by [`next_chain`](AvailNotify::next_chain) while
+///   it's called.
+///
+/// Async backends can use a closure returning a future instead, and await the chains with
+/// [`next_chain_async`](AvailNotify::next_chain_async). The crate doesn't depend on `futures`,
+/// so there's no `Stream` implementation, but one can be built on top of `next_chain_async`,
+/// e.g. with `futures::stream::unfold`.
+pub struct AvailNotify<F> {
+    wait: F,
+}
+
+impl<F> AvailNotify<F> {
+    /// Create a new `AvailNotify` using `wait` to wait until the driver kicks the queue.
+    pub fn new(wait: F) -> Self {
+        AvailNotify { wait }
+    }
+
+    // Pop the next chain, or enable the notifications and return `None` if the ring is empty.
+    fn pop_or_enable<Q, M>(queue: &mut Q, mem: &M) -> Result<Option<DescriptorChain<M>>, Error>
+    where
+        Q: QueueT,
+        M: Clone + Deref,
+        M::Target: GuestMemory + Sized,
+    {
+        if !queue.ready() {
+            return Err(Error::QueueNotReady);
+        }
+
+        if let Some(chain) = queue.pop_descriptor_chain(mem.clone()) {
+            return Ok(Some(chain));
+        }
+
+        // The driver may have added chains between the last pop and enabling the
+        // notifications, in which case it won't kick the queue for them.
+        if queue.enable_notification(mem.deref())? {
+            queue.disable_notification(mem.deref())?;
+            // `pop_descriptor_chain` doesn't report why it failed, but the ring has entries
+            // that can't be popped, e.g. because the driver published an `idx` too far ahead.
+            // Waiting for a kick or trying again would never make progress.
+            return queue
+                .pop_descriptor_chain(mem.clone())
+                .map(Some)
+                .ok_or(Error::InvalidAvailRingIndex);
+        }
+
+        Ok(None)
+    }
+}
+
+impl<F> AvailNotify<F>
+where
+    F: FnMut() -> bool,
+{
+    /// Return the next descriptor chain made available by the driver, waiting for one if the
+    /// available ring is empty.
+    ///
+    /// `Ok(None)` is returned when the `wait` closure asks to stop. The chains are popped from
+    /// `queue` with [`QueueT::pop_descriptor_chain`], so the usual checks apply, and an error
+    /// is returned if the notifications can't be enabled or disabled.
+    /// `Error::QueueNotReady` is returned if the queue is not ready, and
+    /// `Error::InvalidAvailRingIndex` if the available ring has entries that can't be popped,
+    /// e.g. because the driver published a bogus `idx`.
+    pub fn next_chain<Q, M>(
+        &mut self,
+        queue: &mut Q,
+        mem: M,
+    ) -> Result<Option<DescriptorChain<M>>, Error>
+    where
+        Q: QueueT,
+        M: Clone + Deref,
+        M::Target: GuestMemory + Sized,
+    {
+        loop {
+            if let Some(chain) = Self::pop_or_enable(queue, &mem)? {
+                return Ok(Some(chain));
+            }
+
+            if !(self.wait)() {
+                return Ok(None);
+            }
+            queue.disable_notification(mem.deref())?;
+        }
+    }
+}
+
+impl<F, W> AvailNotify<F>
+where
+    F: FnMut() -> W,
+    W: Future<Output = bool>,
+{
+    /// Same as [`next_chain`](AvailNotify::next_chain), for a `wait` closure returning a future
+    /// which resolves when the driver kicks the queue.
+    pub async fn next_chain_async<Q, M>(
+        &mut self,
+        queue: &mut Q,
+        mem: M,
+    ) -> Result<Option<DescriptorChain<M>>, Error>
+    where
+        Q: QueueT,
+        M: Clone + Deref,
+        M::Target: GuestMemory + Sized,
+    {
+        loop {
+            if let Some(chain) = Self::pop_or_enable(queue, &mem)? {
+                return Ok(Some(chain));
+            }
+
+            if !(self.wait)().await {
+                return Ok(None);
+            }
+            queue.disable_notification(mem.deref())?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desc::{split::Descriptor as SplitDescriptor, RawDescriptor};
+    use crate::mock::MockSplitQueue;
+    use crate::Queue;
+    use std::pin::pin;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    use virtio_bindings::bindings::virtio_ring::VRING_USED_F_NO_NOTIFY;
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    // Poll `fut` until it completes, for futures which never need to be woken up.
+    fn block_on<T>(fut: impl Future<Output = T>) -> T {
+        fn raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+
+        // SAFETY: the vtable functions don't access the data pointer.
+        let waker = unsafe { Waker::from_raw(raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = pin!(fut);
+        loop {
+            if let Poll::Ready(val) = fut.as_mut().poll(&mut cx) {
+                return val;
+            }
+        }
+    }
+
+    #[test]
+    fn test_avail_notify() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let desc = |addr| RawDescriptor::from(SplitDescriptor::new(addr, 0x100, 0, 0));
+        let used_flags = || {
+            mem.read_obj::<u16>(vq.used_addr())
+                .map(u16::from_le)
+                .unwrap()
+        };
+        vq.add_desc_chains(&[desc(0x1000), desc(0x2000)], 0)
+            .unwrap();
+
+        // Each kick makes one more chain available, until the device stops after two kicks.
+        let mut kicks = 0u16;
+        let mut addrs = Vec::new();
+        let mut notify = AvailNotify::new(|| {
+            // Notifications are enabled before waiting.
+            assert_eq!(used_flags(), 0);
+            if kicks == 2 {
+                return false;
+            }
+            let addr = 0x3000 + u64::from(kicks) * 0x1000;
+            vq.add_desc_chains(&[desc(addr)], 2 + kicks).unwrap();
+            kicks += 1;
+            true
+        });
+
+        while let Some(mut chain) = notify.next_chain(&mut q, mem).unwrap() {
+            let addr = chain.next().unwrap().addr().0;
+            if addr >= 0x3000 {
+                // Notifications are disabled while the chains following a kick are processed.
+                assert_eq!(used_flags(), VRING_USED_F_NO_NOTIFY as u16);
+            }
+            addrs.push(addr);
+        }
+        assert_eq!(addrs, [0x1000, 0x2000, 0x3000, 0x4000]);
+        assert_eq!(q.next_avail(), 4);
+        assert_eq!(kicks, 2);
+        // Notifications are left enabled when the device stops waiting.
+        assert_eq!(used_flags(), 0);
+    }
+
+    #[test]
+    fn test_avail_notify_async() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let desc = |addr| RawDescriptor::from(SplitDescriptor::new(addr, 0x100, 0, 0));
+        vq.add_desc_chains(&[desc(0x1000)], 0).unwrap();
+
+        let mut kicks = 0u16;
+        let mut notify = AvailNotify::new(|| {
+            let kick = kicks < 2;
+            if kick {
+                let addr = 0x2000 + u64::from(kicks) * 0x1000;
+                vq.add_desc_chains(&[desc(addr)], 1 + kicks).unwrap();
+                kicks += 1;
+            }
+            async move { kick }
+        });
+
+        let mut addrs = Vec::new();
+        while let Some(mut chain) = block_on(notify.next_chain_async(&mut q, mem)).unwrap() {
+            addrs.push(chain.next().unwrap().addr().0);
+        }
+        assert_eq!(addrs, [0x1000, 0x2000, 0x3000]);
+        assert_eq!(q.next_avail(), 3);
+    }
+
+    #[test]
+    fn test_avail_notify_invalid_idx() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // The driver claims more chains are available than the queue size, so none of them can
+        // be popped. The device must not wait for a kick, nor spin on the ring.
+        vq.avail().idx().store(u16::to_le(17));
+        let mut notify = AvailNotify::new(|| -> bool { panic!("unexpected wait") });
+        assert_eq!(
+            notify.next_chain(&mut q, mem).unwrap_err(),
+            Error::InvalidAvailRingIndex
+        );
+        assert_eq!(q.next_avail(), 0);
+
+        // A queue that's not ready anymore is reported as such, whatever the ring holds.
+        vq.avail().idx().store(u16::to_le(1));
+        q.set_ready(false);
+        assert_eq!(
+            notify.next_chain(&mut q, mem).unwrap_err(),
+            Error::QueueNotReady
+        );
+    }
+}
@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use std::mem::size_of;
+use std::ops::Deref;
+use std::sync::atomic::Ordering;
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory};
+
+use crate::desc::packed::Descriptor as PackedDescriptor;
+use crate::Error;
+use virtio_bindings::bindings::virtio_ring::{VRING_PACKED_DESC_F_AVAIL, VRING_PACKED_DESC_F_USED};
+
+// Offset of the `flags` field in a packed descriptor.
+pub(crate) const PACKED_DESC_FLAGS_OFFSET: u64 = 14;
+
+/// A virtio packed descriptor chain.
+///
+/// The descriptors of a packed chain are consecutive entries of the descriptor ring, linked by
+/// the `VRING_DESC_F_NEXT` flag, and wrapping around to the start of the ring after its last
+/// entry. Each descriptor must have been made available by the driver for the wrap counter of
+/// its position. Descriptors referring to indirect tables are returned as is.
+#[derive(Clone, Debug)]
+pub struct PackedDescriptorChain<M> {
+    mem: M,
+    desc_table: GuestAddress,
+    queue_size: u16,
+    head_index: u16,
+    next_index: u16,
+    wrap_counter: bool,
+    ttl: u16,
+}
+
+impl<M> PackedDescriptorChain<M>
+where
+    M: Deref,
+    M::Target: GuestMemory,
+{
+    /// Create a new `PackedDescriptorChain` instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - the `GuestMemory` object that can be used to access the buffers pointed to by the descriptor chain.
+    /// * `desc_table` - the address of the descriptor ring.
+    /// * `queue_size` - the size of the queue, which is also the maximum size of a descriptor chain.
+    /// * `head_index` - the position of the chain head in the descriptor ring.
+    /// * `wrap_counter` - the driver ring wrap counter the head descriptor was made available with.
+    pub fn new(
+        mem: M,
+        desc_table: GuestAddress,
+        queue_size: u16,
+        head_index: u16,
+        wrap_counter: bool,
+    ) -> Self {
+        PackedDescriptorChain {
+            mem,
+            desc_table,
+            queue_size,
+            head_index,
+            next_index: head_index,
+            wrap_counter,
+            ttl: queue_size,
+        }
+    }
+
+    /// Get the position of the chain head in the descriptor ring.
+    pub fn head_index(&self) -> u16 {
+        self.head_index
+    }
+
+    /// Return a `GuestMemory` object that can be used to access the buffers pointed to by the
+    /// descriptor chain.
+    pub fn memory(&self) -> &M::Target {
+        self.mem.deref()
+    }
+
+    /// Return the position in the descriptor ring following the descriptors returned so far,
+    /// along with the wrap counter for that position.
+    ///
+    /// Once the whole chain is consumed, this is where the next chain starts.
+    pub fn next_position(&self) -> (u16, bool) {
+        (self.next_index, self.wrap_counter)
+    }
+
+    /// Return the next descriptor in this descriptor chain, `Ok(None)` at the end of the chain,
+    /// or an error if the chain is malformed.
+    ///
+    /// A chain whose head descriptor is not available is empty. [`Error::InvalidChain`] is
+    /// returned if a descriptor following the head is not available, and
+    /// [`Error::DescriptorChainTooLong`] if the chain is longer than the descriptor ring.
+    pub fn try_next(&mut self) -> Result<Option<PackedDescriptor>, Error> {
+        if self.ttl == 0 {
+            return Ok(None);
+        }
+
+        if self.next_index >= self.queue_size {
+            return Err(Error::InvalidDescriptorIndex);
+        }
+
+        let desc_addr = self
+            .desc_table
+            // The multiplication can not overflow an u64 since we are multiplying an u16 with a
+            // small number.
+            .checked_add(u64::from(self.next_index) * size_of::<PackedDescriptor>() as u64)
+            .ok_or(Error::AddressOverflow)?;
+        let flags_addr = desc_addr
+            .checked_add(PACKED_DESC_FLAGS_OFFSET)
+            .ok_or(Error::AddressOverflow)?;
+
+        // The acquire load makes sure the rest of the descriptor is read after the driver
+        // published it.
+        let flags = self
+            .mem
+            .load(flags_addr, Ordering::Acquire)
+            .map(u16::from_le)
+            .map_err(Error::GuestMemory)?;
+        if !self.is_available(flags) {
+            let is_head = self.ttl == self.queue_size;
+            self.ttl = 0;
+            return if is_head {
+                Ok(None)
+            } else {
+                Err(Error::InvalidChain)
+            };
+        }
+
+        let desc = self
+            .mem
+            .read_obj::<PackedDescriptor>(desc_addr)
+            .map_err(Error::GuestMemory)?;
+
+        self.next_index += 1;
+        if self.next_index == self.queue_size {
+            self.next_index = 0;
+            self.wrap_counter = !self.wrap_counter;
+        }
+
+        if desc.has_next() {
+            // It's ok to decrement `self.ttl` here because we check at the start of the method
+            // that it's greater than 0.
+            self.ttl -= 1;
+            // A chain can't span more than the whole ring.
+            if self.ttl == 0 {
+                return Err(Error::DescriptorChainTooLong(self.head_index));
+            }
+        } else {
+            self.ttl = 0;
+        }
+
+        Ok(Some(desc))
+    }
+
+    // A descriptor is available when its `AVAIL` flag matches the wrap counter, and its `USED`
+    // flag doesn't.
+    fn is_available(&self, flags: u16) -> bool {
+        let avail = flags & (1 << VRING_PACKED_DESC_F_AVAIL) != 0;
+        let used = flags & (1 << VRING_PACKED_DESC_F_USED) != 0;
+        avail == self.wrap_counter && used != self.wrap_counter
+    }
+}
+
+impl<M> Iterator for PackedDescriptorChain<M>
+where
+    M: Deref,
+    M::Target: GuestMemory,
+{
+    type Item = PackedDescriptor;
+
+    /// Return the next descriptor in this descriptor chain, if there is one.
+    ///
+    /// Iteration stops silently on a malformed chain; use
+    /// [`try_next`](PackedDescriptorChain::try_next) to find out why.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPackedQueue;
+    use crate::QueueCommonT;
+    use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+    use vm_memory::GuestMemoryMmap;
+
+    #[test]
+    fn test_packed_descriptor_chain() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockPackedQueue::new(m, 4);
+        let q = vq.create_queue().unwrap();
+        let chain_at = |head, wrap_counter| {
+            PackedDescriptorChain::new(m, q.desc_table(), q.size(), head, wrap_counter)
+        };
+
+        // Nothing is available yet.
+        assert!(chain_at(0, true).try_next().unwrap().is_none());
+
+        let head = vq
+            .add_desc_chain(&[
+                PackedDescriptor::new(0x1000, 0x100, 0, 0),
+                PackedDescriptor::new(0x2000, 0x200, 0, VRING_DESC_F_WRITE as u16),
+            ])
+            .unwrap();
+        assert_eq!(head, 0);
+        let mut c = chain_at(head, true);
+        assert_eq!(c.head_index(), 0);
+        assert_eq!(c.next().unwrap().addr(), GuestAddress(0x1000));
+        let desc = c.next().unwrap();
+        assert_eq!(desc.addr(), GuestAddress(0x2000));
+        assert!(desc.is_write_only());
+        assert!(c.next().is_none());
+        assert_eq!(c.next_position(), (2, true));
+
+        // The three descriptors occupy the positions 2, 3 and 0 of the ring.
+        let head = vq
+            .add_desc_chain(&[
+                PackedDescriptor::new(0x3000, 0x300, 1, 0),
+                PackedDescriptor::new(0x4000, 0x400, 1, 0),
+                PackedDescriptor::new(0x5000, 0x500, 1, 0),
+            ])
+            .unwrap();
+        assert_eq!(head, 2);
+        let mut c = chain_at(head, true);
+        let addrs: Vec<u64> = c.by_ref().map(|desc| desc.addr().0).collect();
+        assert_eq!(addrs, [0x3000, 0x4000, 0x5000]);
+        assert_eq!(c.next_position(), (1, false));
+
+        // Position 1 still holds a descriptor made available with the previous wrap counter,
+        // while position 0 was reused with the current one.
+        assert!(chain_at(1, false).try_next().unwrap().is_none());
+        assert!(chain_at(0, false).try_next().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_packed_descriptor_chain_errors() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockPackedQueue::new(m, 4);
+        let q = vq.create_queue().unwrap();
+
+        vq.add_desc_chain(&[
+            PackedDescriptor::new(0x1000, 0x100, 0, 0),
+            PackedDescriptor::new(0x2000, 0x200, 0, 0),
+        ])
+        .unwrap();
+        // The driver takes back the second descriptor.
+        vq.set_used_flags(1, true).unwrap();
+        let mut c = PackedDescriptorChain::new(m, q.desc_table(), q.size(), 0, true);
+        assert!(c.try_next().unwrap().is_some());
+        assert_eq!(c.try_next().unwrap_err(), Error::InvalidChain);
+        assert!(c.try_next().unwrap().is_none());
+
+        // Every descriptor of the ring has the `NEXT` flag set.
+        vq.add_desc_chain(&[
+            PackedDescriptor::new(0x1000, 0x100, 0, 0),
+            PackedDescriptor::new(0x2000, 0x200, 0, 0),
+            PackedDescriptor::new(0x3000, 0x300, 0, 0),
+            PackedDescriptor::new(0x4000, 0x400, 0, 0),
+        ])
+        .unwrap();
+        let desc_ref = vq.desc_ring().ref_at(1).unwrap();
+        let mut desc = desc_ref.load();
+        desc.set_flags(desc.flags() | VRING_DESC_F_NEXT as u16);
+        desc_ref.store(desc);
+        let mut c = PackedDescriptorChain::new(m, q.desc_table(), q.size(), 2, true);
+        for _ in 0..3 {
+            assert!(c.try_next().unwrap().is_some());
+        }
+        assert_eq!(c.try_next().unwrap_err(), Error::DescriptorChainTooLong(2));
+
+        let mut c = PackedDescriptorChain::new(m, q.desc_table(), q.size(), 4, true);
+        assert_eq!(c.try_next().unwrap_err(), Error::InvalidDescriptorIndex);
+    }
+}
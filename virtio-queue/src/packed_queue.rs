@@ -0,0 +1,833 @@
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::num::Wrapping;
+use std::ops::Deref;
+use std::sync::atomic::{fence, Ordering};
+
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory};
+
+use crate::desc::packed::{Descriptor as PackedDescriptor, PackedDescEvent};
+use crate::packed_chain::PACKED_DESC_FLAGS_OFFSET;
+use crate::queue::MAX_QUEUE_SIZE;
+use crate::{error, Error, PackedDescriptorChain, QueueCommonT, RingLayout};
+use virtio_bindings::bindings::virtio_ring::{
+    VRING_DESC_F_WRITE, VRING_PACKED_DESC_F_AVAIL, VRING_PACKED_DESC_F_USED,
+    VRING_PACKED_EVENT_FLAG_DESC, VRING_PACKED_EVENT_FLAG_DISABLE, VRING_PACKED_EVENT_FLAG_ENABLE,
+    VRING_PACKED_EVENT_F_WRAP_CTR,
+};
+
+/// Struct to maintain information about a packed virtio queue.
+///
+/// A packed virtqueue uses a single descriptor ring, shared by the driver and the device, plus
+/// two event suppression structures. The transport registers that configure the available and
+/// used rings of a split virtqueue configure the driver and device event suppression areas of
+/// a packed one.
+///
+/// # Example
+///
+/// ```rust
+/// use virtio_queue::{PackedQueue, QueueCommonT};
+/// use vm_memory::{GuestAddress, GuestMemoryMmap};
+///
+/// let m = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+/// let mut queue = PackedQueue::new(256).unwrap();
+///
+/// queue.try_set_size(16).unwrap();
+/// queue.try_set_desc_table_address(GuestAddress(0x1000)).unwrap();
+/// queue.try_set_driver_event_address(GuestAddress(0x2000)).unwrap();
+/// queue.try_set_device_event_address(GuestAddress(0x2004)).unwrap();
+/// queue.set_ready(true);
+/// assert!(queue.is_valid(&m));
+/// ```
+#[derive(Debug, PartialEq, Eq)]
+pub struct PackedQueue {
+    /// The maximum size in elements offered by the device.
+    max_size: u16,
+
+    /// The queue size in elements the driver selected.
+    size: u16,
+
+    /// Indicates if the queue is finished with configuration.
+    ready: bool,
+
+    /// VIRTIO_F_RING_EVENT_IDX negotiated.
+    event_idx_enabled: bool,
+
+    /// Guest physical address of the descriptor ring.
+    desc_table: GuestAddress,
+
+    /// Guest physical address of the driver event suppression structure.
+    driver_event: GuestAddress,
+
+    /// Guest physical address of the device event suppression structure.
+    device_event: GuestAddress,
+
+    /// Position in the descriptor ring of the next descriptor made available by the driver.
+    next_avail: u16,
+
+    /// Wrap counter the driver uses for the descriptors starting at `next_avail`.
+    avail_wrap_counter: bool,
+
+    /// Position in the descriptor ring where the next used descriptor is written.
+    next_used: u16,
+
+    /// Wrap counter the device uses for the descriptors written starting at `next_used`.
+    used_wrap_counter: bool,
+
+    /// Number of positions of the descriptor ring marked as used since the last call to
+    /// `needs_notification`, capped at the queue size.
+    num_added: Wrapping<u16>,
+
+    /// Buffer IDs of the descriptor chains popped and not used yet, along with the number of
+    /// positions of the descriptor ring each of them takes.
+    in_flight: BTreeMap<u16, u16>,
+}
+
+impl PackedQueue {
+    /// Construct an empty packed virtio queue with the given `max_size`.
+    ///
+    /// Returns an error if `max_size` is invalid.
+    pub fn new(max_size: u16) -> Result<Self, Error> {
+        // Packed virtqueues are not required to have a power of 2 size, but keeping the same
+        // constraint as the split ones keeps the transport code identical for both layouts.
+        if max_size == 0 || max_size > MAX_QUEUE_SIZE || (max_size & (max_size - 1)) != 0 {
+            return Err(Error::InvalidMaxSize);
+        }
+        Ok(PackedQueue {
+            max_size,
+            size: max_size,
+            ready: false,
+            event_idx_enabled: false,
+            desc_table: GuestAddress(0),
+            driver_event: GuestAddress(0),
+            device_event: GuestAddress(0),
+            next_avail: 0,
+            avail_wrap_counter: true,
+            next_used: 0,
+            used_wrap_counter: true,
+            num_added: Wrapping(0),
+            in_flight: BTreeMap::new(),
+        })
+    }
+
+    /// Tries to set the driver event suppression area address, which is configured through the
+    /// same transport register as the available ring of a split queue. In case of an invalid
+    /// value, the address is not updated.
+    pub fn try_set_driver_event_address(
+        &mut self,
+        driver_event: GuestAddress,
+    ) -> Result<(), Error> {
+        if driver_event.mask(0x3) != 0 {
+            return Err(Error::InvalidAvailRingAlign);
+        }
+        self.driver_event = driver_event;
+        Ok(())
+    }
+
+    /// Tries to set the device event suppression area address, which is configured through the
+    /// same transport register as the used ring of a split queue. In case of an invalid value,
+    /// the address is not updated.
+    pub fn try_set_device_event_address(
+        &mut self,
+        device_event: GuestAddress,
+    ) -> Result<(), Error> {
+        if device_event.mask(0x3) != 0 {
+            return Err(Error::InvalidUsedRingAlign);
+        }
+        self.device_event = device_event;
+        Ok(())
+    }
+
+    /// Return the address of the descriptor ring.
+    pub fn desc_table(&self) -> GuestAddress {
+        self.desc_table
+    }
+
+    /// Return the address of the driver event suppression area.
+    pub fn driver_event(&self) -> GuestAddress {
+        self.driver_event
+    }
+
+    /// Return the address of the device event suppression area.
+    pub fn device_event(&self) -> GuestAddress {
+        self.device_event
+    }
+
+    /// Return the position of the next descriptor the driver makes available.
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail
+    }
+
+    /// Return the wrap counter expected for the descriptor at `next_avail`.
+    pub fn avail_wrap_counter(&self) -> bool {
+        self.avail_wrap_counter
+    }
+
+    /// Return the position where the next used descriptor is written.
+    pub fn next_used(&self) -> u16 {
+        self.next_used
+    }
+
+    /// Return the wrap counter used for the descriptor written at `next_used`.
+    pub fn used_wrap_counter(&self) -> bool {
+        self.used_wrap_counter
+    }
+
+    /// Pop and return the next available descriptor chain, or `None` when there are no more
+    /// descriptor chains available.
+    ///
+    /// The chain is walked once to find out where the next one starts, so `next_avail` and the
+    /// avail wrap counter move past it. A malformed chain is not consumed: an error is logged
+    /// and `None` is returned. Once the device is done with the chain, it must be passed to
+    /// [`add_used`](QueueCommonT::add_used) using its buffer ID, i.e. the `id` field of its last
+    /// descriptor.
+    pub fn pop_descriptor_chain<M>(&mut self, mem: M) -> Option<PackedDescriptorChain<M>>
+    where
+        M: Clone + Deref,
+        M::Target: GuestMemory,
+    {
+        if !self.ready {
+            error!("attempt to use virtio queue that is not marked ready");
+            return None;
+        }
+
+        let chain = PackedDescriptorChain::new(
+            mem,
+            self.desc_table,
+            self.size,
+            self.next_avail,
+            self.avail_wrap_counter,
+        );
+        let mut walk = chain.clone();
+        let mut last = None;
+        // A chain can't be longer than the ring, so this can not overflow.
+        let mut len = 0u16;
+        loop {
+            match walk.try_next() {
+                Ok(Some(desc)) => {
+                    last = Some(desc);
+                    len += 1;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!(
+                        "invalid descriptor chain at position {}: {}",
+                        self.next_avail, e
+                    );
+                    return None;
+                }
+            }
+        }
+
+        let id = last?.id();
+        (self.next_avail, self.avail_wrap_counter) = walk.next_position();
+        self.in_flight.insert(id, len);
+        Some(chain)
+    }
+
+    /// Ask the driver to notify the device once it makes the descriptor at `next_avail`
+    /// available, with the current avail wrap counter.
+    ///
+    /// This is the packed counterpart of the split `avail_event` field: the offset and wrap
+    /// counter are written to the `off_wrap` field of the device event suppression area, along
+    /// with the `VRING_PACKED_EVENT_FLAG_DESC` mode. That mode is only valid with
+    /// `VIRTIO_F_RING_EVENT_IDX`, so nothing is written when it's not negotiated.
+    pub fn set_avail_event<M: GuestMemory>(&self, mem: &M) -> Result<(), Error> {
+        if !self.event_idx_enabled {
+            return Ok(());
+        }
+
+        let off_wrap =
+            self.next_avail | (u16::from(self.avail_wrap_counter) << VRING_PACKED_EVENT_F_WRAP_CTR);
+        // The flags are constants that fit in an u16.
+        let event = PackedDescEvent::new(off_wrap, VRING_PACKED_EVENT_FLAG_DESC as u16);
+        mem.write_obj(event, self.device_event)
+            .map_err(Error::GuestMemory)
+    }
+
+    // Update the mode of the device event suppression area, keeping its `off_wrap` field.
+    fn set_device_event_flags<M: GuestMemory>(&self, mem: &M, flags: u16) -> Result<(), Error> {
+        let mut event = mem
+            .read_obj::<PackedDescEvent>(self.device_event)
+            .map_err(Error::GuestMemory)?;
+        event.set_flags(flags);
+        mem.write_obj(event, self.device_event)
+            .map_err(Error::GuestMemory)
+    }
+}
+
+impl QueueCommonT for PackedQueue {
+    fn layout(&self) -> RingLayout {
+        RingLayout::Packed
+    }
+
+    fn is_valid<M: GuestMemory>(&self, mem: &M) -> bool {
+        // The multiplication can not overflow an u64 since we are multiplying an u16 with a
+        // small number.
+        let desc_ring_size = size_of::<PackedDescriptor>() as u64 * u64::from(self.size);
+        let event_size = size_of::<PackedDescEvent>() as u64;
+
+        if !self.ready {
+            error!("attempt to use virtio queue that is not marked ready");
+            false
+        } else if self
+            .desc_table
+            .checked_add(desc_ring_size)
+            .is_none_or(|v| !mem.address_in_range(v))
+        {
+            error!(
+                "virtio queue descriptor ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                self.desc_table.raw_value(),
+                desc_ring_size
+            );
+            false
+        } else if self
+            .driver_event
+            .checked_add(event_size)
+            .is_none_or(|v| !mem.address_in_range(v))
+        {
+            error!(
+                "virtio queue driver event area goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                self.driver_event.raw_value(),
+                event_size
+            );
+            false
+        } else if self
+            .device_event
+            .checked_add(event_size)
+            .is_none_or(|v| !mem.address_in_range(v))
+        {
+            error!(
+                "virtio queue device event area goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                self.device_event.raw_value(),
+                event_size
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = PackedQueue::new(self.max_size).expect("max_size was validated by new()");
+    }
+
+    fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn try_set_size(&mut self, size: u16) -> Result<(), Error> {
+        if size > self.max_size || size == 0 || (size & (size - 1)) != 0 {
+            return Err(Error::InvalidSize);
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    fn ready(&self) -> bool {
+        self.ready
+    }
+
+    fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx_enabled = enabled;
+    }
+
+    fn event_idx_enabled(&self) -> bool {
+        self.event_idx_enabled
+    }
+
+    /// Tries to set the descriptor ring address. In case of an invalid value, the address is
+    /// not updated.
+    fn try_set_desc_table_address(&mut self, desc_table: GuestAddress) -> Result<(), Error> {
+        if desc_table.mask(0xf) != 0 {
+            return Err(Error::InvalidDescTableAlign);
+        }
+        self.desc_table = desc_table;
+        Ok(())
+    }
+
+    /// Mark the descriptor chain with the buffer ID `id` as used, after the device wrote `len`
+    /// bytes to its buffers.
+    ///
+    /// A single used descriptor is written at `next_used`, which then skips all the positions
+    /// of the descriptor ring taken by the chain. The `flags` field is written last, with a
+    /// release store, so the driver can't observe a partially written descriptor.
+    /// `Error::InvalidDescriptorIndex` is returned if no chain with this buffer ID was popped
+    /// and not used yet.
+    fn add_used<M: GuestMemory>(&mut self, mem: &M, id: u16, len: u32) -> Result<(), Error> {
+        let Some(&chain_len) = self.in_flight.get(&id) else {
+            error!("attempted to use a buffer id that is not in flight: {}", id);
+            return Err(Error::InvalidDescriptorIndex);
+        };
+
+        let desc_addr = self
+            .desc_table
+            // The multiplication can not overflow an u64 since we are multiplying an u16 with a
+            // small number.
+            .checked_add(u64::from(self.next_used) * size_of::<PackedDescriptor>() as u64)
+            .ok_or(Error::AddressOverflow)?;
+        let flags_addr = desc_addr
+            .checked_add(PACKED_DESC_FLAGS_OFFSET)
+            .ok_or(Error::AddressOverflow)?;
+
+        // The flags are constants that fit in an u16.
+        let mut flags = if len > 0 {
+            VRING_DESC_F_WRITE as u16
+        } else {
+            0
+        };
+        if self.used_wrap_counter {
+            flags |= (1 << VRING_PACKED_DESC_F_AVAIL) | (1 << VRING_PACKED_DESC_F_USED);
+        }
+        let desc = PackedDescriptor::new(0, len, id, 0);
+        mem.write_slice(
+            &desc.as_slice()[..PACKED_DESC_FLAGS_OFFSET as usize],
+            desc_addr,
+        )
+        .map_err(Error::GuestMemory)?;
+        mem.store(u16::to_le(flags), flags_addr, Ordering::Release)
+            .map_err(Error::GuestMemory)?;
+
+        self.in_flight.remove(&id);
+        // Both values are smaller than the queue size, so this can not overflow.
+        let next_used = self.next_used + chain_len;
+        if next_used >= self.size {
+            self.next_used = next_used - self.size;
+            self.used_wrap_counter = !self.used_wrap_counter;
+        } else {
+            self.next_used = next_used;
+        }
+        self.num_added = Wrapping(self.num_added.0.saturating_add(chain_len).min(self.size));
+
+        Ok(())
+    }
+
+    /// Enable notification events from the driver, and return `true` if descriptors were made
+    /// available since the device last stopped processing the ring.
+    ///
+    /// With `VIRTIO_F_RING_EVENT_IDX`, the driver is asked to notify the device about the
+    /// descriptor at `next_avail` (see [`set_avail_event`](PackedQueue::set_avail_event)),
+    /// otherwise the `VRING_PACKED_EVENT_FLAG_ENABLE` mode is written to the device event
+    /// suppression area. The descriptor at `next_avail` is then checked again, since the driver
+    /// may have made it available right before the notifications were enabled, in which case
+    /// no notification is sent for it.
+    fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        if self.event_idx_enabled {
+            self.set_avail_event(mem)?;
+        } else {
+            // The flags are constants that fit in an u16.
+            self.set_device_event_flags(mem, VRING_PACKED_EVENT_FLAG_ENABLE as u16)?;
+        }
+        // Ensures the following read is not reordered before any previous write operation.
+        fence(Ordering::SeqCst);
+
+        PackedDescriptorChain::new(
+            mem,
+            self.desc_table,
+            self.size,
+            self.next_avail,
+            self.avail_wrap_counter,
+        )
+        .try_next()
+        .map(|desc| desc.is_some())
+    }
+
+    /// Disable notification events from the driver, by writing the
+    /// `VRING_PACKED_EVENT_FLAG_DISABLE` mode to the device event suppression area.
+    fn disable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<(), Error> {
+        // The flags are constants that fit in an u16.
+        self.set_device_event_flags(mem, VRING_PACKED_EVENT_FLAG_DISABLE as u16)
+    }
+
+    /// Check whether a notification to the driver is needed.
+    ///
+    /// The decision is based on the driver event suppression structure. Notifications can be
+    /// enabled, disabled, or, when `VIRTIO_F_RING_EVENT_IDX` is negotiated, requested for the
+    /// moment the device writes a used descriptor at a specific offset of the ring with a
+    /// specific wrap counter.
+    ///
+    /// Same as for split queues, this method has side effects: it considers the driver is
+    /// notified about all the descriptors used so far whenever it's called.
+    fn needs_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        let new = Wrapping(self.next_used);
+        let old = new - self.num_added;
+        self.num_added = Wrapping(0);
+
+        // Complete all the writes to the descriptor ring before reading the event.
+        fence(Ordering::SeqCst);
+
+        // Event suppression is not synchronized with the driver and only serves as an
+        // optimization, so there's no need to order this read with other memory accesses.
+        let event = mem
+            .read_obj::<PackedDescEvent>(self.driver_event)
+            .map_err(Error::GuestMemory)?;
+
+        match u32::from(event.flags()) {
+            VRING_PACKED_EVENT_FLAG_DISABLE => Ok(false),
+            VRING_PACKED_EVENT_FLAG_DESC if self.event_idx_enabled => {
+                let wrap_bit = 1 << VRING_PACKED_EVENT_F_WRAP_CTR;
+                let off_wrap = event.off_wrap();
+                let mut event_idx = Wrapping(off_wrap & !wrap_bit);
+                // Bring the event offset in the same sequence space as `next_used`, which is
+                // one lap ahead when the wrap counters differ.
+                if (off_wrap & wrap_bit != 0) != self.used_wrap_counter {
+                    event_idx -= Wrapping(self.size);
+                }
+
+                // Same check as for the split `used_event`: notify if the event offset is
+                // between the position of the last notification and `next_used`.
+                Ok(new - event_idx - Wrapping(1) < new - old)
+            }
+            // `VRING_PACKED_EVENT_FLAG_ENABLE`, as well as modes the driver is not supposed to
+            // use, which are treated as if notifications are enabled.
+            _ => Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPackedQueue;
+    use vm_memory::GuestMemoryMmap;
+
+    #[test]
+    fn test_packed_queue_config() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+
+        assert_eq!(PackedQueue::new(0).unwrap_err(), Error::InvalidMaxSize);
+        assert_eq!(PackedQueue::new(15).unwrap_err(), Error::InvalidMaxSize);
+
+        let mut q = PackedQueue::new(16).unwrap();
+        assert_eq!(q.size(), 16);
+        assert!(q.avail_wrap_counter());
+        assert!(q.used_wrap_counter());
+        assert!(!q.is_valid(m));
+
+        assert_eq!(q.try_set_size(32).unwrap_err(), Error::InvalidSize);
+        q.try_set_size(8).unwrap();
+
+        assert_eq!(
+            q.try_set_desc_table_address(GuestAddress(0x1008))
+                .unwrap_err(),
+            Error::InvalidDescTableAlign
+        );
+        q.try_set_desc_table_address(GuestAddress(0x1000)).unwrap();
+        assert_eq!(
+            q.try_set_driver_event_address(GuestAddress(0x2002))
+                .unwrap_err(),
+            Error::InvalidAvailRingAlign
+        );
+        q.try_set_driver_event_address(GuestAddress(0x2000))
+            .unwrap();
+        assert_eq!(
+            q.try_set_device_event_address(GuestAddress(0x2006))
+                .unwrap_err(),
+            Error::InvalidUsedRingAlign
+        );
+        q.try_set_device_event_address(GuestAddress(0x2004))
+            .unwrap();
+        q.set_ready(true);
+        assert!(q.is_valid(m));
+
+        // The descriptor ring ends past the end of guest memory.
+        q.try_set_desc_table_address(GuestAddress(0xff80)).unwrap();
+        q.try_set_size(16).unwrap();
+        assert!(!q.is_valid(m));
+
+        q.reset();
+        assert_eq!(q, PackedQueue::new(16).unwrap());
+    }
+
+    #[test]
+    fn test_address_alignment() {
+        let mut q = PackedQueue::new(16).unwrap();
+
+        // The descriptor ring is aligned on 16 bytes, unlike the 2 bytes of a split available
+        // ring, and the event suppression areas on 4 bytes.
+        for offset in 1..16 {
+            let addr = GuestAddress(0x1000 + offset);
+            assert_eq!(
+                q.try_set_desc_table_address(addr).unwrap_err(),
+                Error::InvalidDescTableAlign
+            );
+            if offset % 4 != 0 {
+                assert_eq!(
+                    q.try_set_driver_event_address(addr).unwrap_err(),
+                    Error::InvalidAvailRingAlign
+                );
+                assert_eq!(
+                    q.try_set_device_event_address(addr).unwrap_err(),
+                    Error::InvalidUsedRingAlign
+                );
+            }
+        }
+        assert_eq!(q, PackedQueue::new(16).unwrap());
+
+        q.try_set_desc_table_address(GuestAddress(0x1010)).unwrap();
+        assert_eq!(q.desc_table(), GuestAddress(0x1010));
+        q.try_set_driver_event_address(GuestAddress(0x2004))
+            .unwrap();
+        assert_eq!(q.driver_event(), GuestAddress(0x2004));
+        q.try_set_device_event_address(GuestAddress(0x2008))
+            .unwrap();
+        assert_eq!(q.device_event(), GuestAddress(0x2008));
+    }
+
+    #[test]
+    fn test_set_avail_event() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockPackedQueue::new(m, 8);
+        let mut q = vq.create_queue().unwrap();
+        let desc_flag = VRING_PACKED_EVENT_FLAG_DESC as u16;
+
+        // The device processed three single descriptor chains. Nothing is written without
+        // `EVENT_IDX`.
+        for _ in 0..3 {
+            vq.add_desc_chain(&[PackedDescriptor::new(0x1000, 0x100, 0, 0)])
+                .unwrap();
+            q.pop_descriptor_chain(m).unwrap();
+        }
+        q.set_avail_event(m).unwrap();
+        assert_eq!(vq.device_event().load().flags(), 0);
+
+        q.set_event_idx(true);
+        q.set_avail_event(m).unwrap();
+        let event = vq.device_event().load();
+        assert_eq!(event.off_wrap(), 3 | (1 << 15));
+        assert_eq!(event.flags(), desc_flag);
+
+        // The driver wrapped around the ring.
+        vq.add_desc_chain(&[PackedDescriptor::new(0x1000, 0x100, 1, 0); 6])
+            .unwrap();
+        q.pop_descriptor_chain(m).unwrap();
+        assert_eq!((q.next_avail(), q.avail_wrap_counter()), (1, false));
+        q.set_avail_event(m).unwrap();
+        let event = vq.device_event().load();
+        assert_eq!(event.off_wrap(), 1);
+        assert_eq!(event.flags(), desc_flag);
+        // The driver event area is left untouched.
+        assert_eq!(vq.driver_event().load().flags(), 0);
+    }
+
+    #[test]
+    fn test_enable_disable_notification() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockPackedQueue::new(m, 4);
+        let mut q = vq.create_queue().unwrap();
+        let flag = |f: u32| f as u16;
+
+        q.disable_notification(m).unwrap();
+        assert_eq!(
+            vq.device_event().load().flags(),
+            flag(VRING_PACKED_EVENT_FLAG_DISABLE)
+        );
+        assert!(!q.enable_notification(m).unwrap());
+        assert_eq!(
+            vq.device_event().load().flags(),
+            flag(VRING_PACKED_EVENT_FLAG_ENABLE)
+        );
+
+        // The driver makes a descriptor available while notifications are disabled.
+        q.disable_notification(m).unwrap();
+        vq.add_desc_chain(&[PackedDescriptor::new(0x1000, 0x100, 0, 0)])
+            .unwrap();
+        assert!(q.enable_notification(m).unwrap());
+
+        // Once the device processed it, the driver is asked to notify the next descriptor.
+        q.pop_descriptor_chain(m).unwrap();
+        q.set_event_idx(true);
+        q.disable_notification(m).unwrap();
+        assert!(!q.enable_notification(m).unwrap());
+        let event = vq.device_event().load();
+        assert_eq!(event.off_wrap(), 1 | (1 << 15));
+        assert_eq!(event.flags(), flag(VRING_PACKED_EVENT_FLAG_DESC));
+
+        // Disabling keeps the event offset.
+        q.disable_notification(m).unwrap();
+        let event = vq.device_event().load();
+        assert_eq!(event.off_wrap(), 1 | (1 << 15));
+        assert_eq!(event.flags(), flag(VRING_PACKED_EVENT_FLAG_DISABLE));
+
+        // The re-check looks at where the device stopped, with `EVENT_IDX` too.
+        vq.add_desc_chain(&[PackedDescriptor::new(0x2000, 0x100, 1, 0)])
+            .unwrap();
+        assert!(q.enable_notification(m).unwrap());
+        q.pop_descriptor_chain(m).unwrap();
+        assert!(!q.enable_notification(m).unwrap());
+        assert_eq!(vq.device_event().load().off_wrap(), 2 | (1 << 15));
+    }
+
+    // Make a chain of `len` descriptors available with the buffer ID `id`, then pop it and
+    // mark it as used.
+    fn use_chain(
+        vq: &mut MockPackedQueue<GuestMemoryMmap>,
+        q: &mut PackedQueue,
+        m: &GuestMemoryMmap,
+        id: u16,
+        len: usize,
+    ) {
+        let descs = vec![PackedDescriptor::new(0x1000, 0x100, id, 0); len];
+        vq.add_desc_chain(&descs).unwrap();
+        assert_eq!(q.pop_descriptor_chain(m).unwrap().count(), len);
+        q.add_used(m, id, 0x100).unwrap();
+    }
+
+    #[test]
+    fn test_pop_add_used() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockPackedQueue::new(m, 4);
+        let mut q = vq.create_queue().unwrap();
+
+        // Nothing is available yet.
+        assert!(q.pop_descriptor_chain(m).is_none());
+
+        vq.add_desc_chain(&[
+            PackedDescriptor::new(0x1000, 0x100, 0, 0),
+            PackedDescriptor::new(0x2000, 0x200, 7, VRING_DESC_F_WRITE as u16),
+        ])
+        .unwrap();
+        vq.add_desc_chain(&[PackedDescriptor::new(0x3000, 0x300, 3, 0)])
+            .unwrap();
+        let chain = q.pop_descriptor_chain(m).unwrap();
+        assert_eq!(chain.head_index(), 0);
+        assert_eq!((q.next_avail(), q.avail_wrap_counter()), (2, true));
+        let chain = q.pop_descriptor_chain(m).unwrap();
+        assert_eq!(chain.head_index(), 2);
+        assert_eq!((q.next_avail(), q.avail_wrap_counter()), (3, true));
+        assert!(q.pop_descriptor_chain(m).is_none());
+
+        // Only the buffer IDs of the chains in flight can be used.
+        assert_eq!(
+            q.add_used(m, 0, 0x100).unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
+
+        // The chains are used out of order. The used descriptor of the second one is written at
+        // position 0, and the next one skips the two positions taken by the first chain.
+        q.add_used(m, 3, 0).unwrap();
+        let desc = vq.desc_ring().ref_at(0).unwrap().load();
+        assert_eq!((desc.id(), desc.len()), (3, 0));
+        let used = (1 << VRING_PACKED_DESC_F_AVAIL) | (1 << VRING_PACKED_DESC_F_USED);
+        assert_eq!(desc.flags(), used);
+        assert_eq!((q.next_used(), q.used_wrap_counter()), (1, true));
+
+        q.add_used(m, 7, 0x180).unwrap();
+        let desc = vq.desc_ring().ref_at(1).unwrap().load();
+        assert_eq!((desc.id(), desc.len()), (7, 0x180));
+        assert_eq!(desc.flags(), used | VRING_DESC_F_WRITE as u16);
+        assert_eq!((q.next_used(), q.used_wrap_counter()), (3, true));
+        assert_eq!(
+            q.add_used(m, 7, 0x180).unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
+
+        // The next chain wraps around the ring, and so does its used descriptor.
+        use_chain(&mut vq, &mut q, m, 1, 2);
+        assert_eq!((q.next_avail(), q.avail_wrap_counter()), (1, false));
+        assert_eq!((q.next_used(), q.used_wrap_counter()), (1, false));
+        let desc = vq.desc_ring().ref_at(3).unwrap().load();
+        assert_eq!(desc.id(), 1);
+        assert_eq!(desc.flags(), used | VRING_DESC_F_WRITE as u16);
+        // Once the used wrap counter flips, the flags are cleared to mark descriptors as used.
+        use_chain(&mut vq, &mut q, m, 2, 1);
+        let desc = vq.desc_ring().ref_at(1).unwrap().load();
+        assert_eq!(desc.flags(), VRING_DESC_F_WRITE as u16);
+
+        // A malformed chain is not consumed: the driver takes back the second descriptor.
+        vq.add_desc_chain(&[
+            PackedDescriptor::new(0x1000, 0x100, 0, 0),
+            PackedDescriptor::new(0x2000, 0x200, 0, 0),
+        ])
+        .unwrap();
+        vq.set_used_flags(3, false).unwrap();
+        assert!(q.pop_descriptor_chain(m).is_none());
+        assert_eq!((q.next_avail(), q.avail_wrap_counter()), (2, false));
+
+        // Nothing is popped from a queue which is not ready.
+        vq.set_avail_flags(3, false).unwrap();
+        q.set_ready(false);
+        assert!(q.pop_descriptor_chain(m).is_none());
+        q.set_ready(true);
+        assert!(q.pop_descriptor_chain(m).is_some());
+    }
+
+    #[test]
+    fn test_needs_notification() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockPackedQueue::new(m, 8);
+        let mut q = vq.create_queue().unwrap();
+        let flag = |f: u32| f as u16;
+
+        // Notifications are enabled.
+        vq.driver_event().store(PackedDescEvent::new(
+            0,
+            flag(VRING_PACKED_EVENT_FLAG_ENABLE),
+        ));
+        use_chain(&mut vq, &mut q, m, 0, 1);
+        assert!(q.needs_notification(m).unwrap());
+
+        // Notifications are disabled.
+        vq.driver_event().store(PackedDescEvent::new(
+            0,
+            flag(VRING_PACKED_EVENT_FLAG_DISABLE),
+        ));
+        use_chain(&mut vq, &mut q, m, 0, 1);
+        assert!(!q.needs_notification(m).unwrap());
+
+        // The driver asks to be notified once the descriptor at offset 4 is used in the current
+        // lap. Without `EVENT_IDX` the mode is invalid and notifications stay enabled.
+        let desc_flag = flag(VRING_PACKED_EVENT_FLAG_DESC);
+        vq.driver_event()
+            .store(PackedDescEvent::new(4 | (1 << 15), desc_flag));
+        use_chain(&mut vq, &mut q, m, 0, 1);
+        assert!(q.needs_notification(m).unwrap());
+
+        q.set_event_idx(true);
+        // Offsets 3 and 4 are used, the event is crossed.
+        use_chain(&mut vq, &mut q, m, 0, 2);
+        assert_eq!(q.next_used(), 5);
+        assert!(q.needs_notification(m).unwrap());
+        // It's not crossed again by the next descriptors.
+        use_chain(&mut vq, &mut q, m, 0, 2);
+        assert!(!q.needs_notification(m).unwrap());
+
+        // The driver asks to be notified about offset 1 of the next lap. Using offsets 7 and 0
+        // wraps around the ring but doesn't reach it.
+        vq.driver_event().store(PackedDescEvent::new(1, desc_flag));
+        use_chain(&mut vq, &mut q, m, 0, 2);
+        assert_eq!((q.next_used(), q.used_wrap_counter()), (1, false));
+        assert!(!q.needs_notification(m).unwrap());
+        use_chain(&mut vq, &mut q, m, 0, 1);
+        assert!(q.needs_notification(m).unwrap());
+
+        // The driver asks to be notified about offset 7 of a lap with the wrap counter set.
+        vq.driver_event()
+            .store(PackedDescEvent::new(7 | (1 << 15), desc_flag));
+        use_chain(&mut vq, &mut q, m, 0, 4);
+        assert!(!q.needs_notification(m).unwrap());
+        // Offsets 6 and 7 of a lap with the wrap counter cleared, and offset 0 of the next lap
+        // are used. The event belongs to the new lap and is not reached yet.
+        use_chain(&mut vq, &mut q, m, 0, 3);
+        assert_eq!((q.next_used(), q.used_wrap_counter()), (1, true));
+        assert!(!q.needs_notification(m).unwrap());
+
+        // Offsets 6 and 7 of a lap with the wrap counter set, and offset 0 of the next lap are
+        // used. The event is crossed right before wrapping around the ring.
+        use_chain(&mut vq, &mut q, m, 0, 5);
+        assert!(!q.needs_notification(m).unwrap());
+        use_chain(&mut vq, &mut q, m, 0, 3);
+        assert_eq!((q.next_used(), q.used_wrap_counter()), (1, false));
+        assert!(q.needs_notification(m).unwrap());
+    }
+}
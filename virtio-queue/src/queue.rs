@@ -7,21 +7,29 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+use std::collections::BTreeMap;
 use std::mem::size_of;
 use std::num::Wrapping;
 use std::ops::Deref;
 use std::sync::atomic::{fence, Ordering};
+use std::time::{Duration, Instant};
 
-use vm_memory::{Address, Bytes, GuestAddress, GuestMemory};
+use vm_memory::bitmap::MS;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryRegion, VolatileSlice};
 
 use crate::defs::{
     DEFAULT_AVAIL_RING_ADDR, DEFAULT_DESC_TABLE_ADDR, DEFAULT_USED_RING_ADDR,
     VIRTQ_AVAIL_ELEMENT_SIZE, VIRTQ_AVAIL_RING_HEADER_SIZE, VIRTQ_AVAIL_RING_META_SIZE,
     VIRTQ_USED_ELEMENT_SIZE, VIRTQ_USED_RING_HEADER_SIZE, VIRTQ_USED_RING_META_SIZE,
 };
-use crate::desc::{split::VirtqUsedElem, RawDescriptor};
-use crate::{error, DescriptorChain, Error, QueueGuard, QueueOwnedT, QueueState, QueueT};
-use virtio_bindings::bindings::virtio_ring::VRING_USED_F_NO_NOTIFY;
+use crate::desc::{
+    split::{Descriptor as SplitDescriptor, VirtqUsedElem},
+    RawDescriptor,
+};
+use crate::{
+    error, DescriptorChain, Error, QueueGuard, QueueOwnedT, QueueState, QueueT, RingLayout,
+};
+use virtio_bindings::bindings::virtio_ring::{VRING_AVAIL_F_NO_INTERRUPT, VRING_USED_F_NO_NOTIFY};
 
 #[cfg(kani)]
 mod verification;
@@ -92,7 +100,8 @@ pub struct Queue {
     event_idx_enabled: bool,
 
     /// The number of descriptor chains placed in the used ring via `add_used`
-    /// since the last time `needs_notification` was called on the associated queue.
+    /// since the last time `needs_notification` was called on the associated queue, capped at
+    /// the queue size.
     num_added: Wrapping<u16>,
 
     /// The queue size in elements the driver selected.
@@ -109,9 +118,102 @@ pub struct Queue {
 
     /// Guest physical address of the used ring.
     used_ring: GuestAddress,
+
+    /// Whether the length of the descriptor chains popped from the available ring is recorded.
+    track_chain_len: bool,
+
+    /// The length of the longest descriptor chain popped while `track_chain_len` was set.
+    max_chain_len_seen: u16,
+
+    /// Whether the descriptor chains popped from the available ring perform strict checks.
+    strict_validation: bool,
+
+    /// The number of elements ever placed in the used ring.
+    progress: u64,
+
+    /// Whether the descriptor chains popped from the available ring must use an indirect table
+    /// when they have several descriptors.
+    indirect_only: bool,
+
+    /// Whether the descriptor chains popped from the available ring read their descriptors
+    /// ahead.
+    prefetch_descriptors: bool,
+
+    /// Whether adding more elements to the used ring than chains were popped is refused.
+    check_used_count: bool,
+
+    /// Whether writes to the rings are refused.
+    paused: bool,
+
+    /// Whether a `VIRTIO_F_RING_RESET` reset of the queue is in progress.
+    resetting: bool,
+
+    /// Bitmap of the head indexes marked as in flight, if tracking is enabled.
+    in_flight: Option<Vec<u64>>,
+
+    /// Submission time of the chains handed to a backend, by head index.
+    submitted: BTreeMap<u16, Instant>,
+
+    /// Whether the rings and the descriptor table are big-endian, which is the case for legacy
+    /// queues using the native byte order on big-endian hosts.
+    legacy_big_endian: bool,
+
+    /// Counters of the chains popped and of the elements added to the used ring.
+    stats: QueueStats,
+
+    /// Whether the descriptor chains popped from the available ring reject buffers overlapping
+    /// the rings.
+    validate_buffer_regions: bool,
+}
+
+/// The `flags` and `idx` fields of the available ring, read together by
+/// [`Queue::avail_snapshot`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AvailSnapshot {
+    /// Where the driver would put the next descriptor entry in the available ring.
+    pub idx: u16,
+    /// The available ring flags (e.g. `VRING_AVAIL_F_NO_INTERRUPT`).
+    pub flags: u16,
+}
+
+/// Counters of the work processed by a [`Queue`], returned by [`Queue::stats`].
+///
+/// The counters wrap around on overflow, and are only reset by [`Queue::reset_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// The number of descriptor chains popped from the available ring.
+    pub chains_popped: u64,
+    /// The number of elements added to the used ring.
+    pub used_entries: u64,
+    /// The sum of the lengths reported by the elements added to the used ring.
+    pub used_bytes: u64,
+}
+
+impl QueueStats {
+    // Account for used elements reporting the lengths in `lens`.
+    fn record_used<I: IntoIterator<Item = u32>>(&mut self, lens: I) {
+        for len in lens {
+            self.used_entries = self.used_entries.wrapping_add(1);
+            self.used_bytes = self.used_bytes.wrapping_add(u64::from(len));
+        }
+    }
 }
 
 impl Queue {
+    /// Construct an empty virtio queue with the given `max_size` and `VIRTIO_F_RING_EVENT_IDX`
+    /// already enabled.
+    ///
+    /// This is equivalent to calling [`QueueT::new`] followed by
+    /// [`QueueT::set_event_idx(true)`](QueueT::set_event_idx), and is meant for devices that
+    /// always negotiate the feature.
+    ///
+    /// Returns an error if `max_size` is invalid.
+    pub fn with_event_idx(max_size: u16) -> Result<Self, Error> {
+        let mut q = Queue::new(max_size)?;
+        q.set_event_idx(true);
+        Ok(q)
+    }
+
     /// Equivalent of [`QueueT::set_size`] returning an error in case of invalid size.
     ///
     /// This should not be directly used, as the preferred method is part of the [`QueueT`]
@@ -167,1333 +269,4739 @@ impl Queue {
         Ok(())
     }
 
-    /// Returns the state of the `Queue`.
-    ///
-    /// This is useful for implementing save/restore capabilities.
-    /// The state does not have support for serialization, but this can be
-    /// added by VMMs locally through the use of a
-    /// [remote type](https://serde.rs/remote-derive.html).
+    /// Configure the size, the ring addresses and `VIRTIO_F_RING_EVENT_IDX` of the queue at once.
     ///
-    /// Alternatively, a version aware and serializable/deserializable QueueState
-    /// is available in the `virtio-queue-ser` crate.
-    pub fn state(&self) -> QueueState {
-        QueueState {
-            max_size: self.max_size,
-            next_avail: self.next_avail(),
-            next_used: self.next_used(),
-            event_idx_enabled: self.event_idx_enabled,
-            size: self.size,
-            ready: self.ready,
-            desc_table: self.desc_table(),
-            avail_ring: self.avail_ring(),
-            used_ring: self.used_ring(),
+    /// This is meant for VMMs receiving all the parameters together (e.g. through vhost-user
+    /// `SET_VRING_*` messages). The parameters are validated the same way as with
+    /// [`try_set_size`](Queue::try_set_size) and the `try_set_*_address` methods, and the error
+    /// for the first invalid one, in argument order, is returned. The queue is only updated if
+    /// all the parameters are valid.
+    pub fn configure(
+        &mut self,
+        size: u16,
+        desc_table: GuestAddress,
+        avail_ring: GuestAddress,
+        used_ring: GuestAddress,
+        event_idx: bool,
+    ) -> Result<(), Error> {
+        let old = (self.size, self.desc_table, self.avail_ring, self.used_ring);
+
+        let res = self
+            .try_set_size(size)
+            .and_then(|_| self.try_set_desc_table_address(desc_table))
+            .and_then(|_| self.try_set_avail_ring_address(avail_ring))
+            .and_then(|_| self.try_set_used_ring_address(used_ring));
+
+        match res {
+            Ok(()) => self.set_event_idx(event_idx),
+            Err(_) => (self.size, self.desc_table, self.avail_ring, self.used_ring) = old,
         }
+        res
     }
 
-    // Helper method that writes `val` to the `avail_event` field of the used ring, using
-    // the provided ordering.
-    fn set_avail_event<M: GuestMemory>(
-        &self,
+    /// Set the index of the next entry in the available ring, checking it against the `idx`
+    /// field of the available ring in guest memory.
+    ///
+    /// Unlike [`QueueT::set_next_avail`], this rejects a value that is ahead of the ring `idx`,
+    /// or so far behind it that more chains than the queue size would be pending, with
+    /// `Error::InconsistentRestore`. This catches corrupted snapshots at restore time.
+    pub fn try_set_next_avail<M: GuestMemory>(
+        &mut self,
         mem: &M,
-        val: u16,
-        order: Ordering,
+        next_avail: u16,
     ) -> Result<(), Error> {
-        // This can not overflow an u64 since it is working with relatively small numbers compared
-        // to u64::MAX.
-        let avail_event_offset =
-            VIRTQ_USED_RING_HEADER_SIZE + VIRTQ_USED_ELEMENT_SIZE * u64::from(self.size);
-        let addr = self
-            .used_ring
-            .checked_add(avail_event_offset)
-            .ok_or(Error::AddressOverflow)?;
-
-        mem.store(u16::to_le(val), addr, order)
-            .map_err(Error::GuestMemory)
+        let avail_idx = self.avail_idx(mem, Ordering::Acquire)?;
+        if Queue::distance(Wrapping(next_avail), avail_idx) > self.size {
+            return Err(Error::InconsistentRestore);
+        }
+        self.next_avail = Wrapping(next_avail);
+        Ok(())
     }
 
-    // Set the value of the `flags` field of the used ring, applying the specified ordering.
-    fn set_used_flags<M: GuestMemory>(
+    /// Set the index for the next descriptor in the used ring, checking it against the `idx`
+    /// field of the used ring in guest memory.
+    ///
+    /// Unlike [`QueueT::set_next_used`], this rejects a value that is ahead of the ring `idx`,
+    /// or more than the queue size behind it, with `Error::InconsistentRestore`.
+    pub fn try_set_next_used<M: GuestMemory>(
         &mut self,
         mem: &M,
-        val: u16,
-        order: Ordering,
+        next_used: u16,
     ) -> Result<(), Error> {
-        mem.store(u16::to_le(val), self.used_ring, order)
-            .map_err(Error::GuestMemory)
+        let used_idx = self.used_idx(mem, Ordering::Acquire)?;
+        if Queue::distance(Wrapping(next_used), used_idx) > self.size {
+            return Err(Error::InconsistentRestore);
+        }
+        self.next_used = Wrapping(next_used);
+        Ok(())
     }
 
-    // Write the appropriate values to enable or disable notifications from the driver.
-    //
-    // Every access in this method uses `Relaxed` ordering because a fence is added by the caller
-    // when appropriate.
-    fn set_notification<M: GuestMemory>(&mut self, mem: &M, enable: bool) -> Result<(), Error> {
-        if enable {
-            if self.event_idx_enabled {
-                // We call `set_avail_event` using the `next_avail` value, instead of reading
-                // and using the current `avail_idx` to avoid missing notifications. More
-                // details in `enable_notification`.
-                self.set_avail_event(mem, self.next_avail.0, Ordering::Relaxed)
-            } else {
-                self.set_used_flags(mem, 0, Ordering::Relaxed)
-            }
-        } else if !self.event_idx_enabled {
-            self.set_used_flags(mem, VRING_USED_F_NO_NOTIFY as u16, Ordering::Relaxed)
+    /// Enable or disable tracking the descriptor chains in flight.
+    ///
+    /// When enabled, the device marks the chains it starts processing with
+    /// [`mark_in_flight`](Queue::mark_in_flight), and adding a chain to the used ring clears its
+    /// mark. On reset or teardown, the chains that are still marked can be reclaimed with
+    /// [`reclaim_in_flight`](Queue::reclaim_in_flight) instead of being leaked. Enabling the
+    /// tracking starts with no chain in flight, and it's disabled by default.
+    pub fn set_in_flight_tracking(&mut self, enabled: bool) {
+        self.in_flight = if enabled {
+            Some(vec![0; usize::from(self.max_size).div_ceil(64)])
         } else {
-            // Notifications are effectively disabled by default after triggering once when
-            // `VIRTIO_F_EVENT_IDX` is negotiated, so we don't do anything in that case.
-            Ok(())
-        }
+            None
+        };
     }
 
-    // Return the value present in the used_event field of the avail ring.
-    //
-    // If the VIRTIO_F_EVENT_IDX feature bit is not negotiated, the flags field in the available
-    // ring offers a crude mechanism for the driver to inform the device that it doesn’t want
-    // interrupts when buffers are used. Otherwise virtq_avail.used_event is a more performant
-    // alternative where the driver specifies how far the device can progress before interrupting.
-    //
-    // Neither of these interrupt suppression methods are reliable, as they are not synchronized
-    // with the device, but they serve as useful optimizations. So we only ensure access to the
-    // virtq_avail.used_event is atomic, but do not need to synchronize with other memory accesses.
-    fn used_event<M: GuestMemory>(&self, mem: &M, order: Ordering) -> Result<Wrapping<u16>, Error> {
-        // This can not overflow an u64 since it is working with relatively small numbers compared
-        // to u64::MAX.
-        let used_event_offset =
-            VIRTQ_AVAIL_RING_HEADER_SIZE + u64::from(self.size) * VIRTQ_AVAIL_ELEMENT_SIZE;
-        let used_event_addr = self
-            .avail_ring
-            .checked_add(used_event_offset)
-            .ok_or(Error::AddressOverflow)?;
-
-        mem.load(used_event_addr, order)
-            .map(u16::from_le)
-            .map(Wrapping)
-            .map_err(Error::GuestMemory)
+    /// Mark the chain starting at `head_index` as in flight.
+    ///
+    /// `Error::InvalidDescriptorIndex` is returned if `head_index` is out of bounds. This does
+    /// nothing when in-flight tracking is disabled.
+    pub fn mark_in_flight(&mut self, head_index: u16) -> Result<(), Error> {
+        if head_index >= self.max_size {
+            return Err(Error::InvalidDescriptorIndex);
+        }
+        if let Some(bitmap) = self.in_flight.as_mut() {
+            bitmap[usize::from(head_index / 64)] |= 1 << (head_index % 64);
+        }
+        Ok(())
     }
-}
-
-impl<'a> QueueGuard<'a> for Queue {
-    type G = &'a mut Self;
-}
 
-impl QueueT for Queue {
-    fn new(max_size: u16) -> Result<Self, Error> {
-        // We need to check that the max size is a power of 2 because we're setting this as the
-        // queue size, and the valid queue sizes are a power of 2 as per the specification.
-        if max_size == 0 || max_size > MAX_QUEUE_SIZE || (max_size & (max_size - 1)) != 0 {
-            return Err(Error::InvalidMaxSize);
+    /// Return the head indexes of the chains still marked as in flight, in increasing order,
+    /// and clear their marks.
+    ///
+    /// Nothing is returned when in-flight tracking is disabled. The marks are left untouched by
+    /// [`QueueT::reset`], so this can be called after resetting the queue.
+    pub fn reclaim_in_flight(&mut self) -> impl Iterator<Item = u16> {
+        let mut heads = Vec::new();
+        for (i, word) in self.in_flight.iter_mut().flatten().enumerate() {
+            while *word != 0 {
+                // The bitmap holds at most `u16::MAX + 1` bits.
+                heads.push((i * 64) as u16 + word.trailing_zeros() as u16);
+                // Clear the lowest bit set.
+                *word &= *word - 1;
+            }
         }
-        Ok(Queue {
-            max_size,
-            size: max_size,
-            ready: false,
-            desc_table: GuestAddress(DEFAULT_DESC_TABLE_ADDR),
-            avail_ring: GuestAddress(DEFAULT_AVAIL_RING_ADDR),
-            used_ring: GuestAddress(DEFAULT_USED_RING_ADDR),
-            next_avail: Wrapping(0),
-            next_used: Wrapping(0),
-            event_idx_enabled: false,
-            num_added: Wrapping(0),
-        })
+        heads.into_iter()
     }
 
-    fn is_valid<M: GuestMemory>(&self, mem: &M) -> bool {
-        let queue_size = self.size as u64;
-        let desc_table = self.desc_table;
-        // The multiplication can not overflow an u64 since we are multiplying an u16 with a
-        // small number.
-        let desc_table_size = size_of::<RawDescriptor>() as u64 * queue_size;
-        let avail_ring = self.avail_ring;
-        // The operations below can not overflow an u64 since they're working with relatively small
-        // numbers compared to u64::MAX.
-        let avail_ring_size = VIRTQ_AVAIL_RING_META_SIZE + VIRTQ_AVAIL_ELEMENT_SIZE * queue_size;
-        let used_ring = self.used_ring;
-        let used_ring_size = VIRTQ_USED_RING_META_SIZE + VIRTQ_USED_ELEMENT_SIZE * queue_size;
+    // Forget the chain starting at `head_index`, which was added to the used ring.
+    fn clear_completed(&mut self, head_index: u16) {
+        self.clear_in_flight(head_index);
+        self.clear_submitted(head_index);
+    }
 
-        if !self.ready {
-            error!("attempt to use virtio queue that is not marked ready");
-            false
-        } else if desc_table
-            .checked_add(desc_table_size)
-            .is_none_or(|v| !mem.address_in_range(v))
-        {
-            error!(
-                "virtio queue descriptor table goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                desc_table.raw_value(),
-                desc_table_size
-            );
-            false
-        } else if avail_ring
-            .checked_add(avail_ring_size)
-            .is_none_or(|v| !mem.address_in_range(v))
-        {
-            error!(
-                "virtio queue available ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                avail_ring.raw_value(),
-                avail_ring_size
-            );
-            false
-        } else if used_ring
-            .checked_add(used_ring_size)
-            .is_none_or(|v| !mem.address_in_range(v))
+    // Clear the in-flight mark of the chain starting at `head_index`, if any.
+    fn clear_in_flight(&mut self, head_index: u16) {
+        if let Some(word) = self
+            .in_flight
+            .as_mut()
+            .and_then(|bitmap| bitmap.get_mut(usize::from(head_index / 64)))
         {
-            error!(
-                "virtio queue used ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                used_ring.raw_value(),
-                used_ring_size
-            );
-            false
-        } else {
-            true
+            *word &= !(1 << (head_index % 64));
         }
     }
 
-    fn reset(&mut self) {
-        self.ready = false;
-        self.size = self.max_size;
-        self.desc_table = GuestAddress(DEFAULT_DESC_TABLE_ADDR);
-        self.avail_ring = GuestAddress(DEFAULT_AVAIL_RING_ADDR);
-        self.used_ring = GuestAddress(DEFAULT_USED_RING_ADDR);
-        self.next_avail = Wrapping(0);
-        self.next_used = Wrapping(0);
-        self.num_added = Wrapping(0);
-        self.event_idx_enabled = false;
+    /// Record that the chain starting at `head_index` was submitted to a backend now.
+    ///
+    /// Together with [`clear_submitted`](Queue::clear_submitted) and
+    /// [`overdue`](Queue::overdue), this lets a device watch for chains a backend takes too
+    /// long to process. Submitting a chain again restarts its timer. Adding the chain to the
+    /// used ring stops tracking it. `Error::InvalidDescriptorIndex` is returned if `head_index`
+    /// is out of bounds.
+    pub fn mark_submitted(&mut self, head_index: u16) -> Result<(), Error> {
+        self.mark_submitted_at(head_index, Instant::now())
     }
 
-    fn lock(&mut self) -> <Self as QueueGuard<'_>>::G {
-        self
+    /// Same as [`mark_submitted`](Queue::mark_submitted), with the submission time provided by
+    /// the caller.
+    pub fn mark_submitted_at(&mut self, head_index: u16, at: Instant) -> Result<(), Error> {
+        if head_index >= self.max_size {
+            return Err(Error::InvalidDescriptorIndex);
+        }
+        self.submitted.insert(head_index, at);
+        Ok(())
     }
 
-    fn max_size(&self) -> u16 {
-        self.max_size
+    /// Stop tracking the submission time of the chain starting at `head_index`.
+    ///
+    /// This is only needed for chains which are not added to the used ring, e.g. when they are
+    /// resubmitted later.
+    pub fn clear_submitted(&mut self, head_index: u16) {
+        self.submitted.remove(&head_index);
     }
 
-    fn size(&self) -> u16 {
-        self.size
+    /// Return the head indexes of the chains submitted more than `timeout` ago and not
+    /// completed yet, in increasing order.
+    ///
+    /// The submission times are forgotten when the queue is reset.
+    pub fn overdue(&self, timeout: Duration) -> Vec<u16> {
+        self.overdue_at(Instant::now(), timeout)
     }
 
-    fn set_size(&mut self, size: u16) {
-        if self.try_set_size(size).is_err() {
-            error!("virtio queue with invalid size: {}", size);
-        }
+    /// Same as [`overdue`](Queue::overdue), measuring the time elapsed since the submission of
+    /// the chains up to `now` instead of the current time.
+    pub fn overdue_at(&self, now: Instant, timeout: Duration) -> Vec<u16> {
+        self.submitted
+            .iter()
+            .filter(|(_, &submitted)| now.saturating_duration_since(submitted) > timeout)
+            .map(|(&head_index, _)| head_index)
+            .collect()
     }
 
-    fn ready(&self) -> bool {
-        self.ready
+    /// Pause or resume the writes of the queue to guest memory.
+    ///
+    /// While paused, the methods that write to the rings, such as [`QueueT::add_used`] and
+    /// [`QueueT::enable_notification`], fail with `Error::QueuePaused` and leave the queue
+    /// untouched. Reading the rings, e.g. with [`QueueOwnedT::iter`], is still possible. This
+    /// is meant for the final phase of a migration, when guest memory may no longer be written.
+    /// The setting is left untouched by [`QueueT::reset`].
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
     }
 
-    fn set_ready(&mut self, ready: bool) {
-        self.ready = ready;
+    /// Start resetting the queue on its own, as allowed by `VIRTIO_F_RING_RESET`.
+    ///
+    /// The queue goes back to its initial state, as with [`QueueT::reset`], and stays in the
+    /// resetting state until [`complete_reset`](Queue::complete_reset) is called. Meanwhile,
+    /// the driver can reconfigure the queue, but processing it with [`QueueOwnedT::iter`] or
+    /// adding elements to the used ring fails with `Error::QueueResetting`. Resetting the whole
+    /// device with [`QueueT::reset`] also ends the resetting state.
+    pub fn begin_reset(&mut self) {
+        self.reset();
+        self.resetting = true;
     }
 
-    fn set_desc_table_address(&mut self, low: Option<u32>, high: Option<u32>) {
-        let low = low.unwrap_or(self.desc_table.0 as u32) as u64;
-        let high = high.unwrap_or((self.desc_table.0 >> 32) as u32) as u64;
-
-        let desc_table = GuestAddress((high << 32) | low);
-        if self.try_set_desc_table_address(desc_table).is_err() {
-            error!("virtio queue descriptor table breaks alignment constraints");
-        }
+    /// Finish the reset of the queue started with [`begin_reset`](Queue::begin_reset).
+    pub fn complete_reset(&mut self) {
+        self.resetting = false;
     }
 
-    fn set_avail_ring_address(&mut self, low: Option<u32>, high: Option<u32>) {
-        let low = low.unwrap_or(self.avail_ring.0 as u32) as u64;
-        let high = high.unwrap_or((self.avail_ring.0 >> 32) as u32) as u64;
-
-        let avail_ring = GuestAddress((high << 32) | low);
-        if self.try_set_avail_ring_address(avail_ring).is_err() {
-            error!("virtio queue available ring breaks alignment constraints");
-        }
+    /// Move the queue back to the start of its rings, keeping its configuration.
+    ///
+    /// Unlike [`QueueT::reset`], only `next_avail`, `next_used` and the count of used elements
+    /// added since the last notification are set back to 0. The size, ring addresses, readiness
+    /// and `VIRTIO_F_RING_EVENT_IDX` setting are left untouched, and so is guest memory: the
+    /// driver must also go back to the start of the rings for the queue to keep working.
+    pub fn reset_position(&mut self) {
+        self.next_avail = Wrapping(0);
+        self.next_used = Wrapping(0);
+        self.num_added = Wrapping(0);
     }
 
-    fn set_used_ring_address(&mut self, low: Option<u32>, high: Option<u32>) {
-        let low = low.unwrap_or(self.used_ring.0 as u32) as u64;
-        let high = high.unwrap_or((self.used_ring.0 >> 32) as u32) as u64;
-
-        let used_ring = GuestAddress((high << 32) | low);
-        if self.try_set_used_ring_address(used_ring).is_err() {
-            error!("virtio queue used ring breaks alignment constraints");
-        }
+    /// Check whether a reset of the queue started with [`begin_reset`](Queue::begin_reset) is
+    /// still in progress.
+    pub fn is_resetting(&self) -> bool {
+        self.resetting
     }
 
-    fn set_event_idx(&mut self, enabled: bool) {
-        self.event_idx_enabled = enabled;
+    /// Return the number of positions to move forward from ring index `from` to reach `to`.
+    ///
+    /// The `idx` fields of the rings, and the indexes derived from them, are free-running 16-bit
+    /// counters that wrap around from `u16::MAX` to 0, so the distance is computed modulo 2^16:
+    /// it is 2 from 0xffff to 1. For instance, the number of chains made available by the
+    /// driver and not popped yet is `Queue::distance(next_avail, avail_idx)`.
+    pub fn distance(from: Wrapping<u16>, to: Wrapping<u16>) -> u16 {
+        (to - from).0
     }
 
-    fn avail_idx<M>(&self, mem: &M, order: Ordering) -> Result<Wrapping<u16>, Error>
+    /// Return the size in bytes of the available ring of a queue with `size` elements.
+    ///
+    /// The ring ends with the `used_event` field only when `VIRTIO_F_RING_EVENT_IDX` is
+    /// negotiated (`event_idx == true`).
+    pub fn avail_ring_size_for(size: u16, event_idx: bool) -> u64 {
+        let event_size = if event_idx {
+            VIRTQ_AVAIL_RING_META_SIZE - VIRTQ_AVAIL_RING_HEADER_SIZE
+        } else {
+            0
+        };
+        // The operations below can not overflow an u64 since they're working with relatively
+        // small numbers compared to u64::MAX.
+        VIRTQ_AVAIL_RING_HEADER_SIZE + VIRTQ_AVAIL_ELEMENT_SIZE * u64::from(size) + event_size
+    }
+
+    /// Return the size in bytes of the used ring of a queue with `size` elements.
+    ///
+    /// The ring ends with the `avail_event` field only when `VIRTIO_F_RING_EVENT_IDX` is
+    /// negotiated (`event_idx == true`).
+    pub fn used_ring_size_for(size: u16, event_idx: bool) -> u64 {
+        let event_size = if event_idx {
+            VIRTQ_USED_RING_META_SIZE - VIRTQ_USED_RING_HEADER_SIZE
+        } else {
+            0
+        };
+        // The operations below can not overflow an u64 since they're working with relatively
+        // small numbers compared to u64::MAX.
+        VIRTQ_USED_RING_HEADER_SIZE + VIRTQ_USED_ELEMENT_SIZE * u64::from(size) + event_size
+    }
+
+    // Check whether the `size` bytes starting at `addr` belong to a single guest memory region.
+    // The rings are accessed as contiguous objects, which can't span several regions even
+    // when the regions are adjacent.
+    fn in_single_region<M: GuestMemory>(mem: &M, addr: GuestAddress, size: u64) -> bool {
+        mem.find_region(addr).is_some_and(|region| {
+            addr.unchecked_offset_from(region.start_addr())
+                .checked_add(size)
+                .is_some_and(|end| end <= region.len())
+        })
+    }
+
+    /// Check that the descriptor table, the available ring and the used ring don't overlap.
+    ///
+    /// Overlapping structures would corrupt each other as soon as the queue is used, and
+    /// `Error::OverlappingRings` is returned in that case. [`QueueT::is_valid`] runs this check
+    /// along with checking that each of them fits in guest memory. The event
+    /// fields at the end of the rings are only accounted for when `VIRTIO_F_RING_EVENT_IDX` is
+    /// negotiated.
+    pub fn check_rings_overlap(&self) -> Result<(), Error> {
+        let range = |start: GuestAddress, size: u64| {
+            start
+                .checked_add(size)
+                .map(|end| (start, end))
+                .ok_or(Error::AddressOverflow)
+        };
+        // The multiplication can not overflow an u64 since we are multiplying an u16 with a
+        // small number.
+        let desc_table_size = size_of::<RawDescriptor>() as u64 * u64::from(self.size);
+        let mut ranges = [
+            range(self.desc_table, desc_table_size)?,
+            range(
+                self.avail_ring,
+                Queue::avail_ring_size_for(self.size, self.event_idx_enabled),
+            )?,
+            range(
+                self.used_ring,
+                Queue::used_ring_size_for(self.size, self.event_idx_enabled),
+            )?,
+        ];
+
+        ranges.sort_unstable();
+        if ranges.windows(2).any(|w| w[1].0 < w[0].1) {
+            return Err(Error::OverlappingRings);
+        }
+
+        Ok(())
+    }
+
+    /// Read the `flags` field of the available ring, using the provided ordering.
+    ///
+    /// Without `VIRTIO_F_RING_EVENT_IDX`, the driver sets `VRING_AVAIL_F_NO_INTERRUPT` in this
+    /// field to ask the device not to send interrupts. Like [`QueueT::avail_idx`], this accepts
+    /// any handle dereferencing to a `GuestMemory` object.
+    pub fn avail_flags<M>(&self, mem: M, order: Ordering) -> Result<u16, Error>
     where
-        M: GuestMemory + ?Sized,
+        M: Deref,
+        M::Target: GuestMemory,
     {
+        mem.deref()
+            .load(self.avail_ring, order)
+            .map(|val| self.ring_to_cpu(val))
+            .map_err(Error::GuestMemory)
+    }
+
+    /// Write `idx` to the `idx` field of the available ring, using the provided ordering.
+    ///
+    /// Making chains available is the job of the driver, so this is meant for driver models,
+    /// e.g. in tests. The value is converted to the byte order of the ring, which callers would
+    /// otherwise have to do with `u16::to_le`, and can read back with [`QueueT::avail_idx`].
+    /// Same as for the other writes to the rings, `Error::QueuePaused` is returned while the
+    /// queue is paused.
+    pub fn store_avail_idx<M: GuestMemory>(
+        &self,
+        mem: &M,
+        idx: u16,
+        order: Ordering,
+    ) -> Result<(), Error> {
+        self.check_not_paused()?;
         let addr = self
             .avail_ring
             .checked_add(2)
             .ok_or(Error::AddressOverflow)?;
-
-        mem.load(addr, order)
-            .map(u16::from_le)
-            .map(Wrapping)
+        mem.store(self.cpu_to_ring(idx), addr, order)
             .map_err(Error::GuestMemory)
     }
 
-    fn used_idx<M: GuestMemory>(&self, mem: &M, order: Ordering) -> Result<Wrapping<u16>, Error> {
-        let addr = self
-            .used_ring
-            .checked_add(2)
-            .ok_or(Error::AddressOverflow)?;
+    /// Read the `flags` and `idx` fields of the available ring together.
+    ///
+    /// When the available ring is 4-byte aligned, both fields are read with a single 32-bit
+    /// load, so they are consistent with each other. The specification only requires the ring
+    /// to be 2-byte aligned though, in which case the fields are loaded one after the other and
+    /// the driver may update `idx` in between. Either way the loads are followed by a single
+    /// acquire fence, so reading the ring entries up to the returned `idx` afterwards is safe
+    /// without any additional barrier.
+    pub fn avail_snapshot<M: GuestMemory>(&self, mem: &M) -> Result<AvailSnapshot, Error> {
+        let (flags, idx) = if self.avail_ring.raw_value().is_multiple_of(4) {
+            let header = mem
+                .load::<u32>(self.avail_ring, Ordering::Relaxed)
+                .map_err(Error::GuestMemory)?
+                .to_ne_bytes();
+            (
+                self.ring_to_cpu(u16::from_ne_bytes([header[0], header[1]])),
+                self.ring_to_cpu(u16::from_ne_bytes([header[2], header[3]])),
+            )
+        } else {
+            (
+                self.avail_flags(mem, Ordering::Relaxed)?,
+                self.avail_idx(mem, Ordering::Relaxed)?.0,
+            )
+        };
+        fence(Ordering::Acquire);
 
-        mem.load(addr, order)
-            .map(u16::from_le)
-            .map(Wrapping)
-            .map_err(Error::GuestMemory)
+        Ok(AvailSnapshot { idx, flags })
     }
 
-    fn add_used<M: GuestMemory>(
-        &mut self,
-        mem: &M,
-        head_index: u16,
-        len: u32,
-    ) -> Result<(), Error> {
-        if head_index >= self.size {
+    /// Return the number of descriptor chains the driver made available and that were not
+    /// popped yet.
+    ///
+    /// This only loads the `idx` field of the available ring, so it's a cheap way for devices
+    /// to size their batches before iterating. `Error::InvalidAvailRingIndex` is returned if
+    /// the driver claims to have made more chains available than the queue size.
+    pub fn available_count<M: GuestMemory>(&self, mem: &M) -> Result<u16, Error> {
+        let count = Queue::distance(self.next_avail, self.avail_idx(mem, Ordering::Acquire)?);
+        if count > self.size {
             error!(
-                "attempted to add out of bounds descriptor to used ring: {}",
-                head_index
+                "the number of available queue buffer entries {} is greater than the queue size {}",
+                count, self.size
             );
+            return Err(Error::InvalidAvailRingIndex);
+        }
+
+        Ok(count)
+    }
+
+    /// Return a view over the whole descriptor table, if it lies in a single contiguous
+    /// guest memory region.
+    ///
+    /// This allows walking descriptor chains without translating the address of each
+    /// descriptor. Returns `None` when the table crosses a region boundary or is not backed by
+    /// guest memory at all.
+    ///
+    /// The driver may still modify the table concurrently, so the view is volatile rather than
+    /// a plain `&[SplitDescriptor]`. It borrows `mem` only, and can be turned into an array of
+    /// descriptors with [`VolatileMemory::get_array_ref`], each descriptor being copied out with
+    /// [`VolatileArrayRef::load`] before it's inspected.
+    ///
+    /// [`VolatileMemory::get_array_ref`]: vm_memory::VolatileMemory::get_array_ref
+    /// [`VolatileArrayRef::load`]: vm_memory::VolatileArrayRef::load
+    pub fn descriptor_table_slice<'a, M: GuestMemory>(
+        &self,
+        mem: &'a M,
+    ) -> Option<VolatileSlice<'a, MS<'a, M>>> {
+        let (region, addr) = mem.to_region_addr(self.desc_table)?;
+        region
+            .get_slice(addr, size_of::<RawDescriptor>() * usize::from(self.size))
+            .ok()
+    }
+
+    /// Return the number of descriptor table entries that are backed by guest memory.
+    ///
+    /// Unlike [`QueueT::is_valid`], which rejects the queue when any part of the table is not
+    /// backed, this probes every entry individually. It is meant for diagnosing sparsely mapped
+    /// descriptor tables.
+    pub fn backed_descriptor_count<M: GuestMemory>(&self, mem: &M) -> u16 {
+        let desc_size = size_of::<RawDescriptor>();
+        let backed = (0..u64::from(self.size))
+            .filter(|i| {
+                // The multiplication can not overflow an u64 since we are multiplying an u16
+                // with a small number.
+                self.desc_table
+                    .checked_add(i * desc_size as u64)
+                    .is_some_and(|addr| mem.check_range(addr, desc_size))
+            })
+            .count();
+        // There are at most `self.size` entries, so this always fits.
+        backed as u16
+    }
+
+    /// Read the descriptor at `index` in the descriptor table, and check that the buffer it
+    /// points to is backed by guest memory.
+    ///
+    /// This is a building block for walking chains without a [`DescriptorChain`]: the `next`
+    /// and `flags` fields are returned as is, nothing is checked across descriptors, and
+    /// indirect tables are not followed. `Error::InvalidDescriptorIndex` is returned if `index`
+    /// is not below the queue size, and `Error::FindMemoryRegion` if the buffer is not entirely
+    /// backed by guest memory.
+    pub fn load_descriptor<M: GuestMemory>(
+        &self,
+        mem: &M,
+        index: u16,
+    ) -> Result<SplitDescriptor, Error> {
+        if index >= self.size {
             return Err(Error::InvalidDescriptorIndex);
         }
 
-        let next_used_index = u64::from(self.next_used.0 % self.size);
-        // This can not overflow an u64 since it is working with relatively small numbers compared
-        // to u64::MAX.
-        let offset = VIRTQ_USED_RING_HEADER_SIZE + next_used_index * VIRTQ_USED_ELEMENT_SIZE;
-        let addr = self
-            .used_ring
-            .checked_add(offset)
+        let desc_addr = self
+            .desc_table
+            // The multiplication can not overflow an u64 since we are multiplying an u16 with a
+            // small number.
+            .checked_add(u64::from(index) * size_of::<RawDescriptor>() as u64)
             .ok_or(Error::AddressOverflow)?;
-        mem.write_obj(VirtqUsedElem::new(head_index.into(), len), addr)
+        let desc = mem
+            .read_obj::<SplitDescriptor>(desc_addr)
+            .map(|desc| {
+                if self.legacy_big_endian {
+                    desc.swap_bytes()
+                } else {
+                    desc
+                }
+            })
             .map_err(Error::GuestMemory)?;
 
-        self.next_used += Wrapping(1);
-        self.num_added += Wrapping(1);
+        if !mem.check_range(desc.addr(), desc.len() as usize) {
+            return Err(Error::FindMemoryRegion);
+        }
+        Ok(desc)
+    }
 
-        mem.store(
-            u16::to_le(self.next_used.0),
-            self.used_ring
-                .checked_add(2)
-                .ok_or(Error::AddressOverflow)?,
-            Ordering::Release,
-        )
-        .map_err(Error::GuestMemory)
+    /// Enable or disable recording the length of the descriptor chains popped from the
+    /// available ring.
+    ///
+    /// Tracking is disabled by default because it requires walking every chain one extra time
+    /// when it's popped.
+    pub fn set_track_chain_len(&mut self, enabled: bool) {
+        self.track_chain_len = enabled;
     }
 
-    fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
-        self.set_notification(mem, true)?;
-        // Ensures the following read is not reordered before any previous write operation.
-        fence(Ordering::SeqCst);
+    /// Return the number of descriptors in the longest chain popped from the available ring
+    /// since tracking was enabled with [`set_track_chain_len`](Queue::set_track_chain_len).
+    ///
+    /// Devices can use this to size their scatter-gather buffers according to what the driver
+    /// actually submits rather than the worst case.
+    pub fn max_chain_len_seen(&self) -> u16 {
+        self.max_chain_len_seen
+    }
 
-        // We double check here to avoid the situation where the available ring has been updated
-        // just before we re-enabled notifications, and it's possible to miss one. We compare the
-        // current `avail_idx` value to `self.next_avail` because it's where we stopped processing
-        // entries. There are situations where we intentionally avoid processing everything in the
-        // available ring (which will cause this method to return `true`), but in that case we'll
-        // probably not re-enable notifications as we already know there are pending entries.
-        self.avail_idx(mem, Ordering::Relaxed)
-            .map(|idx| idx != self.next_avail)
+    /// Enable or disable strict validation of the descriptor chains popped from the available
+    /// ring.
+    ///
+    /// In strict mode, walking a chain with [`DescriptorChain::try_next`] fails with
+    /// [`Error::ZeroLengthWritableDescriptor`] when it reaches a device-writable descriptor of
+    /// length zero, which is a driver bug that would otherwise show up as confusing zero-byte
+    /// transfers in the device. It also fails with [`Error::InvalidChainOrder`] when a
+    /// device-readable descriptor follows a device-writable one, which the specification
+    /// forbids. Strict validation is disabled by default.
+    ///
+    /// The errors are reported by [`DescriptorChain::try_next`],
+    /// [`DescriptorChainRwIter::try_next`], [`Reader::new`](crate::Reader::new) and
+    /// [`Writer::new`](crate::Writer::new). Plain iteration, e.g. with [`Iterator::next`],
+    /// [`DescriptorChain::readable`], [`DescriptorChain::writable`] or
+    /// [`DescriptorChain::writable_segments`], stops silently at the offending descriptor
+    /// instead, so the chain looks like a shorter valid one.
+    ///
+    /// [`DescriptorChainRwIter::try_next`]: crate::DescriptorChainRwIter::try_next
+    pub fn set_strict_validation(&mut self, enabled: bool) {
+        self.strict_validation = enabled;
     }
 
-    fn disable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<(), Error> {
-        self.set_notification(mem, false)
+    /// Enable or disable checking that the device doesn't complete more chains than it popped.
+    ///
+    /// When enabled, adding an element to the used ring while every chain popped from the
+    /// available ring was already completed fails with [`Error::TooManyUsed`], as this is a bug
+    /// in the device that would hand the driver stale or duplicated buffers. Batches added with
+    /// [`QueueT::add_used_batch`] or [`publish_all`] are checked as a whole, before writing any
+    /// element, so a rejected batch leaves the used ring untouched. The check is disabled by
+    /// default, since devices restoring their position in the rings may not keep
+    /// `next_avail` and `next_used` consistent.
+    pub fn set_used_count_check(&mut self, enabled: bool) {
+        self.check_used_count = enabled;
     }
 
-    fn needs_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
-        let used_idx = self.next_used;
+    /// Enable or disable the indirect-only mode for the descriptor chains popped from the
+    /// available ring.
+    ///
+    /// This is meant for devices that expect drivers to always use indirect tables. In this
+    /// mode, walking a chain whose head descriptor has `VRING_DESC_F_NEXT` set without
+    /// `VRING_DESC_F_INDIRECT` fails with [`Error::IndirectTableRequired`]. Chains made of a
+    /// single descriptor are still accepted. The mode is disabled by default.
+    ///
+    /// Same as for [`set_strict_validation`](Queue::set_strict_validation), the error is only
+    /// reported by the fallible accessors such as [`DescriptorChain::try_next`],
+    /// [`Reader::new`](crate::Reader::new) and [`Writer::new`](crate::Writer::new). Plain
+    /// iteration stops at the head descriptor instead, so the chain looks empty.
+    pub fn set_indirect_only(&mut self, enabled: bool) {
+        self.indirect_only = enabled;
+    }
 
-        // Complete all the writes in add_used() before reading the event.
-        fence(Ordering::SeqCst);
+    /// Enable or disable rejecting descriptors whose buffer overlaps the rings, for the
+    /// descriptor chains popped from the available ring.
+    ///
+    /// A driver pointing a buffer at the descriptor table, the available ring or the used ring
+    /// would get the device to corrupt the ring metadata, which is only expected from a buggy
+    /// or malicious driver. When enabled, walking a chain fails with
+    /// [`Error::BufferOverlapsRing`] on such a descriptor, including descriptors referring to an
+    /// indirect table overlapping the rings. The check is disabled by default.
+    pub fn set_validate_buffer_regions(&mut self, enabled: bool) {
+        self.validate_buffer_regions = enabled;
+    }
 
-        // The VRING_AVAIL_F_NO_INTERRUPT flag isn't supported yet.
+    /// Enable or disable prefetching descriptors for the descriptor chains popped from the
+    /// available ring.
+    ///
+    /// With prefetching, walking a chain reads a few consecutive entries of the descriptor
+    /// table at once instead of one entry per descriptor, which saves guest memory accesses
+    /// for chains whose descriptors are next to each other in the table. The descriptors
+    /// returned are the same either way. Prefetching is disabled by default.
+    pub fn set_descriptor_prefetch(&mut self, enabled: bool) {
+        self.prefetch_descriptors = enabled;
+    }
 
-        // When the `EVENT_IDX` feature is negotiated, the driver writes into `used_event`
-        // a value that's used by the device to determine whether a notification must
-        // be submitted after adding a descriptor chain to the used ring. According to the
-        // standard, the notification must be sent when `next_used == used_event + 1`, but
-        // various device model implementations rely on an inequality instead, most likely
-        // to also support use cases where a bunch of descriptor chains are added to the used
-        // ring first, and only afterwards the `needs_notification` logic is called. For example,
-        // the approach based on `num_added` below is taken from the Linux Kernel implementation
-        // (i.e. https://elixir.bootlin.com/linux/v5.15.35/source/drivers/virtio/virtio_ring.c#L661)
+    /// Add an element to the used ring, and return whether the driver must be notified.
+    ///
+    /// This is equivalent to calling [`QueueT::add_used`] followed by
+    /// [`QueueT::needs_notification`], which is what devices do for each completed request
+    /// most of the time. The only barrier is the `SeqCst` fence separating the update of the
+    /// used ring from the check of the driver's notification suppression settings.
+    pub fn add_used_and_check_notify<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        head_index: u16,
+        len: u32,
+    ) -> Result<bool, Error> {
+        self.add_used(mem, head_index, len)?;
+        self.needs_notification(mem)
+    }
 
-        // The `old` variable below is used to determine the value of `next_used` from when
-        // `needs_notification` was called last (each `needs_notification` call resets `num_added`
-        // to zero, while each `add_used` called increments it by one). Then, the logic below
-        // uses wrapped arithmetic to see whether `used_event` can be found between `old` and
-        // `next_used` in the circular sequence space of the used ring.
-        if self.event_idx_enabled {
-            let used_event = self.used_event(mem, Ordering::Relaxed)?;
-            let old = used_idx - self.num_added;
-            self.num_added = Wrapping(0);
+    /// Use the native byte order (`native == true`) instead of little-endian for accessing the
+    /// descriptors and all the fields of the available and used rings.
+    ///
+    /// This is meant for emulating legacy (pre-1.0) devices on transports where the rings use
+    /// the byte order of the guest. It applies to the chains popped from the queue, but not to
+    /// the raw view returned by [`descriptor_table_slice`](Queue::descriptor_table_slice). The
+    /// setting is left untouched by [`QueueT::reset`].
+    pub fn set_legacy_endian(&mut self, native: bool) {
+        self.legacy_big_endian = native && cfg!(target_endian = "big");
+    }
 
-            return Ok(used_idx - used_event - Wrapping(1) < used_idx - old);
+    /// Return the address of the used ring element the next call to [`QueueT::add_used`] will
+    /// write.
+    ///
+    /// This allows devices to prepare data at the location of the used element before
+    /// completing a request. `Error::InvalidSize` is returned for a queue of size 0.
+    pub fn next_used_slot_addr(&self) -> Result<GuestAddress, Error> {
+        self.used_elem_addr(self.next_used)
+    }
+
+    /// Read the element at position `index` of the used ring.
+    ///
+    /// This is meant for backends reconciling completions and for diagnostics. The index is a
+    /// position in the ring, not a value of the free running `idx` field, and
+    /// `Error::InvalidDescriptorIndex` is returned if it's not smaller than the queue size.
+    pub fn used_elem<M: GuestMemory>(&self, mem: &M, index: u16) -> Result<VirtqUsedElem, Error> {
+        if index >= self.size {
+            return Err(Error::InvalidDescriptorIndex);
         }
 
-        Ok(true)
+        let addr = self.used_elem_addr(Wrapping(index))?;
+        mem.read_obj::<VirtqUsedElem>(addr)
+            .map(|elem| {
+                if self.legacy_big_endian {
+                    elem.swap_bytes()
+                } else {
+                    elem
+                }
+            })
+            .map_err(Error::GuestMemory)
     }
 
-    fn next_avail(&self) -> u16 {
-        self.next_avail.0
+    /// Read the whole array of head indices of the available ring.
+    ///
+    /// All the `size` entries are returned, including the stale ones past the `idx` field of
+    /// the ring, which helps diagnosing driver bugs.
+    pub fn export_avail_ring<M: GuestMemory>(&self, mem: &M) -> Result<Vec<u16>, Error> {
+        (0..u64::from(self.size))
+            .map(|index| {
+                // This can not overflow an u64 since it is working with relatively small numbers
+                // compared to u64::MAX.
+                let offset = VIRTQ_AVAIL_RING_HEADER_SIZE + index * VIRTQ_AVAIL_ELEMENT_SIZE;
+                let addr = self
+                    .avail_ring
+                    .checked_add(offset)
+                    .ok_or(Error::AddressOverflow)?;
+                mem.read_obj::<u16>(addr)
+                    .map(|val| self.ring_to_cpu(val))
+                    .map_err(Error::GuestMemory)
+            })
+            .collect()
     }
 
-    fn set_next_avail(&mut self, next_avail: u16) {
-        self.next_avail = Wrapping(next_avail);
-    }
+    /// Format the state of the queue and of its rings, for debugging purposes.
+    ///
+    /// The output shows the ring addresses, the `idx` fields of the available and used rings,
+    /// `next_avail`, `next_used`, and the head indices made available by the driver that were
+    /// not popped yet. Guest memory is read on a best effort basis: values that can't be read
+    /// are shown as `?`, and the heads are not listed if the available ring `idx` is more than
+    /// the queue size ahead of `next_avail`. The format is meant for humans, and may change.
+    pub fn dump<M: GuestMemory>(&self, mem: &M) -> String {
+        let show =
+            |val: Result<u16, Error>| val.map_or_else(|_| "?".to_string(), |v| v.to_string());
+        let avail_idx = self.avail_idx(mem, Ordering::Acquire).map(|idx| idx.0);
+        let used_idx = self.used_idx(mem, Ordering::Acquire).map(|idx| idx.0);
+
+        let count = avail_idx
+            .as_ref()
+            .map(|&idx| Queue::distance(self.next_avail, Wrapping(idx)));
+        let heads = match count {
+            Ok(count) if count <= self.size => {
+                let heads: Vec<String> = (0..count)
+                    .map(|i| {
+                        let head = (self.next_avail + Wrapping(i))
+                            .0
+                            .checked_rem(self.size)
+                            .map(|pos| {
+                                // This can not overflow an u64 since it is working with
+                                // relatively small numbers compared to u64::MAX.
+                                VIRTQ_AVAIL_RING_HEADER_SIZE
+                                    + u64::from(pos) * VIRTQ_AVAIL_ELEMENT_SIZE
+                            })
+                            .and_then(|offset| self.avail_ring.checked_add(offset))
+                            .ok_or(Error::AddressOverflow)
+                            .and_then(|addr| {
+                                mem.read_obj::<u16>(addr)
+                                    .map(|val| self.ring_to_cpu(val))
+                                    .map_err(Error::GuestMemory)
+                            });
+                        show(head)
+                    })
+                    .collect();
+                format!("[{}]", heads.join(", "))
+            }
+            Ok(_) => "? (avail idx out of range)".to_string(),
+            Err(_) => "?".to_string(),
+        };
 
-    fn next_used(&self) -> u16 {
-        self.next_used.0
+        format!(
+            "desc table: {:#x}, avail ring: {:#x}, used ring: {:#x}, size: {}, ready: {}\n\
+             avail idx: {}, used idx: {}, next_avail: {}, next_used: {}\n\
+             available heads: {}",
+            self.desc_table.raw_value(),
+            self.avail_ring.raw_value(),
+            self.used_ring.raw_value(),
+            self.size,
+            self.ready,
+            show(avail_idx),
+            show(used_idx),
+            self.next_avail.0,
+            self.next_used.0,
+            heads
+        )
     }
 
-    fn set_next_used(&mut self, next_used: u16) {
-        self.next_used = Wrapping(next_used);
+    /// Return a value that increases every time an element is added to the used ring.
+    ///
+    /// A watchdog can compare the tokens returned over time to detect a device that stopped
+    /// completing requests. The token is not reset along with the queue, and doesn't depend on
+    /// guest memory.
+    pub fn progress_token(&self) -> u64 {
+        self.progress
     }
 
-    fn desc_table(&self) -> u64 {
-        self.desc_table.0
+    /// Return the counters of the descriptor chains popped from the available ring, and of the
+    /// elements added to the used ring.
+    ///
+    /// The counters are kept across [`QueueT::reset`], use [`reset_stats`](Queue::reset_stats)
+    /// to clear them.
+    pub fn stats(&self) -> QueueStats {
+        self.stats
     }
 
-    fn avail_ring(&self) -> u64 {
-        self.avail_ring.0
+    /// Reset the counters returned by [`stats`](Queue::stats).
+    pub fn reset_stats(&mut self) {
+        self.stats = QueueStats::default();
     }
 
-    fn used_ring(&self) -> u64 {
-        self.used_ring.0
+    /// Ask the driver to stop (`enable == false`) or resume (`enable == true`) notifying the
+    /// device when it makes new buffers available.
+    ///
+    /// This only concerns driver to device notifications (queue kicks), and can be used as a
+    /// crude flow control mechanism by a device whose backlog is deep. It doesn't affect the
+    /// interrupts sent by the device to the driver, see
+    /// [`QueueT::needs_notification`] for that direction.
+    ///
+    /// The request is expressed by setting or clearing `VRING_USED_F_NO_NOTIFY` in the `flags`
+    /// field of the used ring. The driver ignores this field when `VIRTIO_F_RING_EVENT_IDX` is
+    /// negotiated, in which case [`QueueT::enable_notification`] and
+    /// [`QueueT::disable_notification`] should be used instead. Like any suppression hint it is
+    /// not synchronized with the driver, so after resuming notifications the device should
+    /// still check the available ring for buffers added in the meantime.
+    pub fn set_driver_notifications<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        enable: bool,
+    ) -> Result<(), Error> {
+        let flags = if enable {
+            0
+        } else {
+            VRING_USED_F_NO_NOTIFY as u16
+        };
+        self.set_used_flags(mem, flags, Ordering::Relaxed)
     }
 
-    fn event_idx_enabled(&self) -> bool {
-        self.event_idx_enabled
+    /// Ask the driver to notify the device once it makes the available ring entry at index
+    /// `target` available, by writing `target` to the `avail_event` field of the used ring.
+    ///
+    /// [`QueueT::enable_notification`] uses `next_avail`, to be notified about the next chain.
+    /// Devices can pick an index further ahead to be notified less often, e.g. when they prefer
+    /// processing chains in batches. The field only exists with `VIRTIO_F_RING_EVENT_IDX`, so
+    /// nothing is written when it's not negotiated. The driver may already have gone past
+    /// `target` when it reads the field, in which case it doesn't notify the device: the device
+    /// should check the available ring afterwards, or poll it.
+    pub fn set_avail_event_at<M: GuestMemory>(&self, mem: &M, target: u16) -> Result<(), Error> {
+        self.set_avail_event(mem, target, Ordering::Relaxed)
     }
 
-    fn pop_descriptor_chain<M>(&mut self, mem: M) -> Option<DescriptorChain<M>>
+    /// Pop all the available descriptor chains, and pass them to `f` sorted by `key`.
+    ///
+    /// Chains with a lower key are processed first, and chains with the same key are processed
+    /// in the order the driver made them available. This allows devices to prioritize some
+    /// requests, e.g. based on a priority field found in the buffer of the head descriptor.
+    ///
+    /// Note that this reorders the completion of the chains, which is only allowed when
+    /// `VIRTIO_F_IN_ORDER` is not negotiated.
+    pub fn drain_sorted<M, K, F>(&mut self, mem: M, key: K, f: F) -> Result<(), Error>
     where
         M: Clone + Deref,
         M::Target: GuestMemory,
+        K: Fn(&DescriptorChain<M>) -> u32,
+        F: FnMut(DescriptorChain<M>),
     {
-        // Default, iter-based impl. Will be subsequently improved.
-        match self.iter(mem) {
-            Ok(mut iter) => iter.next(),
-            Err(e) => {
-                error!("Iterator error {}", e);
-                None
+        let mut chains: Vec<DescriptorChain<M>> = self.iter(mem)?.collect();
+        chains.sort_by_cached_key(key);
+        chains.into_iter().for_each(f);
+        Ok(())
+    }
+
+    /// Pop all the available descriptor chains and return them to the driver with a length of
+    /// zero, without looking at their buffers.
+    ///
+    /// This is meant for devices that enter an error state and need to flush the queue before
+    /// it's reset. Chains whose head index is out of bounds can't be placed in the used ring,
+    /// so they are skipped (an error is logged for each). Return the number of chains placed
+    /// in the used ring. If adding a chain to the used ring fails for another reason, the error
+    /// is returned, and that chain and the following ones are left in the available ring.
+    pub fn flush_available<M: GuestMemory>(&mut self, mem: &M) -> Result<usize, Error> {
+        let heads: Vec<u16> = self.iter(mem)?.map(|chain| chain.head_index()).collect();
+
+        let mut flushed = 0;
+        for (pos, &head) in heads.iter().enumerate() {
+            match self.add_used(mem, head, 0) {
+                Ok(()) => flushed += 1,
+                // `add_used` already logged the invalid index.
+                Err(Error::InvalidDescriptorIndex) => {}
+                Err(e) => {
+                    // Move back to the first chain that wasn't added to the used ring, so the
+                    // remaining chains are not lost. There are at most `size` of them.
+                    let remaining = (heads.len() - pos) as u16;
+                    self.next_avail -= Wrapping(remaining);
+                    self.stats.chains_popped =
+                        self.stats.chains_popped.wrapping_sub(u64::from(remaining));
+                    return Err(e);
+                }
             }
         }
+        Ok(flushed)
     }
-}
 
-impl QueueOwnedT for Queue {
-    fn iter<M>(&mut self, mem: M) -> Result<AvailIter<'_, M>, Error>
+    /// Skip all the available descriptor chains without processing them, and return how many
+    /// were skipped.
+    ///
+    /// `next_avail` is moved to the `idx` field of the available ring. Unlike
+    /// [`flush_available`](Queue::flush_available), nothing is written to the used ring: the
+    /// skipped chains are dropped, and their buffers are never returned to the driver, which
+    /// typically stalls until the device is reset. This is only meant for recovering from
+    /// errors where the pending work must be abandoned. `Error::InvalidAvailRingIndex` is
+    /// returned, and nothing is skipped, if the driver claims to have made more chains
+    /// available than the queue size.
+    pub fn skip_available<M: GuestMemory>(&mut self, mem: &M) -> Result<u16, Error> {
+        let count = self.available_count(mem)?;
+        self.next_avail += Wrapping(count);
+        Ok(count)
+    }
+
+    /// Pop all the descriptor chains currently available, in order.
+    ///
+    /// The `idx` field of the available ring is read once, so chains the driver makes
+    /// available meanwhile are left for later. `next_avail` moves forward by the number of
+    /// chains returned. The errors are the ones reported by [`iter`](QueueOwnedT::iter).
+    pub fn drain_available<M>(&mut self, mem: M) -> Result<Vec<DescriptorChain<M>>, Error>
     where
-        M: Deref,
+        M: Clone + Deref,
         M::Target: GuestMemory,
     {
-        // We're checking here that a reset did not happen without re-initializing the queue.
-        // TODO: In the future we might want to also check that the other parameters in the
-        // queue are valid.
-        if !self.ready || self.avail_ring == GuestAddress(0) {
-            return Err(Error::QueueNotReady);
+        Ok(self.iter(mem)?.collect())
+    }
+
+    /// Pop the next descriptor chain from the available ring, and return its descriptor if it
+    /// is the only one of the chain.
+    ///
+    /// This is a fast path for devices whose chains are made of a single descriptor, which
+    /// reads that descriptor without walking the chain. `Ok(None)` is returned when no chain is
+    /// available. If the chain holds several descriptors, `Error::MultiDescriptorChain` is
+    /// returned and the chain is put back in the available ring, so it can be popped again with
+    /// [`pop_descriptor_chain`](QueueT::pop_descriptor_chain). A chain using an indirect table
+    /// with a single entry counts as a single descriptor chain. Other errors are the ones
+    /// reported by [`iter`](QueueT::iter) and [`DescriptorChain::try_next`], in which case the
+    /// chain is popped nonetheless.
+    pub fn pop_single_descriptor<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+    ) -> Result<Option<SplitDescriptor>, Error> {
+        let mut chain = match self.iter(mem)?.next() {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+
+        let desc = chain.try_next()?.ok_or(Error::EmptyDescriptorChain)?;
+        if desc.has_next() {
+            self.go_to_previous_position();
+            return Err(Error::MultiDescriptorChain);
         }
 
-        self.avail_idx(mem.deref(), Ordering::Acquire)
-            .map(move |idx| AvailIter::new(mem, idx, self))?
+        Ok(Some(desc))
     }
 
-    fn go_to_previous_position(&mut self) {
-        self.next_avail -= Wrapping(1);
+    /// Returns the state of the `Queue`.
+    ///
+    /// This is useful for implementing save/restore capabilities.
+    /// The state does not have support for serialization, but this can be
+    /// added by VMMs locally through the use of a
+    /// [remote type](https://serde.rs/remote-derive.html).
+    ///
+    /// Alternatively, a version aware and serializable/deserializable QueueState
+    /// is available in the `virtio-queue-ser` crate.
+    pub fn state(&self) -> QueueState {
+        QueueState {
+            max_size: self.max_size,
+            next_avail: self.next_avail(),
+            next_used: self.next_used(),
+            event_idx_enabled: self.event_idx_enabled,
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table(),
+            avail_ring: self.avail_ring(),
+            used_ring: self.used_ring(),
+        }
     }
-}
 
-/// Consuming iterator over all available descriptor chain heads in the queue.
-///
-/// # Example
-///
-/// ```rust
-/// # use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
-/// # use virtio_queue::mock::MockSplitQueue;
-/// use virtio_queue::{desc::{split::Descriptor as SplitDescriptor, RawDescriptor}, Queue, QueueOwnedT};
-/// use vm_memory::{GuestAddress, GuestMemoryMmap};
-///
-/// # fn populate_queue(m: &GuestMemoryMmap) -> Queue {
-/// #    let vq = MockSplitQueue::new(m, 16);
-/// #    let mut q: Queue = vq.create_queue().unwrap();
-/// #
-/// #    // The chains are (0, 1), (2, 3, 4) and (5, 6).
-/// #    let mut descs = Vec::new();
-/// #    for i in 0..7 {
-/// #        let flags = match i {
-/// #            1 | 6 => 0,
-/// #            2 | 5 => VRING_DESC_F_NEXT | VRING_DESC_F_WRITE,
-/// #            4 => VRING_DESC_F_WRITE,
-/// #            _ => VRING_DESC_F_NEXT,
-/// #        };
-/// #
-/// #        descs.push(RawDescriptor::from(SplitDescriptor::new((0x1000 * (i + 1)) as u64, 0x1000, flags as u16, i + 1)));
-/// #    }
-/// #
-/// #    vq.add_desc_chains(&descs, 0).unwrap();
-/// #    q
-/// # }
-/// let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-/// // Populate the queue with descriptor chains and update the available ring accordingly.
-/// let mut queue = populate_queue(m);
-/// let mut i = queue.iter(m).unwrap();
-///
-/// {
-///     let mut c = i.next().unwrap();
-///     let _first_head_index = c.head_index();
-///     // We should have two descriptors in the first chain.
-///     let _desc1 = c.next().unwrap();
-///     let _desc2 = c.next().unwrap();
-/// }
-///
-/// {
-///     let c = i.next().unwrap();
-///     let _second_head_index = c.head_index();
-///
-///     let mut iter = c.writable();
-///     // We should have two writable descriptors in the second chain.
-///     let _desc1 = iter.next().unwrap();
-///     let _desc2 = iter.next().unwrap();
-/// }
-///
-/// {
-///     let c = i.next().unwrap();
-///     let _third_head_index = c.head_index();
-///
-///     let mut iter = c.readable();
-///     // We should have one readable descriptor in the third chain.
-///     let _desc1 = iter.next().unwrap();
-/// }
-/// // Let's go back one position in the available ring.
-/// i.go_to_previous_position();
-/// // We should be able to access again the third descriptor chain.
-/// let c = i.next().unwrap();
-/// let _third_head_index = c.head_index();
-/// ```
-#[derive(Debug)]
-pub struct AvailIter<'b, M> {
-    mem: M,
-    desc_table: GuestAddress,
-    avail_ring: GuestAddress,
-    queue_size: u16,
-    last_index: Wrapping<u16>,
-    next_avail: &'b mut Wrapping<u16>,
-}
-
-impl<'b, M> AvailIter<'b, M>
-where
-    M: Deref,
-    M::Target: GuestMemory,
-{
-    /// Create a new instance of `AvailInter`.
+    /// Return a copy of the queue with `next_avail` and `next_used` set to the current `idx`
+    /// fields of the available and used rings in guest memory.
     ///
-    /// # Arguments
-    ///
-    /// * `mem` - the `GuestMemory` object that can be used to access the queue buffers.
-    /// * `idx` - the index of the available ring entry where the driver would put the next available descriptor chain.
-    /// * `queue` - the `Queue` object from which the needed data to create the `AvailIter` can be retrieved.
-    pub(crate) fn new(mem: M, idx: Wrapping<u16>, queue: &'b mut Queue) -> Result<Self, Error> {
-        // The number of descriptor chain heads to process should always
-        // be smaller or equal to the queue size, as the driver should
-        // never ask the VMM to process a available ring entry more than
-        // once. Checking and reporting such incorrect driver behavior
-        // can prevent potential hanging and Denial-of-Service from
-        // happening on the VMM side.
-        if (idx - queue.next_avail).0 > queue.size {
-            return Err(Error::InvalidAvailRingIndex);
+    /// This is meant for VMMs taking over a queue whose rings were driven by someone else
+    /// (e.g. a vhost backend), so the positions recorded in the `Queue` are stale. The copy is
+    /// validated like a restored [`QueueState`], so `Error::InconsistentRestore` is returned if
+    /// the driver made more chains available than the queue size, on top of the errors from
+    /// reading guest memory.
+    pub fn clone_with_memory_positions<M: GuestMemory>(&self, mem: &M) -> Result<Queue, Error> {
+        let mut state = self.state();
+        state.next_avail = self.avail_idx(mem, Ordering::Acquire)?.0;
+        state.next_used = self.used_idx(mem, Ordering::Acquire)?.0;
+
+        let mut queue = Queue::try_from_state(state)?;
+        queue.track_chain_len = self.track_chain_len;
+        queue.max_chain_len_seen = self.max_chain_len_seen;
+        queue.strict_validation = self.strict_validation;
+        queue.indirect_only = self.indirect_only;
+        queue.check_used_count = self.check_used_count;
+        queue.prefetch_descriptors = self.prefetch_descriptors;
+        queue.validate_buffer_regions = self.validate_buffer_regions;
+        queue.legacy_big_endian = self.legacy_big_endian;
+        Ok(queue)
+    }
+
+    // Convert a 16-bit field of a ring to the native byte order.
+    fn ring_to_cpu(&self, val: u16) -> u16 {
+        if self.legacy_big_endian {
+            u16::from_be(val)
+        } else {
+            u16::from_le(val)
         }
+    }
 
-        Ok(AvailIter {
-            mem,
-            desc_table: queue.desc_table,
-            avail_ring: queue.avail_ring,
-            queue_size: queue.size,
-            last_index: idx,
-            next_avail: &mut queue.next_avail,
-        })
+    // Convert a value to the byte order of the 16-bit fields of the rings.
+    fn cpu_to_ring(&self, val: u16) -> u16 {
+        if self.legacy_big_endian {
+            u16::to_be(val)
+        } else {
+            u16::to_le(val)
+        }
     }
 
-    /// Goes back one position in the available descriptor chain offered by the driver.
-    ///
-    /// Rust does not support bidirectional iterators. This is the only way to revert the effect
-    /// of an iterator increment on the queue.
-    ///
-    /// Note: this method assumes there's only one thread manipulating the queue, so it should only
-    /// be invoked in single-threaded context.
-    pub fn go_to_previous_position(&mut self) {
-        *self.next_avail -= Wrapping(1);
+    // Check that `count` elements can be added to the used ring, before writing any of them.
+    fn check_add_used(&self, count: usize) -> Result<(), Error> {
+        self.check_not_paused()?;
+        if self.resetting {
+            return Err(Error::QueueResetting);
+        }
+        // Only the chains between `next_used` and `next_avail` were popped and not completed.
+        let outstanding = Queue::distance(self.next_used, self.next_avail);
+        if self.check_used_count && (outstanding > self.size || count > usize::from(outstanding)) {
+            error!(
+                "attempted to add more elements to the used ring than chains were popped: {}",
+                count
+            );
+            return Err(Error::TooManyUsed);
+        }
+        Ok(())
     }
-}
 
-impl<M> Iterator for AvailIter<'_, M>
-where
-    M: Clone + Deref,
-    M::Target: GuestMemory,
-{
-    type Item = DescriptorChain<M>;
+    // Write a used element for `head_index` at position `index` of the used ring, without
+    // making it visible to the driver.
+    fn write_used_elem<M: GuestMemory>(
+        &self,
+        mem: &M,
+        index: Wrapping<u16>,
+        head_index: u16,
+        len: u32,
+    ) -> Result<(), Error> {
+        if head_index >= self.size {
+            error!(
+                "attempted to add out of bounds descriptor to used ring: {}",
+                head_index
+            );
+            return Err(Error::InvalidDescriptorIndex);
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if *self.next_avail == self.last_index {
-            return None;
+        let addr = self.used_elem_addr(index)?;
+        let elem = VirtqUsedElem::new(head_index.into(), len);
+        let elem = if self.legacy_big_endian {
+            elem.swap_bytes()
+        } else {
+            elem
+        };
+        mem.write_obj(elem, addr).map_err(Error::GuestMemory)
+    }
+
+    // Return the address of the used element at position `index` of the used ring.
+    fn used_elem_addr(&self, index: Wrapping<u16>) -> Result<GuestAddress, Error> {
+        let used_index = u64::from(index.0.checked_rem(self.size).ok_or(Error::InvalidSize)?);
+        // This can not overflow an u64 since it is working with relatively small numbers compared
+        // to u64::MAX.
+        let offset = VIRTQ_USED_RING_HEADER_SIZE + used_index * VIRTQ_USED_ELEMENT_SIZE;
+        self.used_ring
+            .checked_add(offset)
+            .ok_or(Error::AddressOverflow)
+    }
+
+    // Return `Error::QueuePaused` if writing to the rings is not allowed.
+    fn check_not_paused(&self) -> Result<(), Error> {
+        if self.paused {
+            return Err(Error::QueuePaused);
         }
+        Ok(())
+    }
 
-        // These two operations can not overflow an u64 since they're working with relatively small
-        // numbers compared to u64::MAX.
-        let elem_off =
-            u64::from(self.next_avail.0.checked_rem(self.queue_size)?) * VIRTQ_AVAIL_ELEMENT_SIZE;
-        let offset = VIRTQ_AVAIL_RING_HEADER_SIZE + elem_off;
+    // Publish `next_used` to the `idx` field of the used ring. The release ordering makes all
+    // the used elements written before visible to the driver.
+    fn publish_used_idx<M: GuestMemory>(&self, mem: &M) -> Result<(), Error> {
+        self.check_not_paused()?;
+        mem.store(
+            self.cpu_to_ring(self.next_used.0),
+            self.used_ring
+                .checked_add(2)
+                .ok_or(Error::AddressOverflow)?,
+            Ordering::Release,
+        )
+        .map_err(Error::GuestMemory)
+    }
 
-        let addr = self.avail_ring.checked_add(offset)?;
-        let head_index: u16 = self
-            .mem
-            .load(addr, Ordering::Acquire)
-            .map(u16::from_le)
-            .map_err(|_| error!("Failed to read from memory {:x}", addr.raw_value()))
-            .ok()?;
+    // Helper method that writes `val` to the `avail_event` field of the used ring, using
+    // the provided ordering.
+    //
+    // The field only exists when `VIRTIO_F_RING_EVENT_IDX` is negotiated, so nothing is written
+    // otherwise, as the driver may not have allocated memory for it.
+    fn set_avail_event<M: GuestMemory>(
+        &self,
+        mem: &M,
+        val: u16,
+        order: Ordering,
+    ) -> Result<(), Error> {
+        if !self.event_idx_enabled {
+            return Ok(());
+        }
+        self.check_not_paused()?;
 
-        *self.next_avail += Wrapping(1);
+        // This can not overflow an u64 since it is working with relatively small numbers compared
+        // to u64::MAX.
+        let avail_event_offset =
+            VIRTQ_USED_RING_HEADER_SIZE + VIRTQ_USED_ELEMENT_SIZE * u64::from(self.size);
+        let addr = self
+            .used_ring
+            .checked_add(avail_event_offset)
+            .ok_or(Error::AddressOverflow)?;
 
-        Some(DescriptorChain::new(
-            self.mem.clone(),
-            self.desc_table,
-            self.queue_size,
-            head_index,
-        ))
+        mem.store(self.cpu_to_ring(val), addr, order)
+            .map_err(Error::GuestMemory)
     }
-}
 
-#[cfg(any(test, feature = "test-utils"))]
-// It is convenient for tests to implement `PartialEq`, but it is not a
-// proper implementation as `GuestMemory` errors cannot implement `PartialEq`.
-impl PartialEq for Error {
-    fn eq(&self, other: &Self) -> bool {
-        format!("{}", &self) == format!("{other}")
+    // Set the value of the `flags` field of the used ring, applying the specified ordering.
+    fn set_used_flags<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        val: u16,
+        order: Ordering,
+    ) -> Result<(), Error> {
+        self.check_not_paused()?;
+        mem.store(self.cpu_to_ring(val), self.used_ring, order)
+            .map_err(Error::GuestMemory)
+    }
+
+    // Write the appropriate values to enable or disable notifications from the driver.
+    //
+    // Every access in this method uses `Relaxed` ordering because a fence is added by the caller
+    // when appropriate.
+    fn set_notification<M: GuestMemory>(&mut self, mem: &M, enable: bool) -> Result<(), Error> {
+        if enable {
+            if self.event_idx_enabled {
+                // We call `set_avail_event` using the `next_avail` value, instead of reading
+                // and using the current `avail_idx` to avoid missing notifications. More
+                // details in `enable_notification`.
+                self.set_avail_event(mem, self.next_avail.0, Ordering::Relaxed)
+            } else {
+                self.set_used_flags(mem, 0, Ordering::Relaxed)
+            }
+        } else if !self.event_idx_enabled {
+            self.set_used_flags(mem, VRING_USED_F_NO_NOTIFY as u16, Ordering::Relaxed)
+        } else {
+            // Notifications are effectively disabled by default after triggering once when
+            // `VIRTIO_F_EVENT_IDX` is negotiated, so we don't do anything in that case.
+            Ok(())
+        }
+    }
+
+    // Return the value present in the used_event field of the avail ring.
+    //
+    // If the VIRTIO_F_EVENT_IDX feature bit is not negotiated, the flags field in the available
+    // ring offers a crude mechanism for the driver to inform the device that it doesn’t want
+    // interrupts when buffers are used. Otherwise virtq_avail.used_event is a more performant
+    // alternative where the driver specifies how far the device can progress before interrupting.
+    //
+    // Neither of these interrupt suppression methods are reliable, as they are not synchronized
+    // with the device, but they serve as useful optimizations. So we only ensure access to the
+    // virtq_avail.used_event is atomic, but do not need to synchronize with other memory accesses.
+    fn used_event<M: GuestMemory>(&self, mem: &M, order: Ordering) -> Result<Wrapping<u16>, Error> {
+        // This can not overflow an u64 since it is working with relatively small numbers compared
+        // to u64::MAX.
+        let used_event_offset =
+            VIRTQ_AVAIL_RING_HEADER_SIZE + u64::from(self.size) * VIRTQ_AVAIL_ELEMENT_SIZE;
+        let used_event_addr = self
+            .avail_ring
+            .checked_add(used_event_offset)
+            .ok_or(Error::AddressOverflow)?;
+
+        mem.load(used_event_addr, order)
+            .map(|val| self.ring_to_cpu(val))
+            .map(Wrapping)
+            .map_err(Error::GuestMemory)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::defs::{DEFAULT_AVAIL_RING_ADDR, DEFAULT_DESC_TABLE_ADDR, DEFAULT_USED_RING_ADDR};
-    use crate::desc::{split::Descriptor as SplitDescriptor, RawDescriptor};
-    use crate::mock::MockSplitQueue;
-    use virtio_bindings::bindings::virtio_ring::{
-        VRING_DESC_F_NEXT, VRING_DESC_F_WRITE, VRING_USED_F_NO_NOTIFY,
-    };
+/// Add elements to the used rings of several queues, and make them visible to the driver
+/// together.
+///
+/// Each entry of `completions` holds a queue and the `(head_index, len)` pairs to add to its
+/// used ring. All the used elements are written first, then a single release fence is issued
+/// before the `idx` fields of all the used rings are updated. The `idx` fields are checked to
+/// be in guest memory along with the elements, so on error neither the queues nor the `idx`
+/// fields are updated, and the driver never observes any of the elements written so far.
+pub fn publish_all<M: GuestMemory>(
+    completions: &mut [(&mut Queue, &[(u16, u32)])],
+    mem: &M,
+) -> Result<(), Error> {
+    for (queue, used) in completions.iter() {
+        queue.check_not_paused()?;
+        if used.is_empty() {
+            continue;
+        }
+        if used.len() > usize::from(queue.size) {
+            return Err(Error::UsedBatchTooLong);
+        }
+        queue.check_add_used(used.len())?;
+        let mut next_used = queue.next_used;
+        for &(head_index, len) in used.iter() {
+            queue.write_used_elem(mem, next_used, head_index, len)?;
+            next_used += Wrapping(1);
+        }
+
+        // Storing the `idx` field is the only step left which could fail, do it for one queue
+        // only if it can be done for all of them.
+        let addr = queue
+            .used_ring
+            .checked_add(2)
+            .ok_or(Error::AddressOverflow)?;
+        if !mem.check_range(addr, size_of::<u16>()) {
+            return Err(Error::FindMemoryRegion);
+        }
+    }
+
+    // Make all the used elements visible before any of the `idx` fields.
+    fence(Ordering::Release);
+
+    for (queue, used) in completions.iter_mut() {
+        if used.is_empty() {
+            continue;
+        }
+        // The batches were checked to be no longer than the queue size.
+        let batch_len = used.len() as u16;
+        let next_used = queue.next_used + Wrapping(batch_len);
+        // The address was checked above.
+        mem.store(
+            queue.cpu_to_ring(next_used.0),
+            queue.used_ring.unchecked_add(2),
+            Ordering::Relaxed,
+        )
+        .map_err(Error::GuestMemory)?;
+
+        for &(head_index, _) in used.iter() {
+            queue.clear_completed(head_index);
+        }
+        queue.next_used = next_used;
+        queue.num_added = Wrapping(queue.num_added.0.saturating_add(batch_len).min(queue.size));
+        queue.progress = queue.progress.wrapping_add(used.len() as u64);
+        queue.stats.record_used(used.iter().map(|&(_, len)| len));
+    }
+
+    Ok(())
+}
+
+impl<'a> QueueGuard<'a> for Queue {
+    type G = &'a mut Self;
+}
+
+impl QueueT for Queue {
+    fn new(max_size: u16) -> Result<Self, Error> {
+        // We need to check that the max size is a power of 2 because we're setting this as the
+        // queue size, and the valid queue sizes are a power of 2 as per the specification.
+        if max_size == 0 || max_size > MAX_QUEUE_SIZE || (max_size & (max_size - 1)) != 0 {
+            return Err(Error::InvalidMaxSize);
+        }
+        Ok(Queue {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: GuestAddress(DEFAULT_DESC_TABLE_ADDR),
+            avail_ring: GuestAddress(DEFAULT_AVAIL_RING_ADDR),
+            used_ring: GuestAddress(DEFAULT_USED_RING_ADDR),
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            event_idx_enabled: false,
+            num_added: Wrapping(0),
+            track_chain_len: false,
+            max_chain_len_seen: 0,
+            strict_validation: false,
+            indirect_only: false,
+            progress: 0,
+            prefetch_descriptors: false,
+            check_used_count: false,
+            paused: false,
+            resetting: false,
+            in_flight: None,
+            submitted: BTreeMap::new(),
+            legacy_big_endian: false,
+            stats: QueueStats::default(),
+            validate_buffer_regions: false,
+        })
+    }
+
+    fn is_valid<M: GuestMemory>(&self, mem: &M) -> bool {
+        let queue_size = self.size as u64;
+        let desc_table = self.desc_table;
+        // The multiplication can not overflow an u64 since we are multiplying an u16 with a
+        // small number.
+        let desc_table_size = size_of::<RawDescriptor>() as u64 * queue_size;
+        let avail_ring = self.avail_ring;
+        let avail_ring_size = Queue::avail_ring_size_for(self.size, self.event_idx_enabled);
+        let used_ring = self.used_ring;
+        let used_ring_size = Queue::used_ring_size_for(self.size, self.event_idx_enabled);
+
+        if !self.ready {
+            error!("attempt to use virtio queue that is not marked ready");
+            false
+        } else if !Queue::in_single_region(mem, desc_table, desc_table_size) {
+            error!(
+                "virtio queue descriptor table goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                desc_table.raw_value(),
+                desc_table_size
+            );
+            false
+        } else if !Queue::in_single_region(mem, avail_ring, avail_ring_size) {
+            error!(
+                "virtio queue available ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                avail_ring.raw_value(),
+                avail_ring_size
+            );
+            false
+        } else if !Queue::in_single_region(mem, used_ring, used_ring_size) {
+            error!(
+                "virtio queue used ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                used_ring.raw_value(),
+                used_ring_size
+            );
+            false
+        } else if self.check_rings_overlap().is_err() {
+            error!(
+                "virtio queue descriptor table and rings overlap: desc table:0x{:08x} avail ring:0x{:08x} used ring:0x{:08x}",
+                desc_table.raw_value(),
+                avail_ring.raw_value(),
+                used_ring.raw_value()
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ready = false;
+        self.size = self.max_size;
+        self.desc_table = GuestAddress(DEFAULT_DESC_TABLE_ADDR);
+        self.avail_ring = GuestAddress(DEFAULT_AVAIL_RING_ADDR);
+        self.used_ring = GuestAddress(DEFAULT_USED_RING_ADDR);
+        self.next_avail = Wrapping(0);
+        self.next_used = Wrapping(0);
+        self.num_added = Wrapping(0);
+        self.event_idx_enabled = false;
+        self.max_chain_len_seen = 0;
+        self.submitted.clear();
+        self.resetting = false;
+    }
+
+    fn layout(&self) -> RingLayout {
+        RingLayout::Split
+    }
+
+    fn lock(&mut self) -> <Self as QueueGuard<'_>>::G {
+        self
+    }
+
+    fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn set_size(&mut self, size: u16) {
+        if self.try_set_size(size).is_err() {
+            error!("virtio queue with invalid size: {}", size);
+        }
+    }
+
+    fn ready(&self) -> bool {
+        self.ready
+    }
+
+    fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+    }
+
+    fn set_desc_table_address(&mut self, low: Option<u32>, high: Option<u32>) {
+        let low = low.unwrap_or(self.desc_table.0 as u32) as u64;
+        let high = high.unwrap_or((self.desc_table.0 >> 32) as u32) as u64;
+
+        let desc_table = GuestAddress((high << 32) | low);
+        if self.try_set_desc_table_address(desc_table).is_err() {
+            error!("virtio queue descriptor table breaks alignment constraints");
+        }
+    }
+
+    fn set_avail_ring_address(&mut self, low: Option<u32>, high: Option<u32>) {
+        let low = low.unwrap_or(self.avail_ring.0 as u32) as u64;
+        let high = high.unwrap_or((self.avail_ring.0 >> 32) as u32) as u64;
+
+        let avail_ring = GuestAddress((high << 32) | low);
+        if self.try_set_avail_ring_address(avail_ring).is_err() {
+            error!("virtio queue available ring breaks alignment constraints");
+        }
+    }
+
+    fn set_used_ring_address(&mut self, low: Option<u32>, high: Option<u32>) {
+        let low = low.unwrap_or(self.used_ring.0 as u32) as u64;
+        let high = high.unwrap_or((self.used_ring.0 >> 32) as u32) as u64;
+
+        let used_ring = GuestAddress((high << 32) | low);
+        if self.try_set_used_ring_address(used_ring).is_err() {
+            error!("virtio queue used ring breaks alignment constraints");
+        }
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx_enabled = enabled;
+    }
+
+    fn avail_idx<M>(&self, mem: M, order: Ordering) -> Result<Wrapping<u16>, Error>
+    where
+        M: Deref,
+        M::Target: GuestMemory,
+    {
+        let addr = self
+            .avail_ring
+            .checked_add(2)
+            .ok_or(Error::AddressOverflow)?;
+
+        mem.deref()
+            .load(addr, order)
+            .map(|val| self.ring_to_cpu(val))
+            .map(Wrapping)
+            .map_err(Error::GuestMemory)
+    }
+
+    fn used_idx<M>(&self, mem: M, order: Ordering) -> Result<Wrapping<u16>, Error>
+    where
+        M: Deref,
+        M::Target: GuestMemory,
+    {
+        let addr = self
+            .used_ring
+            .checked_add(2)
+            .ok_or(Error::AddressOverflow)?;
+
+        mem.deref()
+            .load(addr, order)
+            .map(|val| self.ring_to_cpu(val))
+            .map(Wrapping)
+            .map_err(Error::GuestMemory)
+    }
+
+    fn add_used<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        head_index: u16,
+        len: u32,
+    ) -> Result<(), Error> {
+        self.check_add_used(1)?;
+        self.write_used_elem(mem, self.next_used, head_index, len)?;
+
+        self.next_used += Wrapping(1);
+        // Cap the count at the queue size, the number of elements the used ring holds, instead
+        // of wrapping around, which would make `needs_notification` think nothing was added
+        // since the driver was last notified.
+        self.num_added = Wrapping(self.num_added.0.saturating_add(1).min(self.size));
+        self.progress = self.progress.wrapping_add(1);
+        self.stats.record_used([len]);
+        self.clear_completed(head_index);
+
+        self.publish_used_idx(mem)
+    }
+
+    fn add_used_batch<M, I>(&mut self, mem: &M, used: I) -> Result<(), Error>
+    where
+        M: GuestMemory,
+        I: IntoIterator<Item = (u16, u32)>,
+    {
+        // The whole batch is checked before writing any element. A batch longer than the queue
+        // size would overwrite elements of the same batch the driver didn't consume yet.
+        let used: Vec<_> = used.into_iter().take(usize::from(self.size) + 1).collect();
+        if used.len() > usize::from(self.size) {
+            error!(
+                "attempted to add more elements to the used ring than the queue size: {}",
+                self.size
+            );
+            return Err(Error::UsedBatchTooLong);
+        }
+        if used.is_empty() {
+            return Ok(());
+        }
+        self.check_add_used(used.len())?;
+
+        let mut next_used = self.next_used;
+        let mut used_bytes = 0u64;
+        // Only filled when tracking the chains in flight or their submission time.
+        let track_completed = self.in_flight.is_some() || !self.submitted.is_empty();
+        let mut completed = Vec::new();
+        for (head_index, len) in used {
+            // On error, `next_used` and the `idx` field of the used ring are left untouched, so
+            // the driver never observes the elements written so far.
+            self.write_used_elem(mem, next_used, head_index, len)?;
+            next_used += Wrapping(1);
+            used_bytes = used_bytes.wrapping_add(u64::from(len));
+            if track_completed {
+                completed.push(head_index);
+            }
+        }
+        for head_index in completed {
+            self.clear_completed(head_index);
+        }
+
+        let batch_len = next_used - self.next_used;
+        self.next_used = next_used;
+        self.num_added = Wrapping(self.num_added.0.saturating_add(batch_len.0).min(self.size));
+        self.progress = self.progress.wrapping_add(u64::from(batch_len.0));
+        self.stats.used_entries = self.stats.used_entries.wrapping_add(u64::from(batch_len.0));
+        self.stats.used_bytes = self.stats.used_bytes.wrapping_add(used_bytes);
+
+        self.publish_used_idx(mem)
+    }
+
+    fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        self.set_notification(mem, true)?;
+        // Ensures the following read is not reordered before any previous write operation.
+        fence(Ordering::SeqCst);
+
+        // We double check here to avoid the situation where the available ring has been updated
+        // just before we re-enabled notifications, and it's possible to miss one. We compare the
+        // current `avail_idx` value to `self.next_avail` because it's where we stopped processing
+        // entries. There are situations where we intentionally avoid processing everything in the
+        // available ring (which will cause this method to return `true`), but in that case we'll
+        // probably not re-enable notifications as we already know there are pending entries.
+        self.avail_idx(mem, Ordering::Relaxed)
+            .map(|idx| idx != self.next_avail)
+    }
+
+    fn disable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<(), Error> {
+        self.set_notification(mem, false)
+    }
+
+    fn needs_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        let used_idx = self.next_used;
+
+        // Complete all the writes in add_used() before reading the event.
+        fence(Ordering::SeqCst);
+
+        // When the `EVENT_IDX` feature is negotiated, the driver writes into `used_event`
+        // a value that's used by the device to determine whether a notification must
+        // be submitted after adding a descriptor chain to the used ring. According to the
+        // standard, the notification must be sent when `next_used == used_event + 1`, but
+        // various device model implementations rely on an inequality instead, most likely
+        // to also support use cases where a bunch of descriptor chains are added to the used
+        // ring first, and only afterwards the `needs_notification` logic is called. For example,
+        // the approach based on `num_added` below is taken from the Linux Kernel implementation
+        // (i.e. https://elixir.bootlin.com/linux/v5.15.35/source/drivers/virtio/virtio_ring.c#L661)
+
+        // The `old` variable below is used to determine the value of `next_used` from when
+        // `needs_notification` was called last (each `needs_notification` call resets `num_added`
+        // to zero, while each `add_used` called increments it by one). Then, the logic below
+        // uses wrapped arithmetic to see whether `used_event` can be found between `old` and
+        // `next_used` in the circular sequence space of the used ring.
+        if self.event_idx_enabled {
+            let used_event = self.used_event(mem, Ordering::Relaxed)?;
+            let old = used_idx - self.num_added;
+            self.num_added = Wrapping(0);
+
+            return Ok(used_idx - used_event - Wrapping(1) < used_idx - old);
+        }
+
+        // Without `EVENT_IDX`, the driver can only ask for no interrupts at all through the
+        // `flags` field of the avail ring. Same as for `used_event`, the access only needs to be
+        // atomic.
+        let flags = self.avail_flags(mem, Ordering::Relaxed)?;
+
+        Ok(flags & VRING_AVAIL_F_NO_INTERRUPT as u16 == 0)
+    }
+
+    fn next_avail(&self) -> u16 {
+        self.next_avail.0
+    }
+
+    fn set_next_avail(&mut self, next_avail: u16) {
+        self.next_avail = Wrapping(next_avail);
+    }
+
+    fn next_used(&self) -> u16 {
+        self.next_used.0
+    }
+
+    fn set_next_used(&mut self, next_used: u16) {
+        self.next_used = Wrapping(next_used);
+    }
+
+    fn desc_table(&self) -> u64 {
+        self.desc_table.0
+    }
+
+    fn avail_ring(&self) -> u64 {
+        self.avail_ring.0
+    }
+
+    fn used_ring(&self) -> u64 {
+        self.used_ring.0
+    }
+
+    fn event_idx_enabled(&self) -> bool {
+        self.event_idx_enabled
+    }
+
+    fn pop_descriptor_chain<M>(&mut self, mem: M) -> Option<DescriptorChain<M>>
+    where
+        M: Clone + Deref,
+        M::Target: GuestMemory,
+    {
+        // Default, iter-based impl. Will be subsequently improved.
+        match self.iter(mem) {
+            Ok(mut iter) => iter.next(),
+            Err(e) => {
+                error!("Iterator error {}", e);
+                None
+            }
+        }
+    }
+
+    fn peek_descriptor_chain<M>(&mut self, mem: M) -> Option<DescriptorChain<M>>
+    where
+        M: Clone + Deref,
+        M::Target: GuestMemory,
+    {
+        match self.iter(mem) {
+            Ok(iter) => iter.peek(),
+            Err(e) => {
+                error!("Iterator error {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl crate::QueueCommonT for Queue {
+    fn layout(&self) -> RingLayout {
+        RingLayout::Split
+    }
+
+    fn is_valid<M: GuestMemory>(&self, mem: &M) -> bool {
+        QueueT::is_valid(self, mem)
+    }
+
+    fn reset(&mut self) {
+        QueueT::reset(self)
+    }
+
+    fn max_size(&self) -> u16 {
+        QueueT::max_size(self)
+    }
+
+    fn size(&self) -> u16 {
+        QueueT::size(self)
+    }
+
+    fn try_set_size(&mut self, size: u16) -> Result<(), Error> {
+        Queue::try_set_size(self, size)
+    }
+
+    fn ready(&self) -> bool {
+        QueueT::ready(self)
+    }
+
+    fn set_ready(&mut self, ready: bool) {
+        QueueT::set_ready(self, ready)
+    }
+
+    fn set_event_idx(&mut self, enabled: bool) {
+        QueueT::set_event_idx(self, enabled)
+    }
+
+    fn event_idx_enabled(&self) -> bool {
+        QueueT::event_idx_enabled(self)
+    }
+
+    fn try_set_desc_table_address(&mut self, desc_table: GuestAddress) -> Result<(), Error> {
+        Queue::try_set_desc_table_address(self, desc_table)
+    }
+
+    fn add_used<M: GuestMemory>(&mut self, mem: &M, id: u16, len: u32) -> Result<(), Error> {
+        QueueT::add_used(self, mem, id, len)
+    }
+
+    fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        QueueT::enable_notification(self, mem)
+    }
+
+    fn disable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<(), Error> {
+        QueueT::disable_notification(self, mem)
+    }
+
+    fn needs_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        QueueT::needs_notification(self, mem)
+    }
+}
+
+impl QueueOwnedT for Queue {
+    fn iter<M>(&mut self, mem: M) -> Result<AvailIter<'_, M>, Error>
+    where
+        M: Deref,
+        M::Target: GuestMemory,
+    {
+        // We're checking here that a reset did not happen without re-initializing the queue.
+        // TODO: In the future we might want to also check that the other parameters in the
+        // queue are valid.
+        if !self.ready || self.avail_ring == GuestAddress(0) {
+            return Err(Error::QueueNotReady);
+        }
+        if self.resetting {
+            return Err(Error::QueueResetting);
+        }
+
+        self.avail_idx(mem.deref(), Ordering::Acquire)
+            .map(move |idx| AvailIter::new(mem, idx, self))?
+    }
+
+    fn go_to_previous_position(&mut self) {
+        self.next_avail -= Wrapping(1);
+        self.stats.chains_popped = self.stats.chains_popped.wrapping_sub(1);
+    }
+}
+
+/// Consuming iterator over all available descriptor chain heads in the queue.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+/// # use virtio_queue::mock::MockSplitQueue;
+/// use virtio_queue::{desc::{split::Descriptor as SplitDescriptor, RawDescriptor}, Queue, QueueOwnedT};
+/// use vm_memory::{GuestAddress, GuestMemoryMmap};
+///
+/// # fn populate_queue(m: &GuestMemoryMmap) -> Queue {
+/// #    let vq = MockSplitQueue::new(m, 16);
+/// #    let mut q: Queue = vq.create_queue().unwrap();
+/// #
+/// #    // The chains are (0, 1), (2, 3, 4) and (5, 6).
+/// #    let mut descs = Vec::new();
+/// #    for i in 0..7 {
+/// #        let flags = match i {
+/// #            1 | 6 => 0,
+/// #            2 | 5 => VRING_DESC_F_NEXT | VRING_DESC_F_WRITE,
+/// #            4 => VRING_DESC_F_WRITE,
+/// #            _ => VRING_DESC_F_NEXT,
+/// #        };
+/// #
+/// #        descs.push(RawDescriptor::from(SplitDescriptor::new((0x1000 * (i + 1)) as u64, 0x1000, flags as u16, i + 1)));
+/// #    }
+/// #
+/// #    vq.add_desc_chains(&descs, 0).unwrap();
+/// #    q
+/// # }
+/// let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+/// // Populate the queue with descriptor chains and update the available ring accordingly.
+/// let mut queue = populate_queue(m);
+/// let mut i = queue.iter(m).unwrap();
+///
+/// {
+///     let mut c = i.next().unwrap();
+///     let _first_head_index = c.head_index();
+///     // We should have two descriptors in the first chain.
+///     let _desc1 = c.next().unwrap();
+///     let _desc2 = c.next().unwrap();
+/// }
+///
+/// {
+///     let c = i.next().unwrap();
+///     let _second_head_index = c.head_index();
+///
+///     let mut iter = c.writable();
+///     // We should have two writable descriptors in the second chain.
+///     let _desc1 = iter.next().unwrap();
+///     let _desc2 = iter.next().unwrap();
+/// }
+///
+/// {
+///     let c = i.next().unwrap();
+///     let _third_head_index = c.head_index();
+///
+///     let mut iter = c.readable();
+///     // We should have one readable descriptor in the third chain.
+///     let _desc1 = iter.next().unwrap();
+/// }
+/// // Let's go back one position in the available ring.
+/// i.go_to_previous_position();
+/// // We should be able to access again the third descriptor chain.
+/// let c = i.next().unwrap();
+/// let _third_head_index = c.head_index();
+/// ```
+#[derive(Debug)]
+pub struct AvailIter<'b, M> {
+    mem: M,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    queue_size: u16,
+    last_index: Wrapping<u16>,
+    next_avail: &'b mut Wrapping<u16>,
+    chains_popped: &'b mut u64,
+    max_chain_len_seen: Option<&'b mut u16>,
+    strict_validation: bool,
+    indirect_only: bool,
+    prefetch_descriptors: bool,
+    legacy_big_endian: bool,
+    ring_regions: Option<[(GuestAddress, u64); 3]>,
+}
+
+impl<'b, M> AvailIter<'b, M>
+where
+    M: Deref,
+    M::Target: GuestMemory,
+{
+    /// Create a new instance of `AvailInter`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - the `GuestMemory` object that can be used to access the queue buffers.
+    /// * `idx` - the index of the available ring entry where the driver would put the next available descriptor chain.
+    /// * `queue` - the `Queue` object from which the needed data to create the `AvailIter` can be retrieved.
+    pub(crate) fn new(mem: M, idx: Wrapping<u16>, queue: &'b mut Queue) -> Result<Self, Error> {
+        // The number of descriptor chain heads to process should always
+        // be smaller or equal to the queue size, as the driver should
+        // never ask the VMM to process a available ring entry more than
+        // once. Checking and reporting such incorrect driver behavior
+        // can prevent potential hanging and Denial-of-Service from
+        // happening on the VMM side.
+        if Queue::distance(queue.next_avail, idx) > queue.size {
+            return Err(Error::InvalidAvailRingIndex);
+        }
+
+        Ok(AvailIter {
+            mem,
+            desc_table: queue.desc_table,
+            avail_ring: queue.avail_ring,
+            queue_size: queue.size,
+            last_index: idx,
+            next_avail: &mut queue.next_avail,
+            chains_popped: &mut queue.stats.chains_popped,
+            max_chain_len_seen: if queue.track_chain_len {
+                Some(&mut queue.max_chain_len_seen)
+            } else {
+                None
+            },
+            strict_validation: queue.strict_validation,
+            indirect_only: queue.indirect_only,
+            prefetch_descriptors: queue.prefetch_descriptors,
+            legacy_big_endian: queue.legacy_big_endian,
+            ring_regions: if queue.validate_buffer_regions {
+                Some([
+                    (
+                        queue.desc_table,
+                        // The multiplication can not overflow an u64 since we are multiplying
+                        // an u16 with a small number.
+                        size_of::<RawDescriptor>() as u64 * u64::from(queue.size),
+                    ),
+                    (
+                        queue.avail_ring,
+                        Queue::avail_ring_size_for(queue.size, queue.event_idx_enabled),
+                    ),
+                    (
+                        queue.used_ring,
+                        Queue::used_ring_size_for(queue.size, queue.event_idx_enabled),
+                    ),
+                ])
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Goes back one position in the available descriptor chain offered by the driver.
+    ///
+    /// Rust does not support bidirectional iterators. This is the only way to revert the effect
+    /// of an iterator increment on the queue.
+    ///
+    /// Note: this method assumes there's only one thread manipulating the queue, so it should only
+    /// be invoked in single-threaded context.
+    pub fn go_to_previous_position(&mut self) {
+        *self.next_avail -= Wrapping(1);
+        *self.chains_popped = self.chains_popped.wrapping_sub(1);
+    }
+}
+
+impl<M> AvailIter<'_, M>
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    // Return the next descriptor chain without consuming it, nor updating any counter.
+    fn peek(&self) -> Option<DescriptorChain<M>> {
+        if *self.next_avail == self.last_index {
+            return None;
+        }
+
+        // These two operations can not overflow an u64 since they're working with relatively small
+        // numbers compared to u64::MAX.
+        let elem_off =
+            u64::from(self.next_avail.0.checked_rem(self.queue_size)?) * VIRTQ_AVAIL_ELEMENT_SIZE;
+        let offset = VIRTQ_AVAIL_RING_HEADER_SIZE + elem_off;
+
+        let addr = self.avail_ring.checked_add(offset)?;
+        let head_index: u16 = self
+            .mem
+            .load(addr, Ordering::Acquire)
+            .map(|val: u16| {
+                if self.legacy_big_endian {
+                    u16::from_be(val)
+                } else {
+                    u16::from_le(val)
+                }
+            })
+            .map_err(|_| error!("Failed to read from memory {:x}", addr.raw_value()))
+            .ok()?;
+
+        Some(
+            DescriptorChain::new(
+                self.mem.clone(),
+                self.desc_table,
+                self.queue_size,
+                head_index,
+            )
+            .with_strict_validation(self.strict_validation)
+            .with_indirect_only(self.indirect_only)
+            .with_prefetch(self.prefetch_descriptors)
+            .with_legacy_endian(self.legacy_big_endian)
+            .with_ring_regions(self.ring_regions),
+        )
+    }
+}
+
+impl<M> Iterator for AvailIter<'_, M>
+where
+    M: Clone + Deref,
+    M::Target: GuestMemory,
+{
+    type Item = DescriptorChain<M>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chain = self.peek()?;
+
+        *self.next_avail += Wrapping(1);
+        *self.chains_popped = self.chains_popped.wrapping_add(1);
+
+        if let Some(max_len) = self.max_chain_len_seen.as_deref_mut() {
+            let len = u16::try_from(chain.clone().count()).unwrap_or(u16::MAX);
+            *max_len = (*max_len).max(len);
+        }
+
+        Some(chain)
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+// It is convenient for tests to implement `PartialEq`, but it is not a
+// proper implementation as `GuestMemory` errors cannot implement `PartialEq`.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        format!("{}", &self) == format!("{other}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defs::{DEFAULT_AVAIL_RING_ADDR, DEFAULT_DESC_TABLE_ADDR, DEFAULT_USED_RING_ADDR};
+    use crate::desc::{split::Descriptor as SplitDescriptor, RawDescriptor};
+    use crate::mock::{DescriptorTable, MockSplitQueue};
+    use crate::{Reader, Writer};
+    use std::sync::Arc;
+    use virtio_bindings::bindings::virtio_ring::{
+        VRING_DESC_F_INDIRECT, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE, VRING_USED_F_NO_NOTIFY,
+    };
+
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap, VolatileMemory};
+
+    #[test]
+    fn test_queue_is_valid() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // q is currently valid
+        assert!(q.is_valid(m));
+
+        // shouldn't be valid when not marked as ready
+        q.set_ready(false);
+        assert!(!q.ready());
+        assert!(!q.is_valid(m));
+        q.set_ready(true);
+
+        // shouldn't be allowed to set a size > max_size
+        q.set_size(q.max_size() << 1);
+        assert_eq!(q.size, q.max_size());
+
+        // or set the size to 0
+        q.set_size(0);
+        assert_eq!(q.size, q.max_size());
+
+        // or set a size which is not a power of 2
+        q.set_size(11);
+        assert_eq!(q.size, q.max_size());
+
+        // but should be allowed to set a size if 0 < size <= max_size and size is a power of two
+        q.set_size(4);
+        assert_eq!(q.size, 4);
+        q.size = q.max_size();
+
+        // shouldn't be allowed to set an address that breaks the alignment constraint
+        q.set_desc_table_address(Some(0xf), None);
+        assert_eq!(q.desc_table.0, vq.desc_table_addr().0);
+        // should be allowed to set an aligned out of bounds address
+        q.set_desc_table_address(Some(0xffff_fff0), None);
+        assert_eq!(q.desc_table.0, 0xffff_fff0);
+        // but shouldn't be valid
+        assert!(!q.is_valid(m));
+        // but should be allowed to set a valid description table address
+        q.set_desc_table_address(Some(0x2000), None);
+        assert_eq!(q.desc_table.0, 0x2000);
+        assert!(q.is_valid(m));
+        let addr = vq.desc_table_addr().0;
+        q.set_desc_table_address(Some(addr as u32), Some((addr >> 32) as u32));
+
+        // shouldn't be allowed to set an address that breaks the alignment constraint
+        q.set_avail_ring_address(Some(0x1), None);
+        assert_eq!(q.avail_ring.0, vq.avail_addr().0);
+        // should be allowed to set an aligned out of bounds address
+        q.set_avail_ring_address(Some(0xffff_fffe), None);
+        assert_eq!(q.avail_ring.0, 0xffff_fffe);
+        // but shouldn't be valid
+        assert!(!q.is_valid(m));
+        // but should be allowed to set a valid available ring address
+        q.set_avail_ring_address(Some(0x2002), None);
+        assert_eq!(q.avail_ring.0, 0x2002);
+        assert!(q.is_valid(m));
+        let addr = vq.avail_addr().0;
+        q.set_avail_ring_address(Some(addr as u32), Some((addr >> 32) as u32));
+
+        // shouldn't be allowed to set an address that breaks the alignment constraint
+        q.set_used_ring_address(Some(0x3), None);
+        assert_eq!(q.used_ring.0, vq.used_addr().0);
+        // should be allowed to set an aligned out of bounds address
+        q.set_used_ring_address(Some(0xffff_fffc), None);
+        assert_eq!(q.used_ring.0, 0xffff_fffc);
+        // but shouldn't be valid
+        assert!(!q.is_valid(m));
+        // but should be allowed to set a valid used ring address
+        q.set_used_ring_address(Some(0x2004), None);
+        assert_eq!(q.used_ring.0, 0x2004);
+        let addr = vq.used_addr().0;
+        q.set_used_ring_address(Some(addr as u32), Some((addr >> 32) as u32));
+        assert!(q.is_valid(m));
+    }
+
+    #[test]
+    fn test_check_rings_overlap() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        assert!(q.check_rings_overlap().is_ok());
+        assert!(q.is_valid(m));
+
+        // The available ring starts before the end of the descriptor table, which also makes
+        // the queue invalid.
+        q.try_set_avail_ring_address(vq.desc_table_addr().unchecked_add(0xf0))
+            .unwrap();
+        assert_eq!(
+            q.check_rings_overlap().unwrap_err(),
+            Error::OverlappingRings
+        );
+        assert!(!q.is_valid(m));
+        // Right after it is fine.
+        q.try_set_avail_ring_address(vq.desc_table_addr().unchecked_add(0x100))
+            .unwrap();
+        assert!(q.check_rings_overlap().is_ok());
+        assert!(q.is_valid(m));
+
+        // The used ring starts right after the `used_event` field of the available ring, which
+        // only exists with `EVENT_IDX`.
+        q.try_set_used_ring_address(vq.desc_table_addr().unchecked_add(0x100 + 36))
+            .unwrap();
+        assert!(q.check_rings_overlap().is_ok());
+        q.set_event_idx(true);
+        assert_eq!(
+            q.check_rings_overlap().unwrap_err(),
+            Error::OverlappingRings
+        );
+        assert!(!q.is_valid(m));
+    }
+
+    #[test]
+    fn test_is_valid_desc_table_size() {
+        // Split and packed descriptors have the same 16 bytes layout.
+        assert_eq!(size_of::<RawDescriptor>(), 16);
+
+        // The descriptor table takes exactly `16 * size` bytes, so it can end right at the end of
+        // guest memory.
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = Queue::new(16).unwrap();
+        q.set_ready(true);
+        q.try_set_avail_ring_address(GuestAddress(0x1000)).unwrap();
+        q.try_set_used_ring_address(GuestAddress(0x2000)).unwrap();
+        q.try_set_desc_table_address(GuestAddress(0x10000 - 16 * 16))
+            .unwrap();
+        assert!(q.is_valid(m));
+        q.try_set_desc_table_address(GuestAddress(0x10000 - 16 * 16 + 16))
+            .unwrap();
+        assert!(!q.is_valid(m));
+    }
+
+    #[test]
+    fn test_shared_memory_region() {
+        // A vhost-user backend maps a region of guest memory shared by the frontend, which
+        // doesn't start at 0, and the rings are located at offsets inside of it.
+        let base = GuestAddress(0x1_0000_0000);
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(base, 0x10000)]).unwrap();
+        let vq = MockSplitQueue::create(mem, base.unchecked_add(0x8000), 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        assert!(q.is_valid(mem));
+
+        let buf = base.unchecked_add(0x1000);
+        vq.add_desc_chains(
+            &[RawDescriptor::from(SplitDescriptor::new(
+                buf.0,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            ))],
+            0,
+        )
+        .unwrap();
+        assert_eq!(q.avail_idx(mem, Ordering::Acquire).unwrap(), Wrapping(1));
+        let mut chain = q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(chain.next().unwrap().addr(), buf);
+        q.add_used(mem, chain.head_index(), 0x100).unwrap();
+
+        // The used element is written at the offset of the used ring in the region.
+        let elem: VirtqUsedElem = mem
+            .read_obj(vq.used_addr().unchecked_add(VIRTQ_USED_RING_HEADER_SIZE))
+            .unwrap();
+        assert_eq!((elem.id(), elem.len()), (0, 0x100));
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(1));
+
+        // Offsets that aren't relative to the start of the region point outside of it.
+        q.try_set_used_ring_address(GuestAddress(vq.used_addr().0 - base.0))
+            .unwrap();
+        assert!(!q.is_valid(mem));
+        // So does a ring running past its end.
+        q.try_set_used_ring_address(base.unchecked_add(0x10000 - 0x40))
+            .unwrap();
+        assert!(!q.is_valid(mem));
+    }
+
+    #[test]
+    fn test_is_valid_rings_in_single_region() {
+        // The two regions are adjacent, so any address up to the end of the second one is in
+        // guest memory.
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+        ])
+        .unwrap();
+        let mut q = Queue::new(16).unwrap();
+        q.set_ready(true);
+        q.try_set_desc_table_address(GuestAddress(0x1400)).unwrap();
+        q.try_set_avail_ring_address(GuestAddress(0x1300)).unwrap();
+        q.try_set_used_ring_address(GuestAddress(0x1100)).unwrap();
+        assert!(q.is_valid(m));
+
+        // Each ring in turn straddles the two regions.
+        q.try_set_desc_table_address(GuestAddress(0xf80)).unwrap();
+        assert!(!q.is_valid(m));
+        q.try_set_desc_table_address(GuestAddress(0x1400)).unwrap();
+        q.try_set_avail_ring_address(GuestAddress(0xff0)).unwrap();
+        assert!(!q.is_valid(m));
+        q.try_set_avail_ring_address(GuestAddress(0x1300)).unwrap();
+        q.try_set_used_ring_address(GuestAddress(0xfc0)).unwrap();
+        assert!(!q.is_valid(m));
+
+        // The same ring is valid in a single region covering both ranges.
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x2000)]).unwrap();
+        assert!(q.is_valid(m));
+    }
+
+    #[test]
+    fn test_distance() {
+        let d = |from: u16, to: u16| Queue::distance(Wrapping(from), Wrapping(to));
+
+        assert_eq!(d(0, 0), 0);
+        assert_eq!(d(3, 10), 7);
+        assert_eq!(d(u16::MAX - 1, u16::MAX), 1);
+        assert_eq!(d(u16::MAX, u16::MAX), 0);
+        // Across the boundary, the counters wrap around.
+        assert_eq!(d(u16::MAX, 0), 1);
+        assert_eq!(d(u16::MAX - 2, 5), 8);
+        assert_eq!(d(0, u16::MAX), u16::MAX);
+        // Moving backwards is a distance of almost a whole turn.
+        assert_eq!(d(1, 0), u16::MAX);
+        assert_eq!(d(5, u16::MAX - 2), u16::MAX - 7);
+    }
+
+    #[test]
+    fn test_ring_sizes() {
+        assert_eq!(Queue::avail_ring_size_for(16, false), 4 + 2 * 16);
+        assert_eq!(Queue::avail_ring_size_for(16, true), 4 + 2 * 16 + 2);
+        assert_eq!(Queue::used_ring_size_for(16, false), 4 + 8 * 16);
+        assert_eq!(Queue::used_ring_size_for(16, true), 4 + 8 * 16 + 2);
+        assert_eq!(
+            Queue::used_ring_size_for(MAX_QUEUE_SIZE, true),
+            6 + 8 * 32768
+        );
+
+        // The trailing event fields are only required with `EVENT_IDX`.
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = Queue::new(16).unwrap();
+        q.set_ready(true);
+        q.try_set_used_ring_address(GuestAddress(0x1000)).unwrap();
+        q.try_set_avail_ring_address(GuestAddress(0x10000 - 36))
+            .unwrap();
+        assert!(q.is_valid(m));
+        q.set_event_idx(true);
+        assert!(!q.is_valid(m));
+        q.try_set_avail_ring_address(GuestAddress(0x10000 - 38))
+            .unwrap();
+        assert!(q.is_valid(m));
+    }
+
+    #[test]
+    fn test_add_used() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(0));
+        assert_eq!(u16::from_le(vq.used().idx().load()), 0);
+
+        // index too large
+        assert!(q.add_used(mem, 16, 0x1000).is_err());
+        assert_eq!(u16::from_le(vq.used().idx().load()), 0);
+
+        // should be ok
+        q.add_used(mem, 1, 0x1000).unwrap();
+        assert_eq!(q.next_used, Wrapping(1));
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(1));
+        assert_eq!(u16::from_le(vq.used().idx().load()), 1);
+
+        let x = vq.used().ring().ref_at(0).unwrap().load();
+        assert_eq!(x.id(), 1);
+        assert_eq!(x.len(), 0x1000);
+    }
+
+    #[test]
+    fn test_add_used_batch() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        q.set_next_used(2);
+        q.add_used_batch(mem, [(3, 0x100), (5, 0x200), (7, 0x300)])
+            .unwrap();
+        assert_eq!(q.next_used(), 5);
+        assert_eq!(q.num_added, Wrapping(3));
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(5));
+
+        for (pos, (id, len)) in [(3, 0x100), (5, 0x200), (7, 0x300)].iter().enumerate() {
+            let elem = vq.used().ring().ref_at(pos + 2).unwrap().load();
+            assert_eq!(elem.id(), *id);
+            assert_eq!(elem.len(), *len);
+        }
+
+        // An empty batch doesn't change anything.
+        q.add_used_batch(mem, []).unwrap();
+        assert_eq!(q.next_used(), 5);
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(5));
+
+        // An out of bounds index in the batch fails the whole batch without publishing it.
+        assert_eq!(
+            q.add_used_batch(mem, [(1, 0x100), (16, 0x100)])
+                .unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
+        assert_eq!(q.next_used(), 5);
+        assert_eq!(q.num_added, Wrapping(3));
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(5));
+
+        // A full lap of the used ring is fine, but one more element would overwrite the first
+        // element of the batch.
+        q.add_used_batch(mem, (0..16).map(|i| (i, 0x100))).unwrap();
+        assert_eq!(q.next_used(), 21);
+        assert_eq!(
+            q.add_used_batch(mem, (0..17).map(|i| (i % 16, 0x100)))
+                .unwrap_err(),
+            Error::UsedBatchTooLong
+        );
+        assert_eq!(q.next_used(), 21);
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(21));
+    }
+
+    #[test]
+    fn test_reset_queue() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        q.set_size(8);
+        // The address set by `MockSplitQueue` for the descriptor table is DEFAULT_DESC_TABLE_ADDR,
+        // so let's change it for testing the reset.
+        q.set_desc_table_address(Some(0x5000), None);
+        // Same for `event_idx_enabled`, `next_avail` `next_used` and `signalled_used`.
+        q.set_event_idx(true);
+        q.set_next_avail(2);
+        q.set_next_used(4);
+        q.num_added = Wrapping(15);
+        assert_eq!(q.size, 8);
+        // `create_queue` also marks the queue as ready.
+        assert!(q.ready);
+        assert_ne!(q.desc_table, GuestAddress(DEFAULT_DESC_TABLE_ADDR));
+        assert_ne!(q.avail_ring, GuestAddress(DEFAULT_AVAIL_RING_ADDR));
+        assert_ne!(q.used_ring, GuestAddress(DEFAULT_USED_RING_ADDR));
+        assert_ne!(q.next_avail, Wrapping(0));
+        assert_ne!(q.next_used, Wrapping(0));
+        assert_ne!(q.num_added, Wrapping(0));
+        assert!(q.event_idx_enabled);
+
+        q.reset();
+        assert_eq!(q.size, 16);
+        assert!(!q.ready);
+        assert_eq!(q.desc_table, GuestAddress(DEFAULT_DESC_TABLE_ADDR));
+        assert_eq!(q.avail_ring, GuestAddress(DEFAULT_AVAIL_RING_ADDR));
+        assert_eq!(q.used_ring, GuestAddress(DEFAULT_USED_RING_ADDR));
+        assert_eq!(q.next_avail, Wrapping(0));
+        assert_eq!(q.next_used, Wrapping(0));
+        assert_eq!(q.num_added, Wrapping(0));
+        assert!(!q.event_idx_enabled);
+    }
+
+    #[test]
+    fn test_needs_notification() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let qsize = 16;
+        let vq = MockSplitQueue::new(mem, qsize);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let avail_addr = vq.avail_addr();
+
+        // It should always return true when EVENT_IDX isn't enabled.
+        for i in 0..qsize {
+            q.next_used = Wrapping(i);
+            assert!(q.needs_notification(mem).unwrap());
+        }
+
+        mem.write_obj::<u16>(
+            u16::to_le(4),
+            avail_addr.unchecked_add(4 + qsize as u64 * 2),
+        )
+        .unwrap();
+        q.set_event_idx(true);
+
+        // Incrementing up to this value causes an `u16` to wrap back to 0.
+        let wrap = u32::from(u16::MAX) + 1;
+
+        for i in 0..wrap + 12 {
+            q.next_used = Wrapping(i as u16);
+            // Let's test wrapping around the maximum index value as well.
+            // `num_added` needs to be at least `1` to represent the fact that new descriptor
+            // chains have be added to the used ring since the last time `needs_notification`
+            // returned.
+            q.num_added = Wrapping(1);
+            let expected = i == 5 || i == (5 + wrap);
+            assert_eq!((q.needs_notification(mem).unwrap(), i), (expected, i));
+        }
+
+        mem.write_obj::<u16>(
+            u16::to_le(8),
+            avail_addr.unchecked_add(4 + qsize as u64 * 2),
+        )
+        .unwrap();
+
+        // Returns `false` because the current `used_event` value is behind both `next_used` and
+        // the value of `next_used` at the time when `needs_notification` last returned (which is
+        // computed based on `num_added` as described in the comments for `needs_notification`.
+        assert!(!q.needs_notification(mem).unwrap());
+
+        mem.write_obj::<u16>(
+            u16::to_le(15),
+            avail_addr.unchecked_add(4 + qsize as u64 * 2),
+        )
+        .unwrap();
+
+        q.num_added = Wrapping(1);
+        assert!(!q.needs_notification(mem).unwrap());
+
+        q.next_used = Wrapping(15);
+        q.num_added = Wrapping(1);
+        assert!(!q.needs_notification(mem).unwrap());
+
+        q.next_used = Wrapping(16);
+        q.num_added = Wrapping(1);
+        assert!(q.needs_notification(mem).unwrap());
+
+        // Calling `needs_notification` again immediately returns `false`.
+        assert!(!q.needs_notification(mem).unwrap());
+
+        mem.write_obj::<u16>(
+            u16::to_le(u16::MAX - 3),
+            avail_addr.unchecked_add(4 + qsize as u64 * 2),
+        )
+        .unwrap();
+        q.next_used = Wrapping(u16::MAX - 2);
+        q.num_added = Wrapping(1);
+        // Returns `true` because, when looking at circular sequence of indices of the used ring,
+        // the value we wrote in the `used_event` appears between the "old" value of `next_used`
+        // (i.e. `next_used` - `num_added`) and the current `next_used`, thus suggesting that we
+        // need to notify the driver.
+        assert!(q.needs_notification(mem).unwrap());
+    }
+
+    #[test]
+    fn test_enable_disable_notification() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+
+        let mut q: Queue = vq.create_queue().unwrap();
+        let used_addr = vq.used_addr();
+
+        assert!(!q.event_idx_enabled);
+
+        q.enable_notification(mem).unwrap();
+        let v = mem.read_obj::<u16>(used_addr).map(u16::from_le).unwrap();
+        assert_eq!(v, 0);
+
+        q.disable_notification(mem).unwrap();
+        let v = mem.read_obj::<u16>(used_addr).map(u16::from_le).unwrap();
+        assert_eq!(v, VRING_USED_F_NO_NOTIFY as u16);
+
+        q.enable_notification(mem).unwrap();
+        let v = mem.read_obj::<u16>(used_addr).map(u16::from_le).unwrap();
+        assert_eq!(v, 0);
+
+        q.set_event_idx(true);
+        let avail_addr = vq.avail_addr();
+        mem.write_obj::<u16>(u16::to_le(2), avail_addr.unchecked_add(2))
+            .unwrap();
+
+        assert!(q.enable_notification(mem).unwrap());
+        q.next_avail = Wrapping(2);
+        assert!(!q.enable_notification(mem).unwrap());
+
+        mem.write_obj::<u16>(u16::to_le(8), avail_addr.unchecked_add(2))
+            .unwrap();
+
+        assert!(q.enable_notification(mem).unwrap());
+        q.next_avail = Wrapping(8);
+        assert!(!q.enable_notification(mem).unwrap());
+    }
+
+    #[test]
+    fn test_needs_notification_num_added_cap() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let qsize = 16;
+        let vq = MockSplitQueue::new(mem, qsize);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let used_event_addr = vq.avail_addr().unchecked_add(4 + qsize as u64 * 2);
+        q.set_event_idx(true);
+
+        // The device completes a full lap of `u16` positions without checking, and the driver
+        // wants to be notified about the last element written.
+        for i in 0..=u16::MAX {
+            q.add_used(mem, i % qsize, 0x100).unwrap();
+        }
+        assert_eq!(q.next_used(), 0);
+        assert_eq!(q.num_added, Wrapping(qsize));
+        mem.write_obj::<u16>(u16::to_le(u16::MAX), used_event_addr)
+            .unwrap();
+        assert!(q.needs_notification(mem).unwrap());
+
+        // More elements than the ring holds are added in several batches.
+        q.add_used_batch(mem, (0..qsize).map(|i| (i, 0x100)))
+            .unwrap();
+        q.add_used_batch(mem, [(0, 0x100), (1, 0x100)]).unwrap();
+        assert_eq!(q.num_added, Wrapping(qsize));
+        mem.write_obj::<u16>(u16::to_le(q.next_used() - qsize), used_event_addr)
+            .unwrap();
+        assert!(q.needs_notification(mem).unwrap());
+
+        // The same goes for the batches published to several queues at once.
+        let mut q2: Queue = vq.create_queue().unwrap();
+        q2.set_event_idx(true);
+        let used: Vec<_> = (0..qsize).map(|i| (i, 0x100)).collect();
+        publish_all(&mut [(&mut q2, &used[..])], mem).unwrap();
+        publish_all(&mut [(&mut q2, &[(0, 0x100)][..])], mem).unwrap();
+        assert_eq!(q2.num_added, Wrapping(qsize));
+    }
+
+    #[test]
+    fn test_needs_notification_no_interrupt() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let avail_addr = vq.avail_addr();
+
+        assert!(!q.event_idx_enabled);
+        assert!(q.needs_notification(mem).unwrap());
+
+        mem.write_obj::<u16>(u16::to_le(VRING_AVAIL_F_NO_INTERRUPT as u16), avail_addr)
+            .unwrap();
+        assert!(!q.needs_notification(mem).unwrap());
+
+        mem.write_obj::<u16>(0, avail_addr).unwrap();
+        assert!(q.needs_notification(mem).unwrap());
+
+        // The flag is ignored when `EVENT_IDX` is negotiated.
+        mem.write_obj::<u16>(u16::to_le(VRING_AVAIL_F_NO_INTERRUPT as u16), avail_addr)
+            .unwrap();
+        q.set_event_idx(true);
+        q.add_used(mem, 0, 0x100).unwrap();
+        assert!(q.needs_notification(mem).unwrap());
+    }
+
+    #[test]
+    fn test_add_used_and_check_notify() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let used_event_addr = vq.avail_addr().unchecked_add(4 + 2 * 16);
+
+        for event_idx in [false, true] {
+            let mut q: Queue = vq.create_queue().unwrap();
+            let mut expected_q: Queue = vq.create_queue().unwrap();
+            q.set_event_idx(event_idx);
+            expected_q.set_event_idx(event_idx);
+
+            for i in 0..8 {
+                // Ask for a notification after every other element with `EVENT_IDX`, and flip
+                // `VRING_AVAIL_F_NO_INTERRUPT` otherwise.
+                mem.write_obj::<u16>(u16::to_le(i & !1), used_event_addr)
+                    .unwrap();
+                mem.write_obj::<u16>(
+                    u16::to_le(i & VRING_AVAIL_F_NO_INTERRUPT as u16),
+                    vq.avail_addr(),
+                )
+                .unwrap();
+
+                expected_q.add_used(mem, i, 0x100).unwrap();
+                let expected = expected_q.needs_notification(mem).unwrap();
+                // Both queues share the same rings, and write the same used elements.
+                assert_eq!(
+                    q.add_used_and_check_notify(mem, i, 0x100).unwrap(),
+                    expected
+                );
+            }
+            assert_eq!(q.next_used(), 8);
+            assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(8));
+        }
+
+        let mut q: Queue = vq.create_queue().unwrap();
+        assert!(q.add_used_and_check_notify(mem, 16, 0x100).is_err());
+        assert_eq!(q.next_used(), 0);
+    }
+
+    #[test]
+    fn test_avail_event_requires_event_idx() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let avail_event_addr = vq
+            .used_addr()
+            .unchecked_add(VIRTQ_USED_RING_HEADER_SIZE + VIRTQ_USED_ELEMENT_SIZE * 16);
+        mem.write_obj::<u16>(u16::to_le(0xdead), avail_event_addr)
+            .unwrap();
+        q.set_next_avail(3);
+
+        assert!(!q.event_idx_enabled());
+        q.enable_notification(mem).unwrap();
+        q.disable_notification(mem).unwrap();
+        q.set_avail_event(mem, 5, Ordering::Relaxed).unwrap();
+        assert_eq!(
+            u16::from_le(mem.read_obj::<u16>(avail_event_addr).unwrap()),
+            0xdead
+        );
+
+        q.set_event_idx(true);
+        q.enable_notification(mem).unwrap();
+        assert_eq!(
+            u16::from_le(mem.read_obj::<u16>(avail_event_addr).unwrap()),
+            3
+        );
+    }
+
+    #[test]
+    fn test_set_avail_event_at() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let avail_event_addr = vq
+            .used_addr()
+            .unchecked_add(VIRTQ_USED_RING_HEADER_SIZE + VIRTQ_USED_ELEMENT_SIZE * 16);
+        let read_bytes = || {
+            let mut bytes = [0u8; 2];
+            mem.read_slice(&mut bytes, avail_event_addr).unwrap();
+            bytes
+        };
+        q.set_next_avail(3);
+
+        q.set_avail_event_at(mem, 0x0108).unwrap();
+        assert_eq!(read_bytes(), [0, 0]);
+
+        q.set_event_idx(true);
+        q.enable_notification(mem).unwrap();
+        assert_eq!(read_bytes(), [3, 0]);
+        // Ask to be notified further ahead.
+        q.set_avail_event_at(mem, 0x0108).unwrap();
+        assert_eq!(read_bytes(), [0x08, 0x01]);
+
+        q.set_paused(true);
+        assert_eq!(
+            q.set_avail_event_at(mem, 0).unwrap_err(),
+            Error::QueuePaused
+        );
+    }
+
+    #[test]
+    fn test_set_driver_notifications() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+
+        let mut q: Queue = vq.create_queue().unwrap();
+        let used_addr = vq.used_addr();
+
+        q.set_driver_notifications(mem, false).unwrap();
+        let v = mem.read_obj::<u16>(used_addr).map(u16::from_le).unwrap();
+        assert_eq!(v, VRING_USED_F_NO_NOTIFY as u16);
+
+        q.set_driver_notifications(mem, true).unwrap();
+        let v = mem.read_obj::<u16>(used_addr).map(u16::from_le).unwrap();
+        assert_eq!(v, 0);
+
+        // The used ring index and the device to driver notification logic are not affected.
+        q.set_driver_notifications(mem, false).unwrap();
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(0));
+        assert!(q.needs_notification(mem).unwrap());
+    }
+
+    #[test]
+    fn test_consume_chains_with_notif() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // q is currently valid.
+        assert!(q.is_valid(mem));
+
+        // The chains are (0, 1), (2, 3, 4), (5, 6), (7, 8), (9, 10, 11, 12).
+        let mut descs = Vec::new();
+        for i in 0..13 {
+            let flags = match i {
+                1 | 4 | 6 | 8 | 12 => 0,
+                _ => VRING_DESC_F_NEXT,
+            };
+
+            descs.push(RawDescriptor::from(SplitDescriptor::new(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                flags as u16,
+                i + 1,
+            )));
+        }
+
+        vq.add_desc_chains(&descs, 0).unwrap();
+        // Update the index of the chain that can be consumed to not be the last one.
+        // This enables us to consume chains in multiple iterations as opposed to consuming
+        // all the driver written chains at once.
+        vq.avail().idx().store(u16::to_le(2));
+        // No descriptor chains are consumed at this point.
+        assert_eq!(q.next_avail(), 0);
+
+        let mut i = 0;
+
+        loop {
+            i += 1;
+            q.disable_notification(mem).unwrap();
+
+            while let Some(chain) = q.iter(mem).unwrap().next() {
+                // Process the descriptor chain, and then add entries to the
+                // used ring.
+                let head_index = chain.head_index();
+                let mut desc_len = 0;
+                chain.for_each(|d| {
+                    if d.flags() as u32 & VRING_DESC_F_WRITE == VRING_DESC_F_WRITE {
+                        desc_len += d.len();
+                    }
+                });
+                q.add_used(mem, head_index, desc_len).unwrap();
+            }
+            if !q.enable_notification(mem).unwrap() {
+                break;
+            }
+        }
+        // The chains should be consumed in a single loop iteration because there's nothing updating
+        // the `idx` field of the available ring in the meantime.
+        assert_eq!(i, 1);
+        // The next chain that can be consumed should have index 2.
+        assert_eq!(q.next_avail(), 2);
+        assert_eq!(q.next_used(), 2);
+        // Let the device know it can consume one more chain.
+        vq.avail().idx().store(u16::to_le(3));
+        i = 0;
+
+        loop {
+            i += 1;
+            q.disable_notification(mem).unwrap();
+
+            while let Some(chain) = q.iter(mem).unwrap().next() {
+                // Process the descriptor chain, and then add entries to the
+                // used ring.
+                let head_index = chain.head_index();
+                let mut desc_len = 0;
+                chain.for_each(|d| {
+                    if d.flags() as u32 & VRING_DESC_F_WRITE == VRING_DESC_F_WRITE {
+                        desc_len += d.len();
+                    }
+                });
+                q.add_used(mem, head_index, desc_len).unwrap();
+            }
+
+            // For the simplicity of the test we are updating here the `idx` value of the available
+            // ring. Ideally this should be done on a separate thread.
+            // Because of this update, the loop should be iterated again to consume the new
+            // available descriptor chains.
+            vq.avail().idx().store(u16::to_le(4));
+            if !q.enable_notification(mem).unwrap() {
+                break;
+            }
+        }
+        assert_eq!(i, 2);
+        // The next chain that can be consumed should have index 4.
+        assert_eq!(q.next_avail(), 4);
+        assert_eq!(q.next_used(), 4);
+
+        // Set an `idx` that is bigger than the number of entries added in the ring.
+        // This is an allowed scenario, but the indexes of the chain will have unexpected values.
+        vq.avail().idx().store(u16::to_le(7));
+        loop {
+            q.disable_notification(mem).unwrap();
+
+            while let Some(chain) = q.iter(mem).unwrap().next() {
+                // Process the descriptor chain, and then add entries to the
+                // used ring.
+                let head_index = chain.head_index();
+                let mut desc_len = 0;
+                chain.for_each(|d| {
+                    if d.flags() as u32 & VRING_DESC_F_WRITE == VRING_DESC_F_WRITE {
+                        desc_len += d.len();
+                    }
+                });
+                q.add_used(mem, head_index, desc_len).unwrap();
+            }
+            if !q.enable_notification(mem).unwrap() {
+                break;
+            }
+        }
+        assert_eq!(q.next_avail(), 7);
+        assert_eq!(q.next_used(), 7);
+    }
+
+    #[test]
+    fn test_invalid_avail_idx() {
+        // This is a negative test for the following MUST from the spec: `A driver MUST NOT
+        // decrement the available idx on a virtqueue (ie. there is no way to “unexpose” buffers).`.
+        // We validate that for this misconfiguration, the device does not panic.
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // q is currently valid.
+        assert!(q.is_valid(mem));
+
+        // The chains are (0, 1), (2, 3, 4), (5, 6).
+        let mut descs = Vec::new();
+        for i in 0..7 {
+            let flags = match i {
+                1 | 4 | 6 => 0,
+                _ => VRING_DESC_F_NEXT,
+            };
+
+            descs.push(RawDescriptor::from(SplitDescriptor::new(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                flags as u16,
+                i + 1,
+            )));
+        }
+
+        vq.add_desc_chains(&descs, 0).unwrap();
+        // Let the device know it can consume chains with the index < 2.
+        vq.avail().idx().store(u16::to_le(3));
+        // No descriptor chains are consumed at this point.
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.next_used(), 0);
+
+        loop {
+            q.disable_notification(mem).unwrap();
+
+            while let Some(chain) = q.iter(mem).unwrap().next() {
+                // Process the descriptor chain, and then add entries to the
+                // used ring.
+                let head_index = chain.head_index();
+                let mut desc_len = 0;
+                chain.for_each(|d| {
+                    if d.flags() as u32 & VRING_DESC_F_WRITE == VRING_DESC_F_WRITE {
+                        desc_len += d.len();
+                    }
+                });
+                q.add_used(mem, head_index, desc_len).unwrap();
+            }
+            if !q.enable_notification(mem).unwrap() {
+                break;
+            }
+        }
+        // The next chain that can be consumed should have index 3.
+        assert_eq!(q.next_avail(), 3);
+        assert_eq!(q.avail_idx(mem, Ordering::Acquire).unwrap(), Wrapping(3));
+        assert_eq!(q.next_used(), 3);
+        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(3));
+        assert!(q.lock().ready());
+
+        // Decrement `idx` which should be forbidden. This makes `idx` look like it's way ahead
+        // of `next_avail`, which is reported instead of producing bogus chains.
+        vq.avail().idx().store(u16::to_le(1));
+        assert_eq!(q.iter(mem).unwrap_err(), Error::InvalidAvailRingIndex);
+        assert!(q.pop_descriptor_chain(mem).is_none());
+        assert_eq!(q.next_avail(), 3);
+
+        // Same for a driver moving `idx` far past `next_used`.
+        vq.avail().idx().store(u16::to_le(3 + 17));
+        assert_eq!(q.iter(mem).unwrap_err(), Error::InvalidAvailRingIndex);
+        assert!(q.peek_descriptor_chain(mem).is_none());
+        assert_eq!(q.next_avail(), 3);
+    }
+
+    #[test]
+    fn test_drain_available() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        assert!(q.drain_available(mem).unwrap().is_empty());
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(
+                0x1000,
+                0x100,
+                VRING_DESC_F_NEXT as u16,
+                1,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x4000, 0x100, 0, 0)),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        let chains = q.drain_available(mem).unwrap();
+        let heads: Vec<u16> = chains.iter().map(|chain| chain.head_index()).collect();
+        assert_eq!(heads, [0, 2, 3]);
+        assert_eq!(chains[0].clone().count(), 2);
+        assert_eq!(q.next_avail(), 3);
+        assert!(q.drain_available(mem).unwrap().is_empty());
+        assert_eq!(q.next_avail(), 3);
+
+        q.set_ready(false);
+        assert_eq!(q.drain_available(mem).unwrap_err(), Error::QueueNotReady);
+    }
+
+    #[test]
+    fn test_pop_single_descriptor() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        assert!(q.pop_single_descriptor(mem).unwrap().is_none());
+
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x100,
+                VRING_DESC_F_NEXT as u16,
+                2,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x4000, 0x100, 0, 0)),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        let desc = q.pop_single_descriptor(mem).unwrap().unwrap();
+        assert_eq!(desc.addr(), GuestAddress(0x1000));
+        assert_eq!(desc.len(), 0x100);
+
+        // The second chain has two descriptors, and stays in the available ring.
+        assert_eq!(
+            q.pop_single_descriptor(mem).unwrap_err(),
+            Error::MultiDescriptorChain
+        );
+        assert_eq!(q.next_avail(), 1);
+        assert_eq!(q.stats().chains_popped, 1);
+        let chain = q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(chain.head_index(), 1);
+        assert_eq!(chain.count(), 2);
+
+        let desc = q.pop_single_descriptor(mem).unwrap().unwrap();
+        assert_eq!(desc.addr(), GuestAddress(0x4000));
+        assert!(q.pop_single_descriptor(mem).unwrap().is_none());
+
+        q.set_ready(false);
+        assert_eq!(
+            q.pop_single_descriptor(mem).unwrap_err(),
+            Error::QueueNotReady
+        );
+    }
+
+    #[test]
+    fn test_iterator_and_avail_idx() {
+        // This test ensures constructing a descriptor chain iterator succeeds
+        // with valid available ring indexes while produces an error with invalid
+        // indexes.
+        let queue_size = 2;
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, queue_size);
+
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // q is currently valid.
+        assert!(q.is_valid(mem));
+
+        // Create descriptors to fill up the queue
+        let mut descs = Vec::new();
+        for i in 0..queue_size {
+            descs.push(RawDescriptor::from(SplitDescriptor::new(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                0_u16,
+                i + 1,
+            )));
+        }
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        // Set the 'next_available' index to 'u16:MAX' to test the wrapping scenarios
+        q.set_next_avail(u16::MAX);
+
+        // When the number of chains exposed by the driver is equal to or less than the queue
+        // size, the available ring index is valid and constructs an iterator successfully.
+        let avail_idx = Wrapping(q.next_avail()) + Wrapping(queue_size);
+        vq.avail().idx().store(u16::to_le(avail_idx.0));
+        assert!(q.iter(mem).is_ok());
+        let avail_idx = Wrapping(q.next_avail()) + Wrapping(queue_size - 1);
+        vq.avail().idx().store(u16::to_le(avail_idx.0));
+        assert!(q.iter(mem).is_ok());
+
+        // When the number of chains exposed by the driver is larger than the queue size, the
+        // available ring index is invalid and produces an error from constructing an iterator.
+        let avail_idx = Wrapping(q.next_avail()) + Wrapping(queue_size + 1);
+        vq.avail().idx().store(u16::to_le(avail_idx.0));
+        assert!(q.iter(mem).is_err());
+    }
+
+    #[test]
+    fn test_descriptor_and_iterator() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // q is currently valid
+        assert!(q.is_valid(m));
+
+        // the chains are (0, 1), (2, 3, 4) and (5, 6)
+        let mut descs = Vec::new();
+        for j in 0..7 {
+            let flags = match j {
+                1 | 6 => 0,
+                2 | 5 => VRING_DESC_F_NEXT | VRING_DESC_F_WRITE,
+                4 => VRING_DESC_F_WRITE,
+                _ => VRING_DESC_F_NEXT,
+            };
+
+            descs.push(RawDescriptor::from(SplitDescriptor::new(
+                (0x1000 * (j + 1)) as u64,
+                0x1000,
+                flags as u16,
+                j + 1,
+            )));
+        }
+
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        let mut i = q.iter(m).unwrap();
+
+        {
+            let c = i.next().unwrap();
+            assert_eq!(c.head_index(), 0);
+
+            let mut iter = c;
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_none());
+            assert!(iter.next().is_none());
+        }
+
+        {
+            let c = i.next().unwrap();
+            assert_eq!(c.head_index(), 2);
+
+            let mut iter = c.writable();
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_none());
+            assert!(iter.next().is_none());
+        }
+
+        {
+            let c = i.next().unwrap();
+            assert_eq!(c.head_index(), 5);
+
+            let mut iter = c.readable();
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_none());
+            assert!(iter.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_iterator() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        q.size = q.max_size;
+        q.desc_table = vq.desc_table_addr();
+        q.avail_ring = vq.avail_addr();
+        q.used_ring = vq.used_addr();
+        assert!(q.is_valid(m));
+
+        {
+            // an invalid queue should return an iterator with no next
+            q.ready = false;
+            assert!(q.iter(m).is_err());
+        }
+
+        q.ready = true;
+
+        // now let's create two simple descriptor chains
+        // the chains are (0, 1) and (2, 3, 4)
+        {
+            let mut descs = Vec::new();
+            for j in 0..5u16 {
+                let flags = match j {
+                    1 | 4 => 0,
+                    _ => VRING_DESC_F_NEXT,
+                };
+
+                descs.push(RawDescriptor::from(SplitDescriptor::new(
+                    (0x1000 * (j + 1)) as u64,
+                    0x1000,
+                    flags as u16,
+                    j + 1,
+                )));
+            }
+            vq.add_desc_chains(&descs, 0).unwrap();
+
+            let mut i = q.iter(m).unwrap();
+
+            {
+                let mut c = i.next().unwrap();
+                assert_eq!(c.head_index(), 0);
+
+                c.next().unwrap();
+                assert!(c.next().is_some());
+                assert!(c.next().is_none());
+                assert_eq!(c.head_index(), 0);
+            }
+
+            {
+                let mut c = i.next().unwrap();
+                assert_eq!(c.head_index(), 2);
+
+                c.next().unwrap();
+                c.next().unwrap();
+                c.next().unwrap();
+                assert!(c.next().is_none());
+                assert_eq!(c.head_index(), 2);
+            }
+
+            // also test go_to_previous_position() works as expected
+            {
+                assert!(i.next().is_none());
+                i.go_to_previous_position();
+                let mut c = q.iter(m).unwrap().next().unwrap();
+                c.next().unwrap();
+                c.next().unwrap();
+                c.next().unwrap();
+                assert!(c.next().is_none());
+            }
+        }
+
+        // Test that iterating some broken descriptor chain does not exceed
+        // 2^32 bytes in total (VIRTIO spec version 1.2, 2.7.5.2:
+        // Drivers MUST NOT add a descriptor chain longer than 2^32 bytes in
+        // total)
+        {
+            let descs = vec![
+                RawDescriptor::from(SplitDescriptor::new(
+                    0x1000,
+                    0xffff_ffff,
+                    VRING_DESC_F_NEXT as u16,
+                    1,
+                )),
+                RawDescriptor::from(SplitDescriptor::new(0x1000, 0x1234_5678, 0, 2)),
+            ];
+            vq.add_desc_chains(&descs, 0).unwrap();
+            let mut yielded_bytes_by_iteration = 0_u32;
+            for d in q.iter(m).unwrap().next().unwrap() {
+                yielded_bytes_by_iteration = yielded_bytes_by_iteration
+                    .checked_add(d.len())
+                    .expect("iterator should not yield more than 2^32 bytes");
+            }
+        }
+
+        // Same as above, but test with a descriptor which is self-referential
+        {
+            let descs = vec![RawDescriptor::from(SplitDescriptor::new(
+                0x1000,
+                0xffff_ffff,
+                VRING_DESC_F_NEXT as u16,
+                0,
+            ))];
+            vq.add_desc_chains(&descs, 0).unwrap();
+            let mut yielded_bytes_by_iteration = 0_u32;
+            for d in q.iter(m).unwrap().next().unwrap() {
+                yielded_bytes_by_iteration = yielded_bytes_by_iteration
+                    .checked_add(d.len())
+                    .expect("iterator should not yield more than 2^32 bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn test_go_back() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // Four single-descriptor chains, with head indexes 0 to 3.
+        let descs: Vec<RawDescriptor> = (0..4)
+            .map(|i| RawDescriptor::from(SplitDescriptor::new(0x1000 * (i + 1), 0x100, 0, 0)))
+            .collect();
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        for i in 0..3 {
+            assert_eq!(q.pop_descriptor_chain(mem).unwrap().head_index(), i);
+        }
+        q.go_back(2).unwrap();
+        assert_eq!(q.next_avail(), 1);
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().head_index(), 1);
+
+        // Chains added to the used ring can't be given back.
+        q.add_used(mem, 0, 0x100).unwrap();
+        assert_eq!(q.go_back(3).unwrap_err(), Error::RewindTooFar);
+        assert_eq!(q.next_avail(), 2);
+        q.go_back(1).unwrap();
+        assert_eq!(q.next_avail(), 1);
+
+        // Going back wraps around like the index itself does.
+        q.set_next_used(u16::MAX - 1);
+        q.go_back(3).unwrap();
+        assert_eq!(q.next_avail(), u16::MAX - 1);
+        q.go_back(0).unwrap();
+        assert_eq!(q.next_avail(), u16::MAX - 1);
+        assert_eq!(q.go_back(1).unwrap_err(), Error::RewindTooFar);
+    }
+
+    #[test]
+    fn test_drain_sorted() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // Each single-descriptor chain starts with a priority byte, 0 being the most urgent.
+        let priorities = [2u8, 0, 1, 0, 2];
+        let descs: Vec<RawDescriptor> = (0..priorities.len())
+            .map(|i| {
+                let addr = 0x1000 * (i as u64 + 1);
+                mem.write_obj(priorities[i], GuestAddress(addr)).unwrap();
+                RawDescriptor::from(SplitDescriptor::new(addr, 0x100, 0, 0))
+            })
+            .collect();
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        let mut heads = Vec::new();
+        q.drain_sorted(
+            mem,
+            |chain| {
+                let desc = chain.clone().next().unwrap();
+                u32::from(mem.read_obj::<u8>(desc.addr()).unwrap())
+            },
+            |chain| heads.push(chain.head_index()),
+        )
+        .unwrap();
+        assert_eq!(heads, [1, 3, 2, 0, 4]);
+        assert_eq!(q.next_avail(), 5);
+
+        // Nothing left to drain.
+        q.drain_sorted(mem, |_| 0, |_| panic!("no chain expected"))
+            .unwrap();
+
+        q.set_ready(false);
+        assert_eq!(
+            q.drain_sorted(mem, |_| 0, |_| {}).unwrap_err(),
+            Error::QueueNotReady
+        );
+    }
+
+    #[test]
+    fn test_regression_iterator_division() {
+        // This is a regression test that tests that the iterator does not try to divide
+        // by 0 when the queue size is 0
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 1);
+        // This input was generated by the fuzzer, both for the QueueS and the Descriptor
+        let descriptors: Vec<RawDescriptor> = vec![RawDescriptor::from(SplitDescriptor::new(
+            14178673876262995140,
+            3301229764,
+            50372,
+            50372,
+        ))];
+        vq.build_desc_chain(&descriptors).unwrap();
+
+        let mut q = Queue {
+            max_size: 38,
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            event_idx_enabled: false,
+            num_added: Wrapping(0),
+            size: 0,
+            ready: false,
+            desc_table: GuestAddress(12837708984796196),
+            avail_ring: GuestAddress(0),
+            used_ring: GuestAddress(9943947977301164032),
+            ..Default::default()
+        };
+
+        assert!(q.pop_descriptor_chain(m).is_none());
+    }
+
+    #[test]
+    fn test_descriptor_table_slice() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let q: Queue = vq.create_queue().unwrap();
+
+        // The chain is (0, 1, 2).
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x200, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x300, 0, 0)),
+        ];
+        vq.build_desc_chain(&descs).unwrap();
+
+        let table = q.descriptor_table_slice(m).unwrap();
+        let table = table.get_array_ref::<SplitDescriptor>(0, 16).unwrap();
+        assert_eq!(table.len(), 16);
+
+        let mut index = 0;
+        let mut addrs = Vec::new();
+        loop {
+            let desc = table.load(index);
+            addrs.push(desc.addr().0);
+            if !desc.has_next() {
+                break;
+            }
+            index = usize::from(desc.next());
+        }
+        assert_eq!(addrs, vec![0x1000, 0x2000, 0x3000]);
+
+        // A table straddling two regions can't be viewed as a single slice.
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+        ])
+        .unwrap();
+        let mut q = Queue::new(16).unwrap();
+        q.try_set_desc_table_address(GuestAddress(0xf80)).unwrap();
+        assert!(q.descriptor_table_slice(m).is_none());
+
+        // Neither can a table that isn't backed by guest memory.
+        q.try_set_desc_table_address(GuestAddress(0x10_0000))
+            .unwrap();
+        assert!(q.descriptor_table_slice(m).is_none());
+    }
+
+    #[test]
+    fn test_legacy_endian() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let avail_idx_addr = vq.avail_addr().unchecked_add(2);
+        let used_idx_addr = vq.used_addr().unchecked_add(2);
+        let read_bytes = |addr| {
+            let mut bytes = [0u8; 2];
+            mem.read_slice(&mut bytes, addr).unwrap();
+            bytes
+        };
+
+        // Legacy queues only use another byte order than little-endian on big-endian hosts.
+        q.set_legacy_endian(true);
+        assert_eq!(q.legacy_big_endian, cfg!(target_endian = "big"));
+        q.set_legacy_endian(false);
+        assert!(!q.legacy_big_endian);
+
+        for big_endian in [false, true] {
+            // Set the field directly, so the big-endian accesses are also checked on
+            // little-endian hosts.
+            q.legacy_big_endian = big_endian;
+            let to_ring = |val: u16| {
+                if big_endian {
+                    val.to_be_bytes()
+                } else {
+                    val.to_le_bytes()
+                }
+            };
+
+            mem.write_slice(&to_ring(0x0306), avail_idx_addr).unwrap();
+            mem.write_slice(&to_ring(VRING_AVAIL_F_NO_INTERRUPT as u16), vq.avail_addr())
+                .unwrap();
+            assert_eq!(
+                q.avail_idx(mem, Ordering::Acquire).unwrap(),
+                Wrapping(0x0306)
+            );
+            assert_eq!(
+                q.avail_flags(mem, Ordering::Acquire).unwrap(),
+                VRING_AVAIL_F_NO_INTERRUPT as u16
+            );
+            assert!(!q.needs_notification(mem).unwrap());
+
+            q.set_next_used(0x0304);
+            q.add_used(mem, 1, 0x100).unwrap();
+            assert_eq!(read_bytes(used_idx_addr), to_ring(0x0305));
+            assert_eq!(
+                q.used_idx(mem, Ordering::Acquire).unwrap(),
+                Wrapping(0x0305)
+            );
+
+            q.set_driver_notifications(mem, false).unwrap();
+            assert_eq!(
+                read_bytes(vq.used_addr()),
+                to_ring(VRING_USED_F_NO_NOTIFY as u16)
+            );
+
+            // The byte order is kept by the copies of the queue.
+            let copy = q.clone_with_memory_positions(mem).unwrap();
+            assert_eq!(copy.legacy_big_endian, big_endian);
+            assert_eq!(copy.next_avail(), 0x0306);
+            assert_eq!(copy.next_used(), 0x0305);
+        }
+    }
+
+    #[test]
+    fn test_legacy_endian_entries() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        // Return the `size` low bytes of `val`, in the byte order of the rings.
+        let ring_bytes = |val: u64, size: usize, big_endian: bool| {
+            let mut bytes = val.to_le_bytes()[..size].to_vec();
+            if big_endian {
+                bytes.reverse();
+            }
+            bytes
+        };
+
+        for big_endian in [false, true] {
+            let mut q: Queue = vq.create_queue().unwrap();
+            // Set the field directly, so the big-endian accesses are also checked on
+            // little-endian hosts.
+            q.legacy_big_endian = big_endian;
+            let write = |val: u64, size: usize, addr: GuestAddress| {
+                mem.write_slice(&ring_bytes(val, size, big_endian), addr)
+                    .unwrap()
+            };
+
+            // A chain made of descriptors 2 and 5. The byte-swapped `next` field of the head
+            // would be out of the bounds of the table.
+            for (index, addr, len, flags, next) in [
+                (2, 0x4000, 0x0102, VRING_DESC_F_NEXT, 5),
+                (5, 0x5000, 0x0304, VRING_DESC_F_WRITE, 0),
+            ] {
+                let desc_addr = vq.desc_table_addr().unchecked_add(index * 16);
+                write(addr, 8, desc_addr);
+                write(len, 4, desc_addr.unchecked_add(8));
+                write(u64::from(flags), 2, desc_addr.unchecked_add(12));
+                write(next, 2, desc_addr.unchecked_add(14));
+            }
+            write(2, 2, vq.avail_addr().unchecked_add(4));
+            write(1, 2, vq.avail_addr().unchecked_add(2));
+
+            assert_eq!(q.export_avail_ring(mem).unwrap()[0], 2);
+            assert!(q.dump(mem).ends_with("available heads: [2]"));
+            let desc = q.load_descriptor(mem, 5).unwrap();
+            assert_eq!((desc.addr(), desc.len()), (GuestAddress(0x5000), 0x0304));
+
+            let mut chain = q.pop_descriptor_chain(mem).unwrap();
+            assert_eq!(chain.head_index(), 2);
+            let descs: Vec<_> = std::iter::from_fn(|| chain.try_next().unwrap())
+                .map(|desc| (desc.addr().raw_value(), desc.len(), desc.is_write_only()))
+                .collect();
+            assert_eq!(descs, [(0x4000, 0x0102, false), (0x5000, 0x0304, true)]);
+
+            q.add_used(mem, 2, 0x0506).unwrap();
+            let mut elem = [0u8; 8];
+            mem.read_slice(&mut elem, vq.used_addr().unchecked_add(4))
+                .unwrap();
+            assert_eq!(elem[..4], ring_bytes(2, 4, big_endian));
+            assert_eq!(elem[4..], ring_bytes(0x0506, 4, big_endian));
+            let elem = q.used_elem(mem, 0).unwrap();
+            assert_eq!((elem.id(), elem.len()), (2, 0x0506));
+        }
+    }
+
+    #[test]
+    fn test_ring_idx_byte_order() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        let read_bytes = |addr: GuestAddress| {
+            let mut bytes = [0u8; 2];
+            mem.read_slice(&mut bytes, addr.unchecked_add(2)).unwrap();
+            bytes
+        };
+
+        // The rings are little-endian whatever the byte order of the host.
+        q.store_avail_idx(mem, 0x0102, Ordering::Release).unwrap();
+        assert_eq!(read_bytes(vq.avail_addr()), [0x02, 0x01]);
+        assert_eq!(
+            q.avail_idx(mem, Ordering::Acquire).unwrap(),
+            Wrapping(0x0102)
+        );
+
+        mem.write_slice(&[0x04, 0x03], vq.used_addr().unchecked_add(2))
+            .unwrap();
+        assert_eq!(
+            q.used_idx(mem, Ordering::Acquire).unwrap(),
+            Wrapping(0x0304)
+        );
+        q.set_next_used(0x0506);
+        q.add_used(mem, 0, 0x100).unwrap();
+        assert_eq!(read_bytes(vq.used_addr()), [0x07, 0x05]);
+
+        q.set_paused(true);
+        assert_eq!(
+            q.store_avail_idx(mem, 0, Ordering::Release).unwrap_err(),
+            Error::QueuePaused
+        );
+    }
+
+    #[test]
+    fn test_memory_handles() {
+        let mem =
+            Arc::new(GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        let mut vq = MockSplitQueue::new(&*mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        mem.write_obj::<u16>(
+            u16::to_le(VRING_AVAIL_F_NO_INTERRUPT as u16),
+            vq.avail_addr(),
+        )
+        .unwrap();
+        vq.add_chain(1).unwrap();
+        vq.used().idx().store(u16::to_le(3));
+
+        // The read-only accessors take references as before, as well as the same handles as
+        // `iter`.
+        let m: &GuestMemoryMmap = &mem;
+        assert_eq!(q.avail_idx(m, Ordering::Acquire).unwrap(), Wrapping(1));
+        assert_eq!(q.used_idx(m, Ordering::Acquire).unwrap(), Wrapping(3));
+        assert_eq!(
+            q.avail_flags(m, Ordering::Acquire).unwrap(),
+            VRING_AVAIL_F_NO_INTERRUPT as u16
+        );
+
+        assert_eq!(
+            q.avail_idx(mem.clone(), Ordering::Acquire).unwrap(),
+            Wrapping(1)
+        );
+        assert_eq!(
+            q.used_idx(mem.clone(), Ordering::Acquire).unwrap(),
+            Wrapping(3)
+        );
+        assert_eq!(
+            q.avail_flags(mem.clone(), Ordering::Acquire).unwrap(),
+            VRING_AVAIL_F_NO_INTERRUPT as u16
+        );
+        assert_eq!(q.iter(mem.clone()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_avail_flags() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let q: Queue = vq.create_queue().unwrap();
+
+        assert_eq!(q.avail_flags(mem, Ordering::Acquire).unwrap(), 0);
+        mem.write_obj::<u16>(
+            u16::to_le(VRING_AVAIL_F_NO_INTERRUPT as u16),
+            vq.avail_addr(),
+        )
+        .unwrap();
+        assert_eq!(
+            q.avail_flags(mem, Ordering::Acquire).unwrap(),
+            VRING_AVAIL_F_NO_INTERRUPT as u16
+        );
+        mem.write_obj::<u16>(u16::to_le(0x8001), vq.avail_addr())
+            .unwrap();
+        assert_eq!(q.avail_flags(mem, Ordering::Relaxed).unwrap(), 0x8001);
+
+        let mut q = Queue::new(16).unwrap();
+        q.set_avail_ring_address(Some(0xffff_0000), Some(0));
+        assert!(matches!(
+            q.avail_flags(mem, Ordering::Relaxed).unwrap_err(),
+            Error::GuestMemory(_)
+        ));
+    }
+
+    #[test]
+    fn test_available_count() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // The ring is empty.
+        assert_eq!(q.available_count(mem).unwrap(), 0);
+
+        vq.avail().idx().store(u16::to_le(3));
+        assert_eq!(q.available_count(mem).unwrap(), 3);
+        q.set_next_avail(2);
+        assert_eq!(q.available_count(mem).unwrap(), 1);
+
+        // The `idx` field of the ring wrapped around.
+        q.set_next_avail(u16::MAX - 1);
+        vq.avail().idx().store(u16::to_le(2));
+        assert_eq!(q.available_count(mem).unwrap(), 4);
+        q.set_next_avail(2);
+        assert_eq!(q.available_count(mem).unwrap(), 0);
+
+        q.set_next_avail(0);
+        vq.avail().idx().store(u16::to_le(17));
+        assert_eq!(
+            q.available_count(mem).unwrap_err(),
+            Error::InvalidAvailRingIndex
+        );
+    }
+
+    #[test]
+    fn test_avail_snapshot() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let q: Queue = vq.create_queue().unwrap();
+
+        vq.avail().idx().store(u16::to_le(5));
+        mem.write_obj::<u16>(
+            u16::to_le(VRING_AVAIL_F_NO_INTERRUPT as u16),
+            vq.avail_addr(),
+        )
+        .unwrap();
+
+        let snapshot = q.avail_snapshot(mem).unwrap();
+        assert_eq!(
+            snapshot,
+            AvailSnapshot {
+                idx: 5,
+                flags: VRING_AVAIL_F_NO_INTERRUPT as u16
+            }
+        );
+        assert_eq!(
+            Wrapping(snapshot.idx),
+            q.avail_idx(mem, Ordering::Acquire).unwrap()
+        );
+        assert_eq!(
+            snapshot.flags,
+            u16::from_le(mem.read_obj::<u16>(vq.avail_addr()).unwrap())
+        );
+
+        // A 2-byte aligned ring is read with two separate loads.
+        let mut q = Queue::new(16).unwrap();
+        q.set_avail_ring_address(Some(0x2002), Some(0));
+        mem.write_obj::<u16>(u16::to_le(1), GuestAddress(0x2002))
+            .unwrap();
+        mem.write_obj::<u16>(u16::to_le(7), GuestAddress(0x2004))
+            .unwrap();
+        assert_eq!(
+            q.avail_snapshot(mem).unwrap(),
+            AvailSnapshot { idx: 7, flags: 1 }
+        );
+
+        // The ring header is past the end of guest memory.
+        q.set_avail_ring_address(Some(0x10000), Some(0));
+        assert!(matches!(
+            q.avail_snapshot(mem).unwrap_err(),
+            Error::GuestMemory(_)
+        ));
+        q.set_avail_ring_address(Some(0xfffe), Some(0));
+        assert!(matches!(
+            q.avail_snapshot(mem).unwrap_err(),
+            Error::GuestMemory(_)
+        ));
+    }
+
+    #[test]
+    fn test_try_set_next_positions() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        vq.avail().idx().store(u16::to_le(5));
+        vq.used().idx().store(u16::to_le(2));
+
+        q.try_set_next_avail(mem, 5).unwrap();
+        assert_eq!(q.next_avail(), 5);
+        q.try_set_next_avail(mem, 3).unwrap();
+        assert_eq!(q.next_avail(), 3);
+        q.try_set_next_used(mem, 2).unwrap();
+        assert_eq!(q.next_used(), 2);
+
+        // Ahead of the ring `idx`.
+        assert_eq!(
+            q.try_set_next_avail(mem, 6).unwrap_err(),
+            Error::InconsistentRestore
+        );
+        assert_eq!(
+            q.try_set_next_used(mem, 3).unwrap_err(),
+            Error::InconsistentRestore
+        );
+        // More than the queue size behind.
+        assert_eq!(
+            q.try_set_next_avail(mem, u16::MAX - 11).unwrap_err(),
+            Error::InconsistentRestore
+        );
+        // The queue is left untouched on error.
+        assert_eq!(q.next_avail(), 3);
+        assert_eq!(q.next_used(), 2);
+
+        // Up to the queue size behind, across the wrap.
+        q.try_set_next_avail(mem, u16::MAX - 10).unwrap();
+        assert_eq!(q.next_avail(), u16::MAX - 10);
+    }
+
+    #[test]
+    fn test_configure() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut q = Queue::new(16).unwrap();
+
+        q.configure(
+            8,
+            GuestAddress(0x1000),
+            GuestAddress(0x2000),
+            GuestAddress(0x3000),
+            true,
+        )
+        .unwrap();
+        q.set_ready(true);
+        assert!(q.is_valid(m));
+        assert_eq!(q.size(), 8);
+        assert_eq!(q.desc_table(), 0x1000);
+        assert_eq!(q.avail_ring(), 0x2000);
+        assert_eq!(q.used_ring(), 0x3000);
+        assert!(q.event_idx_enabled());
+        let state = q.state();
+
+        // An unaligned descriptor table is reported distinctly from a bad size, and the
+        // queue is left untouched.
+        assert_eq!(
+            q.configure(
+                16,
+                GuestAddress(0x1008),
+                GuestAddress(0x4000),
+                GuestAddress(0x5000),
+                false
+            )
+            .unwrap_err(),
+            Error::InvalidDescTableAlign
+        );
+        assert_eq!(
+            q.configure(
+                12,
+                GuestAddress(0x1000),
+                GuestAddress(0x4000),
+                GuestAddress(0x5000),
+                false
+            )
+            .unwrap_err(),
+            Error::InvalidSize
+        );
+        // The first invalid parameter is the one reported.
+        assert_eq!(
+            q.configure(
+                12,
+                GuestAddress(0x1008),
+                GuestAddress(0x4001),
+                GuestAddress(0x5002),
+                false
+            )
+            .unwrap_err(),
+            Error::InvalidSize
+        );
+        assert_eq!(
+            q.configure(
+                16,
+                GuestAddress(0x1000),
+                GuestAddress(0x4000),
+                GuestAddress(0x5002),
+                false
+            )
+            .unwrap_err(),
+            Error::InvalidUsedRingAlign
+        );
+        // Including when only the last parameter is invalid.
+        assert_eq!(q.state(), state);
+    }
+
+    #[test]
+    fn test_backed_descriptor_count() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let q: Queue = vq.create_queue().unwrap();
+        assert_eq!(q.backed_descriptor_count(m), 16);
+
+        // Only the first half of the table is backed, the second half falls in a hole.
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x2000), 0x1000),
+        ])
+        .unwrap();
+        let mut q = Queue::new(16).unwrap();
+        q.try_set_desc_table_address(GuestAddress(0xf80)).unwrap();
+        assert_eq!(q.backed_descriptor_count(m), 8);
+
+        // The table is backed again once it reaches the second region.
+        q.try_set_desc_table_address(GuestAddress(0x1f80)).unwrap();
+        assert_eq!(q.backed_descriptor_count(m), 8);
+
+        q.try_set_desc_table_address(GuestAddress(0x10_0000))
+            .unwrap();
+        assert_eq!(q.backed_descriptor_count(m), 0);
+    }
+
+    #[test]
+    fn test_load_descriptor() {
+        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(m, 16);
+        let q: Queue = vq.create_queue().unwrap();
+
+        let flags = (VRING_DESC_F_NEXT | VRING_DESC_F_WRITE) as u16;
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, flags, 5)),
+            RawDescriptor::from(SplitDescriptor::new(0xff00, 0x200, 0, 0)),
+        ];
+        vq.desc_table().store(3, descs[0]).unwrap();
+        vq.desc_table().store(15, descs[1]).unwrap();
+
+        let desc = q.load_descriptor(m, 3).unwrap();
+        assert_eq!(desc.addr(), GuestAddress(0x1000));
+        assert_eq!(desc.len(), 0x100);
+        assert_eq!(desc.flags(), flags);
+        assert_eq!(desc.next(), 5);
+
+        // The buffer goes past the end of guest memory.
+        assert_eq!(
+            q.load_descriptor(m, 15).unwrap_err(),
+            Error::FindMemoryRegion
+        );
+        assert_eq!(
+            q.load_descriptor(m, 16).unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
+    }
+
+    #[test]
+    fn test_max_chain_len_seen() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // The chains are (0), (1, 2, 3) and (4, 5).
+        let mut descs = Vec::new();
+        for i in 0..6 {
+            let flags = match i {
+                1 | 2 | 4 => VRING_DESC_F_NEXT,
+                _ => 0,
+            };
+
+            descs.push(RawDescriptor::from(SplitDescriptor::new(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                flags as u16,
+                i + 1,
+            )));
+        }
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        // Nothing is recorded while tracking is disabled.
+        q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(q.max_chain_len_seen(), 0);
+        q.go_to_previous_position();
+
+        q.set_track_chain_len(true);
+        q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(q.max_chain_len_seen(), 1);
+        q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(q.max_chain_len_seen(), 3);
+        q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(q.max_chain_len_seen(), 3);
+
+        q.reset();
+        assert_eq!(q.max_chain_len_seen(), 0);
+    }
+
+    #[test]
+    fn test_strict_validation() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+
+        // A zero-length readable descriptor, followed by a zero-length writable one.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0, VRING_DESC_F_NEXT as u16, 1)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        // Lenient mode, the default.
+        let mut chain = q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(chain.try_next().unwrap().unwrap().len(), 0);
+        assert_eq!(chain.try_next().unwrap().unwrap().len(), 0);
+        assert!(chain.try_next().unwrap().is_none());
+        q.go_to_previous_position();
+
+        q.set_strict_validation(true);
+        let mut chain = q.pop_descriptor_chain(mem).unwrap();
+        // Zero-length readable descriptors are still accepted.
+        assert_eq!(
+            chain.try_next().unwrap().unwrap().addr(),
+            GuestAddress(0x1000)
+        );
+        assert_eq!(
+            chain.try_next().unwrap_err(),
+            Error::ZeroLengthWritableDescriptor(1)
+        );
+        q.go_to_previous_position();
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().count(), 1);
+
+        q.set_strict_validation(false);
+        q.go_to_previous_position();
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_strict_chain_order() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.set_strict_validation(true);
+
+        let (next, write) = (VRING_DESC_F_NEXT as u16, VRING_DESC_F_WRITE as u16);
+        // A correctly ordered chain, followed by a writable descriptor followed by a readable one.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, next, 1)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, next | write, 2)),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x100, write, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x4000, 0x100, next | write, 4)),
+            RawDescriptor::from(SplitDescriptor::new(0x5000, 0x100, 0, 0)),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
+
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().count(), 3);
+        let mut chain = q.pop_descriptor_chain(mem).unwrap();
+        assert!(chain.try_next().unwrap().unwrap().is_write_only());
+        assert_eq!(chain.try_next().unwrap_err(), Error::InvalidChainOrder);
+
+        // Readers and writers don't treat the misordered chain as a shorter valid one.
+        q.go_to_previous_position();
+        let chain = q.pop_descriptor_chain(mem).unwrap();
+        assert!(matches!(
+            Reader::new(mem, chain.clone()),
+            Err(Error::InvalidChainOrder)
+        ));
+        assert!(matches!(
+            Writer::new(mem, chain.clone()),
+            Err(Error::InvalidChainOrder)
+        ));
+        let mut writable = chain.writable();
+        assert_eq!(
+            writable.try_next().unwrap().unwrap().addr(),
+            GuestAddress(0x4000)
+        );
+        assert_eq!(writable.try_next().unwrap_err(), Error::InvalidChainOrder);
 
-    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+        q.set_strict_validation(false);
+        q.go_to_previous_position();
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().count(), 2);
+    }
 
     #[test]
-    fn test_queue_is_valid() {
-        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let vq = MockSplitQueue::new(m, 16);
+    fn test_complete_chain() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
         let mut q: Queue = vq.create_queue().unwrap();
 
-        // q is currently valid
-        assert!(q.is_valid(m));
-
-        // shouldn't be valid when not marked as ready
-        q.set_ready(false);
-        assert!(!q.ready());
-        assert!(!q.is_valid(m));
-        q.set_ready(true);
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x100,
+                VRING_DESC_F_NEXT as u16,
+                2,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x3000,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
 
-        // shouldn't be allowed to set a size > max_size
-        q.set_size(q.max_size() << 1);
-        assert_eq!(q.size, q.max_size());
+        // Pop both chains, and complete them out of order once processed.
+        let first = q.pop_descriptor_chain(mem).unwrap();
+        let mut second = q.pop_descriptor_chain(mem).unwrap();
+        let written: u32 = second
+            .by_ref()
+            .filter(|desc| desc.is_write_only())
+            .map(|desc| desc.len())
+            .sum();
+        second.complete(&mut q, written).unwrap();
+        first.complete(&mut q, 0).unwrap();
+
+        assert_eq!(vq.used().idx().load(), 2);
+        let elem = vq.used().ring().ref_at(0).unwrap().load();
+        assert_eq!((elem.id(), elem.len()), (1, 0x100));
+        let elem = vq.used().ring().ref_at(1).unwrap().load();
+        assert_eq!((elem.id(), elem.len()), (0, 0));
+    }
 
-        // or set the size to 0
-        q.set_size(0);
-        assert_eq!(q.size, q.max_size());
+    #[test]
+    fn test_indirect_only() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.set_indirect_only(true);
 
-        // or set a size which is not a power of 2
-        q.set_size(11);
-        assert_eq!(q.size, q.max_size());
+        // A direct chain of two descriptors, a single descriptor, and an indirect chain.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(
+                0x1000,
+                0x100,
+                VRING_DESC_F_NEXT as u16,
+                1,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x7000,
+                0x20,
+                VRING_DESC_F_INDIRECT as u16,
+                0,
+            )),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
+        let idtable = DescriptorTable::new(mem, GuestAddress(0x7000), 2);
+        idtable
+            .store(
+                0,
+                RawDescriptor::from(SplitDescriptor::new(
+                    0x4000,
+                    0x100,
+                    VRING_DESC_F_NEXT as u16,
+                    1,
+                )),
+            )
+            .unwrap();
+        idtable
+            .store(
+                1,
+                RawDescriptor::from(SplitDescriptor::new(
+                    0x5000,
+                    0x100,
+                    VRING_DESC_F_WRITE as u16,
+                    0,
+                )),
+            )
+            .unwrap();
 
-        // but should be allowed to set a size if 0 < size <= max_size and size is a power of two
-        q.set_size(4);
-        assert_eq!(q.size, 4);
-        q.size = q.max_size();
+        let mut chain = q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(chain.try_next().unwrap_err(), Error::IndirectTableRequired);
+        // Readers and writers don't treat the direct chain as an empty one.
+        q.go_to_previous_position();
+        let chain = q.pop_descriptor_chain(mem).unwrap();
+        assert!(matches!(
+            Reader::new(mem, chain.clone()),
+            Err(Error::IndirectTableRequired)
+        ));
+        assert!(matches!(
+            Writer::new(mem, chain.clone()),
+            Err(Error::IndirectTableRequired)
+        ));
+        assert_eq!(
+            chain.readable().try_next().unwrap_err(),
+            Error::IndirectTableRequired
+        );
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().count(), 1);
+        let addrs: Vec<u64> = q
+            .pop_descriptor_chain(mem)
+            .unwrap()
+            .map(|desc| desc.addr().0)
+            .collect();
+        assert_eq!(addrs, [0x4000, 0x5000]);
+
+        // The direct chain is accepted once the mode is disabled.
+        q.set_indirect_only(false);
+        q.set_next_avail(0);
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().count(), 2);
+    }
 
-        // shouldn't be allowed to set an address that breaks the alignment constraint
-        q.set_desc_table_address(Some(0xf), None);
-        assert_eq!(q.desc_table.0, vq.desc_table_addr().0);
-        // should be allowed to set an aligned out of bounds address
-        q.set_desc_table_address(Some(0xffff_fff0), None);
-        assert_eq!(q.desc_table.0, 0xffff_fff0);
-        // but shouldn't be valid
-        assert!(!q.is_valid(m));
-        // but should be allowed to set a valid description table address
-        q.set_desc_table_address(Some(0x10), None);
-        assert_eq!(q.desc_table.0, 0x10);
-        assert!(q.is_valid(m));
-        let addr = vq.desc_table_addr().0;
-        q.set_desc_table_address(Some(addr as u32), Some((addr >> 32) as u32));
+    #[test]
+    fn test_validate_buffer_regions() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.set_validate_buffer_regions(true);
+        let used_ring = vq.used_addr().0;
+        let used_ring_end = used_ring + Queue::used_ring_size_for(16, false);
 
-        // shouldn't be allowed to set an address that breaks the alignment constraint
-        q.set_avail_ring_address(Some(0x1), None);
-        assert_eq!(q.avail_ring.0, vq.avail_addr().0);
-        // should be allowed to set an aligned out of bounds address
-        q.set_avail_ring_address(Some(0xffff_fffe), None);
-        assert_eq!(q.avail_ring.0, 0xffff_fffe);
-        // but shouldn't be valid
-        assert!(!q.is_valid(m));
-        // but should be allowed to set a valid available ring address
-        q.set_avail_ring_address(Some(0x2), None);
-        assert_eq!(q.avail_ring.0, 0x2);
-        assert!(q.is_valid(m));
-        let addr = vq.avail_addr().0;
-        q.set_avail_ring_address(Some(addr as u32), Some((addr >> 32) as u32));
+        // A chain whose writable buffer overlaps the end of the used ring, a buffer right after
+        // the used ring, and an indirect table in the available ring.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(
+                0x1000,
+                0x100,
+                VRING_DESC_F_NEXT as u16,
+                1,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(
+                used_ring_end - 1,
+                0x100,
+                VRING_DESC_F_WRITE as u16,
+                0,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(used_ring_end, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                vq.avail_addr().0,
+                0x20,
+                VRING_DESC_F_INDIRECT as u16,
+                0,
+            )),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
 
-        // shouldn't be allowed to set an address that breaks the alignment constraint
-        q.set_used_ring_address(Some(0x3), None);
-        assert_eq!(q.used_ring.0, vq.used_addr().0);
-        // should be allowed to set an aligned out of bounds address
-        q.set_used_ring_address(Some(0xffff_fffc), None);
-        assert_eq!(q.used_ring.0, 0xffff_fffc);
-        // but shouldn't be valid
-        assert!(!q.is_valid(m));
-        // but should be allowed to set a valid used ring address
-        q.set_used_ring_address(Some(0x4), None);
-        assert_eq!(q.used_ring.0, 0x4);
-        let addr = vq.used_addr().0;
-        q.set_used_ring_address(Some(addr as u32), Some((addr >> 32) as u32));
-        assert!(q.is_valid(m));
+        let mut chain = q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(chain.try_next().unwrap().unwrap().addr().0, 0x1000);
+        assert_eq!(chain.try_next().unwrap_err(), Error::BufferOverlapsRing);
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().count(), 1);
+        let mut chain = q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(chain.try_next().unwrap_err(), Error::BufferOverlapsRing);
+
+        // The check is disabled by default.
+        q.set_validate_buffer_regions(false);
+        q.set_next_avail(0);
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().count(), 2);
     }
 
     #[test]
-    fn test_add_used() {
+    fn test_in_flight_tracking() {
         let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let vq = MockSplitQueue::new(mem, 16);
+        let vq = MockSplitQueue::new(mem, 128);
         let mut q: Queue = vq.create_queue().unwrap();
 
-        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(0));
-        assert_eq!(u16::from_le(vq.used().idx().load()), 0);
+        // Marks are ignored while tracking is disabled.
+        q.mark_in_flight(1).unwrap();
+        assert_eq!(q.reclaim_in_flight().count(), 0);
 
-        // index too large
-        assert!(q.add_used(mem, 16, 0x1000).is_err());
-        assert_eq!(u16::from_le(vq.used().idx().load()), 0);
+        q.set_in_flight_tracking(true);
+        for head_index in [3, 64, 127, 0, 70] {
+            q.mark_in_flight(head_index).unwrap();
+        }
+        assert_eq!(
+            q.mark_in_flight(128).unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
 
-        // should be ok
-        q.add_used(mem, 1, 0x1000).unwrap();
-        assert_eq!(q.next_used, Wrapping(1));
-        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(1));
-        assert_eq!(u16::from_le(vq.used().idx().load()), 1);
+        // Completing chains clears their marks.
+        q.add_used(mem, 64, 0x100).unwrap();
+        q.add_used_batch(mem, [(0, 0x100), (5, 0x100)]).unwrap();
+        // A failed batch doesn't clear anything.
+        assert!(q.add_used_batch(mem, [(70, 0x100), (128, 0)]).is_err());
 
-        let x = vq.used().ring().ref_at(0).unwrap().load();
-        assert_eq!(x.id(), 1);
-        assert_eq!(x.len(), 0x1000);
+        q.reset();
+        assert_eq!(q.reclaim_in_flight().collect::<Vec<_>>(), [3, 70, 127]);
+        assert_eq!(q.reclaim_in_flight().count(), 0);
+
+        q.set_in_flight_tracking(false);
+        assert_eq!(q.reclaim_in_flight().count(), 0);
     }
 
     #[test]
-    fn test_reset_queue() {
-        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let vq = MockSplitQueue::new(m, 16);
-        let mut q: Queue = vq.create_queue().unwrap();
-
-        q.set_size(8);
-        // The address set by `MockSplitQueue` for the descriptor table is DEFAULT_DESC_TABLE_ADDR,
-        // so let's change it for testing the reset.
-        q.set_desc_table_address(Some(0x5000), None);
-        // Same for `event_idx_enabled`, `next_avail` `next_used` and `signalled_used`.
-        q.set_event_idx(true);
-        q.set_next_avail(2);
-        q.set_next_used(4);
-        q.num_added = Wrapping(15);
-        assert_eq!(q.size, 8);
-        // `create_queue` also marks the queue as ready.
-        assert!(q.ready);
-        assert_ne!(q.desc_table, GuestAddress(DEFAULT_DESC_TABLE_ADDR));
-        assert_ne!(q.avail_ring, GuestAddress(DEFAULT_AVAIL_RING_ADDR));
-        assert_ne!(q.used_ring, GuestAddress(DEFAULT_USED_RING_ADDR));
-        assert_ne!(q.next_avail, Wrapping(0));
-        assert_ne!(q.next_used, Wrapping(0));
-        assert_ne!(q.num_added, Wrapping(0));
-        assert!(q.event_idx_enabled);
+    fn test_overdue_chains() {
+        let mut q = Queue::new(16).unwrap();
+        let timeout = Duration::from_millis(20);
+        let start = Instant::now();
 
+        assert_eq!(
+            q.mark_submitted_at(16, start).unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
+        q.mark_submitted_at(5, start).unwrap();
+        q.mark_submitted_at(2, start).unwrap();
+        assert!(q.overdue_at(start + timeout, timeout).is_empty());
+
+        let now = start + timeout * 2;
+        q.mark_submitted_at(7, now).unwrap();
+        q.clear_submitted(5);
+        assert_eq!(q.overdue_at(now, timeout), [2]);
+        // The times submitted after `now` don't make chains overdue.
+        assert!(q.overdue_at(start, Duration::ZERO).is_empty());
+
+        q.clear_submitted(2);
+        q.clear_submitted(2);
+        assert!(q.overdue_at(now, timeout).is_empty());
+
+        q.mark_submitted(3).unwrap();
         q.reset();
-        assert_eq!(q.size, 16);
-        assert!(!q.ready);
-        assert_eq!(q.desc_table, GuestAddress(DEFAULT_DESC_TABLE_ADDR));
-        assert_eq!(q.avail_ring, GuestAddress(DEFAULT_AVAIL_RING_ADDR));
-        assert_eq!(q.used_ring, GuestAddress(DEFAULT_USED_RING_ADDR));
-        assert_eq!(q.next_avail, Wrapping(0));
-        assert_eq!(q.next_used, Wrapping(0));
-        assert_eq!(q.num_added, Wrapping(0));
-        assert!(!q.event_idx_enabled);
+        assert!(q.overdue(Duration::ZERO).is_empty());
     }
 
     #[test]
-    fn test_needs_notification() {
+    fn test_overdue_chains_used() {
         let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let qsize = 16;
-        let vq = MockSplitQueue::new(mem, qsize);
+        let vq = MockSplitQueue::new(mem, 16);
         let mut q: Queue = vq.create_queue().unwrap();
-        let avail_addr = vq.avail_addr();
+        let start = Instant::now();
 
-        // It should always return true when EVENT_IDX isn't enabled.
-        for i in 0..qsize {
-            q.next_used = Wrapping(i);
-            assert!(q.needs_notification(mem).unwrap());
+        for head_index in [1, 2, 3, 4] {
+            q.mark_submitted_at(head_index, start).unwrap();
         }
+        q.add_used(mem, 1, 0x10).unwrap();
+        q.add_used_batch(mem, [(3, 0x10), (4, 0x10)]).unwrap();
+        // Chains added to the used ring are not overdue anymore.
+        assert_eq!(
+            q.overdue_at(start + Duration::from_secs(1), Duration::ZERO),
+            [2]
+        );
+    }
 
-        mem.write_obj::<u16>(
-            u16::to_le(4),
-            avail_addr.unchecked_add(4 + qsize as u64 * 2),
-        )
-        .unwrap();
-        q.set_event_idx(true);
-
-        // Incrementing up to this value causes an `u16` to wrap back to 0.
-        let wrap = u32::from(u16::MAX) + 1;
+    #[test]
+    fn test_paused() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        vq.add_chain(1).unwrap();
 
-        for i in 0..wrap + 12 {
-            q.next_used = Wrapping(i as u16);
-            // Let's test wrapping around the maximum index value as well.
-            // `num_added` needs to be at least `1` to represent the fact that new descriptor
-            // chains have be added to the used ring since the last time `needs_notification`
-            // returned.
-            q.num_added = Wrapping(1);
-            let expected = i == 5 || i == (5 + wrap);
-            assert_eq!((q.needs_notification(mem).unwrap(), i), (expected, i));
-        }
+        q.set_paused(true);
+        // Reading the rings is still allowed.
+        let head = q.pop_descriptor_chain(mem).unwrap().head_index();
+        assert_eq!(q.available_count(mem).unwrap(), 0);
 
-        mem.write_obj::<u16>(
-            u16::to_le(8),
-            avail_addr.unchecked_add(4 + qsize as u64 * 2),
-        )
-        .unwrap();
+        assert_eq!(
+            q.add_used(mem, head, 0x100).unwrap_err(),
+            Error::QueuePaused
+        );
+        assert_eq!(
+            q.add_used_batch(mem, [(head, 0x100)]).unwrap_err(),
+            Error::QueuePaused
+        );
+        assert_eq!(q.next_used(), 0);
+        assert_eq!(vq.used().idx().load(), 0);
+        assert_eq!(vq.used().ring().ref_at(0).unwrap().load().len(), 0);
 
-        // Returns `false` because the current `used_event` value is behind both `next_used` and
-        // the value of `next_used` at the time when `needs_notification` last returned (which is
-        // computed based on `num_added` as described in the comments for `needs_notification`.
-        assert!(!q.needs_notification(mem).unwrap());
+        assert_eq!(q.disable_notification(mem).unwrap_err(), Error::QueuePaused);
+        assert_eq!(q.enable_notification(mem).unwrap_err(), Error::QueuePaused);
+        q.set_event_idx(true);
+        assert_eq!(q.enable_notification(mem).unwrap_err(), Error::QueuePaused);
+        q.set_event_idx(false);
 
-        mem.write_obj::<u16>(
-            u16::to_le(15),
-            avail_addr.unchecked_add(4 + qsize as u64 * 2),
-        )
-        .unwrap();
+        q.set_paused(false);
+        q.add_used(mem, head, 0x100).unwrap();
+        assert_eq!(vq.used().idx().load(), 1);
+        q.disable_notification(mem).unwrap();
+    }
 
-        q.num_added = Wrapping(1);
-        assert!(!q.needs_notification(mem).unwrap());
+    #[test]
+    fn test_used_count_check() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.set_used_count_check(true);
 
-        q.next_used = Wrapping(15);
-        q.num_added = Wrapping(1);
-        assert!(!q.needs_notification(mem).unwrap());
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, 0, 0)),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
 
-        q.next_used = Wrapping(16);
-        q.num_added = Wrapping(1);
-        assert!(q.needs_notification(mem).unwrap());
+        // Nothing was popped yet.
+        assert_eq!(q.add_used(mem, 0, 0x100).unwrap_err(), Error::TooManyUsed);
 
-        // Calling `needs_notification` again immediately returns `false`.
-        assert!(!q.needs_notification(mem).unwrap());
+        let head = q.pop_descriptor_chain(mem).unwrap().head_index();
+        q.add_used(mem, head, 0x100).unwrap();
+        assert_eq!(
+            q.add_used(mem, head, 0x100).unwrap_err(),
+            Error::TooManyUsed
+        );
 
-        mem.write_obj::<u16>(
-            u16::to_le(u16::MAX - 3),
-            avail_addr.unchecked_add(4 + qsize as u64 * 2),
-        )
-        .unwrap();
-        q.next_used = Wrapping(u16::MAX - 2);
-        q.num_added = Wrapping(1);
-        // Returns `true` because, when looking at circular sequence of indices of the used ring,
-        // the value we wrote in the `used_event` appears between the "old" value of `next_used`
-        // (i.e. `next_used` - `num_added`) and the current `next_used`, thus suggesting that we
-        // need to notify the driver.
-        assert!(q.needs_notification(mem).unwrap());
+        // A batch completing more chains than popped is rejected as a whole.
+        let head = q.pop_descriptor_chain(mem).unwrap().head_index();
+        assert_eq!(
+            q.add_used_batch(mem, [(head, 0x100), (head, 0x100)])
+                .unwrap_err(),
+            Error::TooManyUsed
+        );
+        assert_eq!(q.next_used(), 1);
+        assert_eq!(vq.used().idx().load(), 1);
+        // None of its elements was written.
+        assert_eq!(vq.used().ring().ref_at(1).unwrap().load().id(), 0);
+        q.add_used_batch(mem, [(head, 0x100)]).unwrap();
+        assert_eq!(vq.used().idx().load(), 2);
+
+        q.set_used_count_check(false);
+        q.add_used(mem, head, 0x100).unwrap();
+        assert_eq!(q.next_used(), 3);
     }
 
     #[test]
-    fn test_enable_disable_notification() {
+    fn test_reset_position() {
         let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.try_set_size(8).unwrap();
+        q.set_event_idx(true);
+        q.set_next_avail(5);
+        q.set_next_used(3);
+        q.num_added = Wrapping(2);
+
+        q.reset_position();
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.next_used(), 0);
+        assert_eq!(q.num_added, Wrapping(0));
+        assert_eq!(q.size(), 8);
+        assert_eq!(q.desc_table(), vq.desc_table_addr().0);
+        assert_eq!(q.avail_ring(), vq.avail_addr().0);
+        assert_eq!(q.used_ring(), vq.used_addr().0);
+        assert!(q.ready());
+        assert!(q.event_idx_enabled());
+    }
 
+    #[test]
+    fn test_ring_reset() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vq = MockSplitQueue::new(mem, 16);
         let mut q: Queue = vq.create_queue().unwrap();
-        let used_addr = vq.used_addr();
+        vq.add_chain(1).unwrap();
+        q.set_event_idx(true);
 
-        assert!(!q.event_idx_enabled);
+        q.begin_reset();
+        assert!(q.is_resetting());
+        assert!(!q.ready());
+        assert!(!q.event_idx_enabled());
 
-        q.enable_notification(mem).unwrap();
-        let v = mem.read_obj::<u16>(used_addr).map(u16::from_le).unwrap();
-        assert_eq!(v, 0);
+        // The driver reconfigures the queue while the reset is in progress.
+        q.try_set_desc_table_address(vq.desc_table_addr()).unwrap();
+        q.try_set_avail_ring_address(vq.avail_addr()).unwrap();
+        q.try_set_used_ring_address(vq.used_addr()).unwrap();
+        q.set_ready(true);
+        assert_eq!(q.iter(mem).unwrap_err(), Error::QueueResetting);
+        assert!(q.pop_descriptor_chain(mem).is_none());
+        assert_eq!(
+            q.add_used(mem, 0, 0x100).unwrap_err(),
+            Error::QueueResetting
+        );
+        assert_eq!(
+            q.add_used_batch(mem, [(0, 0x100)]).unwrap_err(),
+            Error::QueueResetting
+        );
+        assert_eq!(vq.used().idx().load(), 0);
 
-        q.disable_notification(mem).unwrap();
-        let v = mem.read_obj::<u16>(used_addr).map(u16::from_le).unwrap();
-        assert_eq!(v, VRING_USED_F_NO_NOTIFY as u16);
+        q.complete_reset();
+        assert!(!q.is_resetting());
+        let head = q.pop_descriptor_chain(mem).unwrap().head_index();
+        q.add_used(mem, head, 0x100).unwrap();
+        assert_eq!(vq.used().idx().load(), 1);
 
-        q.enable_notification(mem).unwrap();
-        let v = mem.read_obj::<u16>(used_addr).map(u16::from_le).unwrap();
-        assert_eq!(v, 0);
+        // A device reset ends the queue reset.
+        q.begin_reset();
+        q.reset();
+        assert!(!q.is_resetting());
+    }
 
-        q.set_event_idx(true);
-        let avail_addr = vq.avail_addr();
-        mem.write_obj::<u16>(u16::to_le(2), avail_addr.unchecked_add(2))
-            .unwrap();
+    #[test]
+    fn test_publish_all() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq1 = MockSplitQueue::new(mem, 16);
+        let mut q1: Queue = vq1.create_queue().unwrap();
+        let vq2 = MockSplitQueue::create(mem, GuestAddress(0x4000), 16);
+        let mut q2: Queue = vq2.create_queue().unwrap();
+        q2.set_next_used(3);
+
+        publish_all(
+            &mut [
+                (&mut q1, &[(1, 0x100), (2, 0x200)][..]),
+                (&mut q2, &[(5, 0x500)][..]),
+            ],
+            mem,
+        )
+        .unwrap();
 
-        assert!(q.enable_notification(mem).unwrap());
-        q.next_avail = Wrapping(2);
-        assert!(!q.enable_notification(mem).unwrap());
+        assert_eq!(q1.next_used(), 2);
+        assert_eq!(vq1.used().idx().load(), 2);
+        assert_eq!(q1.used_elem(mem, 0).unwrap().id(), 1);
+        assert_eq!(q1.used_elem(mem, 1).unwrap().len(), 0x200);
+        assert_eq!(q2.next_used(), 4);
+        assert_eq!(vq2.used().idx().load(), 4);
+        assert_eq!(q2.used_elem(mem, 3).unwrap().id(), 5);
+        assert_eq!(q2.used_elem(mem, 3).unwrap().len(), 0x500);
 
-        mem.write_obj::<u16>(u16::to_le(8), avail_addr.unchecked_add(2))
-            .unwrap();
+        // An invalid element for the second queue prevents publishing the first one as well.
+        assert_eq!(
+            publish_all(
+                &mut [(&mut q1, &[(3, 0x300)][..]), (&mut q2, &[(16, 0x100)][..]),],
+                mem,
+            )
+            .unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
+        assert_eq!(q1.next_used(), 2);
+        assert_eq!(vq1.used().idx().load(), 2);
+        assert_eq!(q2.next_used(), 4);
 
-        assert!(q.enable_notification(mem).unwrap());
-        q.next_avail = Wrapping(8);
-        assert!(!q.enable_notification(mem).unwrap());
+        let too_long: Vec<(u16, u32)> = (0..17).map(|i| (i % 16, 0x100)).collect();
+        assert_eq!(
+            publish_all(&mut [(&mut q1, &too_long[..])], mem).unwrap_err(),
+            Error::UsedBatchTooLong
+        );
+        assert_eq!(q1.next_used(), 2);
+
+        // The elements of the last queue can be written, but the `idx` field of its used ring
+        // is in a hole of guest memory.
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x20000), 0x10000),
+        ])
+        .unwrap();
+        let vq1 = MockSplitQueue::new(mem, 16);
+        let mut q1: Queue = vq1.create_queue().unwrap();
+        let mut q2 = Queue::new(16).unwrap();
+        q2.try_set_used_ring_address(GuestAddress(0x1fffc)).unwrap();
+        q2.set_ready(true);
+
+        assert_eq!(
+            publish_all(
+                &mut [(&mut q1, &[(1, 0x100)][..]), (&mut q2, &[(2, 0x200)][..])],
+                mem,
+            )
+            .unwrap_err(),
+            Error::FindMemoryRegion
+        );
+        assert_eq!(q1.next_used(), 0);
+        assert_eq!(vq1.used().idx().load(), 0);
+        assert_eq!(q1.stats(), QueueStats::default());
+        assert_eq!(q2.next_used(), 0);
+        assert_eq!(q2.stats(), QueueStats::default());
     }
 
     #[test]
-    fn test_consume_chains_with_notif() {
+    fn test_flush_available() {
         let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = MockSplitQueue::new(mem, 16);
-
         let mut q: Queue = vq.create_queue().unwrap();
 
-        // q is currently valid.
-        assert!(q.is_valid(mem));
-
-        // The chains are (0, 1), (2, 3, 4), (5, 6), (7, 8), (9, 10, 11, 12).
-        let mut descs = Vec::new();
-        for i in 0..13 {
-            let flags = match i {
-                1 | 4 | 6 | 8 | 12 => 0,
-                _ => VRING_DESC_F_NEXT,
-            };
-
-            descs.push(RawDescriptor::from(SplitDescriptor::new(
-                (0x1000 * (i + 1)) as u64,
-                0x1000,
-                flags as u16,
-                i + 1,
-            )));
-        }
+        // Nothing to flush.
+        assert_eq!(q.flush_available(mem).unwrap(), 0);
 
+        // Three chains, with the heads 0, 1 and 3.
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(
+                0x2000,
+                0x100,
+                VRING_DESC_F_NEXT as u16,
+                2,
+            )),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x4000, 0x100, 0, 0)),
+        ];
         vq.add_desc_chains(&descs, 0).unwrap();
-        // Update the index of the chain that can be consumed to not be the last one.
-        // This enables us to consume chains in multiple iterations as opposed to consuming
-        // all the driver written chains at once.
-        vq.avail().idx().store(u16::to_le(2));
-        // No descriptor chains are consumed at this point.
-        assert_eq!(q.next_avail(), 0);
-
-        let mut i = 0;
-
-        loop {
-            i += 1;
-            q.disable_notification(mem).unwrap();
+        // The driver also makes an out of bounds head index available.
+        vq.avail().ring().ref_at(3).unwrap().store(u16::to_le(16));
+        vq.avail().idx().store(u16::to_le(4));
 
-            while let Some(chain) = q.iter(mem).unwrap().next() {
-                // Process the descriptor chain, and then add entries to the
-                // used ring.
-                let head_index = chain.head_index();
-                let mut desc_len = 0;
-                chain.for_each(|d| {
-                    if d.flags() as u32 & VRING_DESC_F_WRITE == VRING_DESC_F_WRITE {
-                        desc_len += d.len();
-                    }
-                });
-                q.add_used(mem, head_index, desc_len).unwrap();
-            }
-            if !q.enable_notification(mem).unwrap() {
-                break;
-            }
+        assert_eq!(q.flush_available(mem).unwrap(), 3);
+        assert_eq!(q.next_avail(), 4);
+        assert_eq!(q.next_used(), 3);
+        assert_eq!(vq.used().idx().load(), 3);
+        for (pos, head) in [0, 1, 3].iter().enumerate() {
+            let elem = vq.used().ring().ref_at(pos).unwrap().load();
+            assert_eq!(elem.id(), *head);
+            assert_eq!(elem.len(), 0);
         }
-        // The chains should be consumed in a single loop iteration because there's nothing updating
-        // the `idx` field of the available ring in the meantime.
-        assert_eq!(i, 1);
-        // The next chain that can be consumed should have index 2.
+        assert_eq!(q.flush_available(mem).unwrap(), 0);
+
+        // Only two elements fit in the used ring before the end of guest memory, so the
+        // chains from head 3 onwards are left available.
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.try_set_used_ring_address(GuestAddress(0x10000 - 4 - 2 * 8))
+            .unwrap();
+        assert!(matches!(
+            q.flush_available(mem).unwrap_err(),
+            Error::GuestMemory(_)
+        ));
         assert_eq!(q.next_avail(), 2);
         assert_eq!(q.next_used(), 2);
-        // Let the device know it can consume one more chain.
-        vq.avail().idx().store(u16::to_le(3));
-        i = 0;
+        assert_eq!(q.stats().chains_popped, 2);
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().head_index(), 3);
 
-        loop {
-            i += 1;
-            q.disable_notification(mem).unwrap();
+        // Nothing is lost when the queue is paused either.
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.set_paused(true);
+        assert_eq!(q.flush_available(mem).unwrap_err(), Error::QueuePaused);
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.stats().chains_popped, 0);
+        q.set_paused(false);
+        assert_eq!(q.flush_available(mem).unwrap(), 3);
+    }
 
-            while let Some(chain) = q.iter(mem).unwrap().next() {
-                // Process the descriptor chain, and then add entries to the
-                // used ring.
-                let head_index = chain.head_index();
-                let mut desc_len = 0;
-                chain.for_each(|d| {
-                    if d.flags() as u32 & VRING_DESC_F_WRITE == VRING_DESC_F_WRITE {
-                        desc_len += d.len();
-                    }
-                });
-                q.add_used(mem, head_index, desc_len).unwrap();
-            }
+    #[test]
+    fn test_skip_available() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
 
-            // For the simplicity of the test we are updating here the `idx` value of the available
-            // ring. Ideally this should be done on a separate thread.
-            // Because of this update, the loop should be iterated again to consume the new
-            // available descriptor chains.
-            vq.avail().idx().store(u16::to_le(4));
-            if !q.enable_notification(mem).unwrap() {
-                break;
-            }
-        }
-        assert_eq!(i, 2);
-        // The next chain that can be consumed should have index 4.
-        assert_eq!(q.next_avail(), 4);
-        assert_eq!(q.next_used(), 4);
+        assert_eq!(q.skip_available(mem).unwrap(), 0);
 
-        // Set an `idx` that is bigger than the number of entries added in the ring.
-        // This is an allowed scenario, but the indexes of the chain will have unexpected values.
-        vq.avail().idx().store(u16::to_le(7));
-        loop {
-            q.disable_notification(mem).unwrap();
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x100, 0, 0)),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
+        q.pop_descriptor_chain(mem).unwrap();
 
-            while let Some(chain) = q.iter(mem).unwrap().next() {
-                // Process the descriptor chain, and then add entries to the
-                // used ring.
-                let head_index = chain.head_index();
-                let mut desc_len = 0;
-                chain.for_each(|d| {
-                    if d.flags() as u32 & VRING_DESC_F_WRITE == VRING_DESC_F_WRITE {
-                        desc_len += d.len();
-                    }
-                });
-                q.add_used(mem, head_index, desc_len).unwrap();
-            }
-            if !q.enable_notification(mem).unwrap() {
-                break;
-            }
-        }
-        assert_eq!(q.next_avail(), 7);
-        assert_eq!(q.next_used(), 7);
+        assert_eq!(q.skip_available(mem).unwrap(), 2);
+        assert_eq!(q.next_avail(), 3);
+        assert!(q.pop_descriptor_chain(mem).is_none());
+        // Nothing was returned to the driver.
+        assert_eq!(q.next_used(), 0);
+        assert_eq!(vq.used().idx().load(), 0);
+        assert_eq!(vq.used().ring().ref_at(0).unwrap().load().len(), 0);
+
+        vq.avail().idx().store(u16::to_le(3 + 17));
+        assert_eq!(
+            q.skip_available(mem).unwrap_err(),
+            Error::InvalidAvailRingIndex
+        );
+        assert_eq!(q.next_avail(), 3);
     }
 
     #[test]
-    fn test_invalid_avail_idx() {
-        // This is a negative test for the following MUST from the spec: `A driver MUST NOT
-        // decrement the available idx on a virtqueue (ie. there is no way to “unexpose” buffers).`.
-        // We validate that for this misconfiguration, the device does not panic.
+    fn test_descriptor_prefetch() {
         let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let vq = MockSplitQueue::new(mem, 16);
-
         let mut q: Queue = vq.create_queue().unwrap();
+        q.set_descriptor_prefetch(true);
 
-        // q is currently valid.
-        assert!(q.is_valid(mem));
+        // The first six descriptors of the chain span two prefetch windows, and the last one is
+        // at the end of the table, where the window is shorter.
+        let descs: Vec<RawDescriptor> = (0..6)
+            .map(|i| {
+                let next = if i == 5 { 15 } else { i + 1 };
+                RawDescriptor::from(SplitDescriptor::new(
+                    0x1000 * (u64::from(i) + 1),
+                    0x100,
+                    VRING_DESC_F_NEXT as u16,
+                    next,
+                ))
+            })
+            .collect();
+        vq.add_desc_chains(&descs, 0).unwrap();
+        vq.desc_table()
+            .store(
+                15,
+                RawDescriptor::from(SplitDescriptor::new(0xf000, 0x100, 0, 0)),
+            )
+            .unwrap();
 
-        // The chains are (0, 1), (2, 3, 4), (5, 6).
-        let mut descs = Vec::new();
-        for i in 0..7 {
-            let flags = match i {
-                1 | 4 | 6 => 0,
-                _ => VRING_DESC_F_NEXT,
-            };
+        let chain = q.pop_descriptor_chain(mem).unwrap();
+        let addrs: Vec<u64> = chain.map(|desc| desc.addr().0).collect();
+        assert_eq!(
+            addrs,
+            [0x1000, 0x2000, 0x3000, 0x4000, 0x5000, 0x6000, 0xf000]
+        );
 
-            descs.push(RawDescriptor::from(SplitDescriptor::new(
-                (0x1000 * (i + 1)) as u64,
-                0x1000,
-                flags as u16,
-                i + 1,
-            )));
-        }
+        // The same chain, without prefetching.
+        q.go_to_previous_position();
+        q.set_descriptor_prefetch(false);
+        let chain = q.pop_descriptor_chain(mem).unwrap();
+        let addrs_without_prefetch: Vec<u64> = chain.map(|desc| desc.addr().0).collect();
+        assert_eq!(addrs, addrs_without_prefetch);
+    }
 
-        vq.add_desc_chains(&descs, 0).unwrap();
-        // Let the device know it can consume chains with the index < 2.
-        vq.avail().idx().store(u16::to_le(3));
-        // No descriptor chains are consumed at this point.
+    #[test]
+    fn test_clone_with_memory_positions() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.set_event_idx(true);
+        q.set_strict_validation(true);
+
+        vq.avail().idx().store(u16::to_le(7));
+        vq.used().idx().store(u16::to_le(3));
         assert_eq!(q.next_avail(), 0);
         assert_eq!(q.next_used(), 0);
 
-        loop {
-            q.disable_notification(mem).unwrap();
-
-            while let Some(chain) = q.iter(mem).unwrap().next() {
-                // Process the descriptor chain, and then add entries to the
-                // used ring.
-                let head_index = chain.head_index();
-                let mut desc_len = 0;
-                chain.for_each(|d| {
-                    if d.flags() as u32 & VRING_DESC_F_WRITE == VRING_DESC_F_WRITE {
-                        desc_len += d.len();
-                    }
-                });
-                q.add_used(mem, head_index, desc_len).unwrap();
-            }
-            if !q.enable_notification(mem).unwrap() {
-                break;
+        let synced = q.clone_with_memory_positions(mem).unwrap();
+        assert_eq!(synced.next_avail(), 7);
+        assert_eq!(synced.next_used(), 3);
+        assert_eq!(
+            synced.state(),
+            QueueState {
+                next_avail: 7,
+                next_used: 3,
+                ..q.state()
             }
-        }
-        // The next chain that can be consumed should have index 3.
-        assert_eq!(q.next_avail(), 3);
-        assert_eq!(q.avail_idx(mem, Ordering::Acquire).unwrap(), Wrapping(3));
-        assert_eq!(q.next_used(), 3);
-        assert_eq!(q.used_idx(mem, Ordering::Acquire).unwrap(), Wrapping(3));
-        assert!(q.lock().ready());
+        );
+        assert!(synced.strict_validation);
+        // The original queue is left untouched.
+        assert_eq!(q.next_avail(), 0);
 
-        // Decrement `idx` which should be forbidden. We don't enforce this thing, but we should
-        // test that we don't panic in case the driver decrements it.
-        vq.avail().idx().store(u16::to_le(1));
-        // Invalid available ring index
-        assert!(q.iter(mem).is_err());
+        // More chains available than the queue can hold.
+        vq.avail().idx().store(u16::to_le(20));
+        assert_eq!(
+            q.clone_with_memory_positions(mem).unwrap_err(),
+            Error::InconsistentRestore
+        );
     }
 
     #[test]
-    fn test_iterator_and_avail_idx() {
-        // This test ensures constructing a descriptor chain iterator succeeds
-        // with valid available ring indexes while produces an error with invalid
-        // indexes.
-        let queue_size = 2;
+    fn test_peek_descriptor_chain() {
         let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let vq = MockSplitQueue::new(mem, queue_size);
-
+        let vq = MockSplitQueue::new(mem, 16);
         let mut q: Queue = vq.create_queue().unwrap();
 
-        // q is currently valid.
-        assert!(q.is_valid(mem));
+        assert!(q.peek_descriptor_chain(mem).is_none());
 
-        // Create descriptors to fill up the queue
-        let mut descs = Vec::new();
-        for i in 0..queue_size {
-            descs.push(RawDescriptor::from(SplitDescriptor::new(
-                (0x1000 * (i + 1)) as u64,
-                0x1000,
-                0_u16,
-                i + 1,
-            )));
-        }
+        let descs: Vec<RawDescriptor> = (0..2)
+            .map(|i| RawDescriptor::from(SplitDescriptor::new(0x1000 * (i + 1), 0x100, 0, 0)))
+            .collect();
         vq.add_desc_chains(&descs, 0).unwrap();
 
-        // Set the 'next_available' index to 'u16:MAX' to test the wrapping scenarios
-        q.set_next_avail(u16::MAX);
+        assert_eq!(q.peek_descriptor_chain(mem).unwrap().head_index(), 0);
+        assert_eq!(q.peek_descriptor_chain(mem).unwrap().head_index(), 0);
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().head_index(), 0);
 
-        // When the number of chains exposed by the driver is equal to or less than the queue
-        // size, the available ring index is valid and constructs an iterator successfully.
-        let avail_idx = Wrapping(q.next_avail()) + Wrapping(queue_size);
-        vq.avail().idx().store(u16::to_le(avail_idx.0));
-        assert!(q.iter(mem).is_ok());
-        let avail_idx = Wrapping(q.next_avail()) + Wrapping(queue_size - 1);
-        vq.avail().idx().store(u16::to_le(avail_idx.0));
-        assert!(q.iter(mem).is_ok());
+        assert_eq!(q.peek_descriptor_chain(mem).unwrap().head_index(), 1);
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().head_index(), 1);
+        assert!(q.peek_descriptor_chain(mem).is_none());
+        assert_eq!(q.next_avail(), 2);
 
-        // When the number of chains exposed by the driver is larger than the queue size, the
-        // available ring index is invalid and produces an error from constructing an iterator.
-        let avail_idx = Wrapping(q.next_avail()) + Wrapping(queue_size + 1);
-        vq.avail().idx().store(u16::to_le(avail_idx.0));
-        assert!(q.iter(mem).is_err());
+        // Peeking doesn't update any of the counters of the queue.
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.set_track_chain_len(true);
+        q.peek_descriptor_chain(mem).unwrap();
+        assert_eq!(q.max_chain_len_seen(), 0);
+        assert_eq!(q.stats(), QueueStats::default());
+        q.pop_descriptor_chain(mem).unwrap();
+        assert_eq!(q.max_chain_len_seen(), 1);
+        assert_eq!(q.stats().chains_popped, 1);
     }
 
     #[test]
-    fn test_descriptor_and_iterator() {
-        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let vq = MockSplitQueue::new(m, 16);
-
+    fn test_next_used_slot_addr() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
         let mut q: Queue = vq.create_queue().unwrap();
-
-        // q is currently valid
-        assert!(q.is_valid(m));
-
-        // the chains are (0, 1), (2, 3, 4) and (5, 6)
-        let mut descs = Vec::new();
-        for j in 0..7 {
-            let flags = match j {
-                1 | 6 => 0,
-                2 | 5 => VRING_DESC_F_NEXT | VRING_DESC_F_WRITE,
-                4 => VRING_DESC_F_WRITE,
-                _ => VRING_DESC_F_NEXT,
-            };
-
-            descs.push(RawDescriptor::from(SplitDescriptor::new(
-                (0x1000 * (j + 1)) as u64,
-                0x1000,
-                flags as u16,
-                j + 1,
-            )));
+        q.set_next_used(15);
+
+        for (head_index, len) in [(3, 0x100), (4, 0x200)] {
+            let addr = q.next_used_slot_addr().unwrap();
+            q.add_used(mem, head_index, len).unwrap();
+            let elem = mem.read_obj::<VirtqUsedElem>(addr).unwrap();
+            assert_eq!(elem.id(), u32::from(head_index));
+            assert_eq!(elem.len(), len);
         }
+        // The second element wrapped around to the start of the ring.
+        assert_eq!(
+            q.next_used_slot_addr().unwrap(),
+            vq.used_addr()
+                .unchecked_add(VIRTQ_USED_RING_HEADER_SIZE + VIRTQ_USED_ELEMENT_SIZE)
+        );
 
-        vq.add_desc_chains(&descs, 0).unwrap();
-
-        let mut i = q.iter(m).unwrap();
+        let q = Queue::default();
+        assert_eq!(q.next_used_slot_addr().unwrap_err(), Error::InvalidSize);
+    }
 
-        {
-            let c = i.next().unwrap();
-            assert_eq!(c.head_index(), 0);
+    #[test]
+    fn test_used_elem() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        q.set_next_used(15);
 
-            let mut iter = c;
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_none());
-            assert!(iter.next().is_none());
-        }
+        q.add_used(mem, 3, 0x100).unwrap();
+        q.add_used(mem, 4, 0x200).unwrap();
+        let elem = q.used_elem(mem, 15).unwrap();
+        assert_eq!(elem.id(), 3);
+        assert_eq!(elem.len(), 0x100);
+        let elem = q.used_elem(mem, 0).unwrap();
+        assert_eq!(elem.id(), 4);
+        assert_eq!(elem.len(), 0x200);
 
-        {
-            let c = i.next().unwrap();
-            assert_eq!(c.head_index(), 2);
+        assert_eq!(
+            q.used_elem(mem, 16).unwrap_err(),
+            Error::InvalidDescriptorIndex
+        );
+    }
 
-            let mut iter = c.writable();
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_none());
-            assert!(iter.next().is_none());
+    #[test]
+    fn test_export_avail_ring() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 4);
+        let q: Queue = vq.create_queue().unwrap();
+        assert_eq!(q.export_avail_ring(mem).unwrap(), [0, 0, 0, 0]);
+
+        for (i, head) in [3u16, 1, 2, 0].iter().enumerate() {
+            vq.avail()
+                .ring()
+                .ref_at(i)
+                .unwrap()
+                .store(u16::to_le(*head));
         }
+        // Only the first two entries are published, but the stale ones are returned as well.
+        vq.avail().idx().store(u16::to_le(2));
+        assert_eq!(q.export_avail_ring(mem).unwrap(), [3, 1, 2, 0]);
 
-        {
-            let c = i.next().unwrap();
-            assert_eq!(c.head_index(), 5);
-
-            let mut iter = c.readable();
-            assert!(iter.next().is_some());
-            assert!(iter.next().is_none());
-            assert!(iter.next().is_none());
-        }
+        let q = Queue::default();
+        assert!(q.export_avail_ring(mem).unwrap().is_empty());
     }
 
     #[test]
-    fn test_iterator() {
-        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let vq = MockSplitQueue::new(m, 16);
-
+    fn test_dump() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 4);
         let mut q: Queue = vq.create_queue().unwrap();
 
-        q.size = q.max_size;
-        q.desc_table = vq.desc_table_addr();
-        q.avail_ring = vq.avail_addr();
-        q.used_ring = vq.used_addr();
-        assert!(q.is_valid(m));
+        let descs = [
+            RawDescriptor::from(SplitDescriptor::new(0x1000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x2000, 0x100, 0, 0)),
+            RawDescriptor::from(SplitDescriptor::new(0x3000, 0x100, 0, 0)),
+        ];
+        vq.add_desc_chains(&descs, 0).unwrap();
+        let head = q.pop_descriptor_chain(mem).unwrap().head_index();
+        q.add_used(mem, head, 0x100).unwrap();
 
-        {
-            // an invalid queue should return an iterator with no next
-            q.ready = false;
-            assert!(q.iter(m).is_err());
-        }
+        assert_eq!(
+            q.dump(mem),
+            format!(
+                "desc table: 0x0, avail ring: 0x40, used ring: {:#x}, size: 4, ready: true\n\
+                 avail idx: 3, used idx: 1, next_avail: 1, next_used: 1\n\
+                 available heads: [1, 2]",
+                vq.used_addr().0
+            )
+        );
 
-        q.ready = true;
+        // The driver claims more chains than the queue size are available.
+        vq.avail().idx().store(u16::to_le(6));
+        assert!(q.dump(mem).ends_with(
+            "avail idx: 6, used idx: 1, next_avail: 1, next_used: 1\n\
+             available heads: ? (avail idx out of range)"
+        ));
+
+        // The rings are outside of guest memory.
+        q.try_set_avail_ring_address(GuestAddress(0x20000)).unwrap();
+        q.try_set_used_ring_address(GuestAddress(0x20000)).unwrap();
+        assert!(q.dump(mem).ends_with(
+            "avail idx: ?, used idx: ?, next_avail: 1, next_used: 1\n\
+             available heads: ?"
+        ));
+    }
 
-        // now let's create two simple descriptor chains
-        // the chains are (0, 1) and (2, 3, 4)
-        {
-            let mut descs = Vec::new();
-            for j in 0..5u16 {
-                let flags = match j {
-                    1 | 4 => 0,
-                    _ => VRING_DESC_F_NEXT,
-                };
+    #[test]
+    fn test_progress_token() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
 
-                descs.push(RawDescriptor::from(SplitDescriptor::new(
-                    (0x1000 * (j + 1)) as u64,
-                    0x1000,
-                    flags as u16,
-                    j + 1,
-                )));
-            }
-            vq.add_desc_chains(&descs, 0).unwrap();
+        let token = q.progress_token();
+        q.enable_notification(mem).unwrap();
+        q.needs_notification(mem).unwrap();
+        assert_eq!(q.progress_token(), token);
 
-            let mut i = q.iter(m).unwrap();
+        q.add_used(mem, 0, 0x100).unwrap();
+        assert_eq!(q.progress_token(), token + 1);
+        q.add_used_batch(mem, [(1, 0x100), (2, 0x100)]).unwrap();
+        assert_eq!(q.progress_token(), token + 3);
 
-            {
-                let mut c = i.next().unwrap();
-                assert_eq!(c.head_index(), 0);
+        // Failed completions don't count.
+        assert!(q.add_used(mem, 16, 0x100).is_err());
+        q.add_used_batch(mem, []).unwrap();
+        assert_eq!(q.progress_token(), token + 3);
 
-                c.next().unwrap();
-                assert!(c.next().is_some());
-                assert!(c.next().is_none());
-                assert_eq!(c.head_index(), 0);
-            }
+        q.reset();
+        assert_eq!(q.progress_token(), token + 3);
+    }
 
-            {
-                let mut c = i.next().unwrap();
-                assert_eq!(c.head_index(), 2);
+    #[test]
+    fn test_stats() {
+        let mem = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(mem, 16);
+        let mut q: Queue = vq.create_queue().unwrap();
+        assert_eq!(q.stats(), QueueStats::default());
 
-                c.next().unwrap();
-                c.next().unwrap();
-                c.next().unwrap();
-                assert!(c.next().is_none());
-                assert_eq!(c.head_index(), 2);
-            }
+        let descs: Vec<RawDescriptor> = (0..4)
+            .map(|i| RawDescriptor::from(SplitDescriptor::new(0x1000 * (i + 1), 0x100, 0, 0)))
+            .collect();
+        vq.add_desc_chains(&descs, 0).unwrap();
 
-            // also test go_to_previous_position() works as expected
-            {
-                assert!(i.next().is_none());
-                i.go_to_previous_position();
-                let mut c = q.iter(m).unwrap().next().unwrap();
-                c.next().unwrap();
-                c.next().unwrap();
-                c.next().unwrap();
-                assert!(c.next().is_none());
+        let head = q.pop_descriptor_chain(mem).unwrap().head_index();
+        q.add_used(mem, head, 0x100).unwrap();
+        // Peeking, or going back in the available ring, doesn't count as popping.
+        q.peek_descriptor_chain(mem).unwrap();
+        let mut iter = q.iter(mem).unwrap();
+        iter.next().unwrap();
+        iter.go_to_previous_position();
+        q.pop_descriptor_chain(mem).unwrap();
+        q.go_to_previous_position();
+        q.pop_descriptor_chain(mem).unwrap();
+        q.pop_descriptor_chain(mem).unwrap();
+        q.go_back(2).unwrap();
+        assert_eq!(q.stats().chains_popped, 1);
+        assert_eq!(q.pop_descriptor_chain(mem).unwrap().head_index(), 1);
+        q.pop_descriptor_chain(mem).unwrap();
+        q.add_used_batch(mem, [(1, 0x200), (2, 0x300)]).unwrap();
+        // Failed completions don't count.
+        assert!(q.add_used(mem, 16, 0x100).is_err());
+        assert_eq!(
+            q.stats(),
+            QueueStats {
+                chains_popped: 3,
+                used_entries: 3,
+                used_bytes: 0x600,
             }
-        }
+        );
 
-        // Test that iterating some broken descriptor chain does not exceed
-        // 2^32 bytes in total (VIRTIO spec version 1.2, 2.7.5.2:
-        // Drivers MUST NOT add a descriptor chain longer than 2^32 bytes in
-        // total)
-        {
-            let descs = vec![
-                RawDescriptor::from(SplitDescriptor::new(
-                    0x1000,
-                    0xffff_ffff,
-                    VRING_DESC_F_NEXT as u16,
-                    1,
-                )),
-                RawDescriptor::from(SplitDescriptor::new(0x1000, 0x1234_5678, 0, 2)),
-            ];
-            vq.add_desc_chains(&descs, 0).unwrap();
-            let mut yielded_bytes_by_iteration = 0_u32;
-            for d in q.iter(m).unwrap().next().unwrap() {
-                yielded_bytes_by_iteration = yielded_bytes_by_iteration
-                    .checked_add(d.len())
-                    .expect("iterator should not yield more than 2^32 bytes");
+        q.pop_descriptor_chain(mem).unwrap();
+        publish_all(&mut [(&mut q, &[(3, 0x400)][..])], mem).unwrap();
+        assert_eq!(
+            q.stats(),
+            QueueStats {
+                chains_popped: 4,
+                used_entries: 4,
+                used_bytes: 0xa00,
             }
-        }
+        );
 
-        // Same as above, but test with a descriptor which is self-referential
-        {
-            let descs = vec![RawDescriptor::from(SplitDescriptor::new(
-                0x1000,
-                0xffff_ffff,
-                VRING_DESC_F_NEXT as u16,
-                0,
-            ))];
-            vq.add_desc_chains(&descs, 0).unwrap();
-            let mut yielded_bytes_by_iteration = 0_u32;
-            for d in q.iter(m).unwrap().next().unwrap() {
-                yielded_bytes_by_iteration = yielded_bytes_by_iteration
-                    .checked_add(d.len())
-                    .expect("iterator should not yield more than 2^32 bytes");
-            }
-        }
+        // The counters are kept across a queue reset, until explicitly cleared.
+        q.reset();
+        assert_eq!(q.stats().chains_popped, 4);
+        q.reset_stats();
+        assert_eq!(q.stats(), QueueStats::default());
     }
 
     #[test]
-    fn test_regression_iterator_division() {
-        // This is a regression test that tests that the iterator does not try to divide
-        // by 0 when the queue size is 0
-        let m = &GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
-        let vq = MockSplitQueue::new(m, 1);
-        // This input was generated by the fuzzer, both for the QueueS and the Descriptor
-        let descriptors: Vec<RawDescriptor> = vec![RawDescriptor::from(SplitDescriptor::new(
-            14178673876262995140,
-            3301229764,
-            50372,
-            50372,
-        ))];
-        vq.build_desc_chain(&descriptors).unwrap();
-
-        let mut q = Queue {
-            max_size: 38,
-            next_avail: Wrapping(0),
-            next_used: Wrapping(0),
-            event_idx_enabled: false,
-            num_added: Wrapping(0),
-            size: 0,
-            ready: false,
-            desc_table: GuestAddress(12837708984796196),
-            avail_ring: GuestAddress(0),
-            used_ring: GuestAddress(9943947977301164032),
-        };
+    fn test_with_event_idx() {
+        let q = Queue::with_event_idx(16).unwrap();
+        assert!(q.event_idx_enabled());
+        assert_eq!(q.max_size(), 16);
+        assert_eq!(q.size(), 16);
+        assert!(!q.ready());
 
-        assert!(q.pop_descriptor_chain(m).is_none());
+        assert_eq!(
+            Queue::with_event_idx(15).unwrap_err(),
+            Error::InvalidMaxSize
+        );
     }
 
     #[test]
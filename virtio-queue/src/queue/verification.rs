@@ -248,9 +248,18 @@ fn verify_spec_2_7_7_2() {
         // After the device writes a descriptor index into the used ring:
         // – If flags is 1, the device SHOULD NOT send a notification.
         // – If flags is 0, the device MUST send a notification
-        // flags is the first field in the avail_ring_address, which we completely ignore. We
-        // always send a notification, and as there only is a SHOULD NOT, that is okay
-        assert!(needs_notification.unwrap());
+        // flags is the first field in the avail ring. We only send a notification when it is 0.
+        let flags = mem
+            .load::<u16>(queue.avail_ring, Ordering::Relaxed)
+            .map(u16::from_le);
+        if let Ok(flags) = flags {
+            assert_eq!(
+                needs_notification.unwrap(),
+                flags & VRING_AVAIL_F_NO_INTERRUPT as u16 == 0
+            );
+
+            kani::cover!();
+        }
     } else {
         // next_used - 1 is where the previous descriptor was placed
         if Wrapping(queue.used_event(&mem, Ordering::Relaxed).unwrap())
@@ -9,7 +9,7 @@ use std::sync::{Arc, Mutex, MutexGuard};
 
 use vm_memory::GuestMemory;
 
-use crate::{DescriptorChain, Error, Queue, QueueGuard, QueueT};
+use crate::{DescriptorChain, Error, Queue, QueueGuard, QueueT, RingLayout};
 
 /// Struct to maintain information and manipulate state of a virtio queue for multi-threaded
 /// context.
@@ -68,6 +68,10 @@ impl QueueT for QueueSync {
         self.lock_state().reset();
     }
 
+    fn layout(&self) -> RingLayout {
+        RingLayout::Split
+    }
+
     fn lock(&mut self) -> <Self as QueueGuard<'_>>::G {
         self.lock_state()
     }
@@ -108,14 +112,19 @@ impl QueueT for QueueSync {
         self.lock_state().set_event_idx(enabled);
     }
 
-    fn avail_idx<M>(&self, mem: &M, order: Ordering) -> Result<Wrapping<u16>, Error>
+    fn avail_idx<M>(&self, mem: M, order: Ordering) -> Result<Wrapping<u16>, Error>
     where
-        M: GuestMemory + ?Sized,
+        M: Deref,
+        M::Target: GuestMemory,
     {
         self.lock_state().avail_idx(mem, order)
     }
 
-    fn used_idx<M: GuestMemory>(&self, mem: &M, order: Ordering) -> Result<Wrapping<u16>, Error> {
+    fn used_idx<M>(&self, mem: M, order: Ordering) -> Result<Wrapping<u16>, Error>
+    where
+        M: Deref,
+        M::Target: GuestMemory,
+    {
         self.lock_state().used_idx(mem, order)
     }
 
@@ -128,6 +137,14 @@ impl QueueT for QueueSync {
         self.lock_state().add_used(mem, head_index, len)
     }
 
+    fn add_used_batch<M, I>(&mut self, mem: &M, used: I) -> Result<(), Error>
+    where
+        M: GuestMemory,
+        I: IntoIterator<Item = (u16, u32)>,
+    {
+        self.lock_state().add_used_batch(mem, used)
+    }
+
     fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
         self.lock_state().enable_notification(mem)
     }
@@ -179,6 +196,14 @@ impl QueueT for QueueSync {
     {
         self.lock_state().pop_descriptor_chain(mem)
     }
+
+    fn peek_descriptor_chain<M>(&mut self, mem: M) -> Option<DescriptorChain<M>>
+    where
+        M: Clone + Deref,
+        M::Target: GuestMemory,
+    {
+        self.lock_state().peek_descriptor_chain(mem)
+    }
 }
 
 #[cfg(test)]
@@ -12,6 +12,7 @@ use vm_memory::GuestAddress;
 /// As this structure has all the fields public it is consider to be untrusted. A validated
 /// queue can be created from the state by calling the associated `try_from` function.
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct QueueState {
     /// The maximum size in elements offered by the device.
     pub max_size: u16,
@@ -33,10 +34,171 @@ pub struct QueueState {
     pub used_ring: u64,
 }
 
-impl TryFrom<QueueState> for Queue {
-    type Error = Error;
+impl QueueState {
+    /// Return a copy of the state with the ring positions (`next_avail` and `next_used`) reset
+    /// to zero, and everything else, like the size and the ring addresses, preserved.
+    ///
+    /// This is useful for re-creating a queue with the same configuration from scratch.
+    /// Note that the number of used elements added since the last notification isn't part of
+    /// the state, and always starts from zero in a restored `Queue`.
+    pub fn with_reset_positions(&self) -> QueueState {
+        QueueState {
+            next_avail: 0,
+            next_used: 0,
+            ..*self
+        }
+    }
 
-    fn try_from(q_state: QueueState) -> Result<Self, Self::Error> {
+    /// Convert the state to its C-compatible representation.
+    pub fn to_ffi(&self) -> QueueStateFfi {
+        QueueStateFfi {
+            desc_table: self.desc_table,
+            avail_ring: self.avail_ring,
+            used_ring: self.used_ring,
+            max_size: self.max_size,
+            next_avail: self.next_avail,
+            next_used: self.next_used,
+            size: self.size,
+            event_idx_enabled: u8::from(self.event_idx_enabled),
+            ready: u8::from(self.ready),
+        }
+    }
+
+    /// Create a state from its C-compatible representation.
+    ///
+    /// Any non-zero value is accepted as `true` for the boolean fields. Like any `QueueState`,
+    /// the result is untrusted until a `Queue` is created from it.
+    pub fn from_ffi(ffi: &QueueStateFfi) -> QueueState {
+        QueueState {
+            max_size: ffi.max_size,
+            next_avail: ffi.next_avail,
+            next_used: ffi.next_used,
+            event_idx_enabled: ffi.event_idx_enabled != 0,
+            size: ffi.size,
+            ready: ffi.ready != 0,
+            desc_table: ffi.desc_table,
+            avail_ring: ffi.avail_ring,
+            used_ring: ffi.used_ring,
+        }
+    }
+
+    /// Convert the state to its compact representation, with the addresses of the rings stored
+    /// as offsets from the descriptor table.
+    ///
+    /// `Error::AddressOverflow` is returned if one of the rings is located before the
+    /// descriptor table, or more than `u32::MAX` bytes after it.
+    pub fn to_compact(&self) -> Result<CompactQueueState, Error> {
+        let offset = |addr: u64| {
+            addr.checked_sub(self.desc_table)
+                .and_then(|offset| u32::try_from(offset).ok())
+                .ok_or(Error::AddressOverflow)
+        };
+
+        Ok(CompactQueueState {
+            base: self.desc_table,
+            avail_offset: offset(self.avail_ring)?,
+            used_offset: offset(self.used_ring)?,
+            max_size: self.max_size,
+            next_avail: self.next_avail,
+            next_used: self.next_used,
+            size: self.size,
+            event_idx_enabled: self.event_idx_enabled,
+            ready: self.ready,
+        })
+    }
+
+    /// Create a state from its compact representation.
+    ///
+    /// `Error::AddressOverflow` is returned if the address of a ring doesn't fit in an `u64`.
+    /// Like any `QueueState`, the result is untrusted until a `Queue` is created from it.
+    pub fn from_compact(compact: &CompactQueueState) -> Result<QueueState, Error> {
+        let addr = |offset: u32| {
+            compact
+                .base
+                .checked_add(u64::from(offset))
+                .ok_or(Error::AddressOverflow)
+        };
+
+        Ok(QueueState {
+            max_size: compact.max_size,
+            next_avail: compact.next_avail,
+            next_used: compact.next_used,
+            event_idx_enabled: compact.event_idx_enabled,
+            size: compact.size,
+            ready: compact.ready,
+            desc_table: compact.base,
+            avail_ring: addr(compact.avail_offset)?,
+            used_ring: addr(compact.used_offset)?,
+        })
+    }
+}
+
+/// Compact representation of [`QueueState`], for saving the state of many queues.
+///
+/// The rings of a queue are usually laid out right after each other, so the addresses of the
+/// available and used rings are stored as 32-bit offsets from the descriptor table instead of
+/// full 64-bit addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompactQueueState {
+    /// Guest physical address of the descriptor table.
+    pub base: u64,
+    /// Offset of the available ring from the descriptor table.
+    pub avail_offset: u32,
+    /// Offset of the used ring from the descriptor table.
+    pub used_offset: u32,
+    /// The maximum size in elements offered by the device.
+    pub max_size: u16,
+    /// Tail position of the available ring.
+    pub next_avail: u16,
+    /// Head position of the used ring.
+    pub next_used: u16,
+    /// The queue size in elements the driver selected.
+    pub size: u16,
+    /// VIRTIO_F_RING_EVENT_IDX negotiated.
+    pub event_idx_enabled: bool,
+    /// Indicates if the queue is finished with configuration.
+    pub ready: bool,
+}
+
+/// C-compatible representation of [`QueueState`], for passing the state of a queue across an FFI
+/// boundary.
+///
+/// All the fields are plain integers, with the boolean fields of `QueueState` stored as `0` or
+/// `1`. The fields are ordered by size, so there is no padding between them.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStateFfi {
+    /// Guest physical address of the descriptor table.
+    pub desc_table: u64,
+    /// Guest physical address of the available ring.
+    pub avail_ring: u64,
+    /// Guest physical address of the used ring.
+    pub used_ring: u64,
+    /// The maximum size in elements offered by the device.
+    pub max_size: u16,
+    /// Tail position of the available ring.
+    pub next_avail: u16,
+    /// Head position of the used ring.
+    pub next_used: u16,
+    /// The queue size in elements the driver selected.
+    pub size: u16,
+    /// VIRTIO_F_RING_EVENT_IDX negotiated.
+    pub event_idx_enabled: u8,
+    /// Indicates if the queue is finished with configuration.
+    pub ready: u8,
+}
+
+impl Queue {
+    /// Create a `Queue` from a previously saved `QueueState`.
+    ///
+    /// The state is validated with the same checks the setters apply (`max_size`, `size` and
+    /// the alignment of the ring addresses), so a corrupted snapshot is rejected instead of
+    /// producing a queue that can't be operated. On top of that, `next_avail` can't be ahead of
+    /// `next_used` by more than `size` positions, since the device can't have more chains in
+    /// flight than the ring holds; `Error::InconsistentRestore` is returned otherwise.
+    /// Equivalent to `Queue::try_from(state)`.
+    pub fn try_from_state(q_state: QueueState) -> Result<Self, Error> {
         let mut q = Queue::new(q_state.max_size)?;
 
         q.set_next_avail(q_state.next_avail);
@@ -48,10 +210,22 @@ impl TryFrom<QueueState> for Queue {
         q.try_set_avail_ring_address(GuestAddress(q_state.avail_ring))?;
         q.try_set_used_ring_address(GuestAddress(q_state.used_ring))?;
 
+        if q_state.next_avail.wrapping_sub(q_state.next_used) > q_state.size {
+            return Err(Error::InconsistentRestore);
+        }
+
         Ok(q)
     }
 }
 
+impl TryFrom<QueueState> for Queue {
+    type Error = Error;
+
+    fn try_from(q_state: QueueState) -> Result<Self, Self::Error> {
+        Queue::try_from_state(q_state)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -73,6 +247,43 @@ mod tests {
         assert_eq!(queue, restored_q);
     }
 
+    #[test]
+    fn test_queue_state_round_trip() {
+        let mut queue = Queue::new(16).unwrap();
+        queue.set_size(8);
+        queue.set_ready(true);
+        queue.set_event_idx(true);
+        queue.set_next_avail(5);
+        queue.set_next_used(3);
+        queue
+            .try_set_desc_table_address(GuestAddress(0x1000))
+            .unwrap();
+        queue
+            .try_set_avail_ring_address(GuestAddress(0x2000))
+            .unwrap();
+        queue
+            .try_set_used_ring_address(GuestAddress(0x3000))
+            .unwrap();
+
+        let q_state = queue.state();
+        assert_eq!(q_state.size, 8);
+        assert_eq!(q_state.next_avail, 5);
+        assert_eq!(q_state.next_used, 3);
+        assert_eq!(q_state.desc_table, 0x1000);
+        assert_eq!(q_state.avail_ring, 0x2000);
+        assert_eq!(q_state.used_ring, 0x3000);
+
+        let restored_q = Queue::try_from_state(q_state).unwrap();
+        assert_eq!(restored_q.state(), q_state);
+
+        // A size that is not a power of two is rejected.
+        let q_state = QueueState { size: 6, ..q_state };
+        assert_eq!(
+            Queue::try_from_state(q_state).unwrap_err(),
+            Error::InvalidSize
+        );
+    }
+
     #[test]
     fn test_invalid_queue_state() {
         // Let's generate a state that we know is valid so we can just alter one field at a time.
@@ -116,4 +327,164 @@ mod tests {
         q_state.used_ring = 0x3;
         assert!(Queue::try_from(q_state).is_err());
     }
+
+    #[test]
+    fn test_queue_state_ffi() {
+        let q_state = QueueState {
+            max_size: 256,
+            next_avail: 7,
+            next_used: 5,
+            event_idx_enabled: true,
+            size: 128,
+            ready: true,
+            desc_table: 0x1000,
+            avail_ring: 0x2000,
+            used_ring: 0x3000,
+        };
+
+        let ffi = q_state.to_ffi();
+        assert_eq!(ffi.event_idx_enabled, 1);
+        assert_eq!(ffi.ready, 1);
+        assert_eq!(QueueState::from_ffi(&ffi), q_state);
+        assert_eq!(
+            QueueState::from_ffi(&QueueStateFfi::default()),
+            QueueState::default()
+        );
+        assert_eq!(std::mem::size_of::<QueueStateFfi>(), 40);
+
+        // The restored state is still validated when creating a queue out of it.
+        let ffi = QueueStateFfi { size: 3, ..ffi };
+        assert_eq!(
+            Queue::try_from(QueueState::from_ffi(&ffi)).unwrap_err(),
+            Error::InvalidSize
+        );
+    }
+
+    #[test]
+    fn test_compact_queue_state() {
+        let mut queue = Queue::new(256).unwrap();
+        queue.set_ready(true);
+        queue.set_next_avail(7);
+        queue.set_next_used(5);
+        queue
+            .configure(
+                128,
+                GuestAddress(0x10_0000_0000),
+                GuestAddress(0x10_0000_0800),
+                GuestAddress(0x10_0000_1000),
+                true,
+            )
+            .unwrap();
+        let q_state = queue.state();
+
+        let compact = q_state.to_compact().unwrap();
+        assert_eq!(compact.base, 0x10_0000_0000);
+        assert_eq!(compact.avail_offset, 0x800);
+        assert_eq!(compact.used_offset, 0x1000);
+        assert_eq!(QueueState::from_compact(&compact).unwrap(), q_state);
+        assert!(std::mem::size_of::<CompactQueueState>() < std::mem::size_of::<QueueState>());
+        let restored_q =
+            Queue::try_from_state(QueueState::from_compact(&compact).unwrap()).unwrap();
+        assert_eq!(restored_q, queue);
+
+        // The rings must follow the descriptor table, and be close enough to it.
+        let q_state = QueueState {
+            avail_ring: 0x0f_ffff_f000,
+            ..queue.state()
+        };
+        assert_eq!(q_state.to_compact().unwrap_err(), Error::AddressOverflow);
+        let q_state = QueueState {
+            used_ring: 0x11_0000_0000,
+            ..queue.state()
+        };
+        assert_eq!(q_state.to_compact().unwrap_err(), Error::AddressOverflow);
+
+        let compact = CompactQueueState {
+            base: u64::MAX,
+            used_offset: 1,
+            ..compact
+        };
+        assert_eq!(
+            QueueState::from_compact(&compact).unwrap_err(),
+            Error::AddressOverflow
+        );
+    }
+
+    #[test]
+    fn test_with_reset_positions() {
+        let mut q_state = create_valid_queue_state();
+        q_state.size = 8;
+        q_state.ready = true;
+        q_state.event_idx_enabled = true;
+        q_state.next_avail = 7;
+        q_state.next_used = 2;
+        q_state.desc_table = 0x1000;
+        q_state.avail_ring = 0x2000;
+        q_state.used_ring = 0x3000;
+
+        let reset_state = q_state.with_reset_positions();
+        assert_eq!(reset_state.next_avail, 0);
+        assert_eq!(reset_state.next_used, 0);
+        assert_eq!(
+            reset_state,
+            QueueState {
+                next_avail: 0,
+                next_used: 0,
+                ..q_state
+            }
+        );
+        // The original state is left untouched.
+        assert_eq!(q_state.next_avail, 7);
+
+        let q = Queue::try_from_state(reset_state).unwrap();
+        assert_eq!(q.desc_table(), 0x1000);
+        assert_eq!(q.avail_ring(), 0x2000);
+        assert_eq!(q.used_ring(), 0x3000);
+        assert_eq!(q.size(), 8);
+        assert_eq!(q.next_avail(), 0);
+        assert_eq!(q.next_used(), 0);
+    }
+
+    #[test]
+    fn test_inconsistent_restore() {
+        let mut q_state = create_valid_queue_state();
+        q_state.size = 8;
+
+        // Exactly `size` chains in flight is fine, including across the u16 wrap.
+        q_state.next_avail = 10;
+        q_state.next_used = 2;
+        assert!(Queue::try_from_state(q_state).is_ok());
+        q_state.next_avail = 3;
+        q_state.next_used = u16::MAX - 4;
+        assert!(Queue::try_from_state(q_state).is_ok());
+
+        // One more than `size` in flight.
+        q_state.next_avail = 11;
+        q_state.next_used = 2;
+        assert_eq!(
+            Queue::try_from_state(q_state).unwrap_err(),
+            Error::InconsistentRestore
+        );
+
+        // `next_used` ahead of `next_avail` wraps to a huge in-flight count.
+        q_state.next_avail = 2;
+        q_state.next_used = 3;
+        assert_eq!(
+            Queue::try_from(q_state).unwrap_err(),
+            Error::InconsistentRestore
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_queue_state_serde() {
+        let mut q_state = create_valid_queue_state();
+        q_state.next_avail = 3;
+        q_state.next_used = 1;
+        q_state.desc_table = 0x1000;
+
+        let json = serde_json::to_string(&q_state).unwrap();
+        let restored: QueueState = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, q_state);
+    }
 }